@@ -0,0 +1,267 @@
+use std::fmt;
+use chrono::{ Datelike, Duration, NaiveDate };
+
+use crate::decimal::Decimal;
+use crate::readings::DatedChange;
+use crate::units::KilowattHours;
+
+/// Generation below this fraction of the seasonal norm is a drop.
+const DROP_FRACTION: Decimal = Decimal::new(5, 1);
+
+/// Imports above this multiple of the seasonal norm are a spike.
+const SPIKE_FACTOR: Decimal = Decimal::new(2, 0);
+
+/// How many other periods in the same month are needed
+/// before we trust the seasonal norm.
+const MIN_SAMPLES: usize = 2;
+
+/// The kinds of problem we look for in the readings.
+#[derive(Debug, PartialEq)]
+pub enum AnomalyKind {
+    /// Nothing was generated, the inverter may have tripped.
+    ZeroGeneration,
+    /// Daily generation well below the norm for the time of year.
+    GenerationDrop { expected: KilowattHours, actual: KilowattHours },
+    /// Daily imports well above the norm for the time of year.
+    ImportSpike { expected: KilowattHours, actual: KilowattHours },
+    /// More was exported than generated, which can't happen.
+    ExportsExceedGeneration { generation: KilowattHours, exports: KilowattHours },
+}
+
+/// A suspicious period between two readings.
+#[derive(Debug, PartialEq)]
+pub struct Anomaly {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub kind: AnomalyKind,
+}
+
+/// Allow an Anomaly to be passed to println!() etc.
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.start.format("%d/%m/%Y");
+        let end = self.end.format("%d/%m/%Y");
+        match self.kind {
+            AnomalyKind::ZeroGeneration =>
+                write!(f, "{} to {}: no generation", start, end),
+            AnomalyKind::GenerationDrop { expected, actual } =>
+                write!(f, "{} to {}: generation {:.2} kWh/day, expected about {:.2}",
+                       start, end, actual, expected),
+            AnomalyKind::ImportSpike { expected, actual } =>
+                write!(f, "{} to {}: imports {:.2} kWh/day, expected about {:.2}",
+                       start, end, actual, expected),
+            AnomalyKind::ExportsExceedGeneration { generation, exports } =>
+                write!(f, "{} to {}: exports {:.2} kWh/day exceed generation {:.2}",
+                       start, end, exports, generation),
+        }
+    }
+}
+
+// The month a period mostly falls in.
+fn month_of(change: &DatedChange) -> u32 {
+    let half = change.end.signed_duration_since(change.start).num_days() / 2;
+    (change.start + Duration::days(half)).month()
+}
+
+// Average daily (generation, imports) over every other period in the
+// same month, weighted by the number of days. Periods marked in
+// `left_out` are ignored. None if too few samples.
+fn seasonal_norm(changes: &[DatedChange], index: usize,
+                 left_out: &[bool]) -> Option<(KilowattHours, KilowattHours)> {
+    let month = month_of(&changes[index]);
+    let others: Vec<&DatedChange> = changes.iter()
+        .enumerate()
+        .filter(|(i, change)| *i != index && !left_out[*i] && month_of(change) == month)
+        .map(|(_, change)| change)
+        .collect();
+
+    if others.len() < MIN_SAMPLES {
+        return None;
+    }
+
+    let days: i64 = others.iter().map(|c| c.days_spanned()).sum();
+    let generation: KilowattHours = others.iter()
+        .map(|c| c.change.generation * c.days_spanned())
+        .sum();
+    let imports: KilowattHours = others.iter()
+        .map(|c| c.change.imports * c.days_spanned())
+        .sum();
+    Some((generation / days, imports / days))
+}
+
+// Generation drops and import spikes against the seasonal norm.
+fn departures(dated: &DatedChange, norm: Option<(KilowattHours, KilowattHours)>) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    let change = &dated.change;
+
+    if let Some((generation, imports)) = norm {
+        if change.generation > KilowattHours::ZERO && change.generation < generation * DROP_FRACTION {
+            anomalies.push(Anomaly {
+                start: dated.start,
+                end: dated.end,
+                kind: AnomalyKind::GenerationDrop {
+                    expected: generation,
+                    actual: change.generation,
+                },
+            });
+        }
+
+        if imports > KilowattHours::ZERO && change.imports > imports * SPIKE_FACTOR {
+            anomalies.push(Anomaly {
+                start: dated.start,
+                end: dated.end,
+                kind: AnomalyKind::ImportSpike {
+                    expected: imports,
+                    actual: change.imports,
+                },
+            });
+        }
+    }
+
+    anomalies
+}
+
+/// Look through a series of changes, sorted by date, for
+/// anything that suggests a fault with the inverter or meter.
+pub fn detect_anomalies(changes: &[DatedChange]) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    let mut zero_span: Option<(NaiveDate, NaiveDate)> = None;
+
+    // Faulty periods would drag the norm towards themselves and hide
+    // the next fault, so periods that are plainly wrong are left out
+    // of it, then so are any that stand out from what's left.
+    let impossible: Vec<bool> = changes.iter()
+        .map(|dated| dated.change.generation <= KilowattHours::ZERO
+                     || dated.change.exports > dated.change.generation)
+        .collect();
+    let left_out: Vec<bool> = changes.iter()
+        .enumerate()
+        .map(|(index, dated)| {
+            impossible[index] || !departures(dated, seasonal_norm(changes, index, &impossible)).is_empty()
+        })
+        .collect();
+
+    for (index, dated) in changes.iter().enumerate() {
+        let change = &dated.change;
+
+        // Join consecutive periods with no generation into one span.
+        if change.generation <= KilowattHours::ZERO {
+            zero_span = match zero_span {
+                Some((start, _)) => Some((start, dated.end)),
+                None => Some((dated.start, dated.end)),
+            };
+        } else if let Some((start, end)) = zero_span.take() {
+            anomalies.push(Anomaly { start, end, kind: AnomalyKind::ZeroGeneration });
+        }
+
+        if change.exports > change.generation {
+            anomalies.push(Anomaly {
+                start: dated.start,
+                end: dated.end,
+                kind: AnomalyKind::ExportsExceedGeneration {
+                    generation: change.generation,
+                    exports: change.exports,
+                },
+            });
+        }
+
+        anomalies.extend(departures(dated, seasonal_norm(changes, index, &left_out)));
+    }
+
+    if let Some((start, end)) = zero_span {
+        anomalies.push(Anomaly { start, end, kind: AnomalyKind::ZeroGeneration });
+    }
+
+    anomalies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::kwh;
+    use crate::readings::DiurnalChange;
+
+    fn change(day: u32, generation: f64, exports: f64, imports: f64) -> DatedChange {
+        DatedChange {
+            start: NaiveDate::from_ymd(2019, 3, day),
+            end: NaiveDate::from_ymd(2019, 3, day + 1),
+            change: DiurnalChange {
+                generation: kwh(generation),
+                exports: kwh(exports),
+                imports: kwh(imports),
+            },
+            estimated: false,
+        }
+    }
+
+    #[test]
+    fn normal_changes_ok() {
+        let changes = vec![change(1, 20.0, 10.0, 5.0),
+                           change(2, 22.0, 11.0, 6.0),
+                           change(3, 18.0, 9.0, 5.0)];
+        assert!(detect_anomalies(&changes).is_empty());
+    }
+
+    #[test]
+    fn zero_generation_span() {
+        let changes = vec![change(1, 20.0, 10.0, 5.0),
+                           change(2, 0.0, 0.0, 6.0),
+                           change(3, 0.0, 0.0, 6.0),
+                           change(4, 20.0, 10.0, 5.0)];
+        let anomalies = detect_anomalies(&changes);
+        assert!(anomalies.contains(&Anomaly {
+            start: NaiveDate::from_ymd(2019, 3, 2),
+            end: NaiveDate::from_ymd(2019, 3, 4),
+            kind: AnomalyKind::ZeroGeneration,
+        }));
+    }
+
+    #[test]
+    fn generation_drop() {
+        let changes = vec![change(1, 20.0, 10.0, 5.0),
+                           change(2, 20.0, 10.0, 5.0),
+                           change(3, 5.0, 1.0, 5.0)];
+        let anomalies = detect_anomalies(&changes);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind,
+                   AnomalyKind::GenerationDrop { expected: kwh(20.0), actual: kwh(5.0) });
+    }
+
+    #[test]
+    fn faults_left_out_of_norm() {
+        // An outage and an earlier drop would otherwise pull
+        // the norm down far enough to hide the later drop.
+        let changes = vec![change(1, 20.0, 10.0, 5.0),
+                           change(2, 20.0, 10.0, 5.0),
+                           change(3, 0.0, 0.0, 5.0),
+                           change(4, 4.0, 1.0, 5.0),
+                           change(5, 8.0, 2.0, 5.0)];
+        let drops: Vec<NaiveDate> = detect_anomalies(&changes).iter()
+            .filter(|anomaly| matches!(anomaly.kind, AnomalyKind::GenerationDrop { .. }))
+            .map(|anomaly| anomaly.start)
+            .collect();
+        assert_eq!(drops, vec![NaiveDate::from_ymd(2019, 3, 4), NaiveDate::from_ymd(2019, 3, 5)]);
+    }
+
+    #[test]
+    fn import_spike() {
+        let changes = vec![change(1, 20.0, 10.0, 5.0),
+                           change(2, 20.0, 10.0, 5.0),
+                           change(3, 20.0, 10.0, 15.0)];
+        let anomalies = detect_anomalies(&changes);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind,
+                   AnomalyKind::ImportSpike { expected: kwh(5.0), actual: kwh(15.0) });
+    }
+
+    #[test]
+    fn exports_exceed_generation() {
+        let changes = vec![change(1, 10.0, 12.0, 5.0)];
+        let anomalies = detect_anomalies(&changes);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].kind, AnomalyKind::ExportsExceedGeneration {
+            generation: kwh(10.0),
+            exports: kwh(12.0),
+        });
+    }
+}
@@ -0,0 +1,441 @@
+use chrono::NaiveDate;
+
+use crate::calc::{ calculate, Tariffs };
+use crate::database::Database;
+use crate::decimal::Decimal;
+use crate::http::{ Request, Response };
+use crate::json::{ calculation_json, parse_json, reading_json, Json };
+use crate::readings::{ find_change, pair_for_period, Reading, ReadingPair, Source };
+use crate::units::{ Dollars, DollarsPerKwh };
+
+const JSON_CONTENT: &str = "application/json";
+
+// A JSON response.
+fn json(status: u16, body: &Json) -> Response {
+    Response::new(status, JSON_CONTENT, body.to_string())
+}
+
+// A JSON response explaining what went wrong.
+fn error(status: u16, message: &str) -> Response {
+    json(status, &Json::object(vec![("error", message.into())]))
+}
+
+// Read a YYYY-MM-DD date from a path or query string.
+fn parse_date(text: &str) -> Result<NaiveDate, Response> {
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map_err(|_e| error(400, &format!("{} is not a YYYY-MM-DD date", text)))
+}
+
+// An optional date from the query string.
+fn query_date(request: &Request, name: &str) -> Result<Option<NaiveDate>, Response> {
+    request.query_param(name).map(|text| parse_date(&text)).transpose()
+}
+
+// The body of the request as a JSON object.
+fn parse_body(request: &Request) -> Result<Json, Response> {
+    match parse_json(&request.body) {
+        Ok(body @ Json::Object(_)) => Ok(body),
+        Ok(_) => Err(error(422, "expected a JSON object")),
+        Err(e) => Err(error(400, &format!("bad JSON {}", e))),
+    }
+}
+
+// A number member of an object that must be there, read
+// straight from its digits so nothing is lost to floats.
+fn number<T: From<Decimal>>(body: &Json, name: &str) -> Result<T, Response> {
+    match body.get(name).and_then(|value| value.as_decimal()) {
+        Some(value) => Ok(value.into()),
+        None => Err(error(422, &format!("{} must be a number", name))),
+    }
+}
+
+// A reading from a request body. If the date is already
+// known from the path the body may leave it out.
+fn reading_from_body(request: &Request, date: Option<NaiveDate>) -> Result<Reading, Response> {
+    let body = parse_body(request)?;
+    let date = match (body.get("date").map(|value| value.as_str()), date) {
+        (None, Some(date)) => date,
+        (Some(Some(text)), None) => parse_date(text)?,
+        (Some(Some(text)), Some(date)) if parse_date(text)? == date => date,
+        (Some(Some(_)), Some(_)) => return Err(error(422, "date doesn't match the path")),
+        _ => return Err(error(422, "date must be a YYYY-MM-DD string")),
+    };
+
+    let source = match body.get("source").map(|value| value.as_str()) {
+        None => Source::Actual,
+        Some(Some(text)) => text.parse().map_err(|e: String| error(422, &e))?,
+        Some(None) => return Err(error(422, "source must be a string")),
+    };
+    let note = match body.get("note").map(|value| value.as_str()) {
+        None => String::new(),
+        Some(Some(text)) => text.to_string(),
+        Some(None) => return Err(error(422, "note must be a string")),
+    };
+
+    Ok(Reading {
+        date,
+        generation: number(&body, "generation")?,
+        exports: number(&body, "exports")?,
+        imports: number(&body, "imports")?,
+        source,
+        note,
+    })
+}
+
+// The readings from the query string's dates, both inclusive.
+fn readings_in_range(readings: Vec<Reading>, request: &Request) -> Result<Vec<Reading>, Response> {
+    let from = query_date(request, "from")?;
+    let to = query_date(request, "to")?;
+    Ok(readings.into_iter()
+        .filter(|reading| from.is_none_or(|from| reading.date >= from))
+        .filter(|reading| to.is_none_or(|to| reading.date <= to))
+        .collect())
+}
+
+/// A JSON API over the readings for use on the local network.
+///
+/// ```text
+/// GET    /readings[?from=DATE&to=DATE]
+/// POST   /readings               {"date", "generation", "exports", "imports", "source", "note"}
+/// GET    /readings/DATE
+/// PUT    /readings/DATE          {"generation", "exports", "imports", "source", "note"}
+/// DELETE /readings/DATE
+/// GET    /tariffs
+/// PUT    /tariffs                {"import", "export"}
+/// GET    /calculation[?from=DATE&to=DATE]
+/// GET    /report[?from=DATE&to=DATE]
+/// ```
+pub struct Api {
+    pub db: Database,
+    /// If set, requests must have an "Authorization: Bearer TOKEN" header.
+    pub token: Option<String>,
+    /// Used until tariffs are stored through the API.
+    pub default_tariffs: Tariffs,
+}
+
+impl Api {
+    /// Answer a request.
+    pub fn handle(&self, request: &Request) -> Response {
+        if let Some(token) = &self.token {
+            let expected = format!("Bearer {}", token);
+            if request.header("authorization") != Some(expected.as_str()) {
+                return error(401, "a valid token is needed");
+            }
+        }
+
+        let segments: Vec<&str> = request.path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let method = request.method.as_str();
+
+        let result = match (method, segments.as_slice()) {
+            ("GET", ["readings"]) => self.list_readings(request),
+            ("POST", ["readings"]) => self.add_reading(request),
+            ("GET", ["readings", date]) => self.get_reading(date),
+            ("PUT", ["readings", date]) => self.update_reading(request, date),
+            ("DELETE", ["readings", date]) => self.delete_reading(date),
+            ("GET", ["tariffs"]) => Ok(self.get_tariffs()),
+            ("PUT", ["tariffs"]) => self.set_tariffs(request),
+            ("GET", ["calculation"]) => self.calculation(request),
+            ("GET", ["report"]) => self.report(request),
+            (_, ["readings"]) | (_, ["readings", _]) | (_, ["tariffs"])
+                | (_, ["calculation"]) | (_, ["report"]) =>
+                Err(error(405, &format!("{} is not allowed here", method))),
+            _ => Err(error(404, "no such resource")),
+        };
+
+        result.unwrap_or_else(|response| response)
+    }
+
+    // The stored tariffs, or the defaults if there aren't any.
+    fn tariffs(&self) -> Tariffs {
+        self.db.stored_tariffs().unwrap_or_else(|| self.default_tariffs.clone())
+    }
+
+    // Save a reading if it fits between the readings either side of it.
+    fn save(&self, reading: &Reading) -> Result<(), Response> {
+        self.db.save_reading(reading).map_err(|e| error(422, &e))
+    }
+
+    fn list_readings(&self, request: &Request) -> Result<Response, Response> {
+        let readings = readings_in_range(self.db.all_readings(), request)?;
+        Ok(json(200, &Json::Array(readings.iter().map(reading_json).collect())))
+    }
+
+    fn add_reading(&self, request: &Request) -> Result<Response, Response> {
+        let reading = reading_from_body(request, None)?;
+        if self.db.get_reading_for_date(reading.date).is_some() {
+            return Err(error(409, "there is already a reading for that date"));
+        }
+        self.save(&reading)?;
+        Ok(json(201, &reading_json(&reading)))
+    }
+
+    fn get_reading(&self, date: &str) -> Result<Response, Response> {
+        match self.db.get_reading_for_date(parse_date(date)?) {
+            Some(reading) => Ok(json(200, &reading_json(&reading))),
+            None => Err(error(404, "no reading for that date")),
+        }
+    }
+
+    fn update_reading(&self, request: &Request, date: &str) -> Result<Response, Response> {
+        let date = parse_date(date)?;
+        if self.db.get_reading_for_date(date).is_none() {
+            return Err(error(404, "no reading for that date"));
+        }
+        let reading = reading_from_body(request, Some(date))?;
+        self.save(&reading)?;
+        Ok(json(200, &reading_json(&reading)))
+    }
+
+    fn delete_reading(&self, date: &str) -> Result<Response, Response> {
+        if self.db.delete_reading(parse_date(date)?) {
+            Ok(Response::new(204, JSON_CONTENT, String::new()))
+        } else {
+            Err(error(404, "no reading for that date"))
+        }
+    }
+
+    fn get_tariffs(&self) -> Response {
+        let tariffs = self.tariffs();
+        json(200, &Json::object(vec![
+            ("import", tariffs.import.into()),
+            ("export", tariffs.export.into()),
+        ]))
+    }
+
+    fn set_tariffs(&self, request: &Request) -> Result<Response, Response> {
+        let body = parse_body(request)?;
+        let import: DollarsPerKwh = number(&body, "import")?;
+        let export: DollarsPerKwh = number(&body, "export")?;
+        let rates = DollarsPerKwh::ZERO..=DollarsPerKwh::from(10);
+        if !rates.contains(&import) || !rates.contains(&export) {
+            return Err(error(422, "rates must be between 0 and 10 dollars per kWh"));
+        }
+        self.db.store_tariffs(import, export);
+        Ok(self.get_tariffs())
+    }
+
+    // The daily averages between the readings nearest the dates,
+    // or between the last two readings.
+    fn calculation(&self, request: &Request) -> Result<Response, Response> {
+        let readings = self.db.all_readings();
+        let pair = match (query_date(request, "from")?, query_date(request, "to")?) {
+            (Some(from), Some(to)) => pair_for_period(&readings, from, to),
+            (None, None) if readings.len() >= 2 => {
+                let last = readings.len() - 1;
+                pair_for_period(&readings, readings[last - 1].date, readings[last].date)
+            },
+            (None, None) => None,
+            _ => return Err(error(400, "give both from and to, or neither")),
+        };
+
+        let pair = pair.ok_or_else(|| error(404, "not enough readings near those dates"))?;
+        let change = find_change(&pair)
+            .ok_or_else(|| error(404, "not enough readings near those dates"))?;
+        let calculation = calculate(change, self.tariffs());
+        Ok(json(200, &calculation_json(&pair, &calculation)))
+    }
+
+    // Each period between readings in the range, with the total savings.
+    fn report(&self, request: &Request) -> Result<Response, Response> {
+        let readings = readings_in_range(self.db.all_readings(), request)?;
+        let tariffs = self.tariffs();
+
+        let mut days = 0;
+        let mut savings = Dollars::ZERO;
+        let mut periods = Vec::new();
+        for window in readings.windows(2) {
+            let pair = ReadingPair {
+                first: window[0].clone(),
+                second: window[1].clone(),
+            };
+            let change = match find_change(&pair) {
+                Some(change) => change,
+                None => continue,
+            };
+            let calculation = calculate(change, tariffs.clone());
+            days += pair.days_spanned();
+            savings += calculation.savings.total * pair.days_spanned();
+            periods.push(calculation_json(&pair, &calculation));
+        }
+
+        Ok(json(200, &Json::object(vec![
+            ("days", days.into()),
+            ("savings_total", savings.into()),
+            ("periods", Json::Array(periods)),
+        ])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::net::{ TcpListener, TcpStream };
+    use std::thread;
+
+    fn api() -> Api {
+        Api {
+            db: Database::open(":memory:"),
+            token: None,
+            default_tariffs: Tariffs::flat(2.into(), 1.into()),
+        }
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        let (path, query) = match path.find('?') {
+            Some(position) => (&path[..position], Some(path[position + 1..].to_string())),
+            None => (path, None),
+        };
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    fn post_reading(api: &Api, date: &str, generation: f32) -> Response {
+        let body = format!("{{\"date\": \"{}\", \"generation\": {}, \"exports\": {}, \
+                            \"imports\": {}}}", date, generation, generation / 2.0, generation);
+        api.handle(&request("POST", "/readings", &body))
+    }
+
+    #[test]
+    fn readings_crud() {
+        let api = api();
+
+        let response = post_reading(&api, "2020-01-01", 100.0);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.content_type, "application/json");
+        assert_eq!(response.body,
+                   "{\"date\":\"2020-01-01\",\"generation\":100,\"exports\":50,\"imports\":100,\
+                    \"source\":\"actual\",\"note\":\"\"}");
+        assert_eq!(post_reading(&api, "2020-01-01", 100.0).status, 409);
+        assert_eq!(post_reading(&api, "2020-01-03", 120.0).status, 201);
+
+        let response = api.handle(&request("GET", "/readings/2020-01-03", ""));
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("\"generation\":120"));
+
+        let response = api.handle(&request("PUT", "/readings/2020-01-03",
+                                           "{\"generation\": 130, \"exports\": 60, \"imports\": 120, \
+                                            \"source\": \"estimated\", \"note\": \"from the bill\"}"));
+        assert_eq!(response.status, 200);
+        let stored = api.db.get_reading_for_date(NaiveDate::from_ymd(2020, 1, 3)).unwrap();
+        assert_eq!(stored.generation, 130.into());
+        assert_eq!((stored.source, stored.note.as_str()), (Source::Estimated, "from the bill"));
+        let response = api.handle(&request("PUT", "/readings/2020-01-03",
+                                           "{\"generation\": 130, \"exports\": 60, \"imports\": 120, \
+                                            \"source\": \"guessed\"}"));
+        assert_eq!(response.status, 422);
+
+        let response = api.handle(&request("GET", "/readings?from=2020-01-02", ""));
+        assert_eq!(response.status, 200);
+        assert!(response.body.starts_with("[{\"date\":\"2020-01-03\""));
+
+        assert_eq!(api.handle(&request("DELETE", "/readings/2020-01-03", "")).status, 204);
+        assert_eq!(api.handle(&request("DELETE", "/readings/2020-01-03", "")).status, 404);
+        assert_eq!(api.handle(&request("GET", "/readings/2020-01-03", "")).status, 404);
+        assert_eq!(api.handle(&request("PUT", "/readings/2020-01-03", "{}")).status, 404);
+    }
+
+    #[test]
+    fn readings_validated() {
+        let api = api();
+        assert_eq!(post_reading(&api, "2020-01-01", 100.0).status, 201);
+        assert_eq!(post_reading(&api, "2020-01-05", 140.0).status, 201);
+
+        // Registers can't go backwards either side of a new reading.
+        assert_eq!(post_reading(&api, "2020-01-06", 130.0).status, 422);
+        assert_eq!(post_reading(&api, "2020-01-03", 150.0).status, 422);
+        assert_eq!(post_reading(&api, "2020-01-03", 120.0).status, 201);
+
+        let bad = [
+            ("POST", "/readings", "{\"date\": \"2020-01-07\"", 400),
+            ("POST", "/readings", "[1]", 422),
+            ("POST", "/readings", "{\"date\": \"07/01/2020\", \"generation\": 1}", 400),
+            ("POST", "/readings", "{\"date\": \"2020-01-07\", \"generation\": \"1\"}", 422),
+            ("PUT", "/readings/2020-01-05",
+             "{\"date\": \"2020-01-06\", \"generation\": 150, \"exports\": 1, \"imports\": 1}", 422),
+            ("GET", "/readings/yesterday", "", 400),
+            ("GET", "/readings?from=soon", "", 400),
+            ("PATCH", "/readings", "", 405),
+            ("GET", "/nothing", "", 404),
+        ];
+        for (method, path, body, status) in bad.iter() {
+            let response = api.handle(&request(method, path, body));
+            assert_eq!(response.status, *status, "{} {} {}", method, path, body);
+            assert!(response.body.starts_with("{\"error\":"));
+        }
+        assert_eq!(api.db.number_of_readings(), 3);
+    }
+
+    #[test]
+    fn tariffs_and_calculations() {
+        let api = api();
+        let response = api.handle(&request("GET", "/calculation", ""));
+        assert_eq!(response.status, 404);
+
+        post_reading(&api, "2020-01-01", 100.0);
+        post_reading(&api, "2020-01-03", 120.0);
+        post_reading(&api, "2020-01-04", 130.0);
+
+        let response = api.handle(&request("GET", "/calculation?from=2020-01-01&to=2020-01-03", ""));
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("\"days\":2,\"generation_kwh\":10,"));
+        assert!(response.body.contains("\"savings_total\":15,\"estimated\":false}"));
+        let response = api.handle(&request("GET", "/calculation?from=2020-01-01", ""));
+        assert_eq!(response.status, 400);
+
+        let response = api.handle(&request("PUT", "/tariffs", "{\"import\": 0.5, \"export\": 0.25}"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "{\"import\":0.5,\"export\":0.25}");
+        let response = api.handle(&request("PUT", "/tariffs", "{\"import\": -1, \"export\": 0.25}"));
+        assert_eq!(response.status, 422);
+
+        // Self consumption of 5 kWh a day at 0.5 plus 5 kWh exported at 0.25.
+        let response = api.handle(&request("GET", "/calculation", ""));
+        assert!(response.body.contains("\"start\":\"2020-01-03\""));
+        assert!(response.body.contains("\"savings_total\":3.75,"));
+
+        let response = api.handle(&request("GET", "/report", ""));
+        assert_eq!(response.status, 200);
+        assert!(response.body.starts_with("{\"days\":3,\"savings_total\":11.25,\"periods\":[{"));
+    }
+
+    #[test]
+    fn token_needed() {
+        let mut api = api();
+        api.token = Some("secret".to_string());
+
+        assert_eq!(api.handle(&request("GET", "/readings", "")).status, 401);
+
+        let mut authorised = request("GET", "/readings", "");
+        authorised.headers.push(("authorization".to_string(), "Bearer secret".to_string()));
+        assert_eq!(api.handle(&authorised).status, 200);
+    }
+
+    #[test]
+    fn served_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let api = api();
+            crate::http::serve(listener, |request| api.handle(request));
+        });
+
+        let body = "{\"date\": \"2020-01-01\", \"generation\": 1, \"exports\": 0, \"imports\": 2}";
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "POST /readings HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+               body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with("\"imports\":2,\"source\":\"actual\",\"note\":\"\"}"));
+    }
+}
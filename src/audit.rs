@@ -0,0 +1,221 @@
+use std::fmt;
+use chrono::{ NaiveDate, NaiveDateTime };
+
+use crate::decimal::Decimal;
+use crate::json::{ parse_json, Json };
+use crate::readings::Reading;
+use crate::units::DollarsPerKwh;
+
+/// What a change did to a stored row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Insert,
+    Update,
+    Delete,
+    /// The whole database was replaced by a backup.
+    Restore,
+}
+
+impl Action {
+    /// The name stored in the audit table.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Insert => "insert",
+            Action::Update => "update",
+            Action::Delete => "delete",
+            Action::Restore => "restore",
+        }
+    }
+
+    /// The action with a stored name, if there is one.
+    pub fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "insert" => Some(Action::Insert),
+            "update" => Some(Action::Update),
+            "delete" => Some(Action::Delete),
+            "restore" => Some(Action::Restore),
+            _ => None,
+        }
+    }
+}
+
+/// One change to a reading or the tariffs, as kept in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Counts up from one, in the order changes were made.
+    pub id: i64,
+    /// When the change was made, in local time.
+    pub time: NaiveDateTime,
+    /// Who made the change.
+    pub user: String,
+    /// What was changed, "reading", "tariff" or "database".
+    pub subject: String,
+    /// Which row changed. The date for a reading, or the
+    /// backup file for a restore.
+    pub key: String,
+    pub action: Action,
+    /// The values before the change. None for an insert.
+    pub old: Option<String>,
+    /// The values after the change. None for a delete.
+    pub new: Option<String>,
+    /// Why the change was made.
+    pub reason: String,
+}
+
+/// Allow a Change object to be passed to println!() etc.
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {} {} {} {} {}", self.id, self.time.format("%d/%m/%Y %H:%M:%S"),
+               self.user, self.action.name(), self.subject, self.key)?;
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, ": {} -> {}", old, new)?,
+            (Some(old), None) => write!(f, ": {}", old)?,
+            (None, Some(new)) => write!(f, ": {}", new)?,
+            (None, None) => (),
+        }
+        if !self.reason.is_empty() {
+            write!(f, " ({})", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+// The amount in a member of the values, to the digit.
+fn amount<T: From<Decimal>>(values: &Json, name: &str) -> Option<T> {
+    values.get(name)?.as_decimal().map(T::from)
+}
+
+// The words in a member of the values.
+fn words<'a>(values: &'a Json, name: &str) -> Option<&'a str> {
+    values.get(name)?.as_str()
+}
+
+// The value with a name in "name=value" pairs, the way values
+// were kept in the audit log before they were kept as JSON.
+fn value_in<T: std::str::FromStr>(text: &str, name: &str) -> Option<T> {
+    text.split_whitespace()
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// A reading's values as kept in the audit log, a JSON object.
+pub fn reading_values(reading: &Reading) -> String {
+    Json::object(vec![
+        ("generation", reading.generation.into()),
+        ("exports", reading.exports.into()),
+        ("imports", reading.imports.into()),
+        ("source", Json::String(reading.source.to_string())),
+        ("note", Json::String(reading.note.clone())),
+    ]).to_string()
+}
+
+/// The reading for a date from values kept in the audit log. Changes
+/// logged before readings had a source were all actual readings.
+pub fn parse_reading_values(date: NaiveDate, text: &str) -> Option<Reading> {
+    let values = match parse_json(text) {
+        Ok(values) => values,
+        Err(_e) => return parse_old_reading_values(date, text),
+    };
+    Some(Reading {
+        date,
+        generation: amount(&values, "generation")?,
+        exports: amount(&values, "exports")?,
+        imports: amount(&values, "imports")?,
+        source: words(&values, "source").and_then(|source| source.parse().ok()).unwrap_or_default(),
+        note: words(&values, "note").unwrap_or_default().to_string(),
+    })
+}
+
+// Older logs kept a reading's values as "name=value" pairs,
+// with the note, which may have spaces, last.
+fn parse_old_reading_values(date: NaiveDate, text: &str) -> Option<Reading> {
+    let (text, note) = match text.find(" note=") {
+        Some(start) => (&text[..start], &text[start + " note=".len()..]),
+        None => (text, ""),
+    };
+    Some(Reading {
+        date,
+        generation: value_in(text, "generation")?,
+        exports: value_in(text, "exports")?,
+        imports: value_in(text, "imports")?,
+        source: value_in(text, "source").unwrap_or_default(),
+        note: note.to_string(),
+    })
+}
+
+/// Flat import and export rates as kept in the audit log, a JSON object.
+pub fn tariff_values(import: DollarsPerKwh, export: DollarsPerKwh) -> String {
+    Json::object(vec![("import", import.into()), ("export", export.into())]).to_string()
+}
+
+/// Flat import and export rates from values kept in the audit
+/// log, either as JSON or as the older "name=value" pairs.
+pub fn parse_tariff_values(text: &str) -> Option<(DollarsPerKwh, DollarsPerKwh)> {
+    match parse_json(text) {
+        Ok(values) => Some((amount(&values, "import")?, amount(&values, "export")?)),
+        Err(_e) => Some((value_in(text, "import")?, value_in(text, "export")?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::readings::Source;
+    use crate::units::{ kwh, per_kwh };
+
+    #[test]
+    fn values_read_back() {
+        let mut reading = Reading::new(NaiveDate::from_ymd(2020, 1, 2), kwh(10.5),
+                                       kwh(3.0), kwh(0.125));
+        let text = reading_values(&reading);
+        assert_eq!(text, r#"{"generation":10.5,"exports":3,"imports":0.125,"source":"actual","note":""}"#);
+        assert_eq!(parse_reading_values(reading.date, &text), Some(reading.clone()));
+        assert_eq!(parse_reading_values(reading.date, r#"{"generation":1}"#), None);
+
+        reading.source = Source::Estimated;
+        reading.note = "forgot, so note=\"a guess\"".to_string();
+        let text = reading_values(&reading);
+        assert_eq!(parse_reading_values(reading.date, &text), Some(reading));
+
+        let text = tariff_values(per_kwh(0.25752), per_kwh(0.07));
+        assert_eq!(text, r#"{"import":0.25752,"export":0.07}"#);
+        assert_eq!(parse_tariff_values(&text), Some((per_kwh(0.25752), per_kwh(0.07))));
+    }
+
+    #[test]
+    fn old_values_read_back() {
+        let mut reading = Reading::new(NaiveDate::from_ymd(2020, 1, 2), kwh(10.5),
+                                       kwh(3.0), kwh(0.125));
+        assert_eq!(parse_reading_values(reading.date, "generation=10.5 exports=3 imports=0.125"),
+                   Some(reading.clone()));
+        assert_eq!(parse_reading_values(reading.date, "generation=1"), None);
+
+        reading.source = Source::Estimated;
+        reading.note = "forgot, so a=b guess".to_string();
+        assert_eq!(parse_reading_values(reading.date,
+                       "generation=10.5 exports=3 imports=0.125 source=estimated note=forgot, so a=b guess"),
+                   Some(reading));
+        assert_eq!(parse_tariff_values("import=0.25 export=0.07"), Some((per_kwh(0.25), per_kwh(0.07))));
+    }
+
+    #[test]
+    fn change_display() {
+        let change = Change {
+            id: 3,
+            time: NaiveDate::from_ymd(2020, 1, 2).and_hms(18, 5, 0),
+            user: "sam".to_string(),
+            subject: "reading".to_string(),
+            key: "2020-01-02".to_string(),
+            action: Action::Update,
+            old: Some(r#"{"generation":1,"exports":1,"imports":1}"#.to_string()),
+            new: Some(r#"{"generation":2,"exports":1,"imports":1}"#.to_string()),
+            reason: "typo".to_string(),
+        };
+        assert_eq!(change.to_string(),
+                   "#3 02/01/2020 18:05:00 sam update reading 2020-01-02: \
+                    {\"generation\":1,\"exports\":1,\"imports\":1} -> \
+                    {\"generation\":2,\"exports\":1,\"imports\":1} (typo)");
+        assert_eq!(Action::from_name(Action::Delete.name()), Some(Action::Delete));
+    }
+}
@@ -1,23 +1,84 @@
-use nrgaccounts::calc::{ calculate, Tariffs };
-use nrgaccounts::readings::find_change;
+use std::env;
+use std::process;
+
+use chrono::NaiveDate;
+
+use nrgaccounts::calc::calculate;
+use nrgaccounts::comparison::compare_periods;
+use nrgaccounts::readings::{ find_change, MAX_DAYS_FROM_PERIOD };
 use nrgaccounts::console_input::get_reading_pair;
+use nrgaccounts::database::Database;
+use nrgaccounts::store::{ FileStore, ReadingStore };
 
-fn main() {
-    let tariffs = Tariffs {
-        import: 0.25752,
-        export: 0.07135,
-    };
+// If set, readings are kept in this CSV file instead of the database.
+const READINGS_VARIABLE: &str = "NRGACCOUNTS_READINGS";
 
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    compare_two_readings(tariffs);
+    if args.is_empty() {
+        compare_two_readings();
+    } else if args.len() == 4 {
+        compare_two_periods(&args);
+    } else {
+        eprintln!("Usage: compare [BASELINE_START BASELINE_END START END]");
+        eprintln!("    Dates are YYYY-MM-DD. With no dates readings are entered by hand.");
+        process::exit(2);
+    }
 }
 
-fn compare_two_readings(tariffs : Tariffs) {
-    println!("");
+fn compare_two_readings() {
+    println!();
     let pair = get_reading_pair();
-    let changes = find_change(&pair); 
-    println!("");
+    let changes = match find_change(&pair) {
+        Some(changes) => changes,
+        None => {
+            eprintln!("The second reading must be after the first.");
+            process::exit(1);
+        },
+    };
+    println!();
     println!("Report for {} days.", pair.days_spanned());
+    let tariffs = Database::open("energy.db").current_tariffs();
     let calculation = calculate(changes, tariffs);
     println!("{}", calculation);
 }
+
+fn compare_two_periods(args : &[String]) {
+    let dates: Vec<NaiveDate> = args.iter()
+        .map(|arg| match NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_e) => {
+                eprintln!("Invalid date: {}", arg);
+                process::exit(2);
+            },
+        })
+        .collect();
+
+    let comparison = compare_periods(&open_readings().all_readings(),
+                                     (dates[0], dates[1]),
+                                     (dates[2], dates[3]),
+                                     Database::open("energy.db").current_tariffs());
+    match comparison {
+        Some(comparison) => println!("{}", comparison),
+        None => {
+            eprintln!("Both periods need readings within {} days of their start and end.",
+                      MAX_DAYS_FROM_PERIOD);
+            process::exit(1);
+        },
+    }
+}
+
+// Open wherever the readings are kept, as nrgaccounts does.
+fn open_readings() -> Box<dyn ReadingStore> {
+    match env::var(READINGS_VARIABLE) {
+        Ok(file) => match FileStore::open(&file) {
+            Ok(store) => Box::new(store),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        },
+        Err(_e) => Box::new(Database::open("energy.db")),
+    }
+}
@@ -1,16 +1,120 @@
 /// Calculate stats based on meter readings.
 use std::fmt;
 
+use crate::decimal::Decimal;
 use crate::readings::DiurnalChange;
+use crate::units::{ Dollars, DollarsPerKwh, Fraction, KilowattHours };
 
 
 /// Tariffs set by energy retailer.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Tariffs {
-    /// Dollars paid to export energy to grid.
-    pub export: f32,
-    /// Dollars charged to import energy from the grid.
-    pub import: f32, 
+    /// Dollars paid to export energy to grid once
+    /// any export blocks are used up.
+    pub export: DollarsPerKwh,
+    /// Dollars charged to import energy from the grid
+    /// once any import blocks are used up.
+    pub import: DollarsPerKwh,
+    /// Rates for the first kilowatt / hours imported each period.
+    pub import_blocks: Vec<Block>,
+    /// Rates for the first kilowatt / hours exported each period,
+    /// such as a capped premium feed-in rate.
+    pub export_blocks: Vec<Block>,
+    /// How often the blocks start again.
+    pub block_period: BlockPeriod,
 }
+
+/// A number of kilowatt / hours charged or paid at their own rate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    /// The kilowatt / hours in the block each period.
+    pub kwh: KilowattHours,
+    /// Dollars per kilowatt / hour within the block.
+    pub rate: DollarsPerKwh,
+}
+
+/// How often the blocks of a tariff start again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockPeriod {
+    /// Every day.
+    Daily,
+    /// Every billing period of the given number of days. As we
+    /// work with daily averages the blocks are spread evenly
+    /// over the days of the period. This is only an estimate of
+    /// the bill, which applies the blocks to the period's total:
+    /// a day using more than its share of a block pays the higher
+    /// rate here even when a quieter day would have made up for it.
+    Billing(Decimal),
+}
+
+impl BlockPeriod {
+    /// The number of days in the period.
+    pub fn days(&self) -> Decimal {
+        match self {
+            BlockPeriod::Daily => Decimal::from(1),
+            BlockPeriod::Billing(days) => *days,
+        }
+    }
+}
+
+/// The flat tariffs used when none have been stored.
+pub fn default_tariffs() -> Tariffs {
+    Tariffs::flat(Decimal::new(25752, 5).into(), Decimal::new(7135, 5).into())
+}
+
+impl Tariffs {
+    /// Tariffs with a single rate for each direction.
+    pub fn flat(import: DollarsPerKwh, export: DollarsPerKwh) -> Tariffs {
+        Tariffs {
+            export,
+            import,
+            import_blocks: Vec::new(),
+            export_blocks: Vec::new(),
+            block_period: BlockPeriod::Daily,
+        }
+    }
+
+    /// The daily cost of importing `kwh` once `already` kilowatt / hours
+    /// have been imported that day.
+    pub fn import_cost(&self, already: KilowattHours, kwh: KilowattHours) -> Dollars {
+        tiered(already, kwh, &self.import_blocks, self.import, self.block_period)
+    }
+
+    /// The daily amount paid for exporting `kwh`.
+    pub fn export_credit(&self, kwh: KilowattHours) -> Dollars {
+        tiered(KilowattHours::ZERO, kwh, &self.export_blocks, self.export, self.block_period)
+    }
+
+    /// True if blocks are spread over the days of a billing
+    /// period, so costs are only an estimate of the bill.
+    pub fn spreads_blocks(&self) -> bool {
+        let has_blocks = !self.import_blocks.is_empty() || !self.export_blocks.is_empty();
+        has_blocks && matches!(self.block_period, BlockPeriod::Billing(_))
+    }
+}
+
+// Price `kwh` in a day, starting `already` kilowatt / hours into the
+// blocks. Whatever goes past the last block is charged at `rate`.
+fn tiered(already: KilowattHours, kwh: KilowattHours, blocks: &[Block], rate: DollarsPerKwh,
+          period: BlockPeriod) -> Dollars {
+    let mut total = Dollars::ZERO;
+    let mut priced = KilowattHours::ZERO;
+    let mut lower = KilowattHours::ZERO;
+
+    for block in blocks.iter() {
+        let upper = lower + block.kwh / period.days();
+        let start = already.max(lower);
+        let end = (already + kwh).min(upper);
+        if end > start {
+            total += (end - start) * block.rate;
+            priced += end - start;
+        }
+        lower = upper;
+    }
+
+    total + (kwh - priced) * rate
+}
+
 /// Calculate a variety of values related to energy consumption
 /// and production and return the information as a Calculation.
 pub fn calculate(change: DiurnalChange, tarrifs: Tariffs) -> Calculation {
@@ -23,8 +127,9 @@ pub fn calculate(change: DiurnalChange, tarrifs: Tariffs) -> Calculation {
         fraction_of_generation : self_consumption_kwh / change.generation,
         fraction_of_total_use : self_consumption_kwh / total_consumption_kwh,
     };
-    let from_self_consumption = self_consumption.kwh * tarrifs.import;
-    let from_exports = change.exports * tarrifs.export;
+    // What the energy we used ourselves would have cost on top of our imports.
+    let from_self_consumption = tarrifs.import_cost(change.imports, self_consumption.kwh);
+    let from_exports = tarrifs.export_credit(change.exports);
 
     let savings = Savings {
         from_exports,
@@ -32,28 +137,27 @@ pub fn calculate(change: DiurnalChange, tarrifs: Tariffs) -> Calculation {
         total : from_exports + from_self_consumption,
     };
 
-    let calculation = Calculation {
+    Calculation {
         generation_kwh: change.generation,
         grid_import_kwh: change.imports,
         grid_export_kwh: change.exports,
         total_consumption_kwh,
         self_consumption,
         savings,
-    };
-    calculation
+    }
 }
 
 /// A simple data structure for storing a variety of information
 /// about energy consumption and production.
 pub struct Calculation {
     /// The amount of generated energy.
-    pub generation_kwh: f32,
+    pub generation_kwh: KilowattHours,
     /// The amount of energy imported from the grid.
-    pub grid_import_kwh: f32,
+    pub grid_import_kwh: KilowattHours,
     /// The amount of energy exported to the grid.
-    pub grid_export_kwh: f32,
+    pub grid_export_kwh: KilowattHours,
     /// The total amount of energy consumed.
-    pub total_consumption_kwh: f32,
+    pub total_consumption_kwh: KilowattHours,
 
     /// Information related to self consumption.
     pub self_consumption: SelfConsumption,
@@ -64,23 +168,23 @@ pub struct Calculation {
 /// Information related to energy produced and consumed directly.
 pub struct SelfConsumption {
     /// The amount of energy in kilowatt/hours.
-    pub kwh: f32,
+    pub kwh: KilowattHours,
     /// A number between 0 and 1 representing the self consumption
     /// as a fraction of the total energy used for the period.
-    pub fraction_of_total_use: f32,
+    pub fraction_of_total_use: Fraction,
     /// A number between 0 and 1 representing self consumption as
     /// a fraction of what has been generated on-site.
-    pub fraction_of_generation: f32,
+    pub fraction_of_generation: Fraction,
 }
 
 /// How much money we have saved during this period.
 pub struct Savings {
     /// The amount saved due to energy self-consumed.
-    pub from_self_consumption: f32,
+    pub from_self_consumption: Dollars,
     /// The amount earned by exporting energy to the grid.
-    pub from_exports: f32,
+    pub from_exports: Dollars,
     /// Overall savings due to solar.
-    pub total: f32,
+    pub total: Dollars,
 }
 
 /// Allow a Calculation object to be passed to println!() etc.
@@ -96,9 +200,9 @@ impl fmt::Display for Calculation {
         lines.push("Self consumption:\n".to_string());
         lines.push(format!("    Daily:          {:.2} kWh\n", self.self_consumption.kwh));
         lines.push(format!("    % of total:     {:.2}%\n", 
-                           (self.self_consumption.fraction_of_total_use * 100.0)));
+                           self.self_consumption.fraction_of_total_use.percent()));
         lines.push(format!("    % of generated: {:.2}%\n", 
-                           (self.self_consumption.fraction_of_generation * 100.0)));
+                           self.self_consumption.fraction_of_generation.percent()));
          
         lines.push("Daily savings:\n".to_string());
         lines.push(format!("   By self-consumption: ${:.2}\n", 
@@ -121,19 +225,30 @@ impl fmt::Display for Calculation {
 mod tests {
     use super::*;
 
+    fn kwh(value: i64) -> KilowattHours {
+        KilowattHours::from(value)
+    }
+
     fn  tariffs() -> Tariffs {
+        Tariffs::flat(2.into(), 1.into())
+    }
+
+    fn block_tariffs() -> Tariffs {
         Tariffs {
-            export: 1.0,
-            import: 2.0,
+            export: Decimal::new(5, 2).into(),
+            import: Decimal::new(20, 2).into(),
+            import_blocks: vec![Block { kwh: kwh(10), rate: Decimal::new(30, 2).into() }],
+            export_blocks: vec![Block { kwh: kwh(5), rate: Decimal::new(40, 2).into() }],
+            block_period: BlockPeriod::Daily,
         }
     }
 
     #[test]
     fn generation_kwh() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 2.0,
-            exports: 2.0,
+            generation: kwh(3),
+            imports: kwh(2),
+            exports: kwh(2),
         };
 
         let expected = change.generation;
@@ -144,9 +259,9 @@ mod tests {
     #[test]
     fn grid_import_kwh() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 1.0,
-            exports: 2.0,
+            generation: kwh(3),
+            imports: kwh(1),
+            exports: kwh(2),
         };
 
         let expected = change.imports;
@@ -157,9 +272,9 @@ mod tests {
     #[test]
     fn grid_export_kwh() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 1.0,
-            exports: 2.0,
+            generation: kwh(3),
+            imports: kwh(1),
+            exports: kwh(2),
         };
         let expected = change.exports;
         let calculation = calculate(change, tariffs());
@@ -169,11 +284,11 @@ mod tests {
     #[test]
     fn total_consumption_kwh() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 2.0,
-            exports: 1.0,
+            generation: kwh(3),
+            imports: kwh(2),
+            exports: kwh(1),
         };
-        let expected = 4.0;
+        let expected = kwh(4);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.total_consumption_kwh, expected);
     }
@@ -181,11 +296,11 @@ mod tests {
     #[test]
     fn self_consumption_kwh() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 2.0,
-            exports: 1.0,
+            generation: kwh(3),
+            imports: kwh(2),
+            exports: kwh(1),
         };
-        let expected = 2.0;
+        let expected = kwh(2);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.self_consumption.kwh, expected);
     }
@@ -193,11 +308,11 @@ mod tests {
     #[test]
     fn fraction_of_total_use() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 2.0,
-            exports: 1.0,
+            generation: kwh(3),
+            imports: kwh(2),
+            exports: kwh(1),
         };
-        let expected = 0.5;
+        let expected = Fraction(0.5);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.self_consumption.fraction_of_total_use, expected);
     }
@@ -205,11 +320,11 @@ mod tests {
     #[test]
     fn fraction_of_generation() {
         let change = DiurnalChange {
-            generation: 10.0, 
-            imports: 4.0,
-            exports: 2.5,
+            generation: kwh(10),
+            imports: kwh(4),
+            exports: Decimal::new(25, 1).into(),
         };
-        let expected = 0.75;
+        let expected = Fraction(0.75);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.self_consumption.fraction_of_generation, expected);
     }
@@ -217,11 +332,11 @@ mod tests {
     #[test]
     fn savings_from_self_consumption() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 2.0,
-            exports: 1.0,
+            generation: kwh(3),
+            imports: kwh(2),
+            exports: kwh(1),
         };
-        let expected = 2.0 * tariffs().import;
+        let expected = tariffs().import * kwh(2);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.savings.from_self_consumption, expected);
     }
@@ -229,11 +344,11 @@ mod tests {
     #[test]
     fn savings_from_exports() {
         let change = DiurnalChange {
-            generation: 3.0, 
-            imports: 2.0,
-            exports: 3.0,
+            generation: kwh(3),
+            imports: kwh(2),
+            exports: kwh(3),
         };
-        let expected = 3.0 * tariffs().export;
+        let expected = tariffs().export * kwh(3);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.savings.from_exports, expected);
     }
@@ -241,38 +356,76 @@ mod tests {
     #[test]
     fn savings_total() {
         let change = DiurnalChange {
-            generation: 7.0, 
-            imports: 2.0,
-            exports: 3.0,
+            generation: kwh(7),
+            imports: kwh(2),
+            exports: kwh(3),
         };
-        let expected = 4.0 * tariffs().import + 3.0 * tariffs().export;
+        let expected = tariffs().import * kwh(4) + tariffs().export * kwh(3);
         let calculation = calculate(change, tariffs());
         assert_eq!(calculation.savings.total, expected);
     }
 
     #[test]
     fn test_tariffs() {
-       let tariffs = Tariffs {
-           import: 0.50,
-           export: 0.10,
-       };
+       let tariffs = Tariffs::flat(Decimal::new(50, 2).into(), Decimal::new(10, 2).into());
 
        let change = DiurnalChange {
-           generation: 12.0,
-           imports: 20.0,
-           exports: 2.0,
+           generation: kwh(12),
+           imports: kwh(20),
+           exports: kwh(2),
         };
        
-        assert_eq!(tariffs.import, 0.50);
-        assert_eq!(tariffs.export, 0.10);
+        assert_eq!(tariffs.import, Decimal::new(50, 2).into());
+        assert_eq!(tariffs.export, Decimal::new(10, 2).into());
 
         let calculation = calculate(change, tariffs);
 
-        assert_eq!(calculation.self_consumption.kwh, 10.0, "Self consumption wrong.");
-        assert_eq!(calculation.grid_export_kwh, 2.0, "Grid export kwh wrong.");
-        assert_eq!(calculation.savings.from_exports, 0.20, "Export savings wrong.");
-        assert_eq!(calculation.savings.from_self_consumption, 5.0, 
+        assert_eq!(calculation.self_consumption.kwh, kwh(10), "Self consumption wrong.");
+        assert_eq!(calculation.grid_export_kwh, kwh(2), "Grid export kwh wrong.");
+        assert_eq!(calculation.savings.from_exports, Decimal::new(20, 2).into(), "Export savings wrong.");
+        assert_eq!(calculation.savings.from_self_consumption, Dollars::from(5), 
                    "Self consumption savings wrong.");
-        assert_eq!(calculation.savings.total, 5.20, "Total savings wrong.");
+        assert_eq!(calculation.savings.total, Decimal::new(520, 2).into(), "Total savings wrong.");
+    }
+
+    #[test]
+    fn import_cost_across_blocks() {
+        let tariffs = block_tariffs();
+        assert_eq!(tariffs.import_cost(kwh(0), kwh(4)), Decimal::new(12, 1).into());
+        // 2 kWh left in the block, 3 kWh after it.
+        assert_eq!(tariffs.import_cost(kwh(8), kwh(5)), Decimal::new(12, 1).into());
+        assert_eq!(tariffs.import_cost(kwh(12), kwh(5)), Dollars::from(1));
+    }
+
+    #[test]
+    fn export_credit_capped() {
+        let tariffs = block_tariffs();
+        assert_eq!(tariffs.export_credit(kwh(3)), Decimal::new(12, 1).into());
+        // Premium rate for 5 kWh, then the standard rate.
+        assert_eq!(tariffs.export_credit(kwh(9)), Decimal::new(22, 1).into());
+    }
+
+    #[test]
+    fn billing_period_blocks() {
+        let mut tariffs = block_tariffs();
+        tariffs.block_period = BlockPeriod::Billing(Decimal::from(10));
+        // 100 kWh a quarter of 10 days is 10 kWh a day.
+        tariffs.import_blocks = vec![Block { kwh: kwh(100), rate: Decimal::new(30, 2).into() }];
+        assert_eq!(tariffs.import_cost(KilowattHours::ZERO, kwh(12)), Decimal::new(34, 1).into());
+        assert!(tariffs.spreads_blocks());
+        assert!(!block_tariffs().spreads_blocks());
+    }
+
+    #[test]
+    fn savings_with_blocks() {
+        let change = DiurnalChange {
+            generation: kwh(10),
+            imports: kwh(6),
+            exports: kwh(6),
+        };
+        let calculation = calculate(change, block_tariffs());
+        // Self consumption of 4 kWh on top of 6 kWh imported is all in the block.
+        assert_eq!(calculation.savings.from_self_consumption, Decimal::new(12, 1).into());
+        assert_eq!(calculation.savings.from_exports, Decimal::new(205, 2).into());
     }
 }
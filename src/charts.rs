@@ -0,0 +1,304 @@
+use chrono::NaiveDate;
+
+use crate::calc::{ calculate, Tariffs };
+use crate::readings::DatedChange;
+
+const MARGIN_LEFT: f32 = 50.0;
+const MARGIN_RIGHT: f32 = 10.0;
+const MARGIN_TOP: f32 = 30.0;
+const MARGIN_BOTTOM: f32 = 30.0;
+const GRID_LINES: usize = 4;
+
+const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const ASCII_SPARK_LEVELS: [char; 8] = ['_', '.', ',', '-', '~', '=', '*', '#'];
+
+// Eighths of a block for the end of a bar.
+const PARTIAL_BLOCKS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+/// Colours for the series, in order.
+pub const PALETTE: [&str; 5] = ["#e69f00", "#0072b2", "#009e73", "#cc79a7", "#56b4e9"];
+
+/// Values to chart against dates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    pub points: Vec<(NaiveDate, f32)>,
+}
+
+/// Daily average generation, imports, exports and self consumption
+/// for each period, plotted at the end of the period.
+pub fn usage_series(changes: &[DatedChange], tariffs: &Tariffs) -> Vec<Series> {
+    let mut series: Vec<Series> = ["Generation", "Imports", "Exports", "Self consumption"]
+        .iter()
+        .map(|name| Series { name: name.to_string(), points: Vec::new() })
+        .collect();
+
+    for change in changes.iter() {
+        let calculation = calculate(change.change.clone(), tariffs.clone());
+        let values = [calculation.generation_kwh, calculation.grid_import_kwh,
+                      calculation.grid_export_kwh, calculation.self_consumption.kwh];
+        for (series, value) in series.iter_mut().zip(values.iter()) {
+            series.points.push((change.end, value.to_f32()));
+        }
+    }
+    series
+}
+
+/// Escape text for use in HTML or SVG.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// A round number at or above the value to top the axis with.
+fn nice_ceiling(value: f32) -> f32 {
+    if value <= 0.0 || !value.is_finite() {
+        return 1.0;
+    }
+    let magnitude = 10f32.powf(value.log10().floor());
+    [1.0, 2.0, 2.5, 5.0, 10.0].iter()
+        .map(|step| step * magnitude)
+        .find(|top| *top >= value * 0.9999)
+        .unwrap_or(10.0 * magnitude)
+}
+
+// Format an axis label without needless decimals.
+fn label(value: f32) -> String {
+    if value.fract() == 0.0 {
+        format!("{:.0}", value)
+    } else {
+        format!("{:.1}", value)
+    }
+}
+
+// The opening of an SVG with its title and horizontal grid lines,
+// giving the y position of a value.
+fn frame(svg: &mut String, title: &str, width: f32, height: f32,
+         low: f32, high: f32) -> impl Fn(f32) -> f32 {
+    let plot_height = height - MARGIN_TOP - MARGIN_BOTTOM;
+    let y = move |value: f32| MARGIN_TOP + plot_height * (1.0 - (value - low) / (high - low));
+
+    svg.push_str(&format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" font-family=\"sans-serif\" font-size=\"11\">\n",
+        w = width, h = height));
+    svg.push_str(&format!("<text x=\"{}\" y=\"18\" font-size=\"14\" font-weight=\"bold\">{}</text>\n",
+                          MARGIN_LEFT, escape(title)));
+
+    for line in 0..=GRID_LINES {
+        let value = low + (high - low) * line as f32 / GRID_LINES as f32;
+        svg.push_str(&format!(
+            "<line x1=\"{x1}\" y1=\"{y:.1}\" x2=\"{x2}\" y2=\"{y:.1}\" stroke=\"#ddd\"/>\
+             <text x=\"{lx}\" y=\"{ly:.1}\" text-anchor=\"end\">{text}</text>\n",
+            x1 = MARGIN_LEFT, x2 = width - MARGIN_RIGHT, y = y(value),
+            lx = MARGIN_LEFT - 4.0, ly = y(value) + 4.0, text = label(value)));
+    }
+    y
+}
+
+// A chart with nothing to show.
+fn empty_chart(svg: &mut String, width: f32, height: f32) {
+    svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">No data</text>\n</svg>\n",
+                          width / 2.0, height / 2.0));
+}
+
+/// Draw the series as lines against time in an SVG image.
+pub fn svg_line_chart(title: &str, series: &[Series], width: f32, height: f32) -> String {
+    let mut svg = String::new();
+    let points = series.iter().flat_map(|series| series.points.iter());
+    let dates: Vec<NaiveDate> = points.clone().map(|(date, _)| *date).collect();
+    let high = nice_ceiling(points.map(|(_, value)| *value).fold(0.0, f32::max));
+    let y = frame(&mut svg, title, width, height, 0.0, high);
+
+    let (first, last) = match (dates.iter().min(), dates.iter().max()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => {
+            empty_chart(&mut svg, width, height);
+            return svg;
+        },
+    };
+    let span = last.signed_duration_since(first).num_days().max(1) as f32;
+    let plot_width = width - MARGIN_LEFT - MARGIN_RIGHT;
+    let x = |date: NaiveDate| {
+        MARGIN_LEFT + plot_width * date.signed_duration_since(first).num_days() as f32 / span
+    };
+
+    for (date, anchor) in [(first, "start"), (last, "end")].iter() {
+        svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"{}\">{}</text>\n",
+                              x(*date), height - 10.0, anchor, date.format("%d/%m/%Y")));
+    }
+
+    for (index, line) in series.iter().enumerate() {
+        let colour = PALETTE[index % PALETTE.len()];
+        let coordinates: Vec<String> = line.points.iter()
+            .map(|(date, value)| format!("{:.1},{:.1}", x(*date), y(*value)))
+            .collect();
+        svg.push_str(&format!("<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"2\" \
+                               points=\"{}\"/>\n", colour, coordinates.join(" ")));
+
+        // The legend runs along the top right.
+        let legend_x = width - MARGIN_RIGHT - 120.0 * (series.len() - index) as f32;
+        svg.push_str(&format!("<rect x=\"{:.1}\" y=\"8\" width=\"10\" height=\"10\" fill=\"{}\"/>\
+                               <text x=\"{:.1}\" y=\"17\">{}</text>\n",
+                              legend_x, colour, legend_x + 14.0, escape(&line.name)));
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Draw labelled values as vertical bars in an SVG image.
+pub fn svg_bar_chart(title: &str, bars: &[(String, f32)], colour: &str,
+                     width: f32, height: f32) -> String {
+    let mut svg = String::new();
+    let lowest = bars.iter().map(|(_, value)| *value).fold(0.0, f32::min);
+    let low = if lowest < 0.0 { -nice_ceiling(-lowest) } else { 0.0 };
+    let high = nice_ceiling(bars.iter().map(|(_, value)| *value).fold(0.0, f32::max));
+    let y = frame(&mut svg, title, width, height, low, high);
+
+    if bars.is_empty() {
+        empty_chart(&mut svg, width, height);
+        return svg;
+    }
+
+    let slot = (width - MARGIN_LEFT - MARGIN_RIGHT) / bars.len() as f32;
+    // Only label as many bars as there is room for.
+    let label_every = ((40.0 / slot).ceil() as usize).max(1);
+    for (index, (name, value)) in bars.iter().enumerate() {
+        let x = MARGIN_LEFT + slot * index as f32;
+        let (top, bottom) = if *value >= 0.0 { (y(*value), y(0.0)) } else { (y(0.0), y(*value)) };
+        svg.push_str(&format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" \
+                               fill=\"{}\"><title>{}: {:.2}</title></rect>\n",
+                              x + slot * 0.1, top, slot * 0.8, bottom - top,
+                              colour, escape(name), value));
+        if index % label_every == 0 {
+            svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>\n",
+                                  x + slot / 2.0, height - 10.0, escape(name)));
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// A one line chart of the values, scaled from the smallest to the largest.
+/// With `ascii` set only ASCII characters are used.
+pub fn sparkline(values: &[f32], ascii: bool) -> String {
+    let levels = if ascii { ASCII_SPARK_LEVELS } else { SPARK_LEVELS };
+    let low = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let high = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+
+    values.iter()
+        .map(|value| {
+            if high <= low {
+                return levels[levels.len() / 2];
+            }
+            let level = ((value - low) / (high - low) * (levels.len() - 1) as f32).round();
+            levels[level as usize]
+        })
+        .collect()
+}
+
+/// Horizontal bars for labelled values, the longest `width` characters.
+/// Negative values are drawn as empty bars.
+pub fn bar_chart(bars: &[(String, f32)], width: usize, ascii: bool) -> String {
+    let label_width = bars.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+    let high = bars.iter().map(|(_, value)| *value).fold(0.0, f32::max);
+
+    let mut output = String::new();
+    for (name, value) in bars.iter() {
+        let eighths = if high > 0.0 {
+            (value.max(0.0) / high * width as f32 * 8.0).round() as usize
+        } else {
+            0
+        };
+        let bar: String = if ascii {
+            "#".repeat((eighths + 4) / 8)
+        } else {
+            let mut bar = "█".repeat(eighths / 8);
+            if eighths % 8 > 0 {
+                bar.push(PARTIAL_BLOCKS[eighths % 8]);
+            }
+            bar
+        };
+        output.push_str(&format!("{:<label$}  {:<width$} {:.1}\n", name, bar, value,
+                                 label = label_width, width = width));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::readings::{ find_changes, Reading };
+    use crate::units::KilowattHours;
+
+    #[test]
+    fn nice_ceiling_rounds_up() {
+        assert_eq!(nice_ceiling(0.0), 1.0);
+        assert_eq!(nice_ceiling(7.3), 10.0);
+        assert_eq!(nice_ceiling(13.0), 20.0);
+        assert_eq!(nice_ceiling(20.0), 20.0);
+        assert_eq!(nice_ceiling(0.22), 0.25);
+    }
+
+    #[test]
+    fn usage_series_per_period() {
+        let readings: Vec<Reading> = [(1, 0), (3, 20), (4, 26)].iter()
+            .map(|(day, generation)| Reading::new(NaiveDate::from_ymd(2020, 1, *day),
+                                                  KilowattHours::from(*generation),
+                                                  KilowattHours::from(generation / 2),
+                                                  KilowattHours::ZERO))
+            .collect();
+
+        let series = usage_series(&find_changes(&readings), &Tariffs::flat(2.into(), 1.into()));
+        assert_eq!(series.len(), 4);
+        assert_eq!(series[0].points, vec![(NaiveDate::from_ymd(2020, 1, 3), 10.0),
+                                          (NaiveDate::from_ymd(2020, 1, 4), 6.0)]);
+        assert_eq!(series[3].name, "Self consumption");
+        assert_eq!(series[3].points[1].1, 3.0);
+    }
+
+    #[test]
+    fn svg_charts() {
+        let series = vec![Series {
+            name: "A & B".to_string(),
+            points: vec![(NaiveDate::from_ymd(2020, 1, 1), 5.0),
+                         (NaiveDate::from_ymd(2020, 1, 11), 10.0)],
+        }];
+        let svg = svg_line_chart("Title", &series, 400.0, 200.0);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains("points=\"50.0,100.0 390.0,30.0\""));
+        assert!(svg.contains(">A &amp; B</text>"));
+
+        let svg = svg_line_chart("Title", &[], 400.0, 200.0);
+        assert!(svg.contains("No data"));
+
+        let bars = vec![("Jan".to_string(), 4.0), ("Feb".to_string(), -1.0)];
+        let svg = svg_bar_chart("Savings", &bars, "#000", 400.0, 200.0);
+        assert_eq!(svg.matches("<rect").count(), 2);
+        assert!(svg.contains("<title>Feb: -1.00</title>"));
+    }
+
+    #[test]
+    fn sparklines() {
+        assert_eq!(sparkline(&[0.0, 1.0, 7.0, 3.5], false), "▁▂█▅");
+        assert_eq!(sparkline(&[0.0, 1.0, 7.0, 3.5], true), "_.#~");
+        assert_eq!(sparkline(&[2.0, 2.0], false), "▅▅");
+        assert_eq!(sparkline(&[], false), "");
+    }
+
+    #[test]
+    fn bar_charts() {
+        let bars = vec![("Jan".to_string(), 10.0), ("February".to_string(), 5.5),
+                        ("Mar".to_string(), -1.0)];
+        assert_eq!(bar_chart(&bars, 4, false),
+                   "Jan       ████ 10.0\n\
+                    February  ██▎  5.5\n\
+                    Mar            -1.0\n");
+        assert_eq!(bar_chart(&bars, 4, true).lines().nth(1), Some("February  ##   5.5"));
+    }
+}
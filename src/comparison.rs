@@ -0,0 +1,195 @@
+use std::fmt;
+use chrono::NaiveDate;
+
+use crate::calc::{ calculate, Calculation, Tariffs };
+use crate::decimal::Decimal;
+use crate::units::{ Dollars, KilowattHours };
+use crate::readings::{ find_change, pair_for_period, Reading };
+
+/// One field of a Calculation for two periods, as plain
+/// decimals since the fields are in different units.
+pub struct Difference {
+    /// What is being compared.
+    pub name: &'static str,
+    /// The value for the period compared against.
+    pub baseline: Decimal,
+    /// The value for the period of interest.
+    pub current: Decimal,
+}
+
+impl Difference {
+    /// How much the current value is above the baseline.
+    pub fn absolute(&self) -> Decimal {
+        self.current - self.baseline
+    }
+
+    /// The change as a percentage of the baseline, or None
+    /// if the baseline is zero.
+    pub fn percentage(&self) -> Option<Decimal> {
+        if self.baseline == Decimal::ZERO {
+            None
+        } else {
+            Some(self.absolute() * 100 / self.baseline)
+        }
+    }
+}
+
+// A part as a percentage of the whole, worked out from the
+// energy rather than the float fractions. Zero if there's no whole.
+fn percent(part: KilowattHours, whole: KilowattHours) -> Decimal {
+    if whole == KilowattHours::ZERO {
+        Decimal::ZERO
+    } else {
+        part.value() * 100 / whole.value()
+    }
+}
+
+/// Side by side daily averages for two periods.
+pub struct Comparison {
+    /// Dates of the readings used for the baseline.
+    pub baseline: (NaiveDate, NaiveDate),
+    /// Dates of the readings used for the current period.
+    pub current: (NaiveDate, NaiveDate),
+    /// Each field of the calculations.
+    pub differences: Vec<Difference>,
+}
+
+/// Compare each field of two calculations.
+pub fn compare_calculations(baseline: &Calculation, current: &Calculation) -> Vec<Difference> {
+    let difference = |name, baseline, current| Difference { name, baseline, current };
+    let energy = |name, baseline: KilowattHours, current: KilowattHours| {
+        difference(name, baseline.value(), current.value())
+    };
+    let money = |name, baseline: Dollars, current: Dollars| {
+        difference(name, baseline.value(), current.value())
+    };
+    vec![
+        energy("Generation kWh", baseline.generation_kwh, current.generation_kwh),
+        energy("Exports kWh", baseline.grid_export_kwh, current.grid_export_kwh),
+        energy("Imports kWh", baseline.grid_import_kwh, current.grid_import_kwh),
+        energy("Total use kWh",
+               baseline.total_consumption_kwh, current.total_consumption_kwh),
+        energy("Self consumption kWh",
+               baseline.self_consumption.kwh, current.self_consumption.kwh),
+        difference("Self consumption % of total",
+                   percent(baseline.self_consumption.kwh, baseline.total_consumption_kwh),
+                   percent(current.self_consumption.kwh, current.total_consumption_kwh)),
+        difference("Self consumption % of generated",
+                   percent(baseline.self_consumption.kwh, baseline.generation_kwh),
+                   percent(current.self_consumption.kwh, current.generation_kwh)),
+        money("Savings by self-consumption $",
+              baseline.savings.from_self_consumption,
+              current.savings.from_self_consumption),
+        money("Savings from exports $",
+              baseline.savings.from_exports, current.savings.from_exports),
+        money("Total savings $", baseline.savings.total, current.savings.total),
+    ]
+}
+
+/// Compare the daily averages over two date ranges using the
+/// readings nearest each end of the ranges. None if either
+/// range doesn't have readings close enough to its ends.
+pub fn compare_periods(readings: &[Reading],
+                       baseline: (NaiveDate, NaiveDate),
+                       current: (NaiveDate, NaiveDate),
+                       tariffs: Tariffs) -> Option<Comparison> {
+    let baseline_pair = pair_for_period(readings, baseline.0, baseline.1)?;
+    let current_pair = pair_for_period(readings, current.0, current.1)?;
+
+    let baseline_calc = calculate(find_change(&baseline_pair)?, tariffs.clone());
+    let current_calc = calculate(find_change(&current_pair)?, tariffs);
+
+    Some(Comparison {
+        baseline: (baseline_pair.first.date, baseline_pair.second.date),
+        current: (current_pair.first.date, current_pair.second.date),
+        differences: compare_calculations(&baseline_calc, &current_calc),
+    })
+}
+
+/// Allow a Comparison object to be passed to println!() etc.
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Daily averages between the readings on:")?;
+        writeln!(f, "    Baseline: {} and {}",
+                 self.baseline.0.format("%d/%m/%Y"), self.baseline.1.format("%d/%m/%Y"))?;
+        writeln!(f, "    Current:  {} and {}",
+                 self.current.0.format("%d/%m/%Y"), self.current.1.format("%d/%m/%Y"))?;
+        writeln!(f)?;
+        writeln!(f, "{:<32} {:>10} {:>10} {:>10} {:>9}",
+                 "", "Baseline", "Current", "Change", "%")?;
+
+        for difference in self.differences.iter() {
+            let percentage = match difference.percentage() {
+                Some(p) => format!("{:+.1}%", p),
+                None => "-".to_string(),
+            };
+            writeln!(f, "{:<32} {:>10.2} {:>10.2} {:>+10.2} {:>9}",
+                     difference.name,
+                     difference.baseline,
+                     difference.current,
+                     difference.absolute(),
+                     percentage)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::{ kwh, per_kwh };
+
+    fn tariffs() -> Tariffs {
+        Tariffs::flat(per_kwh(0.5), per_kwh(0.1))
+    }
+
+    #[test]
+    fn difference_percentage() {
+        let difference = Difference { name: "test", baseline: 4.into(), current: 5.into() };
+        assert_eq!(difference.absolute(), 1.into());
+        assert_eq!(difference.percentage(), Some(25.into()));
+
+        // No float artefacts: 0.1 up to 0.3 is exactly 200%.
+        let difference = Difference { name: "test", baseline: Decimal::new(1, 1), current: Decimal::new(3, 1) };
+        assert_eq!(difference.absolute(), Decimal::new(2, 1));
+        assert_eq!(difference.percentage(), Some(200.into()));
+
+        let difference = Difference { name: "test", baseline: Decimal::ZERO, current: 5.into() };
+        assert_eq!(difference.percentage(), None);
+    }
+
+    #[test]
+    fn compare_periods_per_day() {
+        let reading = |year, month, day, total: f64| Reading::new(NaiveDate::from_ymd(year, month, day),
+                                                                  kwh(total),
+                                                                  kwh(total / 2.0),
+                                                                  kwh(total / 4.0));
+
+        // 10 kWh a day last year, 20 kWh a day this year.
+        let readings = vec![reading(2018, 3, 1, 0.0),
+                            reading(2018, 3, 31, 300.0),
+                            reading(2019, 3, 1, 1000.0),
+                            reading(2019, 3, 31, 1600.0)];
+
+        let comparison = compare_periods(
+            &readings,
+            (NaiveDate::from_ymd(2018, 3, 1), NaiveDate::from_ymd(2018, 3, 31)),
+            (NaiveDate::from_ymd(2019, 3, 1), NaiveDate::from_ymd(2019, 3, 31)),
+            tariffs()).unwrap();
+
+        let generation = &comparison.differences[0];
+        assert_eq!(generation.baseline, 10.into());
+        assert_eq!(generation.current, 20.into());
+        assert_eq!(generation.percentage(), Some(100.into()));
+        let text = comparison.to_string();
+        assert!(text.contains("Baseline: 01/03/2018 and 31/03/2018"));
+        assert!(text.contains("Generation kWh                        10.00      20.00     +10.00   +100.0%"), "{}", text);
+
+        let too_far = compare_periods(
+            &readings,
+            (NaiveDate::from_ymd(2018, 3, 1), NaiveDate::from_ymd(2018, 3, 31)),
+            (NaiveDate::from_ymd(2019, 6, 1), NaiveDate::from_ymd(2019, 6, 30)),
+            tariffs());
+        assert!(too_far.is_none());
+    }
+}
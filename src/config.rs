@@ -0,0 +1,131 @@
+use std::fmt;
+
+use crate::decimal::Decimal;
+
+/// A problem found while reading a configuration file.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    /// The line the problem was found on, starting from one.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A named section of a configuration file with its
+/// key and value pairs in the order they appear.
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    /// The line the section header is on.
+    pub line: usize,
+    /// Each entry with the line it was found on.
+    pub entries: Vec<(usize, String, String)>,
+}
+
+impl Section {
+    /// The value for the given key, if there is one.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(_, k, _)| k == key)
+            .map(|(_, _, v)| v.as_str())
+    }
+
+    /// The value for the given key parsed as a number.
+    pub fn get_number(&self, key: &str) -> Result<Option<Decimal>, ConfigError> {
+        match self.entries.iter().find(|(_, k, _)| k == key) {
+            None => Ok(None),
+            Some((line, _, value)) => match value.parse::<Decimal>() {
+                Ok(number) => Ok(Some(number)),
+                Err(_e) => Err(ConfigError {
+                    line: *line,
+                    message: format!("{} is not a number", key),
+                }),
+            },
+        }
+    }
+}
+
+/// Split a simple INI style file into sections. Blank lines and
+/// anything after a '#' are ignored.
+///
+/// ```text
+/// [section name]
+/// key = value
+/// ```
+pub fn parse_sections(text: &str) -> Result<Vec<Section>, ConfigError> {
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = match line.find('#') {
+            Some(position) => &line[..position],
+            None => line,
+        }.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push(Section {
+                name: line[1..line.len() - 1].trim().to_string(),
+                line: number,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(position) => (line[..position].trim(), line[position + 1..].trim()),
+            None => return Err(ConfigError {
+                line: number,
+                message: "expected key = value".to_string(),
+            }),
+        };
+
+        match sections.last_mut() {
+            Some(section) => section.entries.push((number, key.to_string(), value.to_string())),
+            None => return Err(ConfigError {
+                line: number,
+                message: "entry before any [section]".to_string(),
+            }),
+        }
+    }
+
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sections_ok() {
+        let text = "# A comment\n\
+                    [First]\n\
+                    a = 1\n\
+                    \n\
+                    [Second]   \n\
+                    b = two  # trailing comment\n";
+        let sections = parse_sections(text).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name, "First");
+        assert_eq!(sections[0].get_number("a"), Ok(Some(Decimal::from(1))));
+        assert_eq!(sections[1].get("b"), Some("two"));
+        assert_eq!(sections[1].get("a"), None);
+    }
+
+    #[test]
+    fn parse_sections_errors() {
+        assert_eq!(parse_sections("a = 1").unwrap_err().line, 1);
+        assert_eq!(parse_sections("[A]\nnonsense").unwrap_err().line, 2);
+
+        let sections = parse_sections("[A]\na = x").unwrap();
+        assert!(sections[0].get_number("a").is_err());
+    }
+}
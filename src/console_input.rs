@@ -1,36 +1,51 @@
-use chrono::{ NaiveDate };
+use chrono::{ Duration, Local, NaiveDate };
 use std::io;
 use std::io::prelude::*;
-use std::num::ParseFloatError;
-use crate::readings::{ Reading, ReadingPair };
+use std::process;
+use crate::decimal::ParseDecimalError;
+use crate::readings::{ Reading, ReadingPair, Source };
+use crate::units::KilowattHours;
 
 
 /// Using user input from the console get two
 /// readings, the earlier reading first.
 pub fn get_reading_pair() -> ReadingPair {
     println!("First reading:");
-    let first = get_reading();
+    let first = get_reading(None);
     println!("Second reading:");
-    let second = get_reading();
+    let second = get_reading(Some(first.date));
     ReadingPair { first, second }
 }
 
-/// Create a Reading object from console input.
-pub fn get_reading()-> Reading {
+/// Create a Reading object from console input. The date must
+/// be after `last`, the date of the latest reading so far.
+pub fn get_reading(last: Option<NaiveDate>) -> Reading {
 
+    let today = Local::today().naive_local();
     let date = loop {
-        print!("    Date [DD-MM-YY]:    ");
-        io::stdout().flush().ok().expect("Could not flush!");
-        let date = ask_for_date();
-        match date {
-           Ok(d)=> break d, 
-           Err(_e) => println!("Invalid date."), 
+        print!("    Date [today]:       ");
+        io::stdout().flush().expect("Could not flush!");
+        let date = match parse_date(&read_input(), today) {
+            Ok(d) => d,
+            Err(_e) => {
+                println!("Invalid date. Try DD/MM/YYYY, YYYY-MM-DD, yesterday or -2d.");
+                continue;
+            },
+        };
+        if let Err(e) = check_date(date, today, last) {
+            println!("{}", e);
+            continue;
+        }
+        print!("    {}, right? [Y/n]  ", date.format("%A %d/%m/%Y"));
+        io::stdout().flush().expect("Could not flush!");
+        if !read_input().trim().eq_ignore_ascii_case("n") {
+            break date;
         }
     };
 
     let generation = loop {
         print!("    Generation [kWh]:   ");
-        io::stdout().flush().ok().expect("Could not flush!");
+        io::stdout().flush().expect("Could not flush!");
         let generation = ask_for_number();
         match generation {
             Ok(g) => break g,
@@ -40,7 +55,7 @@ pub fn get_reading()-> Reading {
 
     let imports = loop {
         print!("    Grid imports [kWh]: ");
-        io::stdout().flush().ok().expect("Could not flush!");
+        io::stdout().flush().expect("Could not flush!");
         let imports= ask_for_number();
         match imports {
             Ok(i) => break i,
@@ -50,7 +65,7 @@ pub fn get_reading()-> Reading {
     
     let exports = loop {
         print!("    Grid exports [kWh]: ");
-        io::stdout().flush().ok().expect("Could not flush!");
+        io::stdout().flush().expect("Could not flush!");
         let exports = ask_for_number();
         match exports {
             Ok(ex) => break ex,
@@ -58,44 +73,85 @@ pub fn get_reading()-> Reading {
         }
     };
 
+    print!("    Estimated? [y/N]:   ");
+    io::stdout().flush().expect("Could not flush!");
+    let source = if read_input().trim().eq_ignore_ascii_case("y") {
+        Source::Estimated
+    } else {
+        Source::Actual
+    };
+
+    print!("    Note:               ");
+    io::stdout().flush().expect("Could not flush!");
+    let note = read_input().trim().to_string();
+
     Reading {
         date,
         generation,
         exports,
         imports,
+        source,
+        note,
     }
 }
 
-fn ask_for_number() -> Result<f32, ParseFloatError> {
+
+// A line of console input without its newline. There's no
+// reading to make once the input has ended, so give up.
+fn read_input() -> String {
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
-        Ok(_) => {
-            input.pop() // Remove trailing newline.
+        Ok(0) => {
+            println!();
+            eprintln!("No reading entered.");
+            process::exit(1);
         },
-        Err(e) => panic!("Unexpected error {}", e),
-    };
-    input.parse::<f32>() 
-}
-
-fn ask_for_date() -> Result<NaiveDate, &'static str> {
-    
-    let mut input = String::new(); 
-    match io::stdin().read_line(&mut input) {
         Ok(_) => {
             input.pop(); // Remove trailing newline.
-            parse_date(input.as_str())
+            input
         },
         Err(e) => panic!("Unexpected error {}", e),
     }
 }
 
-/// Given a date as an ISO formatted string return
-/// a chrono date object. Assuming input is valid.
-fn parse_date(date_str: &str) -> Result<NaiveDate, &'static str> {
-    let date = NaiveDate::parse_from_str(date_str, "%d-%m-%y");
-    match date {
-        Err(_e) => Err("Failed to parse date"),
-        Ok(d) => Ok(d), 
+fn ask_for_number() -> Result<KilowattHours, ParseDecimalError> {
+    read_input().trim().parse::<KilowattHours>()
+}
+
+/// Read a date as typed at the prompt. Nothing or "today" is today,
+/// "yesterday" and "-2d" count back from today, and full dates are
+/// YYYY-MM-DD or DD/MM/YYYY. The older DD-MM-YY still works.
+fn parse_date(date_str: &str, today: NaiveDate) -> Result<NaiveDate, &'static str> {
+    let date_str = date_str.trim().to_lowercase();
+    let days_ago = match date_str.as_str() {
+        "" | "today" => Some(0),
+        "yesterday" => Some(1),
+        _ => date_str.strip_prefix('-')
+            .and_then(|days| days.strip_suffix('d'))
+            .and_then(|days| days.parse::<u16>().ok()),
+    };
+    if let Some(days) = days_ago {
+        return Ok(today - Duration::days(i64::from(days)));
+    }
+
+    ["%Y-%m-%d", "%d/%m/%Y", "%d-%m-%y"].iter()
+        .find_map(|format| NaiveDate::parse_from_str(&date_str, format).ok())
+        .ok_or("Failed to parse date")
+}
+
+// Refuse dates that haven't happened yet or that would go
+// before the latest reading.
+fn check_date(date: NaiveDate, today: NaiveDate, last: Option<NaiveDate>) -> Result<(), String> {
+    if date > today {
+        return Err(format!("{} hasn't happened yet.", date.format("%d/%m/%Y")));
+    }
+    match last {
+        Some(last) if date == last =>
+            Err(format!("There is already a reading for {}.", last.format("%d/%m/%Y"))),
+        Some(last) if date < last =>
+            Err(format!("The last reading was on {}, so the date must be after it.",
+                        last.format("%d/%m/%Y"))),
+        _ => Ok(()),
     }
 }
 
@@ -103,18 +159,46 @@ fn parse_date(date_str: &str) -> Result<NaiveDate, &'static str> {
 mod tests {
     use super::*;
 
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2020, 3, 2)
+    }
+
     #[test]
     fn good_date_input() {
-        let actual_result = parse_date("31-12-99");
+        let actual_result = parse_date("31-12-99", today());
         let expected_result = Ok(NaiveDate::from_ymd(1999, 12, 31));
         assert_eq!(actual_result, expected_result); 
     }
 
     #[test]
     fn bad_date_input() {
-        let actual_result = parse_date("*31-12-99");
+        let actual_result = parse_date("*31-12-99", today());
         let expected_result = Err("Failed to parse date");
         assert_eq!(actual_result, expected_result); 
+        assert!(parse_date("-d", today()).is_err());
+        assert!(parse_date("31/02/2020", today()).is_err());
+    }
+
+    #[test]
+    fn date_shorthand_input() {
+        assert_eq!(parse_date("", today()), Ok(today()));
+        assert_eq!(parse_date(" Today ", today()), Ok(today()));
+        assert_eq!(parse_date("yesterday", today()), Ok(NaiveDate::from_ymd(2020, 3, 1)));
+        assert_eq!(parse_date("-2d", today()), Ok(NaiveDate::from_ymd(2020, 2, 29)));
+        assert_eq!(parse_date("2020-02-14", today()), Ok(NaiveDate::from_ymd(2020, 2, 14)));
+        assert_eq!(parse_date("14/02/2020", today()), Ok(NaiveDate::from_ymd(2020, 2, 14)));
+    }
+
+    #[test]
+    fn date_checks() {
+        let last = Some(NaiveDate::from_ymd(2020, 2, 14));
+        assert!(check_date(today(), today(), last).is_ok());
+        assert!(check_date(today(), today(), None).is_ok());
+        assert_eq!(check_date(NaiveDate::from_ymd(2020, 3, 3), today(), None),
+                   Err("03/03/2020 hasn't happened yet.".to_string()));
+        assert_eq!(check_date(NaiveDate::from_ymd(2020, 2, 14), today(), last),
+                   Err("There is already a reading for 14/02/2020.".to_string()));
+        assert!(check_date(NaiveDate::from_ymd(2020, 2, 1), today(), last).is_err());
     }
 }
 
@@ -1,53 +1,670 @@
-use sqlite::{ Connection, Value };
-use chrono::{ NaiveDate };
+use std::ffi::{ CStr, CString };
+use std::fmt;
+use std::panic::{ self, AssertUnwindSafe };
+use std::path::Path;
+use sqlite::{ Connection, OpenFlags, State, Value };
+use sqlite3_sys as ffi;
+use chrono::{ Local, NaiveDate, NaiveDateTime };
 
-use crate::readings::Reading;
+use crate::audit::{ parse_reading_values, parse_tariff_values, reading_values, tariff_values,
+                    Action, Change };
+use crate::calc::{ default_tariffs, Tariffs };
+use crate::decimal::Decimal;
+use crate::intervals::{ check_interval_lengths, Interval };
+use crate::readings::{ validate_reading, Reading };
+use crate::store::{ already_stored, ReadingStore };
+use crate::units::DollarsPerKwh;
 
+const DATE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
-// Helper function. Take a row, get a reading.
-fn row_to_reading(row : &[Value]) -> Reading {
-    let date = row[0].as_string().unwrap();
-    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
-    let generation = row[1].as_float().unwrap() as f32;
-    let imports =    row[2].as_float().unwrap() as f32;
-    let exports =    row[3].as_float().unwrap() as f32;
-    Reading { date, generation, imports, exports }
+// Backups are named for when they were made, so they sort by age.
+const BACKUP_NAME_FORMAT: &str = "energy-%Y%m%d-%H%M%S.db";
+
+// How many pages to copy at a time when backing up, letting
+// others use the database in between.
+const PAGES_PER_STEP: i32 = 64;
+
+// How long to wait for another program using the database to
+// finish before giving up. The servers and the command line
+// share one file.
+const BUSY_TIMEOUT_MS: usize = 5000;
+
+// The names of the registers in the reading table, in order.
+const REGISTERS: [&str; 3] = ["generation", "imports", "exports"];
+
+// Energy and money columns, kept as whole millionths of a kilowatt / hour
+// or dollar. Older databases kept them as REAL.
+const EXACT_COLUMNS: [(&str, &[&str]); 3] = [
+    ("reading", &["generation", "imports", "exports"]),
+    ("interval", &["imports", "exports"]),
+    ("tariff", &["import", "export"]),
+];
+
+
+// Helper function. Take a row, get a reading, or say
+// what about the row can't be read.
+fn row_to_reading(row : &[Value]) -> Result<Reading, String> {
+    let date = row[0].as_string()
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        .ok_or_else(|| format!("unreadable date {}", describe(&row[0])))?;
+    let register = |index : usize| row[index].as_integer()
+        .map(|raw| Decimal::from_raw(raw).into())
+        .ok_or_else(|| format!("{}: unreadable {} {}", date.format("%d/%m/%Y"),
+                               REGISTERS[index - 1], describe(&row[index])));
+    let generation = register(1)?;
+    let imports =    register(2)?;
+    let exports =    register(3)?;
+    let source = row[4].as_string().and_then(|source| source.parse().ok()).unwrap_or_default();
+    let note = row[5].as_string().unwrap_or_default().to_string();
+    Ok(Reading { date, generation, imports, exports, source, note })
+}
+
+// The reading in a row, or none if the row can't be read.
+// `check_storage` reports the rows left out this way.
+fn readable(row : &[Value]) -> Option<Reading> {
+    row_to_reading(row).ok()
+}
+
+// A stored value as text, for saying what couldn't be read.
+fn describe(value : &Value) -> String {
+    value.as_string().map_or(format!("{:?}", value), |text| format!("'{}'", text))
+}
+
+// Energy and money are stored as whole millionths.
+fn convert_for_sqlite<T : Into<Decimal>>(value : T) -> Value {
+    Value::Integer(value.into().raw())
+}
+
+fn from_sqlite<T : From<Decimal>>(value : &Value) -> T {
+    Decimal::from_raw(value.as_integer().unwrap()).into()
+}
+
+// Helper function. Take a row, get a change from the audit log.
+fn row_to_change(row : &[Value]) -> Change {
+    let text = |value : &Value| value.as_string().map(String::from);
+    Change {
+        id: row[0].as_integer().unwrap(),
+        time: NaiveDateTime::parse_from_str(row[1].as_string().unwrap(), DATE_TIME_FORMAT).unwrap(),
+        user: text(&row[2]).unwrap(),
+        subject: text(&row[3]).unwrap(),
+        key: text(&row[4]).unwrap(),
+        action: Action::from_name(row[5].as_string().unwrap()).unwrap(),
+        old: text(&row[6]),
+        new: text(&row[7]),
+        reason: text(&row[8]).unwrap(),
+    }
+}
+
+// Helper function. Take a row, get an interval.
+fn row_to_interval(row : &[Value]) -> Interval {
+    let start = row[0].as_string().unwrap();
+    let start = NaiveDateTime::parse_from_str(start, DATE_TIME_FORMAT).unwrap();
+    let minutes = row[1].as_integer().unwrap() as u32;
+    let imports = from_sqlite(&row[2]);
+    let exports = from_sqlite(&row[3]);
+    let estimated = row[4].as_integer().unwrap() != 0;
+    Interval { start, minutes, imports, exports, estimated }
+}
+
+/// How many rows of a batch were written.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatchCounts {
+    pub inserted: usize,
+    /// Rows the same as ones already stored.
+    pub skipped: usize,
+    /// Rows that differ from ones already stored, which were kept.
+    pub conflicting: usize,
+}
+
+/// Allow a BatchCounts object to be passed to println!() etc.
+impl fmt::Display for BatchCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} added, {} already stored, {} conflicting",
+               self.inserted, self.skipped, self.conflicting)
+    }
+}
+
+/// Something wrong with what is stored in the database, as opposed
+/// to anything odd in the readings themselves.
+#[derive(Debug, PartialEq)]
+pub enum StorageProblem {
+    /// Reported by SQLite's own integrity check.
+    Corrupt(String),
+    /// A reading with a date that can't be read.
+    BadDate { row: i64, date: String },
+    /// A register that isn't a whole number of millionths.
+    BadValue { row: i64, register: &'static str },
+    /// More than one reading for a date.
+    DuplicateDate { date: String, count: i64 },
+    /// A register lower than it was in the reading before.
+    WentBackwards { register: &'static str, date: NaiveDate, previous: NaiveDate },
 }
 
-// Convert the f32 to f64 and round to one decimal place.
-fn convert_for_sqlite(f32_val : f32) -> f64 {
-    let f64_val = f32_val as f64;
-    (f64_val * 10.0).round() / 10.0
+impl StorageProblem {
+    /// True if the readings can't safely be read back until it is fixed.
+    pub fn stops_reading(&self) -> bool {
+        matches!(self, StorageProblem::Corrupt(_)
+                     | StorageProblem::BadDate { .. }
+                     | StorageProblem::BadValue { .. })
+    }
 }
 
+/// Allow a StorageProblem to be passed to println!() etc.
+impl fmt::Display for StorageProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageProblem::Corrupt(message) =>
+                write!(f, "integrity check: {}", message),
+            StorageProblem::BadDate { row, date } =>
+                write!(f, "row {}: unreadable date {:?}", row, date),
+            StorageProblem::BadValue { row, register } =>
+                write!(f, "row {}: unreadable {}", row, register),
+            StorageProblem::DuplicateDate { date, count } =>
+                write!(f, "{} readings for {}", count, date),
+            StorageProblem::WentBackwards { register, date, previous } =>
+                write!(f, "{}: {} lower than on {}", date.format("%d/%m/%Y"),
+                       register, previous.format("%d/%m/%Y")),
+        }
+    }
+}
+
+/// The name of a backup made at the given time.
+pub fn backup_name(time : NaiveDateTime) -> String {
+    time.format(BACKUP_NAME_FORMAT).to_string()
+}
+
+/// Of the files named like backups, those older than the newest
+/// `keep`, oldest first. Other files are left out.
+pub fn expired_backups(names : &[String], keep : usize) -> Vec<String> {
+    let mut backups: Vec<(NaiveDateTime, &String)> = names.iter()
+        .filter_map(|name| NaiveDateTime::parse_from_str(name, BACKUP_NAME_FORMAT).ok()
+                    .map(|time| (time, name)))
+        .collect();
+    backups.sort();
+    let expired = backups.len().saturating_sub(keep);
+    backups[..expired].iter().map(|(_, name)| name.to_string()).collect()
+}
+
+// Copy every page of one database into another with SQLite's
+// online backup, which is safe while others are using it.
+fn copy_database(from : &Connection, to : &Connection) -> Result<(), String> {
+    let main = CString::new("main").unwrap();
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(to.as_raw(), main.as_ptr(),
+                                              from.as_raw(), main.as_ptr());
+        if backup.is_null() {
+            return Err(CStr::from_ptr(ffi::sqlite3_errmsg(to.as_raw())).to_string_lossy().to_string());
+        }
+
+        let mut result = ffi::SQLITE_OK;
+        while result == ffi::SQLITE_OK || result == ffi::SQLITE_BUSY || result == ffi::SQLITE_LOCKED {
+            result = ffi::sqlite3_backup_step(backup, PAGES_PER_STEP);
+            if result == ffi::SQLITE_BUSY || result == ffi::SQLITE_LOCKED {
+                ffi::sqlite3_sleep(100);
+            }
+        }
+        ffi::sqlite3_backup_finish(backup);
+
+        if result == ffi::SQLITE_DONE {
+            Ok(())
+        } else {
+            Err(CStr::from_ptr(ffi::sqlite3_errstr(result)).to_string_lossy().to_string())
+        }
+    }
+}
+
+// What SQLite's integrity check finds wrong, if anything.
+fn integrity_problems(connection : &Connection) -> Vec<String> {
+    let mut cursor = connection.prepare("PRAGMA integrity_check").unwrap().cursor();
+
+    let mut problems = Vec::new();
+    while let Some(row) = cursor.next().unwrap() {
+        match row[0].as_string() {
+            Some("ok") => (),
+            Some(message) => problems.push(message.to_string()),
+            None => problems.push(format!("{:?}", row[0])),
+        }
+    }
+    problems
+}
+
+/// Called after a reading is saved with the reading before it, if any,
+/// and the reading itself.
+pub type ReadingHook = Box<dyn Fn(Option<&Reading>, &Reading)>;
+
 pub struct Database {
    connection : Connection,
+   hooks : Vec<ReadingHook>,
+   // Who is making changes and why, for the audit log.
+   user : String,
+   reason : String,
 }
 
 impl Database {
     /// Get a connection to a database with the given filename.
     pub fn open(file : &str) -> Database {
-        let connection = sqlite::open(file).unwrap();
-        
+        let mut connection = sqlite::open(file).unwrap();
+        connection.set_busy_timeout(BUSY_TIMEOUT_MS).unwrap();
+
         let db = Database {
             connection,
+            hooks: Vec::new(),
+            user: "unknown".to_string(),
+            reason: String::new(),
         };
 
-        if !db.table_exists("reading") {
-            db.create_tables();
-        }
-
+        db.create_tables();
         db
     }
 
-    /// Create all needed tables.
+    /// Create any needed tables that don't exist yet, and bring
+    /// tables from older versions up to date.
     pub fn create_tables(&self) {
-        self.connection.execute("
-            CREATE TABLE reading (
-            date TEXT NOT NULL,
-            generation REAL NOT NULL,
-            imports REAL NOT NULL,
-            exports REAL NOT NULL)").unwrap();
+        self.connection.execute("BEGIN IMMEDIATE TRANSACTION").unwrap();
+
+        // Tables with REAL columns are moved aside, created
+        // afresh below and then filled from the old ones.
+        let outdated: Vec<_> = EXACT_COLUMNS.iter()
+            .filter(|(table, columns)| {
+                self.columns(table).iter()
+                    .any(|(name, kind)| columns.contains(&name.as_str()) && kind == "REAL")
+            })
+            .collect();
+        for (table, _) in outdated.iter() {
+            self.connection.execute(format!("ALTER TABLE {} RENAME TO old_{}", table, table))
+                .unwrap();
+        }
+
+        // Energy is in millionths of a kilowatt / hour. Readings from
+        // before there was a source were all read off the meter.
+        if !self.table_exists("reading") {
+            self.connection.execute("
+                CREATE TABLE reading (
+                date TEXT NOT NULL,
+                generation INTEGER NOT NULL,
+                imports INTEGER NOT NULL,
+                exports INTEGER NOT NULL,
+                source TEXT NOT NULL DEFAULT 'actual',
+                note TEXT NOT NULL DEFAULT '')").unwrap();
+        }
+        let columns = self.columns("reading");
+        if !columns.iter().any(|(name, _)| name == "source") {
+            self.connection.execute("
+                ALTER TABLE reading ADD COLUMN source TEXT NOT NULL DEFAULT 'actual';
+                ALTER TABLE reading ADD COLUMN note TEXT NOT NULL DEFAULT ''").unwrap();
+        }
+
+        // The primary key on start keeps range queries quick.
+        if !self.table_exists("interval") {
+            self.connection.execute("
+                CREATE TABLE interval (
+                start TEXT NOT NULL PRIMARY KEY,
+                minutes INTEGER NOT NULL,
+                imports INTEGER NOT NULL,
+                exports INTEGER NOT NULL,
+                estimated INTEGER NOT NULL DEFAULT 0)").unwrap();
+        }
+        if !self.columns("interval").iter().any(|(name, _)| name == "estimated") {
+            self.connection.execute("
+                ALTER TABLE interval ADD COLUMN estimated INTEGER NOT NULL DEFAULT 0").unwrap();
+        }
+
+        // There is only ever one row, holding the current flat rates
+        // in millionths of a dollar.
+        if !self.table_exists("tariff") {
+            self.connection.execute("
+                CREATE TABLE tariff (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                import INTEGER NOT NULL,
+                export INTEGER NOT NULL)").unwrap();
+        }
+
+        // Every change made to readings and tariffs, oldest first.
+        // Changes can only be added, never altered or taken away.
+        if !self.table_exists("audit") {
+            self.connection.execute("
+                CREATE TABLE audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                time TEXT NOT NULL,
+                user TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                key TEXT NOT NULL,
+                action TEXT NOT NULL,
+                old TEXT,
+                new TEXT,
+                reason TEXT NOT NULL);
+                CREATE TRIGGER audit_no_update BEFORE UPDATE ON audit
+                BEGIN SELECT RAISE(ABORT, 'the audit log can only be added to'); END;
+                CREATE TRIGGER audit_no_delete BEFORE DELETE ON audit
+                BEGIN SELECT RAISE(ABORT, 'the audit log can only be added to'); END").unwrap();
+        }
+
+        // The old values were rounded to at most three places, or were
+        // rates with a few more, which a REAL holds closely enough that
+        // rounding the millionths gives back exactly what was meant.
+        for (table, columns) in outdated.iter() {
+            let old_columns = self.columns(&format!("old_{}", table));
+            let names: Vec<&str> = old_columns.iter().map(|(name, _)| name.as_str()).collect();
+            let values: Vec<String> = names.iter()
+                .map(|name| if columns.contains(name) {
+                    format!("CAST(ROUND({} * 1000000) AS INTEGER)", name)
+                } else {
+                    name.to_string()
+                })
+                .collect();
+            self.connection.execute(format!("INSERT INTO {} ( {} ) SELECT {} FROM old_{}; DROP TABLE old_{}",
+                                            table, names.join(", "), values.join(", "), table, table))
+                .unwrap();
+        }
+
+        // One reading per date. If some dates already have more than one,
+        // check_storage reports them and the index waits until they're fixed.
+        let mut cursor = self.connection.prepare(
+            "SELECT COUNT(*) FROM reading GROUP BY date HAVING COUNT(*) > 1").unwrap().cursor();
+        if cursor.next().unwrap().is_none() {
+            self.connection.execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS reading_date ON reading ( date )").unwrap();
+        }
+
+        self.connection.execute("COMMIT").unwrap();
+    }
+
+    // The names and types of a table's columns, in order.
+    fn columns(&self, table : &str) -> Vec<(String, String)> {
+        let mut cursor = self.connection.prepare(format!("PRAGMA table_info({})", table))
+            .unwrap().cursor();
+
+        let mut columns = Vec::new();
+        while let Some(row) = cursor.next().unwrap() {
+            columns.push((row[1].as_string().unwrap().to_string(),
+                          row[2].as_string().unwrap().to_uppercase()));
+        }
+        columns
+    }
+
+    /// Copy the whole database to a new file, which must not exist yet.
+    /// Readings can be added while the copy is made.
+    pub fn backup_to(&self, file : &Path) -> Result<(), String> {
+        if file.exists() {
+            return Err(format!("{} already exists", file.display()));
+        }
+        let backup = sqlite::open(file).map_err(|e| e.to_string())?;
+        copy_database(&self.connection, &backup)
+    }
+
+    /// Replace everything in the database with a backup, as long
+    /// as the backup passes SQLite's integrity check.
+    pub fn restore_from(&self, file : &Path) -> Result<(), String> {
+        let backup = Connection::open_with_flags(file, OpenFlags::new().set_read_only())
+            .map_err(|e| e.to_string())?;
+        if let Some(problem) = integrity_problems(&backup).first() {
+            return Err(format!("the backup is damaged: {}", problem));
+        }
+        let changes = self.changes_after(0);
+        copy_database(&backup, &self.connection)?;
+
+        // The backup may be from an older version. The audit log
+        // carries on from where it was, changes since the backup
+        // and all.
+        self.create_tables();
+        let last = self.changes_after(0).last().map_or(0, |change| change.id);
+        for change in changes.iter().filter(|change| change.id > last) {
+            self.add_change(change);
+        }
+        self.record("database", &file.display().to_string(), Action::Restore, None, None);
+        Ok(())
+    }
+
+    /// Look for anything stored that would stop the readings being
+    /// read back or used: damage SQLite itself finds, dates and values
+    /// that can't be read, dates with more than one reading and
+    /// registers that go backwards.
+    pub fn check_storage(&self) -> Vec<StorageProblem> {
+        let mut problems: Vec<StorageProblem> = integrity_problems(&self.connection).into_iter()
+            .map(StorageProblem::Corrupt)
+            .collect();
+
+        let mut cursor = self.connection.prepare(
+            "SELECT date, COUNT(*) FROM reading
+             GROUP BY date HAVING COUNT(*) > 1
+             ORDER BY date").unwrap().cursor();
+        while let Some(row) = cursor.next().unwrap() {
+            problems.push(StorageProblem::DuplicateDate {
+                date: row[0].as_string().map_or(format!("{:?}", row[0]), String::from),
+                count: row[1].as_integer().unwrap(),
+            });
+        }
+
+        let mut cursor = self.connection.prepare(
+            "SELECT rowid, date, generation, imports, exports FROM reading
+             ORDER BY date, rowid").unwrap().cursor();
+        let mut previous: Option<(NaiveDate, Vec<i64>)> = None;
+        while let Some(row) = cursor.next().unwrap() {
+            let id = row[0].as_integer().unwrap();
+            let date = match row[1].as_string()
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()) {
+                Some(date) => date,
+                None => {
+                    let date = row[1].as_string().map_or(format!("{:?}", row[1]), String::from);
+                    problems.push(StorageProblem::BadDate { row: id, date });
+                    continue;
+                },
+            };
+
+            let values: Vec<Option<i64>> = row[2..].iter().map(|value| value.as_integer()).collect();
+            for (register, value) in REGISTERS.iter().zip(values.iter()) {
+                if value.is_none() {
+                    problems.push(StorageProblem::BadValue { row: id, register });
+                }
+            }
+            if values.iter().any(|value| value.is_none()) {
+                continue;
+            }
+            let values: Vec<i64> = values.into_iter().flatten().collect();
+
+            if let Some((before, earlier)) = previous.as_ref() {
+                for ((register, value), earlier) in REGISTERS.iter().zip(values.iter()).zip(earlier.iter()) {
+                    if value < earlier && date != *before {
+                        problems.push(StorageProblem::WentBackwards { register, date, previous: *before });
+                    }
+                }
+            }
+            previous = Some((date, values));
+        }
+        problems
+    }
+
+    /// Name who is making changes and why, for the audit log.
+    pub fn set_audit_details(&mut self, user : &str, reason : &str) {
+        self.user = user.to_string();
+        self.reason = reason.to_string();
+    }
+
+    // Add a change to the audit log, made now by the current user.
+    fn record(&self, subject : &str, key : &str, action : Action,
+              old : Option<String>, new : Option<String>) {
+        self.add_change(&Change {
+            id: 0,
+            time: Local::now().naive_local(),
+            user: self.user.clone(),
+            subject: subject.to_string(),
+            key: key.to_string(),
+            action,
+            old,
+            new,
+            reason: self.reason.clone(),
+        });
+    }
+
+    // Add a change to the audit log, numbered next unless it has an id.
+    fn add_change(&self, change : &Change) {
+        let mut cursor = self.connection.prepare(
+            "INSERT INTO audit ( id, time, user, subject, key, action, old, new, reason )
+             VALUES ( ?, ?, ?, ?, ?, ?, ?, ?, ? )").unwrap().cursor();
+
+        let text = |value : &Option<String>| value.clone().map_or(Value::Null, Value::String);
+        let id = if change.id > 0 { Value::Integer(change.id) } else { Value::Null };
+        cursor.bind(&[id,
+                      Value::String(change.time.format(DATE_TIME_FORMAT).to_string()),
+                      Value::String(change.user.clone()),
+                      Value::String(change.subject.clone()),
+                      Value::String(change.key.clone()),
+                      Value::String(change.action.name().to_string()),
+                      text(&change.old),
+                      text(&change.new),
+                      Value::String(change.reason.clone())]).unwrap();
+        cursor.next().unwrap();
+    }
+
+    // The changes in the audit log after the one with an id, oldest first.
+    fn changes_after(&self, id : i64) -> Vec<Change> {
+        let mut cursor = self.connection.prepare(
+            "SELECT * FROM audit WHERE id > ? ORDER BY id").unwrap().cursor();
+        cursor.bind(&[Value::Integer(id)]).unwrap();
+
+        let mut changes = Vec::new();
+        while let Some(row) = cursor.next().unwrap() {
+            changes.push(row_to_change(row));
+        }
+        changes
+    }
+
+    /// Every change made to the reading for a date, oldest first.
+    pub fn reading_history(&self, date : NaiveDate) -> Vec<Change> {
+        self.history("reading", &date.format("%Y-%m-%d").to_string())
+    }
+
+    /// Every change made to the tariffs, oldest first.
+    pub fn tariff_history(&self) -> Vec<Change> {
+        self.history("tariff", "flat")
+    }
+
+    // Every change to one row, oldest first.
+    fn history(&self, subject : &str, key : &str) -> Vec<Change> {
+        let mut cursor = self.connection.prepare(
+            "SELECT * FROM audit WHERE subject = ? AND key = ? ORDER BY id").unwrap().cursor();
+        cursor.bind(&[Value::String(subject.to_string()), Value::String(key.to_string())]).unwrap();
+
+        let mut changes = Vec::new();
+        while let Some(row) = cursor.next().unwrap() {
+            changes.push(row_to_change(row));
+        }
+        changes
+    }
+
+    /// Undo a change from the audit log, giving the change undone. It
+    /// can only be undone if nothing has changed the same row since.
+    /// Undoing it is logged like any other change.
+    pub fn revert(&self, id : i64) -> Result<Change, String> {
+        let change = match self.changes_after(id - 1).into_iter().next() {
+            Some(change) if change.id == id => change,
+            _ => return Err(format!("there is no change #{}", id)),
+        };
+
+        let unreadable = || format!("the values in change #{} can't be read", id);
+        self.transaction(|db| match change.subject.as_str() {
+            "reading" => {
+                let date = NaiveDate::parse_from_str(&change.key, "%Y-%m-%d").map_err(|_e| unreadable())?;
+                let current = db.get_reading_for_date(date);
+                let logged = change.new.as_deref()
+                    .map(|new| parse_reading_values(date, new).ok_or_else(unreadable))
+                    .transpose()?;
+                if current != logged {
+                    return Err(format!("the reading for {} has changed since", change.key));
+                }
+                match (&change.old, current) {
+                    (None, _) => {
+                        db.delete_reading(date);
+                    },
+                    (Some(old), current) => {
+                        let old = parse_reading_values(date, old).ok_or_else(unreadable)?;
+                        if current.is_some() {
+                            db.update_reading(&old);
+                        } else {
+                            db.add_reading(&old)?;
+                        }
+                    },
+                }
+                Ok(())
+            },
+            "tariff" => {
+                let current = db.stored_tariffs().map(|tariffs| (tariffs.import, tariffs.export));
+                let logged = change.new.as_deref()
+                    .map(|new| parse_tariff_values(new).ok_or_else(unreadable))
+                    .transpose()?;
+                if current != logged {
+                    return Err("the tariffs have changed since".to_string());
+                }
+                match &change.old {
+                    None => db.delete_tariffs(),
+                    Some(old) => {
+                        let (import, export) = parse_tariff_values(old).ok_or_else(unreadable)?;
+                        db.store_tariffs(import, export);
+                    },
+                }
+                Ok(())
+            },
+            subject => Err(format!("changes to {} can't be undone", subject)),
+        })?;
+        Ok(change)
+    }
+
+    // Run `f` in a transaction that always keeps what it did.
+    fn in_transaction<F : FnOnce(&Database)>(&self, f : F) {
+        let done: Result<(), ()> = self.transaction(|db| {
+            f(db);
+            Ok(())
+        });
+        done.unwrap();
+    }
+
+    /// Run `f` in a transaction, keeping what it did if it gives Ok and
+    /// undoing all of it if it gives Err or panics. Transactions can be nested.
+    pub fn transaction<T, E, F>(&self, f : F) -> Result<T, E>
+        where F: FnOnce(&Database) -> Result<T, E> {
+        // The outermost transaction takes the write lock up front. One
+        // that reads first and then has to wait to write fails at once
+        // rather than waiting out the busy timeout.
+        let outermost = unsafe { ffi::sqlite3_get_autocommit(self.connection.as_raw()) } != 0;
+        if outermost {
+            self.connection.execute("BEGIN IMMEDIATE").unwrap();
+        }
+        self.connection.execute("SAVEPOINT batch").unwrap();
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(panic) => {
+                // Don't leave the database locked for whoever carries on.
+                let _ = self.connection.execute("ROLLBACK TO batch; RELEASE batch");
+                if outermost {
+                    let _ = self.connection.execute("ROLLBACK");
+                }
+                panic::resume_unwind(panic);
+            },
+        };
+        if result.is_err() {
+            self.connection.execute("ROLLBACK TO batch").unwrap();
+        }
+        self.connection.execute("RELEASE batch").unwrap();
+        if outermost {
+            self.connection.execute("COMMIT").unwrap();
+        }
+        result
+    }
+
+    /// Run the hook each time a reading is added or saved.
+    pub fn on_reading_added(&mut self, hook : ReadingHook) {
+        self.hooks.push(hook);
+    }
+
+    // Let the hooks know about a new reading.
+    fn run_hooks(&self, reading : &Reading) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let previous = self.reading_before(reading.date);
+        for hook in self.hooks.iter() {
+            hook(previous.as_ref(), reading);
+        }
     }
 
     /// True if a table with the given name exists.
@@ -61,25 +678,155 @@ impl Database {
         count == 1
     }
     
-    /// Add a new reading to the database.
-    pub fn add_reading(&self, reading : &Reading) {
-        let mut cursor = self.connection.prepare(
-            "INSERT INTO reading ( date, generation, imports, exports )
-             VALUES ( ?, ?, ?, ? )").unwrap().cursor();
-        
-        let date = reading.date.format("%Y-%m-%d").to_string();
-        let generation = convert_for_sqlite(reading.generation);
-        let imports = convert_for_sqlite(reading.imports);
-        let exports = convert_for_sqlite(reading.exports);
-        cursor.bind(&[Value::String(date), 
-                      Value::Float(generation),
-                      Value::Float(imports),
-                      Value::Float(exports)]).unwrap();
-        
-        cursor.next().unwrap();
+    /// Add a new reading to the database, or give an error if there
+    /// is already one for its date or it doesn't fit between the
+    /// readings either side of it.
+    pub fn add_reading(&self, reading : &Reading) -> Result<(), String> {
+        self.insert_reading(reading)?;
+        self.run_hooks(reading);
+        Ok(())
+    }
+
+    // Add a new reading without letting the hooks know.
+    fn insert_reading(&self, reading : &Reading) -> Result<(), String> {
+        self.transaction(|db| {
+            if db.reading_stored(reading.date) {
+                return Err(already_stored(reading.date));
+            }
+            db.check_fits(reading)?;
+
+            let mut cursor = db.connection.prepare(
+                "INSERT INTO reading ( date, generation, imports, exports, source, note )
+                 VALUES ( ?, ?, ?, ?, ?, ? )").unwrap().cursor();
+
+            let date = reading.date.format("%Y-%m-%d").to_string();
+            let generation = convert_for_sqlite(reading.generation);
+            let imports = convert_for_sqlite(reading.imports);
+            let exports = convert_for_sqlite(reading.exports);
+            cursor.bind(&[Value::String(date.clone()),
+                          generation,
+                          imports,
+                          exports,
+                          Value::String(reading.source.to_string()),
+                          Value::String(reading.note.clone())]).unwrap();
+
+            // Another program may have stored one since the check above.
+            match cursor.next() {
+                Err(e) if e.code == Some(ffi::SQLITE_CONSTRAINT as isize) =>
+                    return Err(already_stored(reading.date)),
+                result => result.unwrap(),
+            };
+            db.record("reading", &date, Action::Insert, None, Some(reading_values(reading)));
+            Ok(())
+        })
     }
     
 
+    /// Add readings in one transaction, earliest first. Readings the
+    /// same as those stored are skipped, and ones that differ are left
+    /// as stored. If a new reading doesn't follow on from the one before
+    /// it, nothing is added.
+    pub fn add_readings(&self, readings : &[Reading]) -> Result<BatchCounts, String> {
+        let mut readings = readings.to_vec();
+        readings.sort_by_key(|reading| reading.date);
+
+        let added: Result<_, String> = self.transaction(|db| {
+            let mut find = db.connection.prepare(
+                "SELECT * FROM reading WHERE date = ?").unwrap().cursor();
+            let mut before = db.connection.prepare(
+                "SELECT * FROM reading
+                 WHERE date < ?
+                 ORDER BY date DESC
+                 LIMIT 1").unwrap().cursor();
+            let mut insert = db.connection.prepare(
+                "INSERT INTO reading ( date, generation, imports, exports, source, note )
+                 VALUES ( ?, ?, ?, ?, ?, ? )").unwrap().cursor();
+
+            let mut counts = BatchCounts::default();
+            let mut added = Vec::new();
+            for reading in readings.iter() {
+                let date = [Value::String(reading.date.format("%Y-%m-%d").to_string())];
+
+                find.bind(&date).unwrap();
+                match find.next().unwrap().map(row_to_reading) {
+                    Some(Ok(stored)) if stored == *reading => counts.skipped += 1,
+                    Some(_) => counts.conflicting += 1,
+                    None => {
+                        before.bind(&date).unwrap();
+                        let previous = before.next().unwrap().and_then(readable);
+                        validate_reading(reading, previous.as_ref())
+                            .map_err(|e| format!("{}: {}", reading.date, e))?;
+
+                        insert.bind(&[date[0].clone(),
+                                      convert_for_sqlite(reading.generation),
+                                      convert_for_sqlite(reading.imports),
+                                      convert_for_sqlite(reading.exports),
+                                      Value::String(reading.source.to_string()),
+                                      Value::String(reading.note.clone())]).unwrap();
+                        insert.next().unwrap();
+                        db.record("reading", &reading.date.format("%Y-%m-%d").to_string(),
+                                  Action::Insert, None, Some(reading_values(reading)));
+                        counts.inserted += 1;
+                        added.push(reading);
+                    },
+                }
+            }
+            Ok((counts, added))
+        });
+
+        // Only once they are kept for good.
+        let (counts, added) = added?;
+        for reading in added.iter() {
+            self.run_hooks(reading);
+        }
+        Ok(counts)
+    }
+
+    /// Replace the values of the stored reading with the same date,
+    /// even if the stored values can't be read.
+    pub fn update_reading(&self, reading : &Reading) {
+        if !self.reading_stored(reading.date) {
+            return;
+        }
+        let old = self.get_reading_for_date(reading.date);
+
+        self.in_transaction(|db| {
+            let mut cursor = db.connection.prepare(
+                "UPDATE reading SET generation = ?, imports = ?, exports = ?, source = ?, note = ?
+                 WHERE date = ?").unwrap().cursor();
+
+            let date = reading.date.format("%Y-%m-%d").to_string();
+            cursor.bind(&[convert_for_sqlite(reading.generation),
+                          convert_for_sqlite(reading.imports),
+                          convert_for_sqlite(reading.exports),
+                          Value::String(reading.source.to_string()),
+                          Value::String(reading.note.clone()),
+                          Value::String(date.clone())]).unwrap();
+
+            cursor.next().unwrap();
+            db.record("reading", &date, Action::Update,
+                      old.as_ref().map(reading_values), Some(reading_values(reading)));
+        });
+    }
+
+    /// Remove the reading for a date, giving false if there wasn't one.
+    pub fn delete_reading(&self, date : NaiveDate) -> bool {
+        if !self.reading_stored(date) {
+            return false;
+        }
+        let old = self.get_reading_for_date(date);
+
+        self.in_transaction(|db| {
+            let date = date.format("%Y-%m-%d").to_string();
+            let mut cursor = db.connection.prepare(
+                "DELETE FROM reading WHERE date = ?").unwrap().cursor();
+            cursor.bind(&[Value::String(date.clone())]).unwrap();
+            cursor.next().unwrap();
+            db.record("reading", &date, Action::Delete, old.as_ref().map(reading_values), None);
+        });
+        true
+    }
+
     /// Get the reading for a given date or none if it doesn't exists.
     pub fn get_reading_for_date(&self, date : NaiveDate) -> Option<Reading> {
         let mut cursor = self.connection.prepare(
@@ -91,10 +838,18 @@ impl Database {
         cursor.bind(&[Value::String(date)]).unwrap();
 
         let first_row = cursor.next().unwrap();
-        match first_row {
-            Some(row) => Some(row_to_reading(&row)),
-            None => None,
-        }
+        first_row.and_then(readable)
+    }
+
+    // Whether there's a row for the date, even one that can't be read.
+    fn reading_stored(&self, date : NaiveDate) -> bool {
+        let mut cursor = self.connection.prepare(
+            "SELECT COUNT(*) FROM reading WHERE date = ?")
+            .unwrap().cursor();
+
+        cursor.bind(&[Value::String(date.format("%Y-%m-%d").to_string())]).unwrap();
+
+        cursor.next().unwrap().unwrap()[0].as_integer().unwrap() > 0
     }
 
     pub fn most_recent_reading(&self) -> Option<Reading> {
@@ -104,12 +859,119 @@ impl Database {
              LIMIT 1").unwrap().cursor();
         
         let first_row = cursor.next().unwrap();
-        match first_row {
-            Some(row) => Some(row_to_reading(&row)),
-            None => None,
-        }
+        first_row.and_then(readable)
     }
     
+    /// The latest reading made before the given date.
+    pub fn reading_before(&self, date : NaiveDate) -> Option<Reading> {
+        let mut cursor = self.connection.prepare(
+            "SELECT * FROM reading
+             WHERE date < ?
+             ORDER BY date DESC
+             LIMIT 1").unwrap().cursor();
+
+        cursor.bind(&[Value::String(date.format("%Y-%m-%d").to_string())]).unwrap();
+
+        let first_row = cursor.next().unwrap();
+        first_row.and_then(readable)
+    }
+
+    /// The earliest reading made after the given date.
+    pub fn reading_after(&self, date : NaiveDate) -> Option<Reading> {
+        let mut cursor = self.connection.prepare(
+            "SELECT * FROM reading
+             WHERE date > ?
+             ORDER BY date ASC
+             LIMIT 1").unwrap().cursor();
+
+        cursor.bind(&[Value::String(date.format("%Y-%m-%d").to_string())]).unwrap();
+
+        let first_row = cursor.next().unwrap();
+        first_row.and_then(readable)
+    }
+
+    /// Add a reading, or replace the one already stored for its date,
+    /// as long as it fits between the readings either side of it.
+    pub fn save_reading(&self, reading : &Reading) -> Result<(), String> {
+        self.store_reading(reading)?;
+        self.run_hooks(reading);
+        Ok(())
+    }
+
+    // Add or replace a reading without letting the hooks know.
+    fn store_reading(&self, reading : &Reading) -> Result<(), String> {
+        self.check_fits(reading)?;
+
+        if self.reading_stored(reading.date) {
+            self.update_reading(reading);
+            Ok(())
+        } else {
+            self.insert_reading(reading)
+        }
+    }
+
+    // Check a reading follows on from the reading before it
+    // and leads on to the reading after it.
+    fn check_fits(&self, reading : &Reading) -> Result<(), String> {
+        validate_reading(reading, self.reading_before(reading.date).as_ref())?;
+        if let Some(after) = self.reading_after(reading.date) {
+            validate_reading(&after, Some(reading))
+                .map_err(|e| format!("the reading after: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Save several readings, earliest first, in one transaction so
+    /// none are kept if any don't follow on from the reading before.
+    pub fn save_readings(&self, readings : &[Reading]) -> Result<(), String> {
+        let mut readings = readings.to_vec();
+        readings.sort_by_key(|reading| reading.date);
+
+        self.transaction(|db| {
+            readings.iter().try_for_each(|reading| {
+                db.store_reading(reading)
+                    .map_err(|e| format!("{}: {}", reading.date.format("%d/%m/%Y"), e))
+            })
+        })?;
+
+        // Only once they are kept for good.
+        for reading in readings.iter() {
+            self.run_hooks(reading);
+        }
+        Ok(())
+    }
+
+    /// Get every reading, earliest first. Rows that can't be read
+    /// are left out; `check_storage` reports them.
+    pub fn all_readings(&self) -> Vec<Reading> {
+        let mut cursor = self.connection.prepare(
+            "SELECT * FROM reading
+             ORDER BY date ASC").unwrap().cursor();
+
+        let mut readings = Vec::new();
+        while let Some(row) = cursor.next().unwrap() {
+            readings.extend(readable(row));
+        }
+        readings
+    }
+
+    /// The readings from `start` up to and including `end`, earliest first.
+    pub fn readings_between(&self, start : NaiveDate, end : NaiveDate) -> Vec<Reading> {
+        let mut cursor = self.connection.prepare(
+            "SELECT * FROM reading
+             WHERE date >= ? AND date <= ?
+             ORDER BY date ASC").unwrap().cursor();
+
+        cursor.bind(&[Value::String(start.format("%Y-%m-%d").to_string()),
+                      Value::String(end.format("%Y-%m-%d").to_string())]).unwrap();
+
+        let mut readings = Vec::new();
+        while let Some(row) = cursor.next().unwrap() {
+            readings.extend(readable(row));
+        }
+        readings
+    }
+
     pub fn number_of_readings(&self) -> i64 {
         let mut cursor = self.connection.prepare(
             "SELECT COUNT(*) FROM reading").unwrap().cursor();
@@ -117,24 +979,209 @@ impl Database {
         let row = cursor.next().unwrap().unwrap();
         row[0].as_integer().unwrap()   
     }
+
+    /// The flat tariffs last stored, if any.
+    pub fn stored_tariffs(&self) -> Option<Tariffs> {
+        let mut cursor = self.connection.prepare(
+            "SELECT import, export FROM tariff WHERE id = 1").unwrap().cursor();
+
+        let first_row = cursor.next().unwrap();
+        first_row.map(|row| Tariffs::flat(from_sqlite(&row[0]), from_sqlite(&row[1])))
+    }
+
+    /// The flat tariffs last stored, or the defaults if none have been.
+    pub fn current_tariffs(&self) -> Tariffs {
+        self.stored_tariffs().unwrap_or_else(default_tariffs)
+    }
+
+    /// Keep flat import and export rates, replacing any stored before.
+    pub fn store_tariffs(&self, import : DollarsPerKwh, export : DollarsPerKwh) {
+        let old = self.stored_tariffs().map(|tariffs| tariff_values(tariffs.import, tariffs.export));
+
+        self.in_transaction(|db| {
+            let mut cursor = db.connection.prepare(
+                "INSERT OR REPLACE INTO tariff ( id, import, export )
+                 VALUES ( 1, ?, ? )").unwrap().cursor();
+
+            cursor.bind(&[convert_for_sqlite(import),
+                          convert_for_sqlite(export)]).unwrap();
+            cursor.next().unwrap();
+            let action = if old.is_some() { Action::Update } else { Action::Insert };
+            db.record("tariff", "flat", action, old, Some(tariff_values(import, export)));
+        });
+    }
+
+    // Forget the stored tariffs, so the defaults are used.
+    fn delete_tariffs(&self) {
+        let old = match self.stored_tariffs() {
+            Some(tariffs) => tariff_values(tariffs.import, tariffs.export),
+            None => return,
+        };
+
+        self.in_transaction(|db| {
+            db.connection.execute("DELETE FROM tariff").unwrap();
+            db.record("tariff", "flat", Action::Delete, Some(old), None);
+        });
+    }
+
+    /// Add intervals to the database, replacing any stored
+    /// intervals with the same start.
+    pub fn add_intervals(&self, intervals : &[Interval]) -> Result<(), String> {
+        check_interval_lengths(intervals)?;
+
+        self.in_transaction(|db| {
+            let mut statement = db.connection.prepare(
+                "INSERT OR REPLACE INTO interval ( start, minutes, imports, exports, estimated )
+                 VALUES ( ?, ?, ?, ?, ? )").unwrap();
+
+            for interval in intervals.iter() {
+                let start = interval.start.format(DATE_TIME_FORMAT).to_string();
+                statement.reset().unwrap();
+                statement.bind(1, start.as_str()).unwrap();
+                statement.bind(2, interval.minutes as i64).unwrap();
+                statement.bind(3, interval.imports.value().raw()).unwrap();
+                statement.bind(4, interval.exports.value().raw()).unwrap();
+                statement.bind(5, interval.estimated as i64).unwrap();
+                while statement.next().unwrap() != State::Done {}
+            }
+        });
+        Ok(())
+    }
+
+    /// Get the intervals starting from `start` up to but 
+    /// not including `end`, earliest first.
+    pub fn intervals_between(&self, start : NaiveDateTime, end : NaiveDateTime) 
+        -> Vec<Interval> {
+        let mut cursor = self.connection.prepare(
+            "SELECT start, minutes, imports, exports, estimated FROM interval
+             WHERE start >= ? AND start < ?
+             ORDER BY start ASC").unwrap().cursor();
+
+        cursor.bind(&[Value::String(start.format(DATE_TIME_FORMAT).to_string()),
+                      Value::String(end.format(DATE_TIME_FORMAT).to_string())]).unwrap();
+
+        let mut intervals = Vec::new();
+        while let Some(row) = cursor.next().unwrap() {
+            intervals.push(row_to_interval(row));
+        }
+        intervals
+    }
+
+    pub fn number_of_intervals(&self) -> i64 {
+        let mut cursor = self.connection.prepare(
+            "SELECT COUNT(*) FROM interval").unwrap().cursor();
+
+        let row = cursor.next().unwrap().unwrap();
+        row[0].as_integer().unwrap()
+    }
+}
+
+// The readings half of the database, for code that works with any store.
+impl ReadingStore for Database {
+    fn add_reading(&mut self, reading : &Reading) -> Result<(), String> {
+        Database::add_reading(self, reading)
+    }
+
+    fn update_reading(&mut self, reading : &Reading) {
+        Database::update_reading(self, reading)
+    }
+
+    fn delete_reading(&mut self, date : NaiveDate) -> bool {
+        Database::delete_reading(self, date)
+    }
+
+    fn get_reading_for_date(&self, date : NaiveDate) -> Option<Reading> {
+        Database::get_reading_for_date(self, date)
+    }
+
+    fn readings_between(&self, start : NaiveDate, end : NaiveDate) -> Vec<Reading> {
+        Database::readings_between(self, start, end)
+    }
+
+    fn all_readings(&self) -> Vec<Reading> {
+        Database::all_readings(self)
+    }
+
+    fn most_recent_reading(&self) -> Option<Reading> {
+        Database::most_recent_reading(self)
+    }
+
+    fn reading_before(&self, date : NaiveDate) -> Option<Reading> {
+        Database::reading_before(self, date)
+    }
+
+    fn number_of_readings(&self) -> i64 {
+        Database::number_of_readings(self)
+    }
+
+    fn save_reading(&mut self, reading : &Reading) -> Result<(), String> {
+        Database::save_reading(self, reading)
+    }
+
+    fn save_readings(&mut self, readings : &[Reading]) -> Result<(), String> {
+        Database::save_readings(self, readings)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::readings::Source;
+    use crate::units::KilowattHours;
+    use chrono::Datelike;
     #[test]
     fn row_to_reading_ok() {
         let row = [Value::String("2010-10-10".to_string()), 
-                   Value::Float(10.0),
-                   Value::Float(20.0),
-                   Value::Float(5.5)];
+                   Value::Integer(10_000_000),
+                   Value::Integer(20_000_000),
+                   Value::Integer(5_500_000),
+                   Value::String("estimated".to_string()),
+                   Value::String("meter hidden by a ladder".to_string())];
 
-        let reading = row_to_reading(&row);
+        let reading = row_to_reading(&row).unwrap();
 
         assert_eq!(reading.date, NaiveDate::from_ymd(2010, 10, 10));
-        assert_eq!(reading.generation, 10.0);
-        assert_eq!(reading.imports, 20.0);
-        assert_eq!(reading.exports, 5.5);
+        assert_eq!(reading.generation, KilowattHours::from(10));
+        assert_eq!(reading.imports, KilowattHours::from(20));
+        assert_eq!(reading.exports, Decimal::new(55, 1).into());
+        assert_eq!(reading.source, Source::Estimated);
+        assert_eq!(reading.note, "meter hidden by a ladder");
+    }
+
+    #[test]
+    fn row_to_reading_bad() {
+        let row = |date : &str, generation : Value| [Value::String(date.to_string()), generation,
+                                                     Value::Integer(1), Value::Integer(1),
+                                                     Value::String("actual".to_string()),
+                                                     Value::String(String::new())];
+
+        assert_eq!(row_to_reading(&row("2/1/2020", Value::Integer(1))),
+                   Err("unreadable date '2/1/2020'".to_string()));
+        assert_eq!(row_to_reading(&row("2020-01-03", Value::String("lots".to_string()))),
+                   Err("03/01/2020: unreadable generation 'lots'".to_string()));
+    }
+
+    #[test]
+    fn unreadable_rows_skipped() {
+        let db = Database::open(":memory:");
+        db.connection.execute("
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-01', 10, 10, 10);
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2/1/2020', 12, 9, 10);
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-03', 'lots', 11, 11)").unwrap();
+        let date = NaiveDate::from_ymd(2020, 1, 3);
+
+        let dates: Vec<NaiveDate> = db.all_readings().iter().map(|reading| reading.date).collect();
+        assert_eq!(dates, vec![NaiveDate::from_ymd(2020, 1, 1)]);
+        assert_eq!(db.readings_between(date, date), vec![]);
+        assert_eq!(db.get_reading_for_date(date), None);
+
+        let reading = Reading::new(date, KilowattHours::from(12), KilowattHours::from(11), KilowattHours::from(11));
+        assert!(db.add_reading(&reading).is_err());
+        db.save_reading(&reading).unwrap();
+        assert_eq!(db.get_reading_for_date(date), Some(reading));
+        let change = db.changes_after(0).pop().unwrap();
+        assert_eq!(change.action, Action::Update);
+        assert_eq!(change.old, None);
     }
 
     #[test]
@@ -147,12 +1194,8 @@ mod tests {
     fn add_retrieve_reading() {
         let db = Database::open(":memory:");
 
-        let reading_in = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 4),
-                generation : 3.0,
-                imports : 5.0,
-                exports : 1.0,
-            };
+        let reading_in = Reading::new(NaiveDate::from_ymd(2019, 10, 4), KilowattHours::from(3),
+                                      KilowattHours::from(1), KilowattHours::from(5));
 
         match db.get_reading_for_date(reading_in.date) {
             None => (),
@@ -160,7 +1203,7 @@ mod tests {
         }
 
 
-        db.add_reading(&reading_in);
+        db.add_reading(&reading_in).unwrap();
     
         let reading_out = match db.get_reading_for_date(reading_in.date) {
             Some(reading) => reading, 
@@ -178,32 +1221,19 @@ mod tests {
     fn most_recent_reading_ok() {
         let db = Database::open(":memory:");
 
-        let reading_1 = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 10),
-                generation : 30.0,
-                imports : 20.0,
-                exports : 5.0,
-            };
+        let reading_1 = Reading::new(NaiveDate::from_ymd(2019, 10, 10), KilowattHours::from(28),
+                                     KilowattHours::from(3), KilowattHours::from(18));
 
         // Most recent reading.
-        let reading_2 = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 12),
-                generation : 29.0,
-                imports : 19.0,
-                exports : 4.0,
-            };
+        let reading_2 = Reading::new(NaiveDate::from_ymd(2019, 10, 12), KilowattHours::from(30),
+                                     KilowattHours::from(5), KilowattHours::from(20));
 
-        let reading_3 = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 11),
-                generation : 28.0,
-                imports : 18.0,
-                exports : 3.0,
-            
-            };
+        let reading_3 = Reading::new(NaiveDate::from_ymd(2019, 10, 11), KilowattHours::from(29),
+                                     KilowattHours::from(4), KilowattHours::from(19));
 
-        db.add_reading(&reading_1);
-        db.add_reading(&reading_2);
-        db.add_reading(&reading_3);
+        db.add_reading(&reading_1).unwrap();
+        db.add_reading(&reading_2).unwrap();
+        db.add_reading(&reading_3).unwrap();
 
         let most_recent = db.most_recent_reading().unwrap();
 
@@ -218,41 +1248,470 @@ mod tests {
     fn number_of_readings_ok() {
         let db = Database::open(":memory:");
         
-        let reading_1 = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 10),
-                generation : 30.0,
-                imports : 20.0,
-                exports : 5.0,
-            };
+        let reading_1 = Reading::new(NaiveDate::from_ymd(2019, 10, 10), KilowattHours::from(30),
+                                     KilowattHours::from(5), KilowattHours::from(20));
 
-        let reading_2 = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 11),
-                generation : 60.0,
-                imports : 30.0,
-                exports : 10.0,
-            };
+        let reading_2 = Reading::new(NaiveDate::from_ymd(2019, 10, 11), KilowattHours::from(60),
+                                     KilowattHours::from(10), KilowattHours::from(30));
 
-        let reading_3 = Reading {
-                date : NaiveDate::from_ymd(2019, 10, 12),
-                generation : 90.0,
-                imports : 60.0,
-                exports : 20.0,
-            };
+        let reading_3 = Reading::new(NaiveDate::from_ymd(2019, 10, 12), KilowattHours::from(90),
+                                     KilowattHours::from(20), KilowattHours::from(60));
 
         assert_eq!(db.number_of_readings(), 0);
-        db.add_reading(&reading_1);
+        db.add_reading(&reading_1).unwrap();
+        assert_eq!(db.number_of_readings(), 1);
+        db.add_reading(&reading_2).unwrap();
+        assert_eq!(db.number_of_readings(), 2);
+        db.add_reading(&reading_3).unwrap();
+        assert_eq!(db.number_of_readings(), 3);
+    }
+
+    #[test]
+    fn update_reading_ok() {
+        let db = Database::open(":memory:");
+
+        let mut reading = Reading::new(NaiveDate::from_ymd(2019, 10, 4), KilowattHours::from(3),
+                                       KilowattHours::from(1), KilowattHours::from(5));
+        db.add_reading(&reading).unwrap();
+
+        reading.generation = KilowattHours::from(4);
+        db.update_reading(&reading);
+
+        assert_eq!(db.get_reading_for_date(reading.date), Some(reading));
         assert_eq!(db.number_of_readings(), 1);
-        db.add_reading(&reading_2);
+    }
+
+    #[test]
+    fn save_reading_validates() {
+        let db = Database::open(":memory:");
+        let reading = |day, generation : i64| Reading::new(NaiveDate::from_ymd(2019, 10, day),
+                                                           KilowattHours::from(generation),
+                                                           KilowattHours::from(5),
+                                                           KilowattHours::from(20));
+
+        db.save_reading(&reading(10, 30)).unwrap();
+        db.save_reading(&reading(11, 32)).unwrap();
+        db.save_reading(&reading(11, 33)).unwrap();
+        assert!(db.save_reading(&reading(12, 31)).is_err());
+
         assert_eq!(db.number_of_readings(), 2);
-        db.add_reading(&reading_3);
+        assert_eq!(db.reading_before(NaiveDate::from_ymd(2019, 10, 12)), Some(reading(11, 33)));
+        assert_eq!(db.reading_before(NaiveDate::from_ymd(2019, 10, 10)), None);
+
+        // Adding checks the readings either side too.
+        assert!(db.add_reading(&reading(13, -1)).is_err());
+        assert!(db.add_reading(&reading(13, 32)).is_err());
+        assert_eq!(db.add_reading(&reading(9, 31)),
+                   Err("the reading after: generation went backwards from 31.0 to 30.0".to_string()));
+        db.add_reading(&reading(9, 29)).unwrap();
+        assert_eq!(db.reading_after(NaiveDate::from_ymd(2019, 10, 9)), Some(reading(10, 30)));
         assert_eq!(db.number_of_readings(), 3);
     }
 
+    #[test]
+    fn hooks_see_new_readings() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut db = Database::open(":memory:");
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&seen);
+        db.on_reading_added(Box::new(move |previous, reading| {
+            log.borrow_mut().push((previous.map(|r| r.date.day()), reading.date.day()));
+        }));
+
+        let reading = |day| Reading::new(NaiveDate::from_ymd(2019, 10, day),
+                                         KilowattHours::from(30),
+                                         KilowattHours::from(5), KilowattHours::from(20));
+        db.add_reading(&reading(10)).unwrap();
+        db.add_reading(&reading(12)).unwrap();
+        db.save_reading(&reading(12)).unwrap();
+        db.update_reading(&reading(10));
+
+        assert_eq!(*seen.borrow(), vec![(None, 10), (Some(10), 12), (Some(10), 12)]);
+
+        // Nothing is heard of readings that end up not being kept.
+        assert!(db.save_readings(&[reading(13), reading(14), Reading::new(
+            NaiveDate::from_ymd(2019, 10, 15), KilowattHours::from(1),
+            KilowattHours::from(5), KilowattHours::from(20))]).is_err());
+        assert_eq!(seen.borrow().len(), 3);
+        db.save_readings(&[reading(14), reading(13)]).unwrap();
+        assert_eq!(seen.borrow()[3..], [(Some(12), 13), (Some(13), 14)]);
+    }
+
+    #[test]
+    fn delete_reading_ok() {
+        let db = Database::open(":memory:");
+        let date = NaiveDate::from_ymd(2019, 10, 4);
+        db.add_reading(&Reading::new(date, KilowattHours::from(3),
+                                     KilowattHours::from(1), KilowattHours::from(5))).unwrap();
+
+        assert!(db.delete_reading(date));
+        assert!(!db.delete_reading(date));
+        assert_eq!(db.number_of_readings(), 0);
+    }
+
+    #[test]
+    fn store_tariffs_replaces() {
+        let db = Database::open(":memory:");
+        assert_eq!(db.stored_tariffs(), None);
+        assert_eq!(db.current_tariffs(), default_tariffs());
+
+        db.store_tariffs(Decimal::new(25, 2).into(), Decimal::new(5, 2).into());
+        assert_eq!(db.current_tariffs().import, Decimal::new(25, 2).into());
+        db.store_tariffs(Decimal::new(25752, 5).into(), Decimal::new(7135, 5).into());
+        let tariffs = db.stored_tariffs().unwrap();
+        assert_eq!(tariffs.import, Decimal::new(25752, 5).into());
+        assert_eq!(tariffs.export, Decimal::new(7135, 5).into());
+    }
+
+    #[test]
+    fn all_readings_sorted() {
+        let db = Database::open(":memory:");
+
+        for day in &[12, 10, 11] {
+            db.add_reading(&Reading::new(NaiveDate::from_ymd(2019, 10, *day),
+                                         KilowattHours::from(30),
+                                         KilowattHours::from(5), KilowattHours::from(20))).unwrap();
+        }
+
+        let dates: Vec<u32> = db.all_readings().iter()
+            .map(|reading| reading.date.day())
+            .collect();
+        assert_eq!(dates, vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn add_intervals_range() {
+        let db = Database::open(":memory:");
+        assert!(db.table_exists("interval"));
+
+        let day = NaiveDate::from_ymd(2019, 10, 1);
+        let intervals: Vec<Interval> = (0..48)
+            .map(|slot| Interval {
+                start: day.and_hms(slot / 2, (slot % 2) * 30, 0),
+                minutes: 30,
+                imports: Decimal::new(125, 3).into(),
+                exports: KilowattHours::ZERO,
+                estimated: slot == 13,
+            })
+            .collect();
+
+        db.add_intervals(&intervals).unwrap();
+        // Adding them again replaces them.
+        db.add_intervals(&intervals).unwrap();
+        assert_eq!(db.number_of_intervals(), 48);
+
+        let mut odd = intervals[0].clone();
+        odd.minutes = 0;
+        assert!(db.add_intervals(&[intervals[1].clone(), odd]).is_err());
+        assert_eq!(db.number_of_intervals(), 48);
+
+        let morning = db.intervals_between(day.and_hms(6, 0, 0), day.and_hms(12, 0, 0));
+        assert_eq!(morning.len(), 12);
+        assert_eq!(morning[0], intervals[12]);
+        assert!(morning[1].estimated);
+    }
+
+    #[test]
+    fn transaction_rolls_back() {
+        let db = Database::open(":memory:");
+        let reading = |day, generation| Reading::new(NaiveDate::from_ymd(2019, 10, day),
+                                                     KilowattHours::from(generation),
+                                                     KilowattHours::from(1),
+                                                     KilowattHours::from(5));
+
+        let kept: Result<(), ()> = db.transaction(|db| {
+            db.add_reading(&reading(1, 10)).unwrap();
+            Ok(())
+        });
+        assert_eq!(kept, Ok(()));
+        let undone: Result<(), ()> = db.transaction(|db| {
+            db.add_reading(&reading(2, 11)).unwrap();
+            db.transaction(|db| {
+                db.add_reading(&reading(3, 12)).unwrap();
+                Ok(())
+            })?;
+            Err(())
+        });
+        assert_eq!(undone, Err(()));
+        assert_eq!(db.all_readings(), vec![reading(1, 10)]);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| db.in_transaction(|db| {
+            db.add_reading(&reading(2, 11)).unwrap();
+            panic!("database is locked");
+        })));
+        assert!(panicked.is_err());
+        assert_eq!(db.all_readings(), vec![reading(1, 10)]);
+        db.add_reading(&reading(2, 11)).unwrap();
+        assert_eq!(db.all_readings().len(), 2);
+    }
+
+    #[test]
+    fn add_readings_counts() {
+        let db = Database::open(":memory:");
+        let reading = |day, generation| Reading::new(NaiveDate::from_ymd(2019, 10, day),
+                                                     KilowattHours::from(generation),
+                                                     KilowattHours::from(1),
+                                                     KilowattHours::from(5));
+        db.add_reading(&reading(1, 10)).unwrap();
+        db.add_reading(&reading(2, 11)).unwrap();
+
+        let counts = db.add_readings(&[reading(4, 14), reading(1, 10), reading(2, 12), reading(3, 13)]);
+        assert_eq!(counts, Ok(BatchCounts { inserted: 2, skipped: 1, conflicting: 1 }));
+        assert_eq!(db.number_of_readings(), 4);
+        assert_eq!(db.get_reading_for_date(reading(2, 0).date), Some(reading(2, 11)));
+        assert_eq!(counts.unwrap().to_string(), "2 added, 1 already stored, 1 conflicting");
+
+        // One going backwards and nothing is added.
+        let error = db.add_readings(&[reading(5, 15), reading(6, 9)]).unwrap_err();
+        assert_eq!(error, "2019-10-06: generation went backwards from 15.0 to 9.0");
+        assert_eq!(db.number_of_readings(), 4);
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let directory = std::env::temp_dir().join(format!("nrgaccounts-{}-backup", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file = directory.join("energy.db");
+
+        let db = Database::open(":memory:");
+        let reading = Reading::new(NaiveDate::from_ymd(2019, 10, 4), KilowattHours::from(3),
+                                   KilowattHours::from(1), KilowattHours::from(5));
+        db.add_reading(&reading).unwrap();
+        assert_eq!(db.backup_to(&file), Ok(()));
+        assert!(db.backup_to(&file).is_err());
+
+        // What was done since the backup stays in the log.
+        let restored = Database::open(":memory:");
+        restored.store_tariffs(Decimal::new(25, 2).into(), Decimal::new(7, 2).into());
+        restored.store_tariffs(Decimal::new(30, 2).into(), Decimal::new(5, 2).into());
+        assert_eq!(restored.restore_from(&file), Ok(()));
+        assert_eq!(restored.all_readings(), vec![reading]);
+        let subjects: Vec<String> = restored.changes_after(0).into_iter().map(|change| change.subject).collect();
+        assert_eq!(subjects, vec!["reading", "tariff", "database"]);
+        let restore = restored.changes_after(2).pop().unwrap();
+        assert_eq!(restore.action, Action::Restore);
+        assert_eq!(restore.key, file.display().to_string());
+        assert!(restored.revert(restore.id).is_err());
+        assert!(restored.restore_from(&directory.join("missing.db")).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn backups_expire() {
+        let time = NaiveDate::from_ymd(2020, 3, 1).and_hms(9, 30, 5);
+        assert_eq!(backup_name(time), "energy-20200301-093005.db");
+
+        let names: Vec<String> = ["energy-20200302-000000.db", "notes.txt", "energy-20200301-093005.db",
+                                  "energy-20200303-000000.db"].iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(expired_backups(&names, 1), vec!["energy-20200301-093005.db", "energy-20200302-000000.db"]);
+        assert_eq!(expired_backups(&names, 3), Vec::<String>::new());
+    }
+
+    #[test]
+    fn check_storage_problems() {
+        let db = Database::open(":memory:");
+        assert_eq!(db.check_storage(), vec![]);
+
+        // As kept before each date could only have one reading.
+        db.connection.execute("
+            DROP INDEX reading_date;
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-01', 10, 10, 10);
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-02', 12, 9, 10);
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-02', 12, 9, 10);
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2/1/2020', 12, 9, 10);
+            INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-03', 'lots', 11, 11)").unwrap();
+
+        let problems = db.check_storage();
+        assert_eq!(problems, vec![
+            StorageProblem::DuplicateDate { date: "2020-01-02".to_string(), count: 2 },
+            StorageProblem::BadDate { row: 4, date: "2/1/2020".to_string() },
+            StorageProblem::WentBackwards { register: "imports",
+                                            date: NaiveDate::from_ymd(2020, 1, 2),
+                                            previous: NaiveDate::from_ymd(2020, 1, 1) },
+            StorageProblem::BadValue { row: 5, register: "generation" },
+        ]);
+        assert_eq!(problems[2].to_string(), "02/01/2020: imports lower than on 01/01/2020");
+        assert!(problems[1].stops_reading());
+        assert!(!problems[2].stops_reading());
+
+        // Each date gets only one reading once the extra ones are gone.
+        db.create_tables();
+        assert!(db.connection.execute("SELECT * FROM reading INDEXED BY reading_date").is_err());
+        db.connection.execute("DELETE FROM reading WHERE rowid = 3").unwrap();
+        db.create_tables();
+        assert!(db.connection.execute(
+            "INSERT INTO reading ( date, generation, imports, exports ) VALUES ('2020-01-02', 12, 9, 10)").is_err());
+    }
+
+    #[test]
+    fn audit_log_records_changes() {
+        let mut db = Database::open(":memory:");
+        db.set_audit_details("sam", "typo");
+        let reading = |generation| Reading::new(NaiveDate::from_ymd(2019, 10, 4),
+                                                KilowattHours::from(generation),
+                                                KilowattHours::from(1), KilowattHours::from(5));
+
+        db.add_reading(&reading(3)).unwrap();
+        db.update_reading(&reading(4));
+        assert!(db.delete_reading(reading(4).date));
+        db.store_tariffs(Decimal::new(25, 2).into(), Decimal::new(7, 2).into());
+
+        let history = db.reading_history(reading(3).date);
+        let actions: Vec<Action> = history.iter().map(|change| change.action).collect();
+        assert_eq!(actions, vec![Action::Insert, Action::Update, Action::Delete]);
+        assert_eq!(history[1].old.as_deref(),
+                   Some(r#"{"generation":3,"exports":1,"imports":5,"source":"actual","note":""}"#));
+        assert_eq!(history[1].new.as_deref(),
+                   Some(r#"{"generation":4,"exports":1,"imports":5,"source":"actual","note":""}"#));
+        assert_eq!((history[2].user.as_str(), history[2].reason.as_str()), ("sam", "typo"));
+        assert_eq!(db.tariff_history()[0].new.as_deref(), Some(r#"{"import":0.25,"export":0.07}"#));
+
+        // Nothing can take changes out of the log.
+        assert!(db.connection.execute("DELETE FROM audit").is_err());
+        assert!(db.connection.execute("UPDATE audit SET user = 'someone'").is_err());
+        assert_eq!(db.changes_after(0).len(), 4);
+    }
+
+    #[test]
+    fn revert_changes() {
+        let db = Database::open(":memory:");
+        let reading = |generation| Reading::new(NaiveDate::from_ymd(2019, 10, 4),
+                                                KilowattHours::from(generation),
+                                                KilowattHours::from(1), KilowattHours::from(5));
+        db.add_reading(&reading(3)).unwrap();
+        db.update_reading(&reading(4));
+
+        // Only the latest change to a reading can be undone.
+        assert!(db.revert(1).is_err());
+        assert_eq!(db.revert(2).map(|change| change.action), Ok(Action::Update));
+        assert_eq!(db.get_reading_for_date(reading(3).date), Some(reading(3)));
+        assert_eq!(db.revert(1).map(|change| change.action), Ok(Action::Insert));
+        assert_eq!(db.get_reading_for_date(reading(3).date), None);
+        assert_eq!(db.revert(4).map(|change| change.action), Ok(Action::Delete));
+        assert_eq!(db.get_reading_for_date(reading(3).date), Some(reading(3)));
+        assert_eq!(db.reading_history(reading(3).date).len(), 5);
+        assert_eq!(db.revert(10), Err("there is no change #10".to_string()));
+
+        db.store_tariffs(Decimal::new(25, 2).into(), Decimal::new(7, 2).into());
+        db.store_tariffs(Decimal::new(30, 2).into(), Decimal::new(5, 2).into());
+        assert!(db.revert(7).is_ok());
+        assert_eq!(db.stored_tariffs().unwrap().import, Decimal::new(25, 2).into());
+        assert!(db.revert(6).is_ok());
+        assert_eq!(db.stored_tariffs(), None);
+
+        // Changes logged before values were kept as JSON.
+        db.add_change(&Change {
+            id: 10,
+            time: NaiveDate::from_ymd(2019, 10, 5).and_hms(9, 0, 0),
+            user: "sam".to_string(),
+            subject: "reading".to_string(),
+            key: "2019-10-04".to_string(),
+            action: Action::Update,
+            old: Some("generation=2 exports=1 imports=5".to_string()),
+            new: Some("generation=3 exports=1 imports=5 source=actual".to_string()),
+            reason: String::new(),
+        });
+        assert!(db.revert(10).is_ok());
+        assert_eq!(db.get_reading_for_date(reading(3).date), Some(reading(2)));
+    }
+
     #[test]
     fn convert_for_sqlite_ok() {
-        let in_f32 : f32 = 1.1;
-        let out_f64 : f64 = convert_for_sqlite(in_f32);
-        assert_eq!(out_f64, 1.1);
+        let value = Decimal::new(1_234_567_891, 6);
+        assert_eq!(convert_for_sqlite(value), Value::Integer(1_234_567_891));
+        assert_eq!(from_sqlite::<Decimal>(&convert_for_sqlite(value)), value);
+    }
+
+    #[test]
+    fn readings_are_kept_exactly() {
+        let db = Database::open(":memory:");
+        let reading = Reading::new(NaiveDate::from_ymd(2019, 10, 4),
+                                   Decimal::new(12_345_678_901, 6).into(),
+                                   Decimal::new(7, 1).into(), Decimal::new(1, 6).into());
+        db.add_reading(&reading).unwrap();
+        assert_eq!(db.get_reading_for_date(reading.date), Some(reading));
+    }
+
+    #[test]
+    fn migrate_real_columns() {
+        let file = std::env::temp_dir()
+            .join(format!("nrgaccounts-migrate-{}.db", std::process::id()));
+        let file = file.to_str().unwrap();
+        {
+            let connection = sqlite::open(file).unwrap();
+            connection.execute("
+                CREATE TABLE reading (date TEXT NOT NULL, generation REAL NOT NULL,
+                                      imports REAL NOT NULL, exports REAL NOT NULL);
+                INSERT INTO reading VALUES ('2019-10-04', 12345.6, 0.1, 7.3);
+                CREATE TABLE interval (start TEXT NOT NULL PRIMARY KEY, minutes INTEGER NOT NULL,
+                                       imports REAL NOT NULL, exports REAL NOT NULL);
+                INSERT INTO interval VALUES ('2019-10-04 00:00:00', 30, 0.123, 0.0);
+                CREATE TABLE tariff (id INTEGER PRIMARY KEY CHECK (id = 1),
+                                     import REAL NOT NULL, export REAL NOT NULL);
+                INSERT INTO tariff VALUES (1, 0.25752, 0.07135);").unwrap();
+        }
+
+        let db = Database::open(file);
+        let day = NaiveDate::from_ymd(2019, 10, 4);
+        let reading = db.get_reading_for_date(day).unwrap();
+        assert_eq!(reading.generation, Decimal::new(123_456, 1).into());
+        assert_eq!(reading.imports, Decimal::new(1, 1).into());
+        assert_eq!(reading.exports, Decimal::new(73, 1).into());
+
+        let intervals = db.intervals_between(day.and_hms(0, 0, 0), day.and_hms(1, 0, 0));
+        assert_eq!(intervals[0].imports, Decimal::new(123, 3).into());
+        assert_eq!(db.stored_tariffs(), Some(Tariffs::flat(Decimal::new(25752, 5).into(),
+                                                           Decimal::new(7135, 5).into())));
+        assert!(!db.table_exists("old_reading"));
+        drop(db);
+
+        // Opening it again leaves it alone.
+        let db = Database::open(file);
+        assert_eq!(db.number_of_readings(), 1);
+        drop(db);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn readings_keep_source_and_note() {
+        let db = Database::open(":memory:");
+        let mut reading = Reading::new(NaiveDate::from_ymd(2019, 10, 4), KilowattHours::from(3),
+                                       KilowattHours::from(1), KilowattHours::from(5));
+        reading.source = Source::Imported("fronius".to_string());
+        reading.note = "from the portal".to_string();
+        db.add_reading(&reading).unwrap();
+        assert_eq!(db.get_reading_for_date(reading.date), Some(reading.clone()));
+
+        reading.source = Source::Estimated;
+        reading.note.clear();
+        db.update_reading(&reading);
+        assert_eq!(db.get_reading_for_date(reading.date), Some(reading));
+    }
+
+    #[test]
+    fn add_source_and_note_columns() {
+        let file = std::env::temp_dir()
+            .join(format!("nrgaccounts-source-{}.db", std::process::id()));
+        let file = file.to_str().unwrap();
+        {
+            let connection = sqlite::open(file).unwrap();
+            connection.execute("
+                CREATE TABLE reading (date TEXT NOT NULL, generation INTEGER NOT NULL,
+                                      imports INTEGER NOT NULL, exports INTEGER NOT NULL);
+                INSERT INTO reading VALUES ('2019-10-04', 3000000, 1000000, 5000000);").unwrap();
+        }
+
+        let db = Database::open(file);
+        let reading = db.get_reading_for_date(NaiveDate::from_ymd(2019, 10, 4)).unwrap();
+        assert_eq!(reading.generation, KilowattHours::from(3));
+        assert_eq!((reading.source, reading.note.as_str()), (Source::Actual, ""));
+        drop(db);
+        std::fs::remove_file(file).unwrap();
     }
 }
 
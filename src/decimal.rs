@@ -0,0 +1,400 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{ Add, AddAssign, Div, Mul, Neg, Sub, SubAssign };
+use std::str::FromStr;
+
+/// The number of decimal places kept.
+pub const PLACES: usize = 6;
+
+// One in the raw units.
+const ONE: i64 = 1_000_000;
+
+/// A fixed point decimal number with six places, used for energy
+/// and money so that sums come out exactly and nothing is lost
+/// in the database. Multiplication and division round half away
+/// from zero at the sixth place. Arithmetic saturates at the largest
+/// and smallest values rather than wrapping. Rounding for display is
+/// done with the precision of the format, as in `{:.2}`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Decimal(i64);
+
+/// A string that isn't a decimal number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseDecimalError;
+
+/// Allow a ParseDecimalError object to be passed to println!() etc.
+impl fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not a decimal number")
+    }
+}
+
+// Divide, rounding half away from zero.
+fn divide_rounded(numerator: i128, denominator: i128) -> i128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder.abs() * 2 >= denominator.abs() {
+        if (numerator < 0) == (denominator < 0) { quotient + 1 } else { quotient - 1 }
+    } else {
+        quotient
+    }
+}
+
+// A raw value with six more places, so a quotient keeps its own.
+fn widen(raw: i64) -> i128 {
+    raw as i128 * ONE as i128
+}
+
+// A wide result back in range, saturating if it's too big.
+fn narrow(wide: i128) -> Decimal {
+    Decimal(i64::try_from(wide).unwrap_or(if wide < 0 { i64::MIN } else { i64::MAX }))
+}
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal(0);
+
+    /// A decimal with the given number of places, so 25752 and 5
+    /// is 0.25752. No more than six places.
+    pub const fn new(mantissa: i64, places: u32) -> Decimal {
+        assert!(places as usize <= PLACES);
+        Decimal(mantissa * 10i64.pow(PLACES as u32 - places))
+    }
+
+    /// A decimal from a count of millionths.
+    pub const fn from_raw(millionths: i64) -> Decimal {
+        Decimal(millionths)
+    }
+
+    /// The number of millionths, which is what the database stores.
+    pub fn raw(self) -> i64 {
+        self.0
+    }
+
+    /// The nearest decimal to a float, or None if it is not finite
+    /// or too large. For values that only arrive as floats.
+    pub fn from_f64(value: f64) -> Option<Decimal> {
+        let raw = (value * ONE as f64).round();
+        if raw.is_finite() && raw.abs() < i64::MAX as f64 {
+            Some(Decimal(raw as i64))
+        } else {
+            None
+        }
+    }
+
+    /// An integer scaled by a power of ten, such as a register
+    /// value and its scale factor. None if it doesn't fit.
+    pub fn from_scaled(value: i64, power_of_ten: i32) -> Option<Decimal> {
+        let exponent = PLACES as i32 + power_of_ten;
+        let raw = if exponent >= 0 {
+            (value as i128).checked_mul(10i128.checked_pow(exponent as u32)?)?
+        } else {
+            divide_rounded(value as i128, 10i128.checked_pow((-exponent) as u32)?)
+        };
+        i64::try_from(raw).ok().map(Decimal)
+    }
+
+    /// The value as a float, for charts and ratios.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / ONE as f64
+    }
+
+    /// The value as a float, for charts and ratios.
+    pub fn to_f32(self) -> f32 {
+        self.to_f64() as f32
+    }
+
+    pub fn abs(self) -> Decimal {
+        Decimal(self.0.saturating_abs())
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Round half away from zero to the given number of places.
+    pub fn round(self, places: usize) -> Decimal {
+        if places >= PLACES {
+            return self;
+        }
+        let unit = 10i128.pow((PLACES - places) as u32);
+        narrow(divide_rounded(self.0 as i128, unit) * unit)
+    }
+
+    /// The ratio of two amounts as a float, NaN or infinite when
+    /// dividing by zero just as for floats.
+    pub fn ratio(self, other: Decimal) -> f32 {
+        (self.0 as f64 / other.0 as f64) as f32
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Decimal {
+        Decimal(value * ONE)
+    }
+}
+
+/// Parse numbers such as "12", "-0.25" or "1234.5678". More than
+/// six places are rounded.
+impl FromStr for Decimal {
+    type Err = ParseDecimalError;
+
+    fn from_str(text: &str) -> Result<Decimal, ParseDecimalError> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (whole, fraction) = match digits.find('.') {
+            Some(point) => (&digits[..point], &digits[point + 1..]),
+            None => (digits, ""),
+        };
+        if whole.is_empty() && fraction.is_empty()
+            || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(ParseDecimalError);
+        }
+
+        let mut raw: i128 = 0;
+        for c in whole.chars() {
+            raw = raw * 10 + c.to_digit(10).unwrap() as i128;
+            if raw > i64::MAX as i128 {
+                return Err(ParseDecimalError);
+            }
+        }
+        raw *= ONE as i128;
+        for (place, c) in fraction.chars().enumerate() {
+            let digit = c.to_digit(10).unwrap() as i128;
+            if place < PLACES {
+                raw += digit * 10i128.pow((PLACES - 1 - place) as u32);
+            } else {
+                if place == PLACES && digit >= 5 {
+                    raw += 1;
+                }
+                break;
+            }
+        }
+
+        let raw = if negative { -raw } else { raw };
+        i64::try_from(raw).map(Decimal).map_err(|_e| ParseDecimalError)
+    }
+}
+
+/// Shorthand for tests, where values are easiest to write as floats.
+#[cfg(test)]
+pub(crate) fn dec(value: f64) -> Decimal {
+    Decimal::from_f64(value).unwrap()
+}
+
+/// Allow a Decimal object to be passed to println!() etc. Without a
+/// precision it is written exactly, without trailing zeros.
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (value, places) = match f.precision() {
+            Some(places) => (self.round(places), places),
+            None => (*self, PLACES),
+        };
+        let whole = value.0.abs() / ONE;
+        let fraction = value.0.abs() % ONE;
+
+        let mut text = if value.0 < 0 {
+            format!("-{}", whole)
+        } else if f.sign_plus() {
+            format!("+{}", whole)
+        } else {
+            whole.to_string()
+        };
+        let mut digits = format!("{:06}", fraction);
+        if f.precision().is_none() {
+            digits = digits.trim_end_matches('0').to_string();
+        }
+        while digits.len() < places && f.precision().is_some() {
+            digits.push('0');
+        }
+        digits.truncate(places);
+        if !digits.is_empty() {
+            text.push('.');
+            text.push_str(&digits);
+        }
+
+        // Pad as numbers are, to the right unless asked otherwise.
+        let width = f.width().unwrap_or(0);
+        let padding = width.saturating_sub(text.chars().count());
+        let fill = f.fill().to_string();
+        let (before, after) = match f.align() {
+            Some(fmt::Alignment::Left) => (0, padding),
+            Some(fmt::Alignment::Center) => (padding / 2, padding - padding / 2),
+            _ => (padding, 0),
+        };
+        write!(f, "{}{}{}", fill.repeat(before), text, fill.repeat(after))
+    }
+}
+
+impl Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Decimal) -> Decimal {
+        Decimal(self.0.saturating_add(other.0))
+    }
+}
+
+impl AddAssign for Decimal {
+    fn add_assign(&mut self, other: Decimal) {
+        self.0 = self.0.saturating_add(other.0);
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Decimal) -> Decimal {
+        Decimal(self.0.saturating_sub(other.0))
+    }
+}
+
+impl SubAssign for Decimal {
+    fn sub_assign(&mut self, other: Decimal) {
+        self.0 = self.0.saturating_sub(other.0);
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal(self.0.saturating_neg())
+    }
+}
+
+impl Mul for Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: Decimal) -> Decimal {
+        narrow(divide_rounded(self.0 as i128 * other.0 as i128, ONE as i128))
+    }
+}
+
+impl Mul<i64> for Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: i64) -> Decimal {
+        Decimal(self.0.saturating_mul(other))
+    }
+}
+
+/// Panics when dividing by zero, as integers do.
+impl Div for Decimal {
+    type Output = Decimal;
+
+    fn div(self, other: Decimal) -> Decimal {
+        narrow(divide_rounded(widen(self.0), other.0 as i128))
+    }
+}
+
+/// Panics when dividing by zero, as integers do.
+impl Div<i64> for Decimal {
+    type Output = Decimal;
+
+    fn div(self, other: i64) -> Decimal {
+        narrow(divide_rounded(self.0 as i128, other as i128))
+    }
+}
+
+impl Sum for Decimal {
+    fn sum<I: Iterator<Item = Decimal>>(iter: I) -> Decimal {
+        iter.fold(Decimal::ZERO, |total, value| total + value)
+    }
+}
+
+impl<'a> Sum<&'a Decimal> for Decimal {
+    fn sum<I: Iterator<Item = &'a Decimal>>(iter: I) -> Decimal {
+        iter.fold(Decimal::ZERO, |total, value| total + *value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(text: &str) -> Decimal {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_decimals() {
+        assert_eq!(d("12"), Decimal::from(12));
+        assert_eq!(d("-0.25").raw(), -250_000);
+        assert_eq!(d("+.5").raw(), 500_000);
+        assert_eq!(d("1.").raw(), 1_000_000);
+        assert_eq!(d("0.0000015").raw(), 2);
+        assert_eq!(d("0.0000014").raw(), 1);
+        assert_eq!("".parse::<Decimal>(), Err(ParseDecimalError));
+        assert_eq!(".".parse::<Decimal>(), Err(ParseDecimalError));
+        assert_eq!("1e3".parse::<Decimal>(), Err(ParseDecimalError));
+        assert_eq!("1.2.3".parse::<Decimal>(), Err(ParseDecimalError));
+        assert_eq!("NaN".parse::<Decimal>(), Err(ParseDecimalError));
+        assert_eq!("99999999999999999999".parse::<Decimal>(), Err(ParseDecimalError));
+    }
+
+    #[test]
+    fn display_exactly_or_rounded() {
+        assert_eq!(d("1234.5").to_string(), "1234.5");
+        assert_eq!(d("-0.25").to_string(), "-0.25");
+        assert_eq!(Decimal::from(3).to_string(), "3");
+        assert_eq!(format!("{:.2}", d("0.125")), "0.13");
+        assert_eq!(format!("{:.2}", d("-0.125")), "-0.13");
+        assert_eq!(format!("{:.0}", d("2.5")), "3");
+        assert_eq!(format!("{:.8}", d("0.1")), "0.10000000");
+        assert_eq!(format!("{:>7.1}|{:<6}|", d("3.14"), d("2")), "    3.1|2     |");
+        assert_eq!(format!("{:+.1}|{:>+6.1}", d("2.5"), d("-2.5")), "+2.5|  -2.5");
+    }
+
+    #[test]
+    fn sums_are_exact() {
+        let total: Decimal = (0..1000).map(|_| d("0.1")).sum();
+        assert_eq!(total, Decimal::from(100));
+        assert_eq!(d("0.1") + d("0.2"), d("0.3"));
+        assert_eq!(d("0.3") - d("0.1") - d("0.2"), Decimal::ZERO);
+    }
+
+    #[test]
+    fn multiply_and_divide() {
+        assert_eq!(d("8.5") * d("0.25752"), d("2.18892"));
+        assert_eq!(d("0.000001") * d("0.5"), d("0.000001"));
+        assert_eq!(d("-0.000001") * d("0.5"), d("-0.000001"));
+        assert_eq!(Decimal::from(10) / Decimal::from(3), d("3.333333"));
+        assert_eq!(Decimal::from(20) / 3, d("6.666667"));
+        assert_eq!(d("1.5") * 4, Decimal::from(6));
+    }
+
+    #[test]
+    fn saturates_instead_of_wrapping() {
+        let max = Decimal::from_raw(i64::MAX);
+        let min = Decimal::from_raw(i64::MIN);
+        assert_eq!(max * Decimal::from(2), max);
+        assert_eq!(max * Decimal::from(-2), min);
+        assert_eq!(max / d("0.5"), max);
+        assert_eq!(min / d("0.000001"), min);
+        assert_eq!(min / -1, max);
+        assert_eq!(max * 3, max);
+        assert_eq!(max + Decimal::from(1), max);
+        assert_eq!(min - Decimal::from(1), min);
+        assert_eq!(-min, max);
+        assert_eq!(min.abs(), max);
+        assert_eq!(max.round(0), max);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Decimal::new(25752, 5), d("0.25752"));
+        assert_eq!(Decimal::new(-3, 0), d("-3"));
+        assert_eq!(Decimal::from_f64(0.1), Some(d("0.1")));
+        assert_eq!(Decimal::from_f64(f64::NAN), None);
+        assert_eq!(Decimal::from_scaled(12345, -1), Some(d("1234.5")));
+        assert_eq!(Decimal::from_scaled(12345, -3), Some(d("12.345")));
+        assert_eq!(Decimal::from_scaled(5, 2), Some(Decimal::from(500)));
+        assert_eq!(Decimal::from_scaled(i64::MAX, 0), None);
+        assert_eq!(d("12.5").to_f64(), 12.5);
+        assert_eq!(d("1").ratio(d("4")), 0.25);
+        assert!(d("1").ratio(Decimal::ZERO).is_infinite());
+        assert_eq!(d("2.345").round(2), d("2.35"));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use chrono::{ NaiveDateTime };
+
+use crate::decimal::Decimal;
+use crate::intervals::Interval;
+use crate::units::KilowattHours;
+
+/// A problem found while reading a Green Button file.
+#[derive(Debug, PartialEq)]
+pub struct GreenButtonError {
+    pub message: String,
+}
+
+impl fmt::Display for GreenButtonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn error(message: &str) -> GreenButtonError {
+    GreenButtonError { message: message.to_string() }
+}
+
+/// ESPI unit of measure for watt / hours.
+const UOM_WATT_HOURS: i64 = 72;
+
+/// ESPI flow direction for energy delivered from the grid.
+const FLOW_FORWARD: i64 = 1;
+
+/// ESPI flow direction for energy sent back to the grid.
+const FLOW_REVERSE: i64 = 19;
+
+// Just enough of an XML element for reading ESPI feeds.
+// Namespace prefixes are dropped from names.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The number held by a child element, such as <value>974</value>.
+    fn number(&self, name: &str) -> Option<i64> {
+        self.child(name).and_then(|child| child.text.trim().parse::<i64>().ok())
+    }
+}
+
+fn local_name(name: &str) -> String {
+    match name.find(':') {
+        Some(position) => name[position + 1..].to_string(),
+        None => name.to_string(),
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Read name="value" pairs from inside a tag.
+fn parse_attributes(text: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = text;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim();
+        let after = rest[equals + 1..].trim_start();
+        let quote = match after.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => break,
+        };
+        let value_end = match after[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+        attributes.push((local_name(name), unescape(&after[1..value_end])));
+        rest = &after[value_end + 1..];
+    }
+    attributes
+}
+
+// Build a tree of elements. Comments, processing instructions
+// and doctypes are skipped.
+fn parse_xml(text: &str) -> Result<Element, GreenButtonError> {
+    let mut stack: Vec<Element> = vec![Element::default()];
+    let mut rest = text;
+
+    while let Some(open) = rest.find('<') {
+        stack.last_mut().unwrap().text.push_str(&unescape(&rest[..open]));
+        rest = &rest[open..];
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or_else(|| error("unclosed comment"))?;
+            rest = &rest[end + 3..];
+            continue;
+        }
+        if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").ok_or_else(|| error("unclosed CDATA"))?;
+            stack.last_mut().unwrap().text.push_str(&rest[9..end]);
+            rest = &rest[end + 3..];
+            continue;
+        }
+
+        let close = rest.find('>').ok_or_else(|| error("unclosed tag"))?;
+        let tag = &rest[1..close];
+        rest = &rest[close + 1..];
+
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let element = stack.pop().unwrap();
+            if element.name != local_name(name.trim()) || stack.is_empty() {
+                return Err(error(&format!("unexpected closing tag {}", name)));
+            }
+            stack.last_mut().unwrap().children.push(element);
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attributes) = match tag.find(char::is_whitespace) {
+            Some(space) => (&tag[..space], parse_attributes(&tag[space..])),
+            None => (tag, Vec::new()),
+        };
+        let element = Element {
+            name: local_name(name),
+            attributes,
+            ..Element::default()
+        };
+
+        if self_closing {
+            stack.last_mut().unwrap().children.push(element);
+        } else {
+            stack.push(element);
+        }
+    }
+
+    if stack.len() != 1 {
+        return Err(error("unclosed element"));
+    }
+    Ok(stack.pop().unwrap())
+}
+
+// What a ReadingType tells us about the values in its blocks.
+#[derive(Clone, Copy)]
+struct ReadingType {
+    flow_direction: i64,
+    // Values times ten to this power are kilowatt / hours.
+    kwh_power_of_ten: i32,
+}
+
+fn reading_type(element: &Element) -> Result<ReadingType, GreenButtonError> {
+    let uom = element.number("uom").unwrap_or(UOM_WATT_HOURS);
+    if uom != UOM_WATT_HOURS {
+        return Err(error(&format!("unsupported unit of measure {}", uom)));
+    }
+    let power_of_ten = element.number("powerOfTenMultiplier").unwrap_or(0);
+    Ok(ReadingType {
+        flow_direction: element.number("flowDirection").unwrap_or(FLOW_FORWARD),
+        kwh_power_of_ten: power_of_ten as i32 - 3,
+    })
+}
+
+fn link<'a>(entry: &'a Element, rel: &str) -> Option<&'a str> {
+    entry.children("link")
+        .find(|link| link.attribute("rel") == Some(rel))
+        .and_then(|link| link.attribute("href"))
+}
+
+/// Read the interval readings from a Green Button (ESPI) Atom feed.
+/// Forward flow readings are imports and reverse flow readings are
+/// exports. Times are moved to local standard time using the feed's
+/// tzOffset, daylight saving isn't applied.
+pub fn parse_green_button(text: &str) -> Result<Vec<Interval>, GreenButtonError> {
+    let root = parse_xml(text)?;
+    let feed = root.child("feed").ok_or_else(|| error("no Atom feed"))?;
+
+    let mut tz_offset = 0;
+    // Reading types by the href of their entry, and the reading
+    // type each meter reading refers to.
+    let mut reading_types: BTreeMap<String, ReadingType> = BTreeMap::new();
+    let mut meter_readings: BTreeMap<String, String> = BTreeMap::new();
+    let mut latest_type: Option<ReadingType> = None;
+    let mut by_start: BTreeMap<NaiveDateTime, Interval> = BTreeMap::new();
+
+    for entry in feed.children("entry") {
+        let content = match entry.child("content") {
+            Some(content) => content,
+            None => continue,
+        };
+
+        if let Some(parameters) = content.child("LocalTimeParameters") {
+            tz_offset = parameters.number("tzOffset").unwrap_or(0);
+        }
+
+        if let Some(element) = content.child("ReadingType") {
+            let found = reading_type(element)?;
+            if let Some(href) = link(entry, "self") {
+                reading_types.insert(href.to_string(), found);
+            }
+            latest_type = Some(found);
+        }
+
+        if content.child("MeterReading").is_some() {
+            if let (Some(own), Some(related)) = (link(entry, "self"), link(entry, "related")) {
+                meter_readings.insert(own.to_string(), related.to_string());
+            }
+        }
+
+        let blocks: Vec<&Element> = content.children("IntervalBlock").collect();
+        if blocks.is_empty() {
+            continue;
+        }
+
+        // Blocks live under their meter reading, .../MeterReading/01/IntervalBlock.
+        let linked = link(entry, "up")
+            .map(|up| up.trim_end_matches('/').trim_end_matches("IntervalBlock"))
+            .map(|up| up.trim_end_matches('/'))
+            .and_then(|meter_reading| meter_readings.get(meter_reading))
+            .and_then(|href| reading_types.get(href))
+            .copied();
+        let kind = linked.or(latest_type)
+            .ok_or_else(|| error("interval block without a reading type"))?;
+
+        if kind.flow_direction != FLOW_FORWARD && kind.flow_direction != FLOW_REVERSE {
+            continue;
+        }
+
+        for block in blocks {
+            for reading in block.children("IntervalReading") {
+                let period = reading.child("timePeriod")
+                    .ok_or_else(|| error("interval reading without a time period"))?;
+                let (start, minutes) = match (period.number("start"), period.number("duration")) {
+                    (Some(start), Some(duration)) if duration > 0 && duration % 60 == 0 => (start, duration / 60),
+                    _ => return Err(error("invalid time period")),
+                };
+                // Whole minutes that split a day evenly, as NEM12 allows.
+                if 1440 % minutes != 0 {
+                    return Err(error("invalid interval length"));
+                }
+                let value = reading.number("value")
+                    .ok_or_else(|| error("interval reading without a value"))?;
+
+                let start = start.checked_add(tz_offset)
+                    .and_then(|seconds| NaiveDateTime::from_timestamp_opt(seconds, 0))
+                    .ok_or_else(|| error("interval start out of range"))?;
+                let kwh: KilowattHours = Decimal::from_scaled(value, kind.kwh_power_of_ten)
+                    .ok_or_else(|| error("interval value out of range"))?
+                    .into();
+                let interval = by_start.entry(start).or_insert(Interval {
+                    start,
+                    minutes: minutes as u32,
+                    imports: KilowattHours::ZERO,
+                    exports: KilowattHours::ZERO,
+                    estimated: false,
+                });
+                if kind.flow_direction == FLOW_FORWARD {
+                    interval.imports += kwh;
+                } else {
+                    interval.exports += kwh;
+                }
+            }
+        }
+    }
+
+    Ok(by_start.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:espi="http://naesb.org/espi">
+  <!-- Hourly data for one day. -->
+  <entry>
+    <content>
+      <espi:LocalTimeParameters>
+        <espi:tzOffset>-18000</espi:tzOffset>
+      </espi:LocalTimeParameters>
+    </content>
+  </entry>
+  <entry>
+    <link rel="self" href="https://example.com/UsagePoint/1/MeterReading/1"/>
+    <link rel="related" href="https://example.com/ReadingType/1"/>
+    <content><espi:MeterReading/></content>
+  </entry>
+  <entry>
+    <link rel="self" href="https://example.com/UsagePoint/1/MeterReading/2"/>
+    <link rel="related" href="https://example.com/ReadingType/2"/>
+    <content><espi:MeterReading/></content>
+  </entry>
+  <entry>
+    <link rel="self" href="https://example.com/ReadingType/1"/>
+    <content>
+      <espi:ReadingType>
+        <espi:flowDirection>1</espi:flowDirection>
+        <espi:powerOfTenMultiplier>0</espi:powerOfTenMultiplier>
+        <espi:uom>72</espi:uom>
+      </espi:ReadingType>
+    </content>
+  </entry>
+  <entry>
+    <link rel="self" href="https://example.com/ReadingType/2"/>
+    <content>
+      <espi:ReadingType>
+        <espi:flowDirection>19</espi:flowDirection>
+        <espi:powerOfTenMultiplier>1</espi:powerOfTenMultiplier>
+        <espi:uom>72</espi:uom>
+      </espi:ReadingType>
+    </content>
+  </entry>
+  <entry>
+    <link rel="up" href="https://example.com/UsagePoint/1/MeterReading/1/IntervalBlock"/>
+    <content>
+      <espi:IntervalBlock>
+        <espi:IntervalReading>
+          <espi:timePeriod><espi:duration>3600</espi:duration><espi:start>1569906000</espi:start></espi:timePeriod>
+        </espi:IntervalReading>
+      </espi:IntervalBlock>
+    </content>
+  </entry>
+</feed>"#;
+
+    fn feed() -> String {
+        // 2019-10-01 05:00 UTC is midnight in UTC-5.
+        let start = 1_569_906_000;
+        let block = |up: &str, values: &[i64]| {
+            let readings: String = values.iter().enumerate()
+                .map(|(hour, value)| format!(
+                    "<espi:IntervalReading><espi:timePeriod>\
+                     <espi:duration>3600</espi:duration><espi:start>{}</espi:start>\
+                     </espi:timePeriod><espi:value>{}</espi:value></espi:IntervalReading>",
+                    start + hour as i64 * 3600, value))
+                .collect();
+            format!("<entry><link rel=\"up\" href=\"{}\"/><content>\
+                     <espi:IntervalBlock>{}</espi:IntervalBlock></content></entry>",
+                    up, readings)
+        };
+
+        let head = &FEED[..FEED.find("  <entry>\n    <link rel=\"up\"").unwrap()];
+        format!("{}{}{}</feed>", head,
+                block("https://example.com/UsagePoint/1/MeterReading/1/IntervalBlock",
+                      &[500, 250]),
+                block("https://example.com/UsagePoint/1/MeterReading/2/IntervalBlock",
+                      &[0, 100]))
+    }
+
+    #[test]
+    fn parse_green_button_ok() {
+        let intervals = parse_green_button(&feed()).unwrap();
+        assert_eq!(intervals.len(), 2);
+        assert_eq!(intervals[0].start, NaiveDate::from_ymd(2019, 10, 1).and_hms(0, 0, 0));
+        assert_eq!(intervals[0].minutes, 60);
+        assert_eq!(intervals[0].imports, Decimal::new(5, 1).into());
+        assert_eq!(intervals[1].imports, Decimal::new(25, 2).into());
+        // Reverse flow with a multiplier of ten.
+        assert_eq!(intervals[1].exports, KilowattHours::from(1));
+    }
+
+    #[test]
+    fn parse_green_button_errors() {
+        assert!(parse_green_button("<feed><entry></feed>").is_err());
+        assert!(parse_green_button("<notafeed/>").is_err());
+        // The sample's last block has no value.
+        assert!(parse_green_button(FEED).is_err());
+        let far_off = feed().replace("<espi:start>1569906000<", "<espi:start>9223372036854775807<");
+        assert_eq!(parse_green_button(&far_off).unwrap_err(), error("interval start out of range"));
+        for (duration, message) in &[("90", "invalid time period"), ("30", "invalid time period"),
+                                     ("0", "invalid time period"), ("4200", "invalid interval length")] {
+            let odd = feed().replace("<espi:duration>3600<", &format!("<espi:duration>{}<", duration));
+            assert_eq!(parse_green_button(&odd).unwrap_err(), error(message), "duration {}", duration);
+        }
+    }
+
+    #[test]
+    fn parse_xml_ok() {
+        let root = parse_xml("<a x='1' y=\"&amp;\"><b>one &lt; two</b><c/></a>").unwrap();
+        let a = root.child("a").unwrap();
+        assert_eq!(a.attribute("y"), Some("&"));
+        assert_eq!(a.child("b").unwrap().text, "one < two");
+        assert!(a.child("c").is_some());
+    }
+}
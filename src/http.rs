@@ -0,0 +1,254 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{ TcpListener, TcpStream };
+use std::panic::{ self, AssertUnwindSafe };
+use std::time::Duration;
+
+/// The largest request body we'll read.
+pub const MAX_BODY: usize = 64 * 1024;
+
+/// The longest request or header line we'll read, in bytes.
+pub const MAX_LINE: usize = 8 * 1024;
+
+/// The most headers we'll read.
+pub const MAX_HEADERS: usize = 64;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An HTTP request, read just far enough for a small local server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// The path without any query string.
+    pub path: String,
+    /// The query string without the '?', if there was one.
+    pub query: Option<String>,
+    /// Header names are lower case.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// The value of a header, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of a query string parameter. Values aren't decoded
+    /// beyond '+', which is all dates and numbers need.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_ref()?.split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                Some((parts.next()?, parts.next().unwrap_or("")))
+            })
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.replace('+', " "))
+    }
+}
+
+/// A response to send back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Response {
+        Response { status, content_type, body }
+    }
+
+    /// A plain text response.
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body.to_string())
+    }
+}
+
+// The reason phrase for the status codes we use.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+// Something wrong with the request itself.
+fn bad_request(message: &str) -> Response {
+    Response::text(400, message)
+}
+
+// One line of the request, or the response to give if it's too long.
+fn read_line<R: BufRead>(reader: &mut R, too_long: u16) -> Result<String, Response> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE as u64).read_line(&mut line)
+        .map_err(|e| bad_request(&e.to_string()))?;
+    if line.len() == MAX_LINE && !line.ends_with('\n') {
+        return Err(Response::text(too_long, "line too long"));
+    }
+    Ok(line)
+}
+
+/// Read a request from the client, or give the response
+/// to send if it can't be read.
+pub fn read_request<R: Read>(stream: R) -> Result<Request, Response> {
+    let mut reader = BufReader::new(stream);
+
+    let line = read_line(&mut reader, 414)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or_else(|| bad_request("empty request"))?.to_string();
+    let target = parts.next().ok_or_else(|| bad_request("no request target"))?;
+    let (path, query) = match target.find('?') {
+        Some(position) => (&target[..position], Some(target[position + 1..].to_string())),
+        None => (target, None),
+    };
+    let path = path.to_string();
+
+    let mut headers = Vec::new();
+    for count in 0.. {
+        let line = read_line(&mut reader, 431)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(Response::text(431, "too many headers"));
+        }
+        if let Some(position) = line.find(':') {
+            headers.push((line[..position].trim().to_ascii_lowercase(),
+                          line[position + 1..].trim().to_string()));
+        }
+    }
+
+    let length = headers.iter()
+        .find(|(name, _)| name == "content-length")
+        .map(|(_, value)| value.parse::<usize>())
+        .unwrap_or(Ok(0))
+        .map_err(|_e| bad_request("bad content length"))?;
+    if length > MAX_BODY {
+        return Err(Response::text(413, "body too large"));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).map_err(|e| bad_request(&e.to_string()))?;
+    let body = String::from_utf8(body).map_err(|_e| bad_request("body is not UTF-8"))?;
+
+    Ok(Request { method, path, query, headers, body })
+}
+
+/// Send a response and close the connection.
+pub fn write_response<W: Write>(mut stream: W, response: &Response) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {} {}\r\n\
+                    Content-Type: {}\r\n\
+                    Content-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}",
+           response.status, reason(response.status), response.content_type,
+           response.body.len(), response.body)?;
+    stream.flush()
+}
+
+// Answer one connection.
+fn handle<F: FnMut(&Request) -> Response>(stream: TcpStream, handler: &mut F) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = match read_request(&stream) {
+        Ok(request) => answer(&request, handler),
+        Err(response) => response,
+    };
+    write_response(&stream, &response)
+}
+
+// The handler's response. If it panics, say because the database
+// couldn't be used, the client gets a 500 and the server carries on.
+fn answer<F: FnMut(&Request) -> Response>(request: &Request, handler: &mut F) -> Response {
+    panic::catch_unwind(AssertUnwindSafe(|| handler(request)))
+        .unwrap_or_else(|_panic| Response::text(500, "the request could not be answered"))
+}
+
+/// Answer requests one at a time, forever.
+pub fn serve<F: FnMut(&Request) -> Response>(listener: TcpListener, mut handler: F) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| handle(stream, &mut handler));
+        if let Err(e) = result {
+            eprintln!("Connection failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_request_ok() {
+        let text = "POST /readings?from=2020-01-01&to=x+y HTTP/1.1\r\n\
+                    Host: localhost\r\n\
+                    Content-Length: 4\r\n\
+                    Authorization: Bearer abc\r\n\r\n\
+                    {}{}";
+        let request = read_request(text.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/readings");
+        assert_eq!(request.query_param("from"), Some("2020-01-01".to_string()));
+        assert_eq!(request.query_param("to"), Some("x y".to_string()));
+        assert_eq!(request.query_param("none"), None);
+        assert_eq!(request.header("AUTHORIZATION"), Some("Bearer abc"));
+        assert_eq!(request.body, "{}{}");
+
+        assert!(read_request("".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn read_request_limits() {
+        let status = |text: String| read_request(text.as_bytes()).unwrap_err().status;
+        assert_eq!(status(format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1)), 413);
+        assert_eq!(status(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE))), 414);
+        assert_eq!(status(format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE))), 431);
+        assert_eq!(status(format!("GET / HTTP/1.1\r\n{}\r\n", "X: a\r\n".repeat(MAX_HEADERS + 1))), 431);
+        assert_eq!(status(format!("GET / HTTP/1.1\r\n{}\r\n", "x\r\n".repeat(MAX_HEADERS + 1))), 431);
+        assert!(read_request(format!("GET / HTTP/1.1\r\n{}\r\n", "X: a\r\n".repeat(MAX_HEADERS)).as_bytes())
+            .is_ok());
+    }
+
+    #[test]
+    fn handler_panics() {
+        let request = read_request("GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        let mut calls = 0;
+        let mut handler = |_request: &Request| {
+            calls += 1;
+            if calls == 1 {
+                panic!("database is locked");
+            }
+            Response::text(200, "ok")
+        };
+        assert_eq!(answer(&request, &mut handler).status, 500);
+        assert_eq!(answer(&request, &mut handler).status, 200);
+    }
+
+    #[test]
+    fn write_response_ok() {
+        let mut output = Vec::new();
+        write_response(&mut output, &Response::text(404, "none")).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "HTTP/1.1 404 Not Found\r\n\
+                    Content-Type: text/plain; charset=utf-8\r\n\
+                    Content-Length: 4\r\n\
+                    Connection: close\r\n\r\nnone");
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::BTreeMap;
+use chrono::{ Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime };
+
+use crate::config::{ parse_sections, ConfigError };
+use crate::readings::{ Reading, Source };
+use crate::units::{ KilowattHours, Kilowatts };
+
+/// Energy through the meter over a short interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    /// When the interval starts.
+    pub start: NaiveDateTime,
+    /// The length of the interval.
+    pub minutes: u32,
+    /// Energy imported from the grid in kilowatt / hours.
+    pub imports: KilowattHours,
+    /// Energy exported to the grid in kilowatt / hours.
+    pub exports: KilowattHours,
+    /// True if the meter data was estimated or substituted
+    /// rather than read from the meter.
+    pub estimated: bool,
+}
+
+/// The interval lengths that can be stored, in minutes. Meters
+/// read every 5, 15 or 30 minutes; Green Button feeds are often hourly.
+pub const INTERVAL_MINUTES: [u32; 4] = [5, 15, 30, 60];
+
+/// Make sure every interval is one of the lengths that can be stored.
+pub fn check_interval_lengths(intervals: &[Interval]) -> Result<(), String> {
+    match intervals.iter().find(|interval| !INTERVAL_MINUTES.contains(&interval.minutes)) {
+        Some(interval) => Err(format!("{}: intervals of {} minutes can't be stored",
+                                      interval.start.format("%d/%m/%Y %H:%M"), interval.minutes)),
+        None => Ok(()),
+    }
+}
+
+/// The energy through the meter over a whole day.
+#[derive(Debug, PartialEq)]
+pub struct DailyTotal {
+    pub date: NaiveDate,
+    /// Energy imported from the grid in kilowatt / hours.
+    pub imports: KilowattHours,
+    /// Energy exported to the grid in kilowatt / hours.
+    pub exports: KilowattHours,
+    /// True if any interval in the day was estimated.
+    pub estimated: bool,
+}
+
+/// Add up intervals, sorted by start, into daily totals.
+pub fn daily_totals(intervals: &[Interval]) -> Vec<DailyTotal> {
+    let mut totals: Vec<DailyTotal> = Vec::new();
+    for interval in intervals.iter() {
+        let date = interval.start.date();
+        match totals.last_mut() {
+            Some(total) if total.date == date => {
+                total.imports += interval.imports;
+                total.exports += interval.exports;
+                total.estimated |= interval.estimated;
+            },
+            _ => totals.push(DailyTotal {
+                date,
+                imports: interval.imports,
+                exports: interval.exports,
+                estimated: interval.estimated,
+            }),
+        }
+    }
+    totals
+}
+
+// Generation on a date found by drawing a straight line between
+// the stored readings either side of it.
+fn interpolate_generation(stored: &[Reading], date: NaiveDate) -> Option<KilowattHours> {
+    let before = stored.iter().rev().find(|r| r.date <= date)?;
+    let after = stored.iter().find(|r| r.date >= date)?;
+
+    if before.date == after.date {
+        return Some(before.generation);
+    }
+
+    let span = after.date.signed_duration_since(before.date).num_days();
+    let part = date.signed_duration_since(before.date).num_days();
+    Some(before.generation + (after.generation - before.generation) * part / span)
+}
+
+/// Readings worked out from daily totals.
+#[derive(Debug, PartialEq)]
+pub struct DerivedReadings {
+    pub readings: Vec<Reading>,
+    /// Dates with totals that couldn't be tied to a stored reading.
+    pub unanchored: Vec<NaiveDate>,
+}
+
+// The meter registers at the end of each day in a run of daily totals
+// without gaps, and whether they were worked out from estimated totals.
+// They're counted back from the first stored reading within the run, or
+// on the day before it, and start again from each stored reading after
+// that so any difference doesn't carry on.
+fn meter_registers(run: &[&DailyTotal],
+                   stored: &[Reading]) -> Option<Vec<(KilowattHours, KilowattHours, bool)>> {
+    let start = run.first()?.date;
+    let end = run.last()?.date;
+    let anchor = stored.iter()
+        .find(|r| r.date >= start - Duration::days(1) && r.date <= end)?;
+
+    let before_anchor = run.iter().filter(|total| total.date <= anchor.date);
+    let mut imports = anchor.imports - before_anchor.clone().map(|total| total.imports).sum();
+    let mut exports = anchor.exports - before_anchor.map(|total| total.exports).sum();
+    let mut estimated = false;
+
+    Some(run.iter()
+        .enumerate()
+        .map(|(index, total)| {
+            imports += total.imports;
+            exports += total.exports;
+            estimated |= total.estimated;
+            if let Some(reading) = stored.iter().find(|r| r.date == total.date) {
+                imports = reading.imports;
+                exports = reading.exports;
+                estimated = false;
+            }
+            // Counting back, the registers depend on the days after.
+            if total.date < anchor.date {
+                let counted_from = run[index + 1..].iter()
+                    .take_while(|later| later.date <= anchor.date)
+                    .any(|later| later.estimated);
+                return (imports, exports, counted_from);
+            }
+            (imports, exports, estimated)
+        })
+        .collect())
+}
+
+/// Turn daily totals into cumulative readings so they can be used with
+/// `find_change` and `calculate`. Each run of days between gaps needs a
+/// stored reading within it, or on the day before it, to count the meter
+/// registers from. The meter doesn't know what the inverter generated,
+/// so generation is interpolated between stored readings and days that
+/// can't be interpolated are left out. Readings counted from estimated
+/// meter data are marked as estimated.
+pub fn derive_readings(totals: &[DailyTotal], stored: &[Reading]) -> DerivedReadings {
+    let mut derived = DerivedReadings { readings: Vec::new(), unanchored: Vec::new() };
+
+    let mut runs: Vec<Vec<&DailyTotal>> = Vec::new();
+    for total in totals.iter() {
+        match runs.last_mut() {
+            Some(run) if run.last().unwrap().date + Duration::days(1) == total.date =>
+                run.push(total),
+            _ => runs.push(vec![total]),
+        }
+    }
+
+    for run in runs.iter() {
+        let registers = match meter_registers(run, stored) {
+            Some(registers) => registers,
+            None => {
+                derived.unanchored.extend(run.iter().map(|total| total.date));
+                continue;
+            },
+        };
+
+        for (total, (imports, exports, estimated)) in run.iter().zip(registers) {
+            if stored.iter().any(|r| r.date == total.date) {
+                continue;
+            }
+            if let Some(generation) = interpolate_generation(stored, total.date) {
+                derived.readings.push(Reading {
+                    date: total.date,
+                    generation,
+                    exports,
+                    imports,
+                    source: if estimated { Source::Estimated } else { Source::Interpolated },
+                    note: String::new(),
+                });
+            }
+        }
+    }
+    derived
+}
+
+/// The average energy through the meter at a time of day.
+#[derive(Debug, PartialEq)]
+pub struct ProfileSlot {
+    /// When the slot starts.
+    pub time: NaiveTime,
+    /// Average energy imported in kilowatt / hours.
+    pub imports: KilowattHours,
+    /// Average energy exported in kilowatt / hours.
+    pub exports: KilowattHours,
+}
+
+/// The average day, found by averaging the intervals
+/// starting at each time of day.
+pub fn load_profile(intervals: &[Interval]) -> Vec<ProfileSlot> {
+    let mut slots: BTreeMap<NaiveTime, (KilowattHours, KilowattHours, i64)> = BTreeMap::new();
+    for interval in intervals.iter() {
+        let slot = slots.entry(interval.start.time())
+            .or_insert((KilowattHours::ZERO, KilowattHours::ZERO, 0));
+        slot.0 += interval.imports;
+        slot.1 += interval.exports;
+        slot.2 += 1;
+    }
+
+    slots.into_iter()
+        .map(|(time, (imports, exports, count))| ProfileSlot {
+            time,
+            imports: imports / count,
+            exports: exports / count,
+        })
+        .collect()
+}
+
+/// The interval with the most imported in a month.
+#[derive(Debug, PartialEq)]
+pub struct PeakDemand {
+    pub year: i32,
+    pub month: u32,
+    pub interval: Interval,
+}
+
+impl PeakDemand {
+    /// The average power drawn from the grid during the
+    /// interval, or none if the interval has no length.
+    pub fn kw(&self) -> Option<Kilowatts> {
+        match self.interval.minutes {
+            0 => None,
+            minutes => Some(self.interval.imports / Duration::minutes(minutes as i64)),
+        }
+    }
+}
+
+/// Find the interval drawing the most power from the grid in each month.
+pub fn peak_demand_by_month(intervals: &[Interval]) -> Vec<PeakDemand> {
+    let mut peaks: Vec<PeakDemand> = Vec::new();
+    for interval in intervals.iter() {
+        let year = interval.start.year();
+        let month = interval.start.month();
+        let candidate = PeakDemand { year, month, interval: interval.clone() };
+        if candidate.kw().is_none() {
+            continue;
+        }
+
+        match peaks.iter_mut().find(|peak| peak.year == year && peak.month == month) {
+            Some(peak) => if candidate.kw() > peak.kw() {
+                *peak = candidate;
+            },
+            None => peaks.push(candidate),
+        }
+    }
+    peaks.sort_by_key(|peak| (peak.year, peak.month));
+    peaks
+}
+
+/// Part of the day belonging to a time of use period. A period
+/// such as shoulder may have more than one window.
+#[derive(Debug, PartialEq)]
+pub struct TouWindow {
+    pub name: String,
+    /// When the window opens.
+    pub start: NaiveTime,
+    /// When the window closes. May be earlier than the start
+    /// for windows running past midnight.
+    pub end: NaiveTime,
+}
+
+impl TouWindow {
+    /// True if the time falls within the window.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// The name given to imports outside every time of use window.
+pub const OTHER_PERIOD: &str = "Other";
+
+/// The fraction of imports falling in each time of use period, going
+/// by when each interval starts. These are the shares to use in a
+/// time of use plan.
+pub fn import_shares(intervals: &[Interval], windows: &[TouWindow]) -> Vec<(String, f32)> {
+    let mut shares: Vec<(String, KilowattHours)> = Vec::new();
+    let mut total = KilowattHours::ZERO;
+
+    for interval in intervals.iter() {
+        let name = match windows.iter().find(|w| w.contains(interval.start.time())) {
+            Some(window) => window.name.as_str(),
+            None => OTHER_PERIOD,
+        };
+        match shares.iter_mut().find(|share| share.0 == name) {
+            Some(share) => share.1 += interval.imports,
+            None => shares.push((name.to_string(), interval.imports)),
+        }
+        total += interval.imports;
+    }
+
+    shares.into_iter()
+        .map(|(name, imports)| {
+            let share = if total > KilowattHours::ZERO { (imports / total).0 } else { imports.to_f32() };
+            (name, share)
+        })
+        .collect()
+}
+
+/// Read time of use windows from a file where each period is a
+/// section with comma separated ranges of hours.
+///
+/// ```text
+/// [Peak]
+/// hours = 07:00-09:00, 16:00-21:00
+///
+/// [Off peak]
+/// hours = 22:00-07:00
+/// ```
+pub fn parse_tou_windows(text: &str) -> Result<Vec<TouWindow>, ConfigError> {
+    let mut windows = Vec::new();
+    for section in parse_sections(text)?.iter() {
+        let error = || ConfigError {
+            line: section.line,
+            message: format!("{} needs hours as HH:MM-HH:MM ranges", section.name),
+        };
+        let hours = section.get("hours").ok_or_else(error)?;
+
+        for range in hours.split(',') {
+            windows.push(parse_window(&section.name, range).ok_or_else(error)?);
+        }
+    }
+    Ok(windows)
+}
+
+// Read a window given as HH:MM-HH:MM.
+pub(crate) fn parse_window(name: &str, range: &str) -> Option<TouWindow> {
+    let times: Vec<Option<NaiveTime>> = range.split('-')
+        .map(|time| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok())
+        .collect();
+    match times.as_slice() {
+        [Some(start), Some(end)] => Some(TouWindow {
+            name: name.to_string(),
+            start: *start,
+            end: *end,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::kwh;
+
+    fn interval(day: u32, hour: u32, imports: f64, exports: f64) -> Interval {
+        Interval {
+            start: NaiveDate::from_ymd(2019, 10, day).and_hms(hour, 0, 0),
+            minutes: 30,
+            imports: kwh(imports),
+            exports: kwh(exports),
+            estimated: false,
+        }
+    }
+
+    fn reading(day: u32, generation: f64) -> Reading {
+        Reading::new(NaiveDate::from_ymd(2019, 10, day), kwh(generation), kwh(100.0), kwh(200.0))
+    }
+
+    #[test]
+    fn daily_totals_ok() {
+        let intervals = vec![interval(1, 0, 1.0, 0.0),
+                             interval(1, 12, 0.5, 2.0),
+                             interval(2, 0, 1.5, 0.0)];
+        let totals = daily_totals(&intervals);
+        assert_eq!(totals, vec![
+            DailyTotal { date: NaiveDate::from_ymd(2019, 10, 1), imports: kwh(1.5), exports: kwh(2.0),
+                         estimated: false },
+            DailyTotal { date: NaiveDate::from_ymd(2019, 10, 2), imports: kwh(1.5), exports: kwh(0.0),
+                         estimated: false },
+        ]);
+
+        let mut estimated = interval(2, 12, 0.5, 0.0);
+        estimated.estimated = true;
+        let totals = daily_totals(&[interval(1, 0, 1.0, 0.0), interval(2, 0, 1.0, 0.0), estimated]);
+        assert_eq!(totals.iter().map(|t| t.estimated).collect::<Vec<_>>(), vec![false, true]);
+    }
+
+    fn totals(days: &[u32]) -> Vec<DailyTotal> {
+        days.iter()
+            .map(|day| DailyTotal {
+                date: NaiveDate::from_ymd(2019, 10, *day),
+                imports: kwh(2.0),
+                exports: kwh(1.0),
+                estimated: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn derive_readings_ok() {
+        let stored = vec![reading(1, 10.0), reading(5, 50.0)];
+        let derived = derive_readings(&totals(&[2, 3, 4]), &stored);
+        let readings = derived.readings;
+        assert_eq!(readings.len(), 3);
+        assert_eq!(readings[0].date, NaiveDate::from_ymd(2019, 10, 2));
+        assert_eq!(readings[0].generation, kwh(20.0));
+        assert_eq!(readings[2].imports, kwh(206.0));
+        assert_eq!(readings[2].exports, kwh(103.0));
+        assert_eq!(readings[0].source, Source::Interpolated);
+        assert!(derived.unanchored.is_empty());
+    }
+
+    #[test]
+    fn derive_readings_gaps() {
+        // The run after the gap has no stored reading to count from.
+        let stored = vec![reading(1, 10.0), reading(9, 90.0)];
+        let derived = derive_readings(&totals(&[2, 3, 5, 6]), &stored);
+        assert_eq!(derived.readings.len(), 2);
+        assert_eq!(derived.unanchored, vec![NaiveDate::from_ymd(2019, 10, 5),
+                                            NaiveDate::from_ymd(2019, 10, 6)]);
+
+        let derived = derive_readings(&totals(&[2, 3]), &stored[1..]);
+        assert!(derived.readings.is_empty());
+        assert_eq!(derived.unanchored.len(), 2);
+    }
+
+    #[test]
+    fn derive_readings_start_again_from_stored() {
+        let mut stored = vec![reading(1, 10.0), reading(3, 30.0), reading(5, 50.0)];
+        stored[1].imports = kwh(210.0);
+        let derived = derive_readings(&totals(&[2, 3, 4]), &stored);
+        let readings = derived.readings;
+        assert_eq!(readings.len(), 2);
+        assert_eq!(readings[0].imports, kwh(202.0));
+        assert_eq!(readings[1].date, NaiveDate::from_ymd(2019, 10, 4));
+        assert_eq!(readings[1].imports, kwh(212.0));
+        assert_eq!(readings[1].exports, kwh(101.0));
+    }
+
+    #[test]
+    fn derive_readings_count_back() {
+        let stored = vec![reading(1, 10.0), reading(5, 50.0)];
+        let derived = derive_readings(&totals(&[3, 4, 5]), &stored);
+        let readings = derived.readings;
+        assert_eq!(readings.len(), 2);
+        assert_eq!(readings[0].date, NaiveDate::from_ymd(2019, 10, 3));
+        assert_eq!(readings[0].imports, kwh(196.0));
+        assert_eq!(readings[1].imports, kwh(198.0));
+        assert_eq!(readings[1].exports, kwh(99.0));
+    }
+
+    #[test]
+    fn derive_readings_from_estimates() {
+        let stored = vec![reading(1, 10.0), reading(4, 40.0), reading(9, 90.0)];
+        let mut daily = totals(&[2, 3, 4, 5, 6, 7]);
+        daily[1].estimated = true;
+        daily[4].estimated = true;
+
+        // Day 2 is counted on from day 1 and day 3 used the estimate,
+        // day 5 starts again from the reading on day 4.
+        let sources: Vec<(u32, Source)> = derive_readings(&daily, &stored).readings.iter()
+            .map(|r| (r.date.day(), r.source.clone()))
+            .collect();
+        assert_eq!(sources, vec![(2, Source::Interpolated),
+                                 (3, Source::Estimated),
+                                 (5, Source::Interpolated),
+                                 (6, Source::Estimated),
+                                 (7, Source::Estimated)]);
+
+        // Counting back from day 5, day 3 depends on the estimate for day 4.
+        let stored = vec![reading(1, 10.0), reading(5, 50.0)];
+        let mut daily = totals(&[3, 4, 5]);
+        daily[1].estimated = true;
+        let sources: Vec<Source> = derive_readings(&daily, &stored).readings.iter()
+            .map(|r| r.source.clone())
+            .collect();
+        assert_eq!(sources, vec![Source::Estimated, Source::Interpolated]);
+    }
+
+    #[test]
+    fn load_profile_ok() {
+        let intervals = vec![interval(1, 0, 1.0, 0.0),
+                             interval(1, 12, 0.5, 2.0),
+                             interval(2, 0, 2.0, 0.0),
+                             interval(2, 12, 0.5, 1.0)];
+        let profile = load_profile(&intervals);
+        assert_eq!(profile, vec![
+            ProfileSlot { time: NaiveTime::from_hms(0, 0, 0), imports: kwh(1.5), exports: kwh(0.0) },
+            ProfileSlot { time: NaiveTime::from_hms(12, 0, 0), imports: kwh(0.5), exports: kwh(1.5) },
+        ]);
+    }
+
+    #[test]
+    fn peak_demand_ok() {
+        let mut november = interval(1, 18, 0.5, 0.0);
+        november.start = NaiveDate::from_ymd(2019, 11, 1).and_hms(18, 0, 0);
+        let intervals = vec![interval(1, 0, 1.0, 0.0),
+                             interval(2, 18, 2.5, 0.0),
+                             interval(3, 12, 0.5, 2.0),
+                             november];
+
+        let peaks = peak_demand_by_month(&intervals);
+        assert_eq!(peaks.len(), 2);
+        assert_eq!(peaks[0].month, 10);
+        assert_eq!(peaks[0].interval.start.day(), 2);
+        assert_eq!(peaks[0].kw(), Some(Kilowatts::from(5)));
+        assert_eq!(peaks[1].month, 11);
+
+        let mut no_length = interval(4, 18, 9.0, 0.0);
+        no_length.minutes = 0;
+        assert_eq!(PeakDemand { year: 2019, month: 10, interval: no_length.clone() }.kw(), None);
+        assert_eq!(peak_demand_by_month(&[no_length]), vec![]);
+    }
+
+    #[test]
+    fn check_interval_lengths_ok() {
+        let mut intervals = vec![interval(1, 0, 1.0, 0.0), interval(1, 1, 1.0, 0.0)];
+        assert_eq!(check_interval_lengths(&intervals), Ok(()));
+        intervals[1].minutes = 0;
+        assert_eq!(check_interval_lengths(&intervals),
+                   Err("01/10/2019 01:00: intervals of 0 minutes can't be stored".to_string()));
+        intervals[1].minutes = 90;
+        assert!(check_interval_lengths(&intervals).is_err());
+    }
+
+    #[test]
+    fn import_shares_ok() {
+        let windows = vec![TouWindow {
+                               name: "Peak".to_string(),
+                               start: NaiveTime::from_hms(16, 0, 0),
+                               end: NaiveTime::from_hms(21, 0, 0),
+                           },
+                           TouWindow {
+                               name: "Off peak".to_string(),
+                               start: NaiveTime::from_hms(22, 0, 0),
+                               end: NaiveTime::from_hms(7, 0, 0),
+                           }];
+        let intervals = vec![interval(1, 18, 3.0, 0.0),
+                             interval(1, 23, 0.5, 0.0),
+                             interval(2, 2, 0.5, 0.0),
+                             interval(2, 12, 1.0, 0.0)];
+
+        let shares = import_shares(&intervals, &windows);
+        assert_eq!(shares, vec![("Peak".to_string(), 0.6),
+                                ("Off peak".to_string(), 0.2),
+                                (OTHER_PERIOD.to_string(), 0.2)]);
+    }
+
+    #[test]
+    fn parse_tou_windows_ok() {
+        let text = "[Peak]\nhours = 07:00-09:00, 16:00-21:00\n[Off peak]\nhours = 22:00-07:00";
+        let windows = parse_tou_windows(text).unwrap();
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[1].start, NaiveTime::from_hms(16, 0, 0));
+        assert_eq!(windows[2].name, "Off peak");
+        assert!(parse_tou_windows("[Peak]\nhours = 7-9").is_err());
+    }
+}
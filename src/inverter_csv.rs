@@ -0,0 +1,391 @@
+use std::fmt;
+use chrono::{ Duration, NaiveDate, NaiveDateTime };
+
+use crate::decimal::Decimal;
+use crate::readings::Reading;
+use crate::units::KilowattHours;
+
+/// Differences in generation smaller than this are
+/// rounding, not something to fix.
+const TOLERANCE_KWH: KilowattHours = KilowattHours::new(Decimal::new(5, 2));
+
+/// A problem found while reading an inverter export.
+#[derive(Debug, PartialEq)]
+pub struct ImportError {
+    /// The line the problem was found on, starting from one.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Generation for a day as given by an inverter portal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationRecord {
+    /// Kilowatt / hours generated during the day.
+    Daily { date: NaiveDate, kwh: KilowattHours },
+    /// The inverter's lifetime total in kilowatt / hours at the
+    /// end of the day, the same figure shown on its screen.
+    Total { date: NaiveDate, kwh: KilowattHours },
+}
+
+impl GenerationRecord {
+    pub fn date(&self) -> NaiveDate {
+        match self {
+            GenerationRecord::Daily { date, .. } => *date,
+            GenerationRecord::Total { date, .. } => *date,
+        }
+    }
+}
+
+/// Reads one vendor's CSV export.
+pub trait GenerationImporter {
+    /// The vendor or portal the importer reads.
+    fn name(&self) -> &'static str;
+
+    /// True if the text looks like this importer's layout.
+    fn recognises(&self, text: &str) -> bool;
+
+    /// Read the generation records in the order they appear.
+    fn parse(&self, text: &str) -> Result<Vec<GenerationRecord>, ImportError>;
+}
+
+// Split a line on the separator, allowing for quoted fields.
+fn split_fields(line: &str, separator: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                fields.push(field.trim().to_string());
+                field.clear();
+            },
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+// Read the data rows after `skip` header lines, turning
+// the first two fields of each into a record.
+fn parse_rows<F>(text: &str, separator: char, skip: usize, mut row: F)
+    -> Result<Vec<GenerationRecord>, ImportError>
+    where F: FnMut(&str, &str) -> Option<GenerationRecord> {
+    let mut records = Vec::new();
+    for (index, line) in text.lines().enumerate().skip(skip) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_fields(line, separator);
+        let record = match fields.as_slice() {
+            [date, value, ..] => row(date, value),
+            _ => None,
+        };
+        match record {
+            Some(record) => records.push(record),
+            None => return Err(ImportError {
+                line: index + 1,
+                message: format!("could not read {}", line.trim()),
+            }),
+        }
+    }
+    Ok(records)
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or("")
+}
+
+/// Fronius Solar.web daily export. A header, a units row and a
+/// row per day with the energy in watt / hours.
+///
+/// ```text
+/// "Date and time","Energy | Symo 5.0-3-M (1)"
+/// "[dd.MM.yyyy]","[Wh]"
+/// "01.10.2019","23456"
+/// ```
+pub struct Fronius;
+
+impl GenerationImporter for Fronius {
+    fn name(&self) -> &'static str {
+        "Fronius"
+    }
+
+    fn recognises(&self, text: &str) -> bool {
+        first_line(text).starts_with("\"Date and time\"")
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<GenerationRecord>, ImportError> {
+        let units = text.lines().nth(1).unwrap_or("");
+        let multiplier = if units.contains("[kWh]") { Decimal::from(1) } else { Decimal::new(1, 3) };
+        parse_rows(text, ',', 2, |date, value| Some(GenerationRecord::Daily {
+            date: NaiveDate::parse_from_str(date, "%d.%m.%Y").ok()?,
+            kwh: value.parse::<KilowattHours>().ok()? * multiplier,
+        }))
+    }
+}
+
+/// SMA Sunny Explorer / Sunny Portal daily export. Semicolon separated
+/// with the inverter's lifetime total yield in kilowatt / hours.
+///
+/// ```text
+/// sep=;
+/// Version CSV1|Tool SE|Linebreaks CR/LF|Delimiter semicolon
+/// dd/MM/yyyy;kWh
+/// 01/10/2019;11234.5
+/// ```
+pub struct Sma;
+
+impl GenerationImporter for Sma {
+    fn name(&self) -> &'static str {
+        "SMA"
+    }
+
+    fn recognises(&self, text: &str) -> bool {
+        first_line(text).starts_with("sep=;")
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<GenerationRecord>, ImportError> {
+        let header = text.lines()
+            .position(|line| line.starts_with("dd/MM/yyyy"))
+            .ok_or(ImportError { line: 1, message: "no dd/MM/yyyy header".to_string() })?;
+        parse_rows(text, ';', header + 1, |date, value| Some(GenerationRecord::Total {
+            date: NaiveDate::parse_from_str(date, "%d/%m/%Y").ok()?,
+            kwh: value.parse::<KilowattHours>().ok()?,
+        }))
+    }
+}
+
+/// Enphase Enlighten daily export with US dates and watt / hours.
+///
+/// ```text
+/// Date/Time,Energy Produced (Wh)
+/// 10/01/2019,23456
+/// ```
+pub struct Enphase;
+
+impl GenerationImporter for Enphase {
+    fn name(&self) -> &'static str {
+        "Enphase"
+    }
+
+    fn recognises(&self, text: &str) -> bool {
+        first_line(text).starts_with("Date/Time,Energy Produced")
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<GenerationRecord>, ImportError> {
+        parse_rows(text, ',', 1, |date, value| Some(GenerationRecord::Daily {
+            date: NaiveDate::parse_from_str(date, "%m/%d/%Y").ok()?,
+            kwh: value.parse::<KilowattHours>().ok()? / 1000,
+        }))
+    }
+}
+
+/// SolarEdge monitoring portal daily export with watt / hours.
+///
+/// ```text
+/// Time,Energy (Wh)
+/// 2019-10-01 00:00:00,23456.0
+/// ```
+pub struct SolarEdge;
+
+impl GenerationImporter for SolarEdge {
+    fn name(&self) -> &'static str {
+        "SolarEdge"
+    }
+
+    fn recognises(&self, text: &str) -> bool {
+        let header = first_line(text);
+        header.starts_with("Time,") && header.contains("Energy (Wh)")
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<GenerationRecord>, ImportError> {
+        parse_rows(text, ',', 1, |time, value| Some(GenerationRecord::Daily {
+            date: NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").ok()?.date(),
+            kwh: value.parse::<KilowattHours>().ok()? / 1000,
+        }))
+    }
+}
+
+/// Every importer we know about.
+pub fn importers() -> Vec<Box<dyn GenerationImporter>> {
+    vec![Box::new(Fronius), Box::new(Sma), Box::new(Enphase), Box::new(SolarEdge)]
+}
+
+/// Find the importer for the text's layout.
+pub fn detect_importer(text: &str) -> Option<Box<dyn GenerationImporter>> {
+    importers().into_iter().find(|importer| importer.recognises(text))
+}
+
+/// What merging imported generation with stored readings found.
+#[derive(Debug, PartialEq)]
+pub struct MergeReport {
+    /// Stored readings with generation replaced by the imported figure.
+    pub updated: Vec<Reading>,
+    /// Dates that appeared more than once in the import.
+    /// Only the first was used.
+    pub duplicates: Vec<NaiveDate>,
+    /// The first and last day of each run of missing days.
+    pub gaps: Vec<(NaiveDate, NaiveDate)>,
+    /// Dates with generation that couldn't be tied to a stored reading.
+    pub unanchored: Vec<NaiveDate>,
+}
+
+// The inverter's register for each date in a run of daily records
+// without gaps, counted from a stored reading within the run or
+// on the day before it starts.
+fn daily_registers(run: &[(NaiveDate, KilowattHours)],
+                   stored: &[Reading]) -> Option<Vec<(NaiveDate, KilowattHours)>> {
+    let start = run.first()?.0;
+    let end = run.last()?.0;
+    let anchor = stored.iter()
+        .find(|r| r.date >= start - Duration::days(1) && r.date <= end)?;
+
+    let before_anchor: KilowattHours = run.iter()
+        .filter(|(date, _)| *date <= anchor.date)
+        .map(|(_, kwh)| kwh)
+        .sum();
+
+    let mut so_far = KilowattHours::ZERO;
+    Some(run.iter()
+        .map(|(date, kwh)| {
+            so_far += *kwh;
+            (*date, anchor.generation + so_far - before_anchor)
+        })
+        .collect())
+}
+
+/// Turn imported records into inverter registers and compare them with
+/// the stored readings. Daily figures are added up from a stored reading,
+/// so each run of days between gaps needs a stored reading within it, or
+/// on the day before it, to start from.
+pub fn merge_generation(records: &[GenerationRecord], stored: &[Reading]) -> MergeReport {
+    let mut report = MergeReport {
+        updated: Vec::new(),
+        duplicates: Vec::new(),
+        gaps: Vec::new(),
+        unanchored: Vec::new(),
+    };
+
+    let mut unique: Vec<&GenerationRecord> = Vec::new();
+    for record in records.iter() {
+        if unique.iter().any(|seen| seen.date() == record.date()) {
+            report.duplicates.push(record.date());
+        } else {
+            unique.push(record);
+        }
+    }
+    unique.sort_by_key(|record| record.date());
+
+    for pair in unique.windows(2) {
+        let (before, after) = (pair[0].date(), pair[1].date());
+        if after > before + Duration::days(1) {
+            report.gaps.push((before + Duration::days(1), after - Duration::days(1)));
+        }
+    }
+
+    let mut registers: Vec<(NaiveDate, KilowattHours)> = Vec::new();
+    let mut runs: Vec<Vec<(NaiveDate, KilowattHours)>> = Vec::new();
+    for record in unique.iter() {
+        match record {
+            GenerationRecord::Total { date, kwh } => registers.push((*date, *kwh)),
+            GenerationRecord::Daily { date, kwh } => match runs.last_mut() {
+                Some(run) if run.last().unwrap().0 + Duration::days(1) == *date =>
+                    run.push((*date, *kwh)),
+                _ => runs.push(vec![(*date, *kwh)]),
+            },
+        }
+    }
+
+    for run in runs.iter() {
+        match daily_registers(run, stored) {
+            Some(found) => registers.extend(found),
+            None => report.unanchored.extend(run.iter().map(|(date, _)| *date)),
+        }
+    }
+    report.unanchored.sort();
+
+    for (date, generation) in registers.iter() {
+        if let Some(reading) = stored.iter().find(|r| r.date == *date) {
+            if (reading.generation - *generation).abs() > TOLERANCE_KWH {
+                let mut updated = reading.clone();
+                updated.generation = *generation;
+                report.updated.push(updated);
+            }
+        }
+    }
+    report.updated.sort_by_key(|reading| reading.date);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2019, 10, day)
+    }
+
+    fn reading(day: u32, generation: i64) -> Reading {
+        Reading::new(date(day), generation.into(), KilowattHours::ZERO, KilowattHours::ZERO)
+    }
+
+    #[test]
+    fn detect_and_parse_each_vendor() {
+        let samples = [
+            ("Fronius", "\"Date and time\",\"Energy | Symo 5.0-3-M (1)\"\n\
+                         \"[dd.MM.yyyy]\",\"[Wh]\"\n\"01.10.2019\",\"12500\"\n"),
+            ("SMA", "sep=;\nVersion CSV1|Tool SE\n\ndd/MM/yyyy;kWh\n01/10/2019;12.5\n"),
+            ("Enphase", "Date/Time,Energy Produced (Wh)\n10/01/2019,12500\n"),
+            ("SolarEdge", "Time,Energy (Wh)\n2019-10-01 00:00:00,12500.0\n"),
+        ];
+
+        for (name, text) in samples.iter() {
+            let importer = detect_importer(text).unwrap();
+            assert_eq!(importer.name(), *name);
+            let records = importer.parse(text).unwrap();
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].date(), date(1));
+        }
+        assert!(detect_importer("something else").is_none());
+    }
+
+    #[test]
+    fn parse_error_line() {
+        let text = "Date/Time,Energy Produced (Wh)\n10/01/2019,12500\n10/02/2019,lots\n";
+        assert_eq!(Enphase.parse(text).unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn merge_daily_records() {
+        let stored = vec![reading(1, 100), reading(3, 125), reading(7, 999)];
+        let records: Vec<GenerationRecord> = [2, 3, 3, 5, 6].iter()
+            .map(|day| GenerationRecord::Daily { date: date(*day), kwh: KilowattHours::from(10) })
+            .collect();
+
+        let report = merge_generation(&records, &stored);
+        // 100 + 10 + 10 on the 3rd, where 125 was typed in.
+        assert_eq!(report.updated, vec![reading(3, 120)]);
+        assert_eq!(report.duplicates, vec![date(3)]);
+        assert_eq!(report.gaps, vec![(date(4), date(4))]);
+        // Nothing stored on the 4th to 6th to count from.
+        assert_eq!(report.unanchored, vec![date(5), date(6)]);
+    }
+
+    #[test]
+    fn merge_total_records() {
+        let stored = vec![reading(1, 100), reading(2, 110)];
+        let records = vec![GenerationRecord::Total { date: date(1), kwh: KilowattHours::from(100) },
+                           GenerationRecord::Total { date: date(2), kwh: KilowattHours::from(112) }];
+        let report = merge_generation(&records, &stored);
+        assert_eq!(report.updated, vec![reading(2, 112)]);
+        assert!(report.gaps.is_empty());
+    }
+}
@@ -0,0 +1,481 @@
+use std::fmt;
+
+use crate::calc::Calculation;
+use crate::decimal::Decimal;
+use crate::readings::{ Reading, ReadingPair };
+use crate::units::{ Dollars, DollarsPerKwh, Fraction, KilowattHours, Kilowatts };
+
+/// A problem found while reading JSON text.
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    /// How far into the text the problem was, in bytes.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}: {}", self.position, self.message)
+    }
+}
+
+/// A JSON value, kept simple enough to build by hand.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// A number as it is written, so decimals keep every digit.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Members are kept in the order they were added.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Build an object from name and value pairs.
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect())
+    }
+
+    /// The value of an object's member, if this is an object and has it.
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The number exactly as written, if it has no exponent
+    /// and no more than six places.
+    pub fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+// The f32's own formatting keeps 0.1 as 0.1 rather than
+// 0.10000000149011612. JSON can't hold NaN or infinity.
+impl From<f32> for Json {
+    fn from(value: f32) -> Json {
+        if value.is_finite() {
+            Json::Number(value.to_string())
+        } else {
+            Json::Null
+        }
+    }
+}
+
+// Written exactly, with no trailing zeros.
+impl From<Decimal> for Json {
+    fn from(value: Decimal) -> Json {
+        Json::Number(value.to_string())
+    }
+}
+
+// Amounts are written as bare numbers; the key names the unit.
+macro_rules! from_quantity {
+    ($($name:ident),*) => {
+        $(impl From<$name> for Json {
+            fn from(value: $name) -> Json {
+                value.value().into()
+            }
+        })*
+    };
+}
+
+from_quantity!(KilowattHours, Dollars, DollarsPerKwh, Kilowatts);
+
+impl From<Fraction> for Json {
+    fn from(value: Fraction) -> Json {
+        value.0.into()
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Json {
+        Json::Number(value.to_string())
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+}
+
+// Quote a string, escaping what JSON requires.
+fn write_string(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Allow a Json object to be passed to println!() etc.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(text) => write!(f, "{}", text),
+            Json::String(text) => write_string(f, text),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+// How deep arrays and objects may go inside each other. Each
+// level is read by a nested call, so this keeps the stack safe.
+const MAX_DEPTH: usize = 32;
+
+// Reads JSON text one value at a time.
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+    // How many arrays and objects the value is inside.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, JsonError> {
+        Err(JsonError { position: self.position, message: message.to_string() })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !matches!(c, ' ' | '\t' | '\n' | '\r') {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(found) if found == c => Ok(()),
+            _ => self.error(&format!("expected '{}'", c)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.nested(Parser::object),
+            Some('[') => self.nested(Parser::array),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('n') => self.literal("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => self.error("unexpected character"),
+            None => self.error("unexpected end of text"),
+        }
+    }
+
+    fn nested(&mut self, read: fn(&mut Self) -> Result<Json, JsonError>) -> Result<Json, JsonError> {
+        if self.depth == MAX_DEPTH {
+            return self.error("nested too deeply");
+        }
+        self.depth += 1;
+        let value = read(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if self.text[self.position..].starts_with(word) {
+            self.position += word.len();
+            Ok(value)
+        } else {
+            self.error("unknown word")
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                break;
+            }
+            self.next();
+        }
+        let text = &self.text[start..self.position];
+        match text.parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(Json::Number(text.to_string())),
+            _ => {
+                self.position = start;
+                self.error("bad number")
+            },
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.text.get(self.position..self.position + 4);
+        match digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()) {
+            Some(code) => {
+                self.position += 4;
+                Ok(code)
+            },
+            None => self.error("bad unicode escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair spells out characters beyond the first plane.
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text[self.position..].starts_with("\\u") {
+                                self.position += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return self.error("bad surrogate pair");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            match char::from_u32(code) {
+                                Some(c) => c,
+                                None => return self.error("bad unicode escape"),
+                            }
+                        },
+                        _ => return self.error("bad escape"),
+                    };
+                    text.push(c);
+                },
+                Some(c) if (c as u32) < 0x20 => return self.error("control character in string"),
+                Some(c) => text.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.next();
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(values)),
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        self.expect('{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let name = self.string()?;
+            self.expect(':')?;
+            members.push((name, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(members)),
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+    }
+}
+
+/// Read a JSON document.
+pub fn parse_json(text: &str) -> Result<Json, JsonError> {
+    let mut parser = Parser { text, position: 0, depth: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position < text.len() {
+        return parser.error("unexpected text after the value");
+    }
+    Ok(value)
+}
+
+/// A reading as a JSON object.
+pub fn reading_json(reading: &Reading) -> Json {
+    Json::object(vec![
+        ("date", Json::String(reading.date.format("%Y-%m-%d").to_string())),
+        ("generation", reading.generation.into()),
+        ("exports", reading.exports.into()),
+        ("imports", reading.imports.into()),
+        ("source", Json::String(reading.source.to_string())),
+        ("note", Json::String(reading.note.clone())),
+    ])
+}
+
+/// The daily averages between two readings as a JSON object.
+pub fn calculation_json(pair: &ReadingPair, calculation: &Calculation) -> Json {
+    Json::object(vec![
+        ("start", Json::String(pair.first.date.format("%Y-%m-%d").to_string())),
+        ("end", Json::String(pair.second.date.format("%Y-%m-%d").to_string())),
+        ("days", pair.days_spanned().into()),
+        ("generation_kwh", calculation.generation_kwh.into()),
+        ("grid_import_kwh", calculation.grid_import_kwh.into()),
+        ("grid_export_kwh", calculation.grid_export_kwh.into()),
+        ("total_consumption_kwh", calculation.total_consumption_kwh.into()),
+        ("self_consumption_kwh", calculation.self_consumption.kwh.into()),
+        ("self_consumption_fraction_of_total_use",
+         calculation.self_consumption.fraction_of_total_use.into()),
+        ("self_consumption_fraction_of_generation",
+         calculation.self_consumption.fraction_of_generation.into()),
+        ("savings_from_self_consumption", calculation.savings.from_self_consumption.into()),
+        ("savings_from_exports", calculation.savings.from_exports.into()),
+        ("savings_total", calculation.savings.total.into()),
+        ("estimated", Json::Bool(pair.depends_on_estimates())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_json() {
+        let json = Json::object(vec![
+            ("name", "say \"hi\"\n".into()),
+            ("kwh", 0.1f32.into()),
+            ("count", 3i64.into()),
+            ("bad", f32::NAN.into()),
+            ("list", Json::Array(vec![Json::Bool(true), Json::Null])),
+            ("empty", Json::Object(vec![])),
+        ]);
+        assert_eq!(json.to_string(),
+                   "{\"name\":\"say \\\"hi\\\"\\n\",\"kwh\":0.1,\"count\":3,\
+                    \"bad\":null,\"list\":[true,null],\"empty\":{}}");
+    }
+
+    #[test]
+    fn parse_json_ok() {
+        let json = parse_json(" {\"a\": [1, -2.5e1, true, null], \"b\" : \"x\\u00e9\\ud83d\\ude00\\n\"} ")
+            .unwrap();
+        assert_eq!(json.get("a"), Some(&Json::Array(vec![Json::Number("1".to_string()),
+                                                          Json::Number("-2.5e1".to_string()),
+                                                          Json::Bool(true), Json::Null])));
+        assert_eq!(json.get("b").and_then(|b| b.as_str()), Some("x\u{e9}\u{1f600}\n"));
+        assert_eq!(json.get("c"), None);
+        assert_eq!(parse_json("[]"), Ok(Json::Array(vec![])));
+
+        let json = Json::object(vec![("a", "b\"\t".into()), ("c", 2.5f32.into())]);
+        assert_eq!(parse_json(&json.to_string()), Ok(json));
+    }
+
+    #[test]
+    fn numbers_keep_their_digits() {
+        let json = parse_json("[1234567890123.456789, -2.5e1, 0.1]").unwrap();
+        let decimal = |index: usize| match &json {
+            Json::Array(values) => values[index].as_decimal(),
+            _ => None,
+        };
+        assert_eq!(decimal(0), Some(Decimal::from_raw(1_234_567_890_123_456_789)));
+        assert_eq!(decimal(1), None);
+        assert_eq!(decimal(2), Some(Decimal::new(1, 1)));
+        assert_eq!(Json::from(Decimal::new(-25752, 5)).to_string(), "-0.25752");
+    }
+
+    #[test]
+    fn parse_json_errors() {
+        for text in &["", "{", "[1,]", "{\"a\" 1}", "tru", "1 2", "\"\\x\"", "-", "{1: 2}"] {
+            assert!(parse_json(text).is_err(), "{} should not parse", text);
+        }
+        assert_eq!(parse_json("[1, x]").unwrap_err().position, 4);
+    }
+
+    #[test]
+    fn parse_json_nested_too_deeply() {
+        let deep = "[".repeat(MAX_DEPTH) + &"]".repeat(MAX_DEPTH);
+        assert!(parse_json(&deep).is_ok());
+
+        let deeper = "[".repeat(MAX_DEPTH + 1) + &"]".repeat(MAX_DEPTH + 1);
+        assert_eq!(parse_json(&deeper).unwrap_err(),
+                   JsonError { position: MAX_DEPTH, message: "nested too deeply".to_string() });
+        let error = parse_json(&"[{\"a\":".repeat(60_000)).unwrap_err();
+        assert_eq!(error.message, "nested too deeply");
+    }
+}
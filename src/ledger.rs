@@ -0,0 +1,303 @@
+use crate::calc::{ calculate, Calculation };
+use crate::config::{ parse_sections, ConfigError };
+use crate::decimal::Decimal;
+use crate::units::{ Dollars, DollarsPerKwh, KilowattHours };
+use crate::plans::{ section_to_plan, Plan };
+use crate::readings::DatedChange;
+
+// Amounts smaller than these aren't worth a posting.
+const SMALLEST_KWH: KilowattHours = KilowattHours::new(Decimal::new(5, 4));
+const SMALLEST_DOLLARS: Dollars = Dollars::new(Decimal::new(5, 3));
+
+/// The plain text accounting tool the journal is written for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalFormat {
+    Ledger,
+    Hledger,
+    Beancount,
+}
+
+/// Where each part of the bill and the savings are posted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accounts {
+    /// What is owed to the retailer.
+    pub bill: String,
+    /// The cost of energy imported from the grid.
+    pub imports: String,
+    /// Daily supply charges.
+    pub supply: String,
+    /// Credits for energy exported to the grid.
+    pub feed_in: String,
+    /// Solar energy used at home, valued at the import rate.
+    pub self_consumed: String,
+    /// The import cost avoided by using solar energy.
+    pub avoided: String,
+}
+
+impl Default for Accounts {
+    fn default() -> Accounts {
+        Accounts {
+            bill: "Liabilities:Electricity".to_string(),
+            imports: "Expenses:Electricity:Imports".to_string(),
+            supply: "Expenses:Electricity:Supply".to_string(),
+            feed_in: "Income:Electricity:FeedIn".to_string(),
+            self_consumed: "Expenses:Electricity:Solar".to_string(),
+            avoided: "Income:Solar:AvoidedImports".to_string(),
+        }
+    }
+}
+
+/// How to write the journal, from the [ledger] section of a config file.
+pub struct JournalConfig {
+    pub format: JournalFormat,
+    /// A symbol such as "$" goes before amounts, a code such as "AUD" after.
+    pub currency: String,
+    /// The plan used to estimate the bill.
+    pub plan: Plan,
+    pub accounts: Accounts,
+}
+
+/// Read the [ledger] section of a config file. The plan is given
+/// with the same keys as a plans file.
+///
+/// ```text
+/// [ledger]
+/// format = hledger          # or ledger, or beancount
+/// currency = $              # beancount needs a code such as AUD
+/// supply = 1.10
+/// import = 0.25752
+/// feed_in = 0.07135
+/// account.bill = Liabilities:Electricity:Retailer
+/// account.avoided = Income:Solar:Savings
+/// ```
+///
+/// The other accounts are `account.imports`, `account.supply`,
+/// `account.feed_in` and `account.self_consumed`.
+pub fn parse_journal_config(text: &str) -> Result<JournalConfig, ConfigError> {
+    let sections = parse_sections(text)?;
+    let section = match sections.iter().find(|section| section.name == "ledger") {
+        Some(section) => section,
+        None => return Err(ConfigError {
+            line: 1,
+            message: "no [ledger] section".to_string(),
+        }),
+    };
+
+    let format = match section.get("format").unwrap_or("ledger") {
+        "ledger" => JournalFormat::Ledger,
+        "hledger" => JournalFormat::Hledger,
+        "beancount" => JournalFormat::Beancount,
+        other => return Err(ConfigError {
+            line: section.line,
+            message: format!("unknown format {}", other),
+        }),
+    };
+    let default_currency = if format == JournalFormat::Beancount { "AUD" } else { "$" };
+    let currency = section.get("currency").unwrap_or(default_currency).to_string();
+    if format == JournalFormat::Beancount && !currency.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(ConfigError {
+            line: section.line,
+            message: "beancount needs a currency code such as AUD".to_string(),
+        });
+    }
+
+    let mut accounts = Accounts::default();
+    let account = |key: &str, account: &mut String| {
+        if let Some(name) = section.get(&format!("account.{}", key)) {
+            *account = name.to_string();
+        }
+    };
+    account("bill", &mut accounts.bill);
+    account("imports", &mut accounts.imports);
+    account("supply", &mut accounts.supply);
+    account("feed_in", &mut accounts.feed_in);
+    account("self_consumed", &mut accounts.self_consumed);
+    account("avoided", &mut accounts.avoided);
+
+    Ok(JournalConfig {
+        format,
+        currency,
+        plan: section_to_plan(section)?,
+        accounts,
+    })
+}
+
+impl JournalConfig {
+    // An amount of money, or a price, in the currency.
+    fn dollars<T: Into<Decimal>>(&self, amount: T, decimals: usize) -> String {
+        let amount = amount.into();
+        let sign = if amount.is_negative() { "-" } else { "" };
+        if self.currency.chars().all(|c| c.is_alphabetic()) {
+            format!("{}{:.*} {}", sign, decimals, amount.abs(), self.currency)
+        } else {
+            format!("{}{}{:.*}", sign, self.currency, decimals, amount.abs())
+        }
+    }
+
+    // Energy at a price, so it balances in money.
+    fn energy(&self, kwh: KilowattHours, dollars: Dollars) -> String {
+        let unit = if self.format == JournalFormat::Beancount { "KWH" } else { "kWh" };
+        format!("{:.3} {} @ {}", kwh, unit, self.dollars(dollars / kwh.abs(), 5))
+    }
+
+    // The first line of a transaction.
+    fn header(&self, change: &DatedChange, payee: &str) -> String {
+        let period = format!("{} to {}", change.start.format("%d/%m/%Y"),
+                             change.end.format("%d/%m/%Y"));
+        let date = change.end.format("%Y-%m-%d");
+        match self.format {
+            JournalFormat::Beancount => format!("{} * \"{}\" \"{}\"\n", date, payee, period),
+            _ => format!("{} * {} {}\n", date, payee, period),
+        }
+    }
+}
+
+// A posting line, or the balancing posting if there's no amount.
+fn posting(account: &str, amount: Option<String>) -> String {
+    match amount {
+        Some(amount) => format!("    {:<40}  {}\n", account, amount),
+        None => format!("    {}\n", account),
+    }
+}
+
+// The import cost avoided by self consumption, using the
+// average time of use rate if the plan has one.
+fn avoided_cost(plan: &Plan, calculation: &Calculation) -> Dollars {
+    if plan.time_of_use.is_empty() {
+        calculation.savings.from_self_consumption
+    } else {
+        let rate: DollarsPerKwh = plan.time_of_use.iter()
+            .map(|period| period.rate * period.share)
+            .sum();
+        calculation.self_consumption.kwh * rate
+    }
+}
+
+/// Write a transaction for the estimated bill and one for the
+/// solar savings of each period between readings.
+pub fn journal(changes: &[DatedChange], config: &JournalConfig) -> String {
+    let mut output = String::new();
+    let accounts = &config.accounts;
+
+    if config.format == JournalFormat::Beancount {
+        if let Some(first) = changes.first() {
+            let date = first.start.format("%Y-%m-%d");
+            output.push_str(&format!("{} commodity KWH\n", date));
+            for account in [&accounts.bill, &accounts.imports, &accounts.supply,
+                            &accounts.feed_in, &accounts.self_consumed, &accounts.avoided].iter() {
+                output.push_str(&format!("{} open {}\n", date, account));
+            }
+            output.push('\n');
+        }
+    }
+
+    for change in changes.iter() {
+        let days = change.days_spanned();
+        let cost = config.plan.daily_cost(&change.change);
+        let imports = change.change.imports * days;
+        let exports = change.change.exports * days;
+
+        let mut postings = String::new();
+        if imports >= SMALLEST_KWH {
+            postings.push_str(&posting(&accounts.imports,
+                                       Some(config.energy(imports, cost.imports * days))));
+        }
+        if cost.supply * days >= SMALLEST_DOLLARS {
+            postings.push_str(&posting(&accounts.supply,
+                                       Some(config.dollars(cost.supply * days, 2))));
+        }
+        if exports >= SMALLEST_KWH {
+            postings.push_str(&posting(&accounts.feed_in,
+                                       Some(config.energy(-exports, cost.feed_in * days))));
+        }
+        if !postings.is_empty() {
+            output.push_str(&config.header(change, "Electricity"));
+            output.push_str(&postings);
+            output.push_str(&posting(&accounts.bill, None));
+            output.push('\n');
+        }
+
+        let calculation = calculate(change.change.clone(), config.plan.tariffs.clone());
+        let self_consumed = calculation.self_consumption.kwh * days;
+        if self_consumed >= SMALLEST_KWH {
+            output.push_str(&config.header(change, "Solar savings"));
+            output.push_str(&posting(&accounts.self_consumed,
+                                     Some(config.energy(self_consumed,
+                                                        avoided_cost(&config.plan, &calculation) * days))));
+            output.push_str(&posting(&accounts.avoided, None));
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use crate::readings::{ find_changes, Reading };
+
+    fn reading(day: u32, generation: i64, exports: i64, imports: i64) -> Reading {
+        Reading::new(NaiveDate::from_ymd(2020, 1, day), generation.into(),
+                     exports.into(), imports.into())
+    }
+
+    fn changes() -> Vec<DatedChange> {
+        // Over two days 20 kWh generated, 12 exported and 8 imported.
+        let readings = vec![reading(1, 100, 50, 200), reading(3, 120, 62, 208)];
+        find_changes(&readings)
+    }
+
+    const CONFIG: &str = "[ledger]\n\
+                          format = hledger\n\
+                          supply = 1.0\n\
+                          import = 0.25\n\
+                          feed_in = 0.05\n\
+                          account.bill = Liabilities:Retailer\n";
+
+    #[test]
+    fn parse_journal_config_ok() {
+        let config = parse_journal_config(CONFIG).unwrap();
+        assert_eq!(config.format, JournalFormat::Hledger);
+        assert_eq!(config.currency, "$");
+        assert_eq!(config.plan.supply_charge, Dollars::from(1));
+        assert_eq!(config.accounts.bill, "Liabilities:Retailer");
+        assert_eq!(config.accounts.supply, "Expenses:Electricity:Supply");
+
+        assert!(parse_journal_config("[ledger]\nformat = gnucash\nimport = 1\n").is_err());
+        assert!(parse_journal_config("[ledger]\nformat = beancount\ncurrency = $\nimport = 1\n")
+                .is_err());
+        assert!(parse_journal_config("[ledger]\nsupply = 1\n").is_err());
+    }
+
+    #[test]
+    fn hledger_journal() {
+        let config = parse_journal_config(CONFIG).unwrap();
+        assert_eq!(journal(&changes(), &config),
+                   "2020-01-03 * Electricity 01/01/2020 to 03/01/2020\n    \
+                    Expenses:Electricity:Imports              8.000 kWh @ $0.25000\n    \
+                    Expenses:Electricity:Supply               $2.00\n    \
+                    Income:Electricity:FeedIn                 -12.000 kWh @ $0.05000\n    \
+                    Liabilities:Retailer\n\
+                    \n\
+                    2020-01-03 * Solar savings 01/01/2020 to 03/01/2020\n    \
+                    Expenses:Electricity:Solar                8.000 kWh @ $0.25000\n    \
+                    Income:Solar:AvoidedImports\n\
+                    \n");
+    }
+
+    #[test]
+    fn beancount_journal() {
+        let mut config = parse_journal_config(CONFIG).unwrap();
+        config.format = JournalFormat::Beancount;
+        config.currency = "AUD".to_string();
+
+        let text = journal(&changes(), &config);
+        assert!(text.starts_with("2020-01-01 commodity KWH\n\
+                                  2020-01-01 open Liabilities:Retailer\n"));
+        assert!(text.contains("2020-01-03 * \"Electricity\" \"01/01/2020 to 03/01/2020\"\n"));
+        assert!(text.contains("  -12.000 KWH @ 0.05000 AUD\n"));
+        assert!(text.contains("  2.00 AUD\n"));
+    }
+}
@@ -1,5 +1,8 @@
 extern crate chrono;
+#[cfg(feature = "sqlite")]
 extern crate sqlite;
+#[cfg(feature = "sqlite")]
+extern crate sqlite3_sys;
 
 /// Calculate statistics.
 pub mod calc;
@@ -11,4 +14,78 @@ pub mod readings;
 pub mod console_input;
 
 /// Access readings stored in a database. 
+#[cfg(feature = "sqlite")]
 pub mod database;
+
+/// Keep readings in a database, a file or memory.
+pub mod store;
+
+/// Keep track of every change to readings and tariffs.
+pub mod audit;
+
+/// Look for faults in the readings.
+pub mod anomaly;
+
+/// Compare two periods side by side.
+pub mod comparison;
+
+/// Read simple configuration files.
+pub mod config;
+
+/// Compare retailer plans against past usage.
+pub mod plans;
+
+/// Tariffs that change with the seasons.
+pub mod seasons;
+
+/// Energy measured over short intervals.
+pub mod intervals;
+
+/// Read NEM12 interval meter data files.
+pub mod nem12;
+
+/// Read Green Button (ESPI) usage data.
+pub mod green_button;
+
+/// Read generation from inverter portal CSV exports.
+pub mod inverter_csv;
+
+/// Talk to devices over Modbus TCP.
+pub mod modbus;
+
+/// Read inverters and meters through their SunSpec models.
+pub mod sunspec;
+
+/// Write values as JSON.
+pub mod json;
+
+/// Publish readings and calculations to an MQTT broker.
+pub mod mqtt;
+
+/// A small HTTP server for local use.
+pub mod http;
+
+/// Metrics for Prometheus to scrape.
+pub mod metrics;
+
+/// A JSON API over the readings.
+#[cfg(feature = "sqlite")]
+pub mod api;
+
+/// Draw charts of the readings.
+pub mod charts;
+
+/// Write reports to share.
+pub mod report;
+
+/// Write savings and estimated bills as plain text accounting journals.
+pub mod ledger;
+
+/// Write workbooks for spreadsheets.
+pub mod workbook;
+
+/// Exact decimal numbers for energy and money.
+pub mod decimal;
+
+/// Amounts of energy, power and money that keep their units apart.
+pub mod units;
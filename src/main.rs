@@ -1,49 +1,894 @@
-use nrgaccounts::calc::{ calculate, Tariffs };
-use nrgaccounts::readings::{ find_change, ReadingPair };
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::thread;
+
+use chrono::{ Duration, Local, NaiveDate };
+
+use nrgaccounts::anomaly::detect_anomalies;
+use nrgaccounts::api::Api;
+use nrgaccounts::calc::{ calculate, default_tariffs, Tariffs };
+use nrgaccounts::readings::{ find_change, find_changes, pair_for_period, ReadingPair, Source,
+                              MAX_DAYS_FROM_PERIOD };
+use nrgaccounts::seasons::{ calculate_seasonal, parse_seasons, SeasonalTariffs };
 use nrgaccounts::console_input::{ get_reading };
-use nrgaccounts::database::Database;
+use nrgaccounts::database::{ backup_name, expired_backups, Database };
+use nrgaccounts::audit::Change;
+use nrgaccounts::store::{ FileStore, ReadingStore };
+use nrgaccounts::green_button::parse_green_button;
+use nrgaccounts::intervals::{ daily_totals, derive_readings, import_shares, load_profile,
+                              parse_tou_windows, peak_demand_by_month, Interval };
+use nrgaccounts::inverter_csv::{ detect_importer, importers, merge_generation };
+use nrgaccounts::http::{ serve, Response };
+use nrgaccounts::metrics::render_metrics;
+use nrgaccounts::mqtt::{ parse_mqtt_config, ReadingPublisher };
+use nrgaccounts::ledger::{ journal, parse_journal_config };
+use nrgaccounts::nem12::parse_nem12;
+use nrgaccounts::charts::{ bar_chart, sparkline, svg_line_chart, usage_series };
+use nrgaccounts::report::{ html_report, monthly_summaries };
+use nrgaccounts::plans::{ parse_plans, rank_plans };
+use nrgaccounts::sunspec::poll;
+use nrgaccounts::workbook::{ energy_workbook, write_xlsx };
+
+// Where to find the broker to publish readings to.
+const MQTT_CONFIG: &str = "mqtt.conf";
+
+// Where Prometheus scrapes metrics from unless told otherwise.
+const METRICS_ADDRESS: &str = "127.0.0.1:9184";
+
+// Where the API listens unless told otherwise.
+const API_ADDRESS: &str = "127.0.0.1:8080";
+
+// If set, API requests must carry this token.
+const TOKEN_VARIABLE: &str = "NRGACCOUNTS_TOKEN";
+
+// Where backups go unless told otherwise, and how many are kept.
+const BACKUP_DIRECTORY: &str = "backups";
+const BACKUPS_KEPT: usize = 14;
+
+// If set, readings are kept in this CSV file instead of the database.
+const READINGS_VARIABLE: &str = "NRGACCOUNTS_READINGS";
 
 fn main() {
-    let tariffs = Tariffs {
-        import: 0.25752,
-        export: 0.07135,
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        None => add_new_reading_to_db(current_tariffs()),
+        Some("seasons") if args.len() == 3 || args.len() == 5 =>
+            seasonal_report(&args[2..], current_tariffs()),
+        Some("seasons") => {
+            eprintln!("Usage: nrgaccounts seasons SEASONS_FILE [START END]");
+            process::exit(2);
+        },
+        Some("check") => check_readings(),
+        Some("backup") if args.len() <= 4 =>
+            backup(args.get(2).map_or(BACKUP_DIRECTORY, |arg| arg.as_str()), args.get(3)),
+        Some("backup") => {
+            eprintln!("Usage: nrgaccounts backup [DIRECTORY [KEEP]]");
+            process::exit(2);
+        },
+        Some("restore") if args.len() == 3 => restore(&args[2]),
+        Some("restore") => {
+            eprintln!("Usage: nrgaccounts restore BACKUP_FILE");
+            process::exit(2);
+        },
+        Some("history") if args.len() == 3 => history(&args[2]),
+        Some("history") => {
+            eprintln!("Usage: nrgaccounts history DATE|tariffs");
+            process::exit(2);
+        },
+        Some("revert") if args.len() >= 3 => revert(&args[2]),
+        Some("revert") => {
+            eprintln!("Usage: nrgaccounts revert CHANGE [REASON ...]");
+            eprintln!("Only the latest change to a reading or the tariffs can be undone;");
+            eprintln!("undo any later changes to it first, newest first.");
+            process::exit(2);
+        },
+        Some("plans") => match args.get(2) {
+            Some(file) => compare_plans(file),
+            None => {
+                eprintln!("Usage: nrgaccounts plans PLANS_FILE");
+                process::exit(2);
+            },
+        },
+        Some("import-nem12") => match args.get(2) {
+            Some(file) => import_nem12(file),
+            None => {
+                eprintln!("Usage: nrgaccounts import-nem12 NEM12_FILE");
+                process::exit(2);
+            },
+        },
+        Some("import-green-button") => match args.get(2) {
+            Some(file) => import_green_button(file),
+            None => {
+                eprintln!("Usage: nrgaccounts import-green-button XML_FILE");
+                process::exit(2);
+            },
+        },
+        Some("import-generation") => match args.get(2) {
+            Some(file) => import_generation(file),
+            None => {
+                eprintln!("Usage: nrgaccounts import-generation CSV_FILE");
+                process::exit(2);
+            },
+        },
+        Some("poll-inverter") if args.len() >= 3 => poll_inverter(&args[2..]),
+        Some("poll-inverter") => {
+            eprintln!("Usage: nrgaccounts poll-inverter HOST[:PORT] [MINUTES [UNIT_ID ...]]");
+            process::exit(2);
+        },
+        Some("serve-metrics") if args.len() <= 3 =>
+            serve_metrics(args.get(2).map_or(METRICS_ADDRESS, |arg| arg.as_str())),
+        Some("serve-metrics") => {
+            eprintln!("Usage: nrgaccounts serve-metrics [ADDRESS:PORT]");
+            process::exit(2);
+        },
+        Some("serve-api") if args.len() <= 3 =>
+            serve_api(args.get(2).map_or(API_ADDRESS, |arg| arg.as_str())),
+        Some("serve-api") => {
+            eprintln!("Usage: nrgaccounts serve-api [ADDRESS:PORT]");
+            process::exit(2);
+        },
+        Some("report") if args.get(2).map(|arg| arg.as_str()) == Some("html") && args.len() <= 4 =>
+            write_html_report(args.get(3).map_or("report.html", |arg| arg.as_str()), current_tariffs()),
+        Some("report") if args.get(2).map(|arg| arg.as_str()) == Some("xlsx") && args.len() <= 4 =>
+            write_workbook(args.get(3).map_or("energy.xlsx", |arg| arg.as_str()), current_tariffs()),
+        Some("report") => {
+            eprintln!("Usage: nrgaccounts report html|xlsx [FILE]");
+            process::exit(2);
+        },
+        Some("chart") if args.len() >= 4 => chart(&args[2..], current_tariffs()),
+        Some("chart") => {
+            eprintln!("Usage: nrgaccounts chart START END [--ascii] [--svg FILE]");
+            process::exit(2);
+        },
+        Some("export-ledger") if args.len() == 3 || args.len() == 5 => export_ledger(&args[2..]),
+        Some("export-ledger") => {
+            eprintln!("Usage: nrgaccounts export-ledger LEDGER_FILE [START END]");
+            process::exit(2);
+        },
+        Some("intervals") if args.len() == 4 || args.len() == 5 =>
+            interval_report(&args[2..]),
+        Some("intervals") => {
+            eprintln!("Usage: nrgaccounts intervals START END [TOU_FILE]");
+            process::exit(2);
+        },
+        Some(command) => {
+            eprintln!("Unknown command: {}", command);
+            process::exit(2);
+        },
+    }
+}
+
+// The tariffs stored in the database, or the defaults if none have been.
+fn current_tariffs() -> Tariffs {
+    open_energy_db().current_tariffs()
+}
+
+// Open the database, logging any changes as made by the
+// user running the command, with the command as the reason.
+fn open_energy_db() -> Database {
+    let mut db = Database::open("energy.db");
+    let user = env::var("USER").or_else(|_e| env::var("USERNAME"))
+        .unwrap_or_else(|_e| "unknown".to_string());
+    let command: Vec<String> = env::args().skip(1).collect();
+    let reason = if command.is_empty() { "console".to_string() } else { command.join(" ") };
+    db.set_audit_details(&user, &reason);
+    db
+}
+
+// Open the database for adding readings. If there is an MQTT config
+// file, each new reading and calculation is published to the broker.
+fn open_database() -> Database {
+    let mut db = open_energy_db();
+
+    if !Path::new(MQTT_CONFIG).exists() {
+        return db;
+    }
+    let config = match parse_mqtt_config(&read_file(MQTT_CONFIG)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", MQTT_CONFIG, e);
+            process::exit(1);
+        },
     };
 
+    // One connection to the broker for the whole command.
+    let publisher = RefCell::new(ReadingPublisher::new(config, db.current_tariffs()));
+    db.on_reading_added(Box::new(move |previous, reading| {
+        let mut publisher = publisher.borrow_mut();
+        if let Err(e) = publisher.publish(previous, reading) {
+            eprintln!("Could not publish to {}: {}", publisher.broker(), e);
+        }
+    }));
+    db
+}
+
+// Open wherever the readings are kept.
+fn open_readings() -> Box<dyn ReadingStore> {
+    match env::var(READINGS_VARIABLE) {
+        Ok(file) => match FileStore::open(&file) {
+            Ok(store) => Box::new(store),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        },
+        Err(_e) => Box::new(open_energy_db()),
+    }
+}
+
+// Open wherever the readings are kept for adding readings. Only
+// readings added to the database can be published over MQTT.
+fn open_readings_for_adding() -> Box<dyn ReadingStore> {
+    if env::var(READINGS_VARIABLE).is_err() {
+        return Box::new(open_database());
+    }
+    if Path::new(MQTT_CONFIG).exists() {
+        eprintln!("{}: readings kept in a file can't be published.", MQTT_CONFIG);
+        process::exit(1);
+    }
+    open_readings()
+}
 
-    add_new_reading_to_db(tariffs);
+// Read a whole file or exit with an error.
+fn read_file(file : &str) -> String {
+    match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", file, e);
+            process::exit(1);
+        },
+    }
 }
 
+// Read a YYYY-MM-DD date from the command line or exit with an error.
+fn parse_date_arg(arg : &str) -> NaiveDate {
+    match NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(_e) => {
+            eprintln!("Invalid date: {}", arg);
+            process::exit(2);
+        },
+    }
+}
 
 fn add_new_reading_to_db(tariffs : Tariffs) {
-    let db = Database::open("energy.db");
+    let mut db = open_readings_for_adding();
    
     let number_of_readings = db.number_of_readings();
     
     if number_of_readings == 0 {
         println!("No readings entered yet. Add your first!");
-        let reading = get_reading();
-        db.add_reading(&reading);
+        let reading = get_reading(None);
+        if let Err(e) = db.add_reading(&reading) {
+            eprintln!("Could not add the reading: {}", e);
+            process::exit(1);
+        }
     } else {
         let first = db.most_recent_reading().unwrap();
 
         println!("{} readings recorded", number_of_readings);
         println!("Add a new reading: ");
-        let second = get_reading();
-        db.add_reading(&second);
+        let second = get_reading(Some(first.date));
+        if let Err(e) = db.add_reading(&second) {
+            eprintln!("Could not add the reading: {}", e);
+            process::exit(1);
+        }
 
         let pair = ReadingPair {
             first,
             second,
         };
-        let changes = find_change(&pair);
+        let changes = match find_change(&pair) {
+            Some(changes) => changes,
+            None => {
+                eprintln!("The new reading must be after the last one.");
+                process::exit(1);
+            },
+        };
         let calculation = calculate(changes, tariffs);
 
         let start_date = pair.first.date.format("%d/%m/%Y");
         let end_date = pair.second.date.format("%d/%m/%Y");
 
-        println!("");
+        println!();
         println!("Changes from {} to {}", start_date, end_date);
         println!("{}", calculation);
+        if pair.depends_on_estimates() {
+            println!("Worked out from an estimated reading.");
+        }
+    }
+}
+
+// Report anything suspicious in the stored readings, 
+// exiting with an error code if there are problems.
+// Readings in the database are first checked for
+// anything wrong with how they are stored.
+fn check_readings() {
+    let mut storage_problems = Vec::new();
+    if env::var(READINGS_VARIABLE).is_err() {
+        storage_problems = open_energy_db().check_storage();
+        if !storage_problems.is_empty() {
+            println!("Found {} problems in energy.db:", storage_problems.len());
+            for problem in storage_problems.iter() {
+                println!("    {}", problem);
+            }
+        }
+        if storage_problems.iter().any(|problem| problem.stops_reading()) {
+            process::exit(1);
+        }
+    }
+
+    let db = open_readings();
+    let changes = find_changes(&db.all_readings());
+    let anomalies = detect_anomalies(&changes);
+
+    if anomalies.is_empty() {
+        println!("No problems found in {} periods.", changes.len());
+    } else {
+        println!("Found {} problems:", anomalies.len());
+        for anomaly in anomalies.iter() {
+            println!("    {}", anomaly);
+        }
+    }
+    if !anomalies.is_empty() || !storage_problems.is_empty() {
+        process::exit(1);
+    }
+}
+
+// Copy the database into a directory of backups, removing
+// the oldest so only so many are kept.
+fn backup(directory : &str, keep : Option<&String>) {
+    let keep = keep.map_or(BACKUPS_KEPT, |arg| match arg.parse::<usize>() {
+        Ok(keep) if keep > 0 => keep,
+        _ => {
+            eprintln!("Invalid number of backups to keep: {}", arg);
+            process::exit(2);
+        },
+    });
+    let file = backup_database(directory);
+    println!("Backed up to {}.", file);
+
+    let names: Vec<String> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(e) => {
+            eprintln!("Could not read {}: {}", directory, e);
+            process::exit(1);
+        },
+    };
+    for name in expired_backups(&names, keep).iter() {
+        let old = Path::new(directory).join(name);
+        match fs::remove_file(&old) {
+            Ok(()) => println!("Removed {}.", old.display()),
+            Err(e) => eprintln!("Could not remove {}: {}", old.display(), e),
+        }
+    }
+}
+
+// Make a backup named for the time, giving its path, or exit with an error.
+fn backup_database(directory : &str) -> String {
+    let file = Path::new(directory).join(backup_name(Local::now().naive_local()));
+    let result = fs::create_dir_all(directory)
+        .map_err(|e| e.to_string())
+        .and_then(|_| open_energy_db().backup_to(&file));
+    if let Err(e) = result {
+        eprintln!("Could not back up to {}: {}", file.display(), e);
+        process::exit(1);
+    }
+    file.display().to_string()
+}
+
+// Replace the database with a backup, first backing up
+// what's there so the restore can be undone.
+fn restore(file : &str) {
+    if !Path::new(file).exists() {
+        eprintln!("No such backup: {}", file);
+        process::exit(1);
+    }
+    println!("Kept the readings being replaced in {}.", backup_database(BACKUP_DIRECTORY));
+
+    let db = open_energy_db();
+    if let Err(e) = db.restore_from(Path::new(file)) {
+        eprintln!("Could not restore {}: {}", file, e);
+        process::exit(1);
+    }
+    println!("Restored {} readings from {}.", db.number_of_readings(), file);
+}
+
+// Show every change made to a date's reading, or to the tariffs.
+fn history(what : &str) {
+    let db = open_energy_db();
+    let changes: Vec<Change> = if what == "tariffs" {
+        db.tariff_history()
+    } else {
+        db.reading_history(parse_date_arg(what))
+    };
+
+    if changes.is_empty() {
+        println!("No changes to {}.", what);
+    }
+    for change in changes.iter() {
+        println!("{}", change);
+    }
+}
+
+// Undo a change shown by history. Any words after the
+// change are kept in the audit log as the reason. Only
+// the latest change to a row can be undone.
+fn revert(id : &str) {
+    let id = match id.trim_start_matches('#').parse::<i64>() {
+        Ok(id) => id,
+        Err(_e) => {
+            eprintln!("Invalid change: {}", id);
+            process::exit(2);
+        },
+    };
+
+    match open_energy_db().revert(id) {
+        Ok(change) => println!("Undid {}", change),
+        Err(e) => {
+            eprintln!("Could not undo change #{}: {}", id, e);
+            process::exit(1);
+        },
+    }
+}
+
+// Rank the plans in a file by what they would have
+// cost over the last year of readings.
+fn compare_plans(file : &str) {
+    let plans = match parse_plans(&read_file(file)) {
+        Ok(plans) => plans,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        },
+    };
+
+    let readings = open_readings().all_readings();
+    let latest = match readings.last() {
+        Some(reading) => reading.date,
+        None => {
+            eprintln!("No readings entered yet.");
+            process::exit(1);
+        },
+    };
+
+    let year_ago = latest - Duration::days(365);
+    let last_year: Vec<_> = readings.into_iter()
+        .filter(|reading| reading.date >= year_ago)
+        .collect();
+    let changes = find_changes(&last_year);
+    let intervals = open_energy_db().intervals_between(year_ago.and_hms(0, 0, 0),
+                                                       (latest + Duration::days(1)).and_hms(0, 0, 0));
+
+    println!("Estimated annual cost, cheapest first:");
+    for (rank, cost) in rank_plans(&plans, &changes, &intervals).iter().enumerate() {
+        println!("{:>3}. {}", rank + 1, cost);
+    }
+    for plan in plans.iter().filter(|plan| plan.tariffs.spreads_blocks()) {
+        println!("{} spreads its blocks evenly over each billing period, \
+                  so uneven use may be billed differently.", plan.name);
+    }
+}
+
+// Split the period between two readings by season. Uses the
+// last two readings unless a start and end date are given.
+fn seasonal_report(args : &[String], tariffs : Tariffs) {
+    let file = &args[0];
+    let seasons = match parse_seasons(&read_file(file)) {
+        Ok(seasons) => seasons,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        },
+    };
+
+    let readings = open_readings().all_readings();
+    let pair = if args.len() == 3 {
+        let dates: Vec<NaiveDate> = args[1..].iter()
+            .map(|arg| parse_date_arg(arg))
+            .collect();
+        pair_for_period(&readings, dates[0], dates[1])
+    } else if readings.len() >= 2 {
+        let last = readings.len() - 1;
+        pair_for_period(&readings, readings[last - 1].date, readings[last].date)
+    } else {
+        None
+    };
+
+    let pair = match pair {
+        Some(pair) => pair,
+        None if args.len() == 3 => {
+            eprintln!("No readings within {} days of both dates.", MAX_DAYS_FROM_PERIOD);
+            process::exit(1);
+        },
+        None => {
+            eprintln!("Not enough readings.");
+            process::exit(1);
+        },
+    };
+
+    let seasonal = SeasonalTariffs {
+        seasons,
+        otherwise: tariffs,
+    };
+    match calculate_seasonal(&pair, &seasonal) {
+        Some(calculation) => println!("{}", calculation),
+        None => {
+            eprintln!("The readings nearest those dates are on the same day.");
+            process::exit(1);
+        },
+    }
+    if pair.depends_on_estimates() {
+        println!("Worked out from an estimated reading.");
     }
 }
 
+// Add daily readings worked out from a NEM12 file.
+fn import_nem12(file : &str) {
+    let nem12 = match parse_nem12(&read_file(file)) {
+        Ok(nem12) => nem12,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        },
+    };
+
+    store_intervals(&nem12.intervals());
+}
+
+// Add daily readings worked out from a Green Button file.
+fn import_green_button(file : &str) {
+    let intervals = match parse_green_button(&read_file(file)) {
+        Ok(intervals) => intervals,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        },
+    };
+
+    store_intervals(&intervals);
+}
+
+// Correct the generation of stored readings from an inverter portal export.
+fn import_generation(file : &str) {
+    let text = read_file(file);
+    let importer = match detect_importer(&text) {
+        Some(importer) => importer,
+        None => {
+            let names: Vec<&str> = importers().iter().map(|i| i.name()).collect();
+            eprintln!("{}: not an export we know. Supported: {}", file, names.join(", "));
+            process::exit(1);
+        },
+    };
+
+    let records = match importer.parse(&text) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        },
+    };
+
+    let mut db = open_readings_for_adding();
+    let mut report = merge_generation(&records, &db.all_readings());
+
+    println!("{} export with {} days.", importer.name(), records.len());
+    for date in report.duplicates.iter() {
+        println!("    Duplicate day {}, the first was used.", date.format("%d/%m/%Y"));
+    }
+    for (start, end) in report.gaps.iter() {
+        println!("    Missing {} to {}.", start.format("%d/%m/%Y"), end.format("%d/%m/%Y"));
+    }
+    if !report.unanchored.is_empty() {
+        println!("    {} days had no stored reading to count from.", report.unanchored.len());
+    }
+    let source = Source::Imported(importer.name().to_lowercase());
+    for reading in report.updated.iter_mut() {
+        reading.source = source.clone();
+    }
+    if let Err(e) = db.save_readings(&report.updated) {
+        eprintln!("No readings updated, {}", e);
+        process::exit(1);
+    }
+    for reading in report.updated.iter() {
+        println!("    {} generation now {:.1} kWh.",
+                 reading.date.format("%d/%m/%Y"), reading.generation);
+    }
+    println!("{} readings updated.", report.updated.len());
+}
+
+// Keep imported intervals and add the daily readings they give us.
+// Nothing is kept if the readings can't all be added.
+fn store_intervals(intervals : &[Interval]) {
+    let db = open_database();
+    let totals = daily_totals(intervals);
+
+    let derived = db.transaction(|db| {
+        db.add_intervals(intervals)?;
+        let derived = derive_readings(&totals, &db.all_readings());
+        db.add_readings(&derived.readings).map(|counts| (counts, derived.unanchored))
+    });
+    match derived {
+        Ok((counts, unanchored)) => {
+            println!("{} days of interval data, readings {}.", totals.len(), counts);
+            if !unanchored.is_empty() {
+                println!("    {} days had no stored reading to count from.", unanchored.len());
+            }
+        },
+        Err(e) => {
+            eprintln!("Could not store the intervals, {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+// Read the lifetime totals from the inverter and meter and save
+// them as today's reading, once or every so many minutes.
+fn poll_inverter(args : &[String]) {
+    let minutes = args.get(1).map(|arg| match arg.parse::<u64>() {
+        Ok(minutes) if minutes > 0 => minutes,
+        _ => {
+            eprintln!("Invalid number of minutes: {}", arg);
+            process::exit(2);
+        },
+    });
+    let units: Vec<u8> = match args.len() {
+        0..=2 => vec![1],
+        _ => args[2..].iter()
+            .map(|arg| arg.parse::<u8>().unwrap_or_else(|_e| {
+                eprintln!("Invalid unit id: {}", arg);
+                process::exit(2);
+            }))
+            .collect(),
+    };
+
+    let mut db = open_readings_for_adding();
+    loop {
+        let today = Local::today().naive_local();
+        let result = poll(&args[0], &units)
+            .map_err(|e| e.to_string())
+            .and_then(|device| device.reading(today).map_err(|e| e.to_string()))
+            .and_then(|reading| db.save_reading(&reading).map(|_| reading));
+
+        match result {
+            Ok(reading) => println!("{}  generation {:.1}  exports {:.1}  imports {:.1}",
+                                    reading.date.format("%d/%m/%Y"), reading.generation,
+                                    reading.exports, reading.imports),
+            Err(e) if minutes.is_some() => eprintln!("{}: {}", args[0], e),
+            Err(e) => {
+                eprintln!("{}: {}", args[0], e);
+                process::exit(1);
+            },
+        }
+
+        match minutes {
+            Some(minutes) => thread::sleep(std::time::Duration::from_secs(minutes * 60)),
+            None => return,
+        }
+    }
+}
+
+// Serve /metrics for Prometheus, reading the database afresh for each scrape.
+fn serve_metrics(address : &str) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", address, e);
+            process::exit(1);
+        },
+    };
+
+    println!("Serving metrics on http://{}/metrics", address);
+    serve(listener, |request| match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let readings = open_readings().all_readings();
+            Response::new(200, "text/plain; version=0.0.4",
+                          render_metrics(&readings, &current_tariffs()))
+        },
+        (_, "/metrics") => Response::text(405, "Only GET is allowed."),
+        _ => Response::text(404, "Try /metrics."),
+    });
+}
+
+// Serve the JSON API, asking for a token if one is set in the environment.
+fn serve_api(address : &str) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", address, e);
+            process::exit(1);
+        },
+    };
+
+    let api = Api {
+        db: open_database(),
+        token: env::var(TOKEN_VARIABLE).ok().filter(|token| !token.is_empty()),
+        default_tariffs: default_tariffs(),
+    };
+    if api.token.is_none() {
+        println!("{} is not set, so anyone on the network can change readings.", TOKEN_VARIABLE);
+    }
+
+    println!("Serving the API on http://{}/readings", address);
+    serve(listener, |request| api.handle(request));
+}
+
+// Write a page summarising every reading that can be opened without a server.
+fn write_html_report(file : &str, tariffs : Tariffs) {
+    let readings = open_readings().all_readings();
+    let html = match html_report(&readings, &tariffs, Local::today().naive_local()) {
+        Some(html) => html,
+        None => {
+            eprintln!("Not enough readings.");
+            process::exit(1);
+        },
+    };
+
+    if let Err(e) = fs::write(file, html) {
+        eprintln!("Could not write {}: {}", file, e);
+        process::exit(1);
+    }
+    println!("Report written to {}", file);
+}
+
+// Write a spreadsheet of the readings, changes, months and tariffs.
+fn write_workbook(file : &str, tariffs : Tariffs) {
+    let readings = open_readings().all_readings();
+    if readings.is_empty() {
+        eprintln!("No readings entered yet.");
+        process::exit(1);
+    }
+
+    if let Err(e) = fs::write(file, write_xlsx(&energy_workbook(&readings, &tariffs))) {
+        eprintln!("Could not write {}: {}", file, e);
+        process::exit(1);
+    }
+    println!("Workbook written to {}", file);
+}
+
+// Chart the daily averages between two dates in the
+// terminal, or as an SVG file if one is given.
+fn chart(args : &[String], tariffs : Tariffs) {
+    let start = parse_date_arg(&args[0]);
+    let end = parse_date_arg(&args[1]);
+
+    let mut ascii = false;
+    let mut svg_file = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--ascii" => ascii = true,
+            "--svg" => match options.next() {
+                Some(file) => svg_file = Some(file),
+                None => {
+                    eprintln!("--svg needs a file name");
+                    process::exit(2);
+                },
+            },
+            _ => {
+                eprintln!("Unknown option: {}", option);
+                process::exit(2);
+            },
+        }
+    }
+
+    let readings: Vec<_> = open_readings().all_readings().into_iter()
+        .filter(|reading| reading.date >= start && reading.date <= end)
+        .collect();
+    let changes = find_changes(&readings);
+    if changes.is_empty() {
+        eprintln!("Not enough readings between those dates.");
+        process::exit(1);
+    }
+    let series = usage_series(&changes, &tariffs);
+
+    if let Some(file) = svg_file {
+        let title = format!("Daily average kWh, {} to {}",
+                            start.format("%d/%m/%Y"), end.format("%d/%m/%Y"));
+        if let Err(e) = fs::write(file, svg_line_chart(&title, &series, 760.0, 300.0)) {
+            eprintln!("Could not write {}: {}", file, e);
+            process::exit(1);
+        }
+        println!("Chart written to {}", file);
+        return;
+    }
+
+    println!("Daily averages for {} periods, {} to {}:", changes.len(),
+             start.format("%d/%m/%Y"), end.format("%d/%m/%Y"));
+    for line in series.iter() {
+        let values: Vec<f32> = line.points.iter().map(|(_, value)| *value).collect();
+        let low = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let high = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        println!("    {:<17} {}  {:.1} to {:.1} kWh", line.name, sparkline(&values, ascii), low, high);
+    }
+
+    println!();
+    println!("Generation a day by month:");
+    let bars: Vec<(String, f32)> = monthly_summaries(&changes, &tariffs).iter()
+        .map(|month| (month.name(), (month.generation / i64::from(month.days)).to_f32()))
+        .collect();
+    for line in bar_chart(&bars, 40, ascii).lines() {
+        println!("    {}", line);
+    }
+}
+
+// Print a journal of estimated bills and savings, for all
+// readings or just those between two dates.
+fn export_ledger(args : &[String]) {
+    let file = &args[0];
+    let config = match parse_journal_config(&read_file(file)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        },
+    };
+
+    let mut readings = open_readings().all_readings();
+    if args.len() == 3 {
+        let start = parse_date_arg(&args[1]);
+        let end = parse_date_arg(&args[2]);
+        readings.retain(|reading| reading.date >= start && reading.date <= end);
+    }
+    let changes = find_changes(&readings);
+    if changes.is_empty() {
+        eprintln!("Not enough readings.");
+        process::exit(1);
+    }
+    print!("{}", journal(&changes, &config));
+}
+
+// Show the average day, peak demand and time of use shares
+// for the stored intervals between two dates.
+fn interval_report(args : &[String]) {
+    let dates: Vec<NaiveDate> = args[..2].iter()
+        .map(|arg| parse_date_arg(arg))
+        .collect();
+
+    let db = open_energy_db();
+    let start = dates[0].and_hms(0, 0, 0);
+    let end = (dates[1] + Duration::days(1)).and_hms(0, 0, 0);
+    let intervals = db.intervals_between(start, end);
+
+    if intervals.is_empty() {
+        eprintln!("No interval data for those dates.");
+        process::exit(1);
+    }
+
+    println!("Average day:");
+    for slot in load_profile(&intervals).iter() {
+        println!("    {}  imports {:>6.3} kWh  exports {:>6.3} kWh",
+                 slot.time.format("%H:%M"), slot.imports, slot.exports);
+    }
+
+    println!("Peak demand:");
+    for peak in peak_demand_by_month(&intervals).iter() {
+        if let Some(kw) = peak.kw() {
+            println!("    {:04}-{:02}  {:>6.2} kW at {}",
+                     peak.year, peak.month, kw, peak.interval.start.format("%d/%m/%Y %H:%M"));
+        }
+    }
+
+    if let Some(file) = args.get(2) {
+        let windows = match parse_tou_windows(&read_file(file)) {
+            Ok(windows) => windows,
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                process::exit(1);
+            },
+        };
+
+        println!("Share of imports:");
+        for (name, share) in import_shares(&intervals, &windows).iter() {
+            println!("    {:<20} {:>5.1}%", name, share * 100.0);
+        }
+    }
+}
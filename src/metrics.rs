@@ -0,0 +1,130 @@
+use chrono::{ Duration, NaiveDate };
+
+use crate::calc::{ calculate, Tariffs };
+use crate::readings::{ find_changes, DatedChange, Reading };
+use crate::units::{ Dollars, KilowattHours };
+
+/// The rolling windows savings are reported over, in days.
+pub const SAVINGS_WINDOWS: [i64; 2] = [7, 30];
+
+/// The savings in dollars from the readings between two dates. Changes
+/// that only partly overlap the dates count for the days they share.
+pub fn savings_between(changes: &[DatedChange], start: NaiveDate, end: NaiveDate,
+                       tariffs: &Tariffs) -> Dollars {
+    changes.iter()
+        .map(|change| {
+            let from = change.start.max(start);
+            let to = change.end.min(end);
+            let days = to.signed_duration_since(from).num_days();
+            if days <= 0 {
+                return Dollars::ZERO;
+            }
+            let calculation = calculate(change.change.clone(), tariffs.clone());
+            calculation.savings.total * days
+        })
+        .sum()
+}
+
+// Add one metric in the text exposition format. Each sample
+// is its labels, if any, and value.
+fn write_metric(output: &mut String, name: &str, kind: &str, help: &str,
+                samples: &[(String, f64)]) {
+    output.push_str(&format!("# HELP nrgaccounts_{} {}\n", name, help));
+    output.push_str(&format!("# TYPE nrgaccounts_{} {}\n", name, kind));
+    for (labels, value) in samples.iter() {
+        output.push_str(&format!("nrgaccounts_{}{} {}\n", name, labels, value));
+    }
+}
+
+/// Work out the metrics for the readings, sorted by date, in
+/// the Prometheus text exposition format.
+pub fn render_metrics(readings: &[Reading], tariffs: &Tariffs) -> String {
+    let mut output = String::new();
+    let latest = match readings.last() {
+        Some(latest) => latest,
+        None => return output,
+    };
+    let value = |value: f64| vec![(String::new(), value)];
+    let energy = |kwh: KilowattHours| value(kwh.value().to_f64());
+
+    write_metric(&mut output, "generation_kwh_total", "counter",
+                 "Lifetime generation from the inverter.", &energy(latest.generation));
+    write_metric(&mut output, "exports_kwh_total", "counter",
+                 "Lifetime exports from the meter.", &energy(latest.exports));
+    write_metric(&mut output, "imports_kwh_total", "counter",
+                 "Lifetime imports from the meter.", &energy(latest.imports));
+    let timestamp = latest.date.and_hms(0, 0, 0).timestamp();
+    write_metric(&mut output, "last_reading_timestamp_seconds", "gauge",
+                 "The date of the latest reading.", &value(timestamp as f64));
+
+    let changes = find_changes(readings);
+    if let Some(change) = changes.last() {
+        let calculation = calculate(change.change.clone(), tariffs.clone());
+        write_metric(&mut output, "daily_generation_kwh", "gauge",
+                     "Average daily generation between the last two readings.",
+                     &energy(calculation.generation_kwh));
+        write_metric(&mut output, "daily_exports_kwh", "gauge",
+                     "Average daily exports between the last two readings.",
+                     &energy(calculation.grid_export_kwh));
+        write_metric(&mut output, "daily_imports_kwh", "gauge",
+                     "Average daily imports between the last two readings.",
+                     &energy(calculation.grid_import_kwh));
+        write_metric(&mut output, "self_consumption_fraction", "gauge",
+                     "Self consumption between the last two readings as a fraction.",
+                     &[("{of=\"total_use\"}".to_string(),
+                        f64::from(calculation.self_consumption.fraction_of_total_use.0)),
+                       ("{of=\"generation\"}".to_string(),
+                        f64::from(calculation.self_consumption.fraction_of_generation.0))]);
+    }
+
+    let savings: Vec<(String, f64)> = SAVINGS_WINDOWS.iter()
+        .map(|days| {
+            let start = latest.date - Duration::days(*days);
+            (format!("{{window=\"{}d\"}}", days),
+             savings_between(&changes, start, latest.date, tariffs).value().to_f64())
+        })
+        .collect();
+    write_metric(&mut output, "savings_dollars", "gauge",
+                 "Savings over the days before the latest reading.", &savings);
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::readings::steady_readings;
+
+    // A long period and then a week, so the windows cover both.
+    fn readings() -> Vec<Reading> {
+        steady_readings(&[NaiveDate::from_ymd(2020, 1, 1), NaiveDate::from_ymd(2020, 1, 21),
+                          NaiveDate::from_ymd(2020, 1, 29)])
+    }
+
+    #[test]
+    fn savings_between_prorates() {
+        let changes = find_changes(&readings());
+        let tariffs = Tariffs::flat(2.into(), 1.into());
+
+        // $15 a day.
+        let start = NaiveDate::from_ymd(2020, 1, 15);
+        let end = NaiveDate::from_ymd(2020, 1, 25);
+        assert_eq!(savings_between(&changes, start, end, &tariffs), Dollars::from(150));
+        assert_eq!(savings_between(&changes, end, start, &tariffs), Dollars::ZERO);
+    }
+
+    #[test]
+    fn render_metrics_ok() {
+        let text = render_metrics(&readings(), &Tariffs::flat(2.into(), 1.into()));
+
+        assert!(text.contains("# TYPE nrgaccounts_generation_kwh_total counter\n\
+                               nrgaccounts_generation_kwh_total 280\n"));
+        assert!(text.contains("nrgaccounts_daily_generation_kwh 10\n"));
+        assert!(text.contains("nrgaccounts_self_consumption_fraction{of=\"generation\"} 0.5\n"));
+        assert!(text.contains("nrgaccounts_savings_dollars{window=\"7d\"} 105\n"));
+        assert!(text.contains("nrgaccounts_savings_dollars{window=\"30d\"} 420\n"));
+        assert!(text.contains("nrgaccounts_last_reading_timestamp_seconds 1580256000\n"));
+
+        assert_eq!(render_metrics(&[], &Tariffs::flat(2.into(), 1.into())), "");
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::time::Duration;
+
+/// How long to wait for the device before giving up.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most registers one request may read.
+pub const MAX_REGISTERS: u16 = 125;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+
+/// Something went wrong talking to a Modbus device.
+#[derive(Debug)]
+pub enum ModbusError {
+    /// The connection failed.
+    Io(io::Error),
+    /// The device answered with a Modbus exception code.
+    Exception(u8),
+    /// The device answered with something we didn't expect.
+    BadResponse(&'static str),
+}
+
+impl fmt::Display for ModbusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModbusError::Io(e) => write!(f, "{}", e),
+            ModbusError::Exception(code) => write!(f, "device returned exception {}", code),
+            ModbusError::BadResponse(message) => write!(f, "bad response: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for ModbusError {
+    fn from(e: io::Error) -> ModbusError {
+        ModbusError::Io(e)
+    }
+}
+
+/// A connection to a Modbus TCP device.
+pub struct ModbusClient {
+    stream: TcpStream,
+    unit_id: u8,
+    transaction: u16,
+}
+
+impl ModbusClient {
+    /// Connect to a device such as "192.168.1.20:502".
+    pub fn connect<A: ToSocketAddrs>(address: A, unit_id: u8) -> Result<ModbusClient, ModbusError> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(ModbusClient { stream, unit_id, transaction: 0 })
+    }
+
+    /// Address later requests to a different device behind the same gateway.
+    pub fn set_unit_id(&mut self, unit_id: u8) {
+        self.unit_id = unit_id;
+    }
+
+    /// Read `count` holding registers starting at the zero based `address`.
+    pub fn read_holding_registers(&mut self, address: u16, count: u16)
+        -> Result<Vec<u16>, ModbusError> {
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(ModbusError::BadResponse("register count out of range"));
+        }
+        self.transaction = self.transaction.wrapping_add(1);
+
+        // MBAP header then the request.
+        let mut request = Vec::with_capacity(12);
+        request.extend_from_slice(&self.transaction.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&6u16.to_be_bytes());
+        request.push(self.unit_id);
+        request.push(READ_HOLDING_REGISTERS);
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&count.to_be_bytes());
+        self.stream.write_all(&request)?;
+
+        let mut header = [0u8; 7];
+        self.stream.read_exact(&mut header)?;
+        if header[0..2] != self.transaction.to_be_bytes() {
+            return Err(ModbusError::BadResponse("transaction id mismatch"));
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if length < 2 {
+            return Err(ModbusError::BadResponse("response too short"));
+        }
+
+        let mut body = vec![0u8; length - 1];
+        self.stream.read_exact(&mut body)?;
+
+        if body[0] == READ_HOLDING_REGISTERS | 0x80 {
+            return Err(ModbusError::Exception(body.get(1).copied().unwrap_or(0)));
+        }
+        if body[0] != READ_HOLDING_REGISTERS || body.len() < 2 {
+            return Err(ModbusError::BadResponse("unexpected function code"));
+        }
+        let bytes = body[1] as usize;
+        if bytes != count as usize * 2 || body.len() != bytes + 2 {
+            return Err(ModbusError::BadResponse("wrong number of registers"));
+        }
+
+        Ok(body[2..].chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+}
+
+/// A Modbus TCP server holding fixed registers, for testing.
+#[cfg(test)]
+pub(crate) mod simulator {
+    use std::collections::HashMap;
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve the registers on a local port, returning the address.
+    /// Unknown registers give an illegal address exception.
+    pub fn serve(registers: HashMap<u16, u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_e) => continue,
+                };
+                let mut request = [0u8; 12];
+                while stream.read_exact(&mut request).is_ok() {
+                    let start = u16::from_be_bytes([request[8], request[9]]);
+                    let count = u16::from_be_bytes([request[10], request[11]]);
+                    let values: Option<Vec<u16>> = (start..start + count)
+                        .map(|address| registers.get(&address).copied())
+                        .collect();
+
+                    let body = match values {
+                        Some(values) => {
+                            let mut body = vec![0x03, (values.len() * 2) as u8];
+                            for value in values {
+                                body.extend_from_slice(&value.to_be_bytes());
+                            }
+                            body
+                        },
+                        None => vec![0x83, 0x02],
+                    };
+
+                    let mut response = request[0..4].to_vec();
+                    response.extend_from_slice(&((body.len() + 1) as u16).to_be_bytes());
+                    response.push(request[6]);
+                    response.extend_from_slice(&body);
+                    if stream.write_all(&response).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn read_holding_registers_ok() {
+        let registers: HashMap<u16, u16> = (100..110).map(|a| (a, a * 2)).collect();
+        let address = simulator::serve(registers);
+
+        let mut client = ModbusClient::connect(address.as_str(), 1).unwrap();
+        assert_eq!(client.read_holding_registers(100, 3).unwrap(), vec![200, 202, 204]);
+        assert_eq!(client.read_holding_registers(109, 1).unwrap(), vec![218]);
+
+        match client.read_holding_registers(109, 2) {
+            Err(ModbusError::Exception(2)) => (),
+            other => panic!("Expected an exception, got {:?}", other),
+        }
+        assert!(client.read_holding_registers(0, 200).is_err());
+    }
+}
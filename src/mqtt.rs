@@ -0,0 +1,416 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::time::{ Duration, Instant };
+
+use crate::calc::{ calculate, Tariffs };
+use crate::config::{ parse_sections, ConfigError };
+use crate::json::{ calculation_json, reading_json };
+use crate::readings::{ find_change, Reading, ReadingPair };
+
+/// The port brokers listen on when none is given.
+pub const DEFAULT_PORT: u16 = 1883;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const KEEP_ALIVE_SECONDS: u16 = 60;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const RETAIN: u8 = 0x01;
+const DISCONNECT: u8 = 0xe0;
+
+/// Something went wrong talking to the broker.
+#[derive(Debug)]
+pub enum MqttError {
+    Io(io::Error),
+    /// The broker turned down the connection with this return code.
+    Refused(u8),
+    /// The broker answered with something we didn't expect.
+    BadResponse,
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "{}", e),
+            MqttError::Refused(code) => write!(f, "broker refused connection, code {}", code),
+            MqttError::BadResponse => write!(f, "unexpected reply from broker"),
+        }
+    }
+}
+
+impl From<io::Error> for MqttError {
+    fn from(e: io::Error) -> MqttError {
+        MqttError::Io(e)
+    }
+}
+
+/// Where and what to publish, from the [mqtt] section of a config file.
+///
+/// ```text
+/// [mqtt]
+/// broker = 192.168.1.10:1883
+/// client_id = nrgaccounts
+/// username = me              # optional
+/// password = secret          # optional
+/// reading_topic = home/energy/reading
+/// calculation_topic = home/energy/calculation
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    /// The broker's "host:port" address.
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Each new reading is published here.
+    pub reading_topic: String,
+    /// The calculation since the previous reading is published here.
+    pub calculation_topic: String,
+}
+
+/// Read the [mqtt] section of a config file.
+pub fn parse_mqtt_config(text: &str) -> Result<MqttConfig, ConfigError> {
+    let sections = parse_sections(text)?;
+    let section = match sections.iter().find(|section| section.name == "mqtt") {
+        Some(section) => section,
+        None => return Err(ConfigError {
+            line: 1,
+            message: "no [mqtt] section".to_string(),
+        }),
+    };
+
+    let broker = match section.get("broker") {
+        Some(broker) if broker.contains(':') => broker.to_string(),
+        Some(broker) => format!("{}:{}", broker, DEFAULT_PORT),
+        None => return Err(ConfigError {
+            line: section.line,
+            message: "mqtt needs a broker".to_string(),
+        }),
+    };
+    let text = |key: &str, default: &str| section.get(key).unwrap_or(default).to_string();
+
+    // MQTT 3.1.1 only sends a password along with a username.
+    if section.get("username").is_none() {
+        if let Some((line, _, _)) = section.entries.iter().find(|(_, key, _)| key == "password") {
+            return Err(ConfigError {
+                line: *line,
+                message: "mqtt password needs a username".to_string(),
+            });
+        }
+    }
+
+    Ok(MqttConfig {
+        broker,
+        client_id: text("client_id", "nrgaccounts"),
+        username: section.get("username").map(|s| s.to_string()),
+        password: section.get("password").map(|s| s.to_string()),
+        reading_topic: text("reading_topic", "nrgaccounts/reading"),
+        calculation_topic: text("calculation_topic", "nrgaccounts/calculation"),
+    })
+}
+
+// Add the remaining length in MQTT's variable length encoding.
+fn push_length(packet: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+// Add a string prefixed with its length.
+fn push_string(packet: &mut Vec<u8>, text: &str) {
+    packet.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    packet.extend_from_slice(text.as_bytes());
+}
+
+// A whole packet from its first byte and body.
+fn packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    push_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// A connection to an MQTT 3.1.1 broker that publishes at QoS 0.
+pub struct MqttClient {
+    stream: TcpStream,
+}
+
+impl MqttClient {
+    /// Connect and log in to the broker.
+    pub fn connect(config: &MqttConfig) -> Result<MqttClient, MqttError> {
+        let mut stream = TcpStream::connect(config.broker.as_str())?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        // Always start a clean session. A password is only
+        // allowed along with a username.
+        let password = config.username.as_ref().and(config.password.as_ref());
+        let mut flags = 0x02;
+        if config.username.is_some() {
+            flags |= 0x80;
+        }
+        if password.is_some() {
+            flags |= 0x40;
+        }
+
+        let mut body = Vec::new();
+        push_string(&mut body, "MQTT");
+        body.push(4);
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE_SECONDS.to_be_bytes());
+        push_string(&mut body, &config.client_id);
+        for text in config.username.iter().chain(password) {
+            push_string(&mut body, text);
+        }
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != CONNACK || connack[1] != 2 {
+            return Err(MqttError::BadResponse);
+        }
+        if connack[3] != 0 {
+            return Err(MqttError::Refused(connack[3]));
+        }
+
+        Ok(MqttClient { stream })
+    }
+
+    /// Publish a message, asking the broker to keep it
+    /// for later subscribers if `retain` is set.
+    pub fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> Result<(), MqttError> {
+        let mut body = Vec::new();
+        push_string(&mut body, topic);
+        body.extend_from_slice(payload.as_bytes());
+
+        let first_byte = if retain { PUBLISH | RETAIN } else { PUBLISH };
+        self.stream.write_all(&packet(first_byte, &body))?;
+        Ok(())
+    }
+
+    /// Tell the broker we're done.
+    pub fn disconnect(mut self) -> Result<(), MqttError> {
+        self.stream.write_all(&[DISCONNECT, 0])?;
+        Ok(())
+    }
+}
+
+/// Publishes readings over one connection to the broker, made when
+/// the first reading is published and closed when the publisher is
+/// dropped. Calculations are worked out with the tariffs it was given.
+pub struct ReadingPublisher {
+    config: MqttConfig,
+    tariffs: Tariffs,
+    // The connection and when it was last used.
+    client: Option<(MqttClient, Instant)>,
+}
+
+impl ReadingPublisher {
+    pub fn new(config: MqttConfig, tariffs: Tariffs) -> ReadingPublisher {
+        ReadingPublisher { config, tariffs, client: None }
+    }
+
+    /// The broker the readings go to.
+    pub fn broker(&self) -> &str {
+        &self.config.broker
+    }
+
+    /// Publish a new reading as a retained message and, if there is a
+    /// reading before it, the calculation for the period between them.
+    pub fn publish(&mut self, previous: Option<&Reading>, reading: &Reading) -> Result<(), MqttError> {
+        // The broker drops connections left quiet past the keep alive,
+        // so start a new one rather than write to a closed one.
+        let keep_alive = Duration::from_secs(u64::from(KEEP_ALIVE_SECONDS));
+        let mut client = match self.client.take() {
+            Some((client, used)) if used.elapsed() < keep_alive => client,
+            _ => MqttClient::connect(&self.config)?,
+        };
+        publish_reading(&mut client, &self.config, previous, reading, &self.tariffs)?;
+        self.client = Some((client, Instant::now()));
+        Ok(())
+    }
+}
+
+impl Drop for ReadingPublisher {
+    fn drop(&mut self) {
+        if let Some((client, _)) = self.client.take() {
+            // Nothing more can be done if the broker has gone.
+            let _ = client.disconnect();
+        }
+    }
+}
+
+// Publish a reading, and the calculation since the reading before it.
+fn publish_reading(client: &mut MqttClient, config: &MqttConfig, previous: Option<&Reading>,
+                   reading: &Reading, tariffs: &Tariffs) -> Result<(), MqttError> {
+    client.publish(&config.reading_topic, &reading_json(reading).to_string(), true)?;
+
+    if let Some(previous) = previous {
+        let pair = ReadingPair {
+            first: previous.clone(),
+            second: reading.clone(),
+        };
+        if let Some(change) = find_change(&pair) {
+            let calculation = calculate(change, tariffs.clone());
+            client.publish(&config.calculation_topic,
+                           &calculation_json(&pair, &calculation).to_string(), true)?;
+        }
+    }
+    Ok(())
+}
+
+/// An MQTT broker that accepts one client, for testing.
+#[cfg(test)]
+pub(crate) mod broker {
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{ channel, Receiver };
+    use std::thread;
+
+    /// A message as the broker received it.
+    #[derive(Debug, PartialEq)]
+    pub struct Message {
+        pub topic: String,
+        pub payload: String,
+        pub retain: bool,
+    }
+
+    /// Listen on a local port, returning the address and a channel
+    /// that gets each message published until the client disconnects.
+    pub fn serve() -> (String, Receiver<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            loop {
+                let mut first = [0u8; 1];
+                if stream.read_exact(&mut first).is_err() {
+                    return;
+                }
+                let mut length = 0usize;
+                let mut multiplier = 1;
+                loop {
+                    let mut byte = [0u8; 1];
+                    stream.read_exact(&mut byte).unwrap();
+                    length += (byte[0] & 0x7f) as usize * multiplier;
+                    multiplier *= 128;
+                    if byte[0] & 0x80 == 0 {
+                        break;
+                    }
+                }
+                let mut body = vec![0u8; length];
+                stream.read_exact(&mut body).unwrap();
+
+                match first[0] & 0xf0 {
+                    0x10 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                    0x30 => {
+                        let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let message = Message {
+                            topic: String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap(),
+                            payload: String::from_utf8(body[2 + topic_length..].to_vec()).unwrap(),
+                            retain: first[0] & 0x01 == 1,
+                        };
+                        if sender.send(message).is_err() {
+                            return;
+                        }
+                    },
+                    _ => return,
+                }
+            }
+        });
+
+        (address, receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn config(broker: String) -> MqttConfig {
+        MqttConfig {
+            broker,
+            client_id: "test".to_string(),
+            username: Some("me".to_string()),
+            password: None,
+            reading_topic: "energy/reading".to_string(),
+            calculation_topic: "energy/calculation".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_mqtt_config_ok() {
+        let text = "[mqtt]\n\
+                    broker = localhost\n\
+                    reading_topic = home/reading\n";
+        let config = parse_mqtt_config(text).unwrap();
+        assert_eq!(config.broker, "localhost:1883");
+        assert_eq!(config.reading_topic, "home/reading");
+        assert_eq!(config.calculation_topic, "nrgaccounts/calculation");
+        assert_eq!(config.username, None);
+
+        assert!(parse_mqtt_config("[mqtt]\nclient_id = x\n").is_err());
+        assert!(parse_mqtt_config("[other]\nbroker = x\n").is_err());
+        assert_eq!(parse_mqtt_config("[mqtt]\nbroker = x\npassword = secret\n").unwrap_err(),
+                   ConfigError { line: 3, message: "mqtt password needs a username".to_string() });
+        let config = parse_mqtt_config("[mqtt]\nbroker = x\nusername = me\npassword = secret\n").unwrap();
+        assert_eq!(config.password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn push_length_encoding() {
+        let mut packet = Vec::new();
+        push_length(&mut packet, 321);
+        assert_eq!(packet, vec![0xc1, 0x02]);
+    }
+
+    #[test]
+    fn publish_reading_and_calculation() {
+        let (address, messages) = broker::serve();
+        let previous = Reading::new(NaiveDate::from_ymd(2020, 1, 1), 100.into(),
+                                    50.into(), 200.into());
+        let reading = Reading::new(NaiveDate::from_ymd(2020, 1, 3), 120.into(),
+                                   60.into(), 210.into());
+
+        let mut publisher = ReadingPublisher::new(config(address),
+                                                  Tariffs::flat(2.into(), 1.into()));
+        publisher.publish(Some(&previous), &reading).unwrap();
+
+        let message = messages.recv().unwrap();
+        assert_eq!(message.topic, "energy/reading");
+        assert!(message.retain);
+        assert_eq!(message.payload,
+                   "{\"date\":\"2020-01-03\",\"generation\":120,\"exports\":60,\"imports\":210,\
+                    \"source\":\"actual\",\"note\":\"\"}");
+
+        let message = messages.recv().unwrap();
+        assert_eq!(message.topic, "energy/calculation");
+        assert!(message.payload.starts_with("{\"start\":\"2020-01-01\",\"end\":\"2020-01-03\",\
+                                             \"days\":2,\"generation_kwh\":10,"));
+        assert!(message.payload.contains("\"savings_total\":15"));
+
+        // The test broker only takes one client, so this
+        // reading must go over the same connection.
+        let next = Reading::new(NaiveDate::from_ymd(2020, 1, 4), 125.into(), 62.into(), 212.into());
+        publisher.publish(None, &next).unwrap();
+        let message = messages.recv().unwrap();
+        assert!(message.payload.starts_with("{\"date\":\"2020-01-04\""));
+
+        drop(publisher);
+        assert!(messages.recv().is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use chrono::{ Duration, NaiveDate, NaiveDateTime };
+
+use crate::decimal::Decimal;
+use crate::intervals::Interval;
+use crate::units::KilowattHours;
+
+/// A problem found while reading a NEM12 file.
+#[derive(Debug, PartialEq)]
+pub struct Nem12Error {
+    /// The line the problem was found on, starting from one.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Nem12Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// How a meter data value was obtained.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    /// Read from the meter.
+    Actual,
+    /// Estimated ahead of an actual read.
+    Estimated,
+    /// A final substitute that won't be replaced.
+    Final,
+    /// No data.
+    Null,
+    /// A substitute that may be replaced.
+    Substituted,
+}
+
+impl Quality {
+    // The first letter of a QualityMethod field, such as A or E52.
+    fn parse(method: &str) -> Option<Quality> {
+        match method.chars().next() {
+            Some('A') => Some(Quality::Actual),
+            Some('E') => Some(Quality::Estimated),
+            Some('F') => Some(Quality::Final),
+            Some('N') => Some(Quality::Null),
+            Some('S') => Some(Quality::Substituted),
+            _ => None,
+        }
+    }
+}
+
+/// A day of interval data from a 300 record, with the quality of
+/// each interval filled in from any 400 records that follow it.
+#[derive(Debug, PartialEq)]
+pub struct IntervalDay {
+    pub date: NaiveDate,
+    /// The value for each interval in kilowatt / hours.
+    pub values: Vec<KilowattHours>,
+    /// The quality of each interval.
+    pub quality: Vec<Quality>,
+    /// The reason code for any substitution or estimate.
+    pub reason_code: Option<u32>,
+}
+
+/// A manual meter read from a 500 record.
+#[derive(Debug, PartialEq)]
+pub struct B2bDetail {
+    pub trans_code: String,
+    pub ret_service_order: String,
+    pub read_date_time: Option<NaiveDateTime>,
+    /// The register reading, if one was given.
+    pub index_read: Option<String>,
+}
+
+/// A stream of data for one NMI and register suffix from a 200 record.
+#[derive(Debug, PartialEq)]
+pub struct NmiData {
+    /// The National Metering Identifier.
+    pub nmi: String,
+    /// E1, B1 etc. E means energy imported from the grid, B exported.
+    pub suffix: String,
+    pub meter_serial: String,
+    /// Length of each interval in minutes.
+    pub interval_minutes: u32,
+    pub days: Vec<IntervalDay>,
+    pub b2b_details: Vec<B2bDetail>,
+}
+
+impl NmiData {
+    /// True if this stream measures energy imported from the grid.
+    pub fn is_import(&self) -> bool {
+        self.suffix.starts_with('E')
+    }
+
+    /// True if this stream measures energy exported to the grid.
+    pub fn is_export(&self) -> bool {
+        self.suffix.starts_with('B')
+    }
+}
+
+/// The contents of a NEM12 file.
+#[derive(Debug, PartialEq)]
+pub struct Nem12 {
+    pub streams: Vec<NmiData>,
+}
+
+impl Nem12 {
+    /// Combine the import and export streams into intervals, sorted
+    /// by start time. Null intervals and streams that aren't imports
+    /// or exports, such as reactive energy, are left out. Estimated,
+    /// final and substituted values make the interval estimated.
+    pub fn intervals(&self) -> Vec<Interval> {
+        let mut by_start: BTreeMap<NaiveDateTime, Interval> = BTreeMap::new();
+
+        for stream in self.streams.iter() {
+            if !stream.is_import() && !stream.is_export() {
+                continue;
+            }
+            for day in stream.days.iter() {
+                let midnight = day.date.and_hms(0, 0, 0);
+                for (index, value) in day.values.iter().enumerate() {
+                    if day.quality[index] == Quality::Null {
+                        continue;
+                    }
+                    let minutes = stream.interval_minutes;
+                    let start = midnight + Duration::minutes((index as u32 * minutes) as i64);
+                    let interval = by_start.entry(start).or_insert(Interval {
+                        start,
+                        minutes,
+                        imports: KilowattHours::ZERO,
+                        exports: KilowattHours::ZERO,
+                        estimated: false,
+                    });
+                    interval.estimated |= day.quality[index] != Quality::Actual;
+                    if stream.is_import() {
+                        interval.imports += *value;
+                    } else {
+                        interval.exports += *value;
+                    }
+                }
+            }
+        }
+
+        by_start.into_values().collect()
+    }
+}
+
+// Kilowatt / hours in one of the energy units NEM12 allows.
+fn kwh_multiplier(uom: &str) -> Option<Decimal> {
+    match uom.to_uppercase().as_str() {
+        "WH" => Some(Decimal::new(1, 3)),
+        "KWH" => Some(Decimal::from(1)),
+        "MWH" => Some(Decimal::from(1000)),
+        _ => None,
+    }
+}
+
+// Keeps track of where we are in the file.
+struct Parser {
+    line: usize,
+    streams: Vec<NmiData>,
+    multiplier: Decimal,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> Nem12Error {
+        Nem12Error { line: self.line, message: message.to_string() }
+    }
+
+    fn field<'a>(&self, fields: &[&'a str], index: usize) -> Result<&'a str, Nem12Error> {
+        fields.get(index).copied()
+            .ok_or_else(|| self.error(&format!("missing field {}", index + 1)))
+    }
+
+    fn current_stream(&mut self) -> Result<&mut NmiData, Nem12Error> {
+        let line = self.line;
+        self.streams.last_mut().ok_or(Nem12Error {
+            line,
+            message: "record before any 200 record".to_string(),
+        })
+    }
+
+    // NMI data details.
+    fn record_200(&mut self, fields: &[&str]) -> Result<(), Nem12Error> {
+        let uom = self.field(fields, 7)?;
+        self.multiplier = kwh_multiplier(uom)
+            .ok_or_else(|| self.error(&format!("unsupported unit {}", uom)))?;
+        let interval_minutes = self.field(fields, 8)?.parse::<u32>()
+            .ok()
+            .filter(|minutes| *minutes > 0 && 1440 % minutes == 0)
+            .ok_or_else(|| self.error("invalid interval length"))?;
+
+        self.streams.push(NmiData {
+            nmi: self.field(fields, 1)?.to_string(),
+            suffix: self.field(fields, 4)?.to_string(),
+            meter_serial: self.field(fields, 6)?.to_string(),
+            interval_minutes,
+            days: Vec::new(),
+            b2b_details: Vec::new(),
+        });
+        Ok(())
+    }
+
+    // Interval data.
+    fn record_300(&mut self, fields: &[&str]) -> Result<(), Nem12Error> {
+        let multiplier = self.multiplier;
+        let date = NaiveDate::parse_from_str(self.field(fields, 1)?, "%Y%m%d")
+            .map_err(|_e| self.error("invalid interval date"))?;
+        let count = (1440 / self.current_stream()?.interval_minutes) as usize;
+
+        let mut values = Vec::with_capacity(count);
+        for index in 0..count {
+            let value = self.field(fields, 2 + index)?.parse::<Decimal>()
+                .map_err(|_e| self.error("invalid interval value"))?;
+            values.push(KilowattHours::from(value * multiplier));
+        }
+
+        let method = self.field(fields, 2 + count)?;
+        let quality = match Quality::parse(method) {
+            Some(quality) => vec![quality; count],
+            // Variable quality, the 400 records fill it in.
+            None if method.starts_with('V') => vec![Quality::Actual; count],
+            None => return Err(self.error(&format!("unknown quality {}", method))),
+        };
+        let reason_code = fields.get(3 + count).and_then(|code| code.parse::<u32>().ok());
+
+        self.current_stream()?.days.push(IntervalDay { date, values, quality, reason_code });
+        Ok(())
+    }
+
+    // Interval event, giving the quality of a range of intervals.
+    fn record_400(&mut self, fields: &[&str]) -> Result<(), Nem12Error> {
+        let start = self.field(fields, 1)?.parse::<usize>().ok();
+        let end = self.field(fields, 2)?.parse::<usize>().ok();
+        let quality = Quality::parse(self.field(fields, 3)?)
+            .ok_or_else(|| self.error("invalid quality"))?;
+        let reason_code = fields.get(4).and_then(|code| code.parse::<u32>().ok());
+
+        let day = match self.current_stream()?.days.last_mut() {
+            Some(day) => day,
+            None => return Err(self.error("400 record before any 300 record")),
+        };
+        match (start, end) {
+            (Some(start), Some(end)) if start >= 1 && start <= end && end <= day.quality.len() => {
+                for index in start - 1..end {
+                    day.quality[index] = quality;
+                }
+                if reason_code.is_some() {
+                    day.reason_code = reason_code;
+                }
+                Ok(())
+            },
+            _ => Err(self.error("invalid interval range")),
+        }
+    }
+
+    // B2B details of a manual read.
+    fn record_500(&mut self, fields: &[&str]) -> Result<(), Nem12Error> {
+        let detail = B2bDetail {
+            trans_code: self.field(fields, 1)?.to_string(),
+            ret_service_order: fields.get(2).unwrap_or(&"").to_string(),
+            read_date_time: fields.get(3)
+                .and_then(|time| NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%S").ok()),
+            index_read: fields.get(4)
+                .filter(|read| !read.is_empty())
+                .map(|read| read.to_string()),
+        };
+        self.current_stream()?.b2b_details.push(detail);
+        Ok(())
+    }
+}
+
+/// Read the contents of a NEM12 file. Values are converted
+/// to kilowatt / hours.
+pub fn parse_nem12(text: &str) -> Result<Nem12, Nem12Error> {
+    let mut parser = Parser {
+        line: 0,
+        streams: Vec::new(),
+        multiplier: Decimal::from(1),
+    };
+
+    for (index, line) in text.lines().enumerate() {
+        parser.line = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        match fields[0] {
+            "100" => {
+                if parser.field(&fields, 1)? != "NEM12" {
+                    return Err(parser.error("not a NEM12 file"));
+                }
+            },
+            "200" => parser.record_200(&fields)?,
+            "300" => parser.record_300(&fields)?,
+            "400" => parser.record_400(&fields)?,
+            "500" => parser.record_500(&fields)?,
+            "900" => break,
+            other => return Err(parser.error(&format!("unknown record {}", other))),
+        }
+    }
+
+    Ok(Nem12 { streams: parser.streams })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 3 hour intervals keep the test data short.
+    fn sample() -> String {
+        let mut text = String::new();
+        text.push_str("100,NEM12,201910010000,MDP1,RETAILER\n");
+        text.push_str("200,6001234567,E1B1,1,E1,N1,METER1,kWh,180,\n");
+        text.push_str("300,20191001,1,1,1,1,2,2,2,2,A,,,20191002000000,\n");
+        text.push_str("300,20191002,1,1,1,1,1,1,1,1,V,,,20191003000000,\n");
+        text.push_str("400,1,2,A,,\n");
+        text.push_str("400,3,4,E52,52,\n");
+        text.push_str("400,5,8,N,,\n");
+        text.push_str("200,6001234567,E1B1,2,B1,N2,METER1,Wh,180,\n");
+        text.push_str("300,20191001,0,0,0,500,1500,0,0,0,A,,,20191002000000,\n");
+        text.push_str("500,O,S01234567,20191002093000,012345\n");
+        text.push_str("900\n");
+        text
+    }
+
+    #[test]
+    fn parse_nem12_ok() {
+        let nem12 = parse_nem12(&sample()).unwrap();
+        assert_eq!(nem12.streams.len(), 2);
+
+        let imports = &nem12.streams[0];
+        assert_eq!(imports.nmi, "6001234567");
+        assert!(imports.is_import());
+        assert_eq!(imports.interval_minutes, 180);
+        assert_eq!(imports.days.len(), 2);
+        assert_eq!(imports.days[1].quality[2], Quality::Estimated);
+        assert_eq!(imports.days[1].quality[5], Quality::Null);
+        assert_eq!(imports.days[1].reason_code, Some(52));
+
+        let exports = &nem12.streams[1];
+        assert!(exports.is_export());
+        assert_eq!(exports.days[0].values[4], Decimal::new(15, 1).into());
+        assert_eq!(exports.b2b_details[0].index_read, Some("012345".to_string()));
+    }
+
+    #[test]
+    fn intervals_combine_streams() {
+        let intervals = parse_nem12(&sample()).unwrap().intervals();
+        // 8 on the first day and 4 that aren't null on the second.
+        assert_eq!(intervals.len(), 12);
+        assert_eq!(intervals[4].start, NaiveDate::from_ymd(2019, 10, 1).and_hms(12, 0, 0));
+        assert_eq!(intervals[4].imports, KilowattHours::from(2));
+        assert_eq!(intervals[4].exports, Decimal::new(15, 1).into());
+
+        // Intervals 3 and 4 of the second day were estimated.
+        let estimated: Vec<bool> = intervals[8..].iter().map(|i| i.estimated).collect();
+        assert_eq!(estimated, vec![false, false, true, true]);
+        assert!(!intervals[4].estimated);
+    }
+
+    #[test]
+    fn parse_nem12_errors() {
+        assert_eq!(parse_nem12("100,NEM13,x\n").unwrap_err().line, 1);
+        let text = "100,NEM12\n300,20191001,1\n";
+        assert_eq!(parse_nem12(text).unwrap_err().line, 2);
+        let text = "100,NEM12\n200,1,E1,1,E1,N1,M,kWh,180\n300,20191001,1,1,1\n";
+        assert_eq!(parse_nem12(text).unwrap_err().line, 3);
+    }
+}
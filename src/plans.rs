@@ -0,0 +1,482 @@
+use std::fmt;
+
+use crate::calc::{ Block, BlockPeriod, Tariffs };
+use crate::config::{ parse_sections, ConfigError, Section };
+use crate::decimal::Decimal;
+use crate::intervals::{ parse_window, Interval, TouWindow };
+use crate::readings::{ DatedChange, DiurnalChange };
+use crate::units::{ Dollars, DollarsPerKwh, KilowattHours };
+
+/// The length of year costs are scaled to.
+const DAYS_PER_YEAR: i64 = 365;
+
+/// Shares of time of use periods may be this far from one.
+const SHARE_TOLERANCE: Decimal = Decimal::new(1, 3);
+
+/// A time of use period such as peak or off-peak.
+#[derive(Debug, PartialEq)]
+pub struct TouPeriod {
+    pub name: String,
+    /// Charged per kilowatt / hour imported in this period.
+    pub rate: DollarsPerKwh,
+    /// The fraction of imports expected to fall in this period.
+    pub share: Decimal,
+    /// When the period applies, so its share can be found from
+    /// interval data. Empty for the period covering the rest of the
+    /// day, or if only the share is known.
+    pub hours: Vec<TouWindow>,
+}
+
+/// A retailer's offer.
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    pub name: String,
+    /// Charged per day regardless of use.
+    pub supply_charge: Dollars,
+    /// Import and feed-in rates, including any blocks.
+    pub tariffs: Tariffs,
+    /// Time of use periods. When there are any they are used
+    /// for imports in place of the import rates in the tariffs.
+    pub time_of_use: Vec<TouPeriod>,
+}
+
+/// What a plan would have cost over a year.
+pub struct PlanCost {
+    pub name: String,
+    /// Daily supply charges.
+    pub supply: Dollars,
+    /// Charges for energy imported.
+    pub imports: Dollars,
+    /// Credit for energy exported.
+    pub feed_in: Dollars,
+    /// True if imports were split between time of use
+    /// periods using interval data rather than the plan's shares.
+    pub measured_shares: bool,
+}
+
+impl PlanCost {
+    /// The amount left to pay after the feed-in credit.
+    pub fn net(&self) -> Dollars {
+        self.supply + self.imports - self.feed_in
+    }
+}
+
+impl Plan {
+    /// The cost of a single day with the given usage, splitting
+    /// imports between time of use periods by the plan's shares.
+    pub fn daily_cost(&self, change: &DiurnalChange) -> PlanCost {
+        let shares: Vec<Decimal> = self.time_of_use.iter().map(|period| period.share).collect();
+        self.cost_with_shares(change, &shares)
+    }
+
+    // The cost of a day with imports split between the
+    // time of use periods by the given shares.
+    fn cost_with_shares(&self, change: &DiurnalChange, shares: &[Decimal]) -> PlanCost {
+        let imports = if self.time_of_use.is_empty() {
+            self.tariffs.import_cost(KilowattHours::ZERO, change.imports)
+        } else {
+            self.time_of_use.iter()
+                .zip(shares.iter())
+                .map(|(period, share)| change.imports * *share * period.rate)
+                .sum()
+        };
+
+        PlanCost {
+            name: self.name.clone(),
+            supply: self.supply_charge,
+            imports,
+            feed_in: self.tariffs.export_credit(change.exports),
+            measured_shares: false,
+        }
+    }
+
+    /// The share of imports in each time of use period going by
+    /// when each interval starts. None if the plan doesn't give
+    /// the periods' hours or nothing was imported.
+    pub fn interval_shares(&self, intervals: &[Interval]) -> Option<Vec<Decimal>> {
+        if self.time_of_use.iter().all(|period| period.hours.is_empty()) {
+            return None;
+        }
+        let rest = self.time_of_use.iter().position(|period| period.hours.is_empty())?;
+
+        let mut imports = vec![KilowattHours::ZERO; self.time_of_use.len()];
+        for interval in intervals.iter() {
+            let time = interval.start.time();
+            let index = self.time_of_use.iter()
+                .position(|period| period.hours.iter().any(|window| window.contains(time)))
+                .unwrap_or(rest);
+            imports[index] += interval.imports;
+        }
+
+        let total: KilowattHours = imports.iter().sum();
+        if total <= KilowattHours::ZERO {
+            return None;
+        }
+        Some(imports.iter().map(|kwh| kwh.value() / total.value()).collect())
+    }
+
+    /// Replay a series of changes against the plan and scale the
+    /// result to a full year. Imports are split between time of use
+    /// periods using the intervals if they can be, otherwise by the
+    /// plan's shares.
+    pub fn annual_cost(&self, changes: &[DatedChange], intervals: &[Interval]) -> PlanCost {
+        let measured = self.interval_shares(intervals);
+        let shares = measured.clone().unwrap_or_else(|| {
+            self.time_of_use.iter().map(|period| period.share).collect()
+        });
+
+        let mut total = PlanCost {
+            name: self.name.clone(),
+            supply: Dollars::ZERO,
+            imports: Dollars::ZERO,
+            feed_in: Dollars::ZERO,
+            measured_shares: measured.is_some(),
+        };
+        let mut days = 0;
+
+        for dated in changes.iter() {
+            let daily = self.cost_with_shares(&dated.change, &shares);
+            let span = dated.days_spanned();
+            total.supply += daily.supply * span;
+            total.imports += daily.imports * span;
+            total.feed_in += daily.feed_in * span;
+            days += span;
+        }
+
+        if days > 0 {
+            total.supply = total.supply * DAYS_PER_YEAR / days;
+            total.imports = total.imports * DAYS_PER_YEAR / days;
+            total.feed_in = total.feed_in * DAYS_PER_YEAR / days;
+        }
+        total
+    }
+}
+
+/// Work out the annual cost of each plan, cheapest first, using
+/// any intervals over the same time for time of use plans.
+pub fn rank_plans(plans: &[Plan], changes: &[DatedChange], intervals: &[Interval]) -> Vec<PlanCost> {
+    let mut costs: Vec<PlanCost> = plans.iter()
+        .map(|plan| plan.annual_cost(changes, intervals))
+        .collect();
+    costs.sort_by_key(|cost| cost.net());
+    costs
+}
+
+// Read the required number for a key or complain.
+pub(crate) fn required(section: &Section, key: &str) -> Result<Decimal, ConfigError> {
+    match section.get_number(key)? {
+        Some(number) => Ok(number),
+        None => Err(ConfigError {
+            line: section.line,
+            message: format!("plan {} has no {}", section.name, key),
+        }),
+    }
+}
+
+// Read a "rate share" pair for a time of use period,
+// followed by any HH:MM-HH:MM windows it applies in.
+fn tou_period(line: usize, name: &str, value: &str) -> Result<TouPeriod, ConfigError> {
+    let error = || ConfigError {
+        line,
+        message: format!("tou.{} should be a rate, a share of imports and any hours", name),
+    };
+    let fields: Vec<&str> = value.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|field| !field.is_empty())
+        .collect();
+    if fields.len() < 2 {
+        return Err(error());
+    }
+    let numbers: Vec<Option<Decimal>> = fields[..2].iter()
+        .map(|n| n.parse::<Decimal>().ok())
+        .collect();
+    let hours = fields[2..].iter()
+        .map(|range| parse_window(name, range))
+        .collect::<Option<Vec<TouWindow>>>()
+        .ok_or_else(error)?;
+
+    match numbers.as_slice() {
+        [Some(rate), Some(share)] => Ok(TouPeriod {
+            name: name.to_string(),
+            rate: (*rate).into(),
+            share: *share,
+            hours,
+        }),
+        _ => Err(error()),
+    }
+}
+
+// Read comma separated "kWh rate" pairs for blocks.
+fn blocks(section: &Section, key: &str) -> Result<Vec<Block>, ConfigError> {
+    let (line, value) = match section.entries.iter().find(|(_, k, _)| k == key) {
+        Some((line, _, value)) => (*line, value),
+        None => return Ok(Vec::new()),
+    };
+
+    value.split(',')
+        .map(|block| {
+            let numbers: Vec<Option<Decimal>> = block.split_whitespace()
+                .map(|n| n.parse::<Decimal>().ok())
+                .collect();
+            match numbers.as_slice() {
+                [Some(kwh), Some(rate)] => Ok(Block { kwh: (*kwh).into(), rate: (*rate).into() }),
+                _ => Err(ConfigError {
+                    line,
+                    message: format!("{} should be a list of kWh and rate pairs", key),
+                }),
+            }
+        })
+        .collect()
+}
+
+// Read how often blocks start again.
+fn block_period(section: &Section) -> Result<BlockPeriod, ConfigError> {
+    match section.get("block_period") {
+        None | Some("daily") => Ok(BlockPeriod::Daily),
+        Some("quarterly") => Ok(BlockPeriod::Billing(Decimal::from(DAYS_PER_YEAR) / 4)),
+        Some(_) => match section.get_number("block_period")? {
+            Some(days) if days > Decimal::ZERO => Ok(BlockPeriod::Billing(days)),
+            _ => Err(ConfigError {
+                line: section.line,
+                message: "block_period should be daily, quarterly or a number of days"
+                    .to_string(),
+            }),
+        },
+    }
+}
+
+// Read a plan from its section of a config file.
+pub(crate) fn section_to_plan(section: &Section) -> Result<Plan, ConfigError> {
+    let mut time_of_use = Vec::new();
+    for (line, key, value) in section.entries.iter() {
+        if let Some(name) = key.strip_prefix("tou.") {
+            time_of_use.push(tou_period(*line, name, value)?);
+        }
+    }
+
+    let import_blocks = blocks(section, "import_blocks")?;
+
+    let import = if time_of_use.is_empty() {
+        required(section, "import")?.into()
+    } else {
+        let shares: Decimal = time_of_use.iter().map(|period| period.share).sum();
+        if (shares - Decimal::from(1)).abs() > SHARE_TOLERANCE {
+            return Err(ConfigError {
+                line: section.line,
+                message: format!("shares of imports for plan {} add up to {}",
+                                 section.name, shares),
+            });
+        }
+        if !import_blocks.is_empty() {
+            return Err(ConfigError {
+                line: section.line,
+                message: format!("plan {} has both time of use and import blocks",
+                                 section.name),
+            });
+        }
+        let without_hours = time_of_use.iter().filter(|period| period.hours.is_empty()).count();
+        if without_hours != time_of_use.len() && without_hours != 1 {
+            return Err(ConfigError {
+                line: section.line,
+                message: format!("plan {} should give hours for all but one time of use \
+                                  period, which covers the rest of the day", section.name),
+            });
+        }
+        DollarsPerKwh::ZERO
+    };
+
+    Ok(Plan {
+        name: section.name.clone(),
+        supply_charge: section.get_number("supply")?.unwrap_or(Decimal::ZERO).into(),
+        tariffs: section_tariffs(section, import)?,
+        time_of_use,
+    })
+}
+
+/// Read the feed-in rate and any blocks from a section, using 
+/// the given rate for imports after the import blocks.
+pub(crate) fn section_tariffs(section: &Section,
+                              import: DollarsPerKwh) -> Result<Tariffs, ConfigError> {
+    Ok(Tariffs {
+        import,
+        export: section.get_number("feed_in")?.unwrap_or(Decimal::ZERO).into(),
+        import_blocks: blocks(section, "import_blocks")?,
+        export_blocks: blocks(section, "feed_in_blocks")?,
+        block_period: block_period(section)?,
+    })
+}
+
+/// Read plans from a file where each plan is a section. When time
+/// of use periods give their hours, interval data is used to split
+/// imports between them and the shares are only a fallback.
+///
+/// ```text
+/// [Flat plan]
+/// supply = 1.03    # dollars per day
+/// import = 0.2575  # dollars per kWh
+/// feed_in = 0.07
+///
+/// [Time of use plan]
+/// supply = 1.10
+/// feed_in = 0.05
+/// tou.peak = 0.45 0.35 07:00-09:00 16:00-21:00   # rate, share of imports and hours
+/// tou.off_peak = 0.15 0.65  # the rest of the day
+///
+/// [Block plan]
+/// import = 0.28               # rate after the blocks
+/// import_blocks = 10 0.32     # kWh and rate, comma separated
+/// feed_in = 0.05
+/// feed_in_blocks = 8 0.20     # premium rate capped at 8 kWh
+/// block_period = daily        # or quarterly, or a number of days
+/// ```
+pub fn parse_plans(text: &str) -> Result<Vec<Plan>, ConfigError> {
+    parse_sections(text)?.iter()
+        .map(section_to_plan)
+        .collect()
+}
+
+/// Allow a PlanCost object to be passed to println!() etc.
+impl fmt::Display for PlanCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<30} supply ${:>8.2}  imports ${:>8.2}  feed-in ${:>8.2}  net ${:>8.2}",
+               self.name, self.supply, self.imports, self.feed_in, self.net())?;
+        if self.measured_shares {
+            write!(f, "  (time of use from interval data)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use crate::decimal::dec;
+    use crate::units::{ dollars, kwh, per_kwh };
+
+    const PLANS: &str = "
+        [Flat]
+        supply = 1.0
+        import = 0.30
+        feed_in = 0.10
+
+        [Time of use]
+        supply = 1.5
+        feed_in = 0.05
+        tou.peak = 0.40 0.25
+        tou.off_peak = 0.20 0.75
+
+        [Blocks]
+        import = 0.20
+        import_blocks = 5 0.40, 5 0.30
+        feed_in = 0.05
+        feed_in_blocks = 4 0.20
+        block_period = quarterly
+    ";
+
+    fn changes() -> Vec<DatedChange> {
+        vec![DatedChange {
+            start: NaiveDate::from_ymd(2019, 1, 1),
+            end: NaiveDate::from_ymd(2019, 1, 11),
+            change: DiurnalChange { generation: kwh(20.0), exports: kwh(10.0), imports: kwh(10.0) },
+            estimated: false,
+        }]
+    }
+
+    #[test]
+    fn parse_plans_ok() {
+        let plans = parse_plans(PLANS).unwrap();
+        assert_eq!(plans.len(), 3);
+        assert_eq!(plans[0].tariffs, Tariffs::flat(per_kwh(0.30), per_kwh(0.10)));
+        assert_eq!(plans[1].supply_charge, dollars(1.5));
+        assert_eq!(plans[1].time_of_use.len(), 2);
+        assert_eq!(plans[2].tariffs.import_blocks,
+                   vec![Block { kwh: kwh(5.0), rate: per_kwh(0.40) },
+                        Block { kwh: kwh(5.0), rate: per_kwh(0.30) }]);
+        assert_eq!(plans[2].tariffs.block_period, BlockPeriod::Billing(dec(91.25)));
+    }
+
+    #[test]
+    fn parse_plans_bad_shares() {
+        let text = "[Bad]\ntou.peak = 0.4 0.5\ntou.off_peak = 0.2 0.4";
+        assert!(parse_plans(text).is_err());
+    }
+
+    #[test]
+    fn parse_plans_missing_import() {
+        assert!(parse_plans("[Bad]\nsupply = 1.0").is_err());
+    }
+
+    #[test]
+    fn daily_cost_time_of_use() {
+        let plans = parse_plans(PLANS).unwrap();
+        let cost = plans[1].daily_cost(&changes()[0].change);
+        // 2.5 kWh at 0.40 and 7.5 kWh at 0.20.
+        assert_eq!(cost.imports, dollars(2.5));
+        assert_eq!(cost.feed_in, dollars(0.5));
+    }
+
+    #[test]
+    fn time_of_use_from_intervals() {
+        let text = "[Time of use]\nfeed_in = 0.05\n\
+                    tou.peak = 0.40 0.25 16:00-21:00\ntou.off_peak = 0.20 0.75";
+        let plans = parse_plans(text).unwrap();
+        assert_eq!(plans[0].time_of_use[0].hours.len(), 1);
+
+        // Half the imports fall in the peak window rather than a quarter.
+        let interval = |hour, imports| Interval {
+            start: NaiveDate::from_ymd(2019, 1, 1).and_hms(hour, 0, 0),
+            minutes: 30,
+            imports: kwh(imports),
+            exports: KilowattHours::ZERO,
+            estimated: false,
+        };
+        let intervals = vec![interval(17, 1.0), interval(2, 0.5), interval(12, 0.5)];
+        assert_eq!(plans[0].interval_shares(&intervals), Some(vec![dec(0.5), dec(0.5)]));
+
+        // 5 kWh at 0.40 and 5 kWh at 0.20 for 365 days.
+        let cost = plans[0].annual_cost(&changes(), &intervals);
+        assert_eq!(cost.imports, dollars(3.0 * 365.0));
+        assert!(cost.measured_shares);
+
+        let cost = plans[0].annual_cost(&changes(), &[]);
+        assert_eq!(cost.imports, dollars(2.5 * 365.0));
+        assert!(!cost.measured_shares);
+        assert_eq!(parse_plans(PLANS).unwrap()[1].interval_shares(&intervals), None);
+
+        let text = "[Bad]\ntou.peak = 0.40 0.25 16:00-21:00\ntou.off_peak = 0.20 0.5\n\
+                    tou.shoulder = 0.30 0.25";
+        assert!(parse_plans(text).is_err());
+        assert!(parse_plans("[Bad]\ntou.peak = 0.40 1.0 16-21").is_err());
+    }
+
+    #[test]
+    fn parse_plans_bad_blocks() {
+        assert!(parse_plans("[Bad]\nimport = 0.2\nimport_blocks = 5").is_err());
+        let text = "[Bad]\ntou.peak = 0.4 1.0\nimport_blocks = 5 0.3";
+        assert!(parse_plans(text).is_err());
+    }
+
+    #[test]
+    fn daily_cost_blocks() {
+        let text = "[Blocks]\nimport = 0.20\nimport_blocks = 4 0.40\n\
+                    feed_in = 0.05\nfeed_in_blocks = 4 0.20";
+        let plans = parse_plans(text).unwrap();
+        let cost = plans[0].daily_cost(&changes()[0].change);
+        // 4 kWh at 0.40 and 6 kWh at 0.20.
+        assert_eq!(cost.imports, dollars(2.8));
+        // 4 kWh at 0.20 and 6 kWh at 0.05.
+        assert_eq!(cost.feed_in, dollars(1.1));
+    }
+
+    #[test]
+    fn rank_plans_cheapest_first() {
+        let plans = parse_plans(PLANS).unwrap();
+        let plans = &plans[..2];
+        let ranked = rank_plans(plans, &changes(), &[]);
+        // Flat: 1.0 + 3.0 - 1.0 = 3.0 a day.
+        // Time of use: 1.5 + 2.5 - 0.5 = 3.5 a day.
+        assert_eq!(ranked[0].name, "Flat");
+        assert_eq!(ranked[0].net(), dollars(3.0 * 365.0));
+        assert_eq!(ranked[1].name, "Time of use");
+    }
+}
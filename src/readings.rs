@@ -1,18 +1,100 @@
+use std::fmt;
+use std::str::FromStr;
 use chrono::{ NaiveDate };
 
+use crate::units::KilowattHours;
+
+/// Where a reading came from, and so how far it can be trusted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Source {
+    /// Read off the meter and inverter.
+    #[default]
+    Actual,
+    /// Guessed, such as when a reading was missed.
+    Estimated,
+    /// Worked out from the readings either side.
+    Interpolated,
+    /// Brought in from a file or a bill, named by a word such as "nem12".
+    Imported(String),
+}
+
+impl Source {
+    /// True for readings that weren't measured.
+    pub fn is_estimate(&self) -> bool {
+        matches!(self, Source::Estimated | Source::Interpolated)
+    }
+}
+
+/// Allow a Source object to be passed to println!() etc. Written as
+/// "actual", "estimated", "interpolated" or "imported:NAME".
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Actual => write!(f, "actual"),
+            Source::Estimated => write!(f, "estimated"),
+            Source::Interpolated => write!(f, "interpolated"),
+            Source::Imported(name) => write!(f, "imported:{}", name),
+        }
+    }
+}
+
+/// Read a source as it is written. Imported sources must be named
+/// with a single word.
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Source, String> {
+        match text {
+            "actual" => Ok(Source::Actual),
+            "estimated" => Ok(Source::Estimated),
+            "interpolated" => Ok(Source::Interpolated),
+            _ => match text.strip_prefix("imported:") {
+                Some(name) if !name.is_empty() && !name.contains(char::is_whitespace) =>
+                    Ok(Source::Imported(name.to_string())),
+                _ => Err(format!("unknown source {}", text)),
+            },
+        }
+    }
+}
+
 /// A collection of readings for a given date. 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Reading {
     /// The date the readings were made.
     pub date: NaiveDate,
     /// The total generated energy from the inverter in kilowatt / hours.
-    pub generation: f32,
+    pub generation: KilowattHours,
     /// The total amount of energy exported to the grid 
     /// from the electricity meter in kilowatt / hours.
-    pub exports: f32,
+    pub exports: KilowattHours,
     /// The total amount of energy imported from 
     /// the grid by the electricity meter in kilowatt / hours.. 
-    pub imports: f32,
+    pub imports: KilowattHours,
+    /// Where the reading came from.
+    pub source: Source,
+    /// Anything worth remembering about the reading.
+    pub note: String,
+}
+
+impl Reading {
+    /// An actual reading with no note.
+    pub fn new(date: NaiveDate, generation: KilowattHours, exports: KilowattHours,
+               imports: KilowattHours) -> Reading {
+        Reading { date, generation, exports, imports, source: Source::Actual, note: String::new() }
+    }
+}
+
+/// Readings on each of the dates for a steady 10 kWh generated, 5
+/// exported and 5 imported each day, counting from the first date.
+#[cfg(test)]
+pub fn steady_readings(dates: &[NaiveDate]) -> Vec<Reading> {
+    dates.iter()
+        .map(|date| {
+            let days = date.signed_duration_since(dates[0]).num_days();
+            Reading::new(*date, KilowattHours::from(10 * days),
+                         KilowattHours::from(5 * days), KilowattHours::from(5 * days))
+        })
+        .collect()
 }
 
 /// Two readings, the first being earlier than the second.
@@ -22,28 +104,37 @@ pub struct ReadingPair {
 }
 
 impl ReadingPair {
-    pub fn days_spanned(&self) -> f32 {
+    pub fn days_spanned(&self) -> i64 {
         let duration = self.second.date.signed_duration_since(self.first.date);
-        let days_spanned = duration.num_days();
-        days_spanned as f32
+        duration.num_days()
+    }
+
+    /// True if either reading is estimated or interpolated.
+    pub fn depends_on_estimates(&self) -> bool {
+        self.first.source.is_estimate() || self.second.source.is_estimate()
     }
 }
 
 /// Amounts of energy from dusk on one day to dusk
 /// on the next. May be an average depending on how
 /// often you read the meter.
+#[derive(Debug, Clone)]
 pub struct DiurnalChange {
     /// The inverter generation in kilowatt / hours.
-    pub generation: f32,
+    pub generation: KilowattHours,
     /// The energy exported to the grid in kilowatt / hours.
-    pub exports: f32,
+    pub exports: KilowattHours,
     /// The energy imported from the grid in kilowatt /hours.
-    pub imports: f32,
+    pub imports: KilowattHours,
 }
 
 /// Given two readings on different days, calculate the 
-/// change in the values per day.
-pub fn find_change(pair: &ReadingPair) -> DiurnalChange {
+/// change in the values per day. None unless the second
+/// reading is after the first.
+pub fn find_change(pair: &ReadingPair) -> Option<DiurnalChange> {
+    if pair.days_spanned() <= 0 {
+        return None;
+    }
 
     // Get the difference.
     let generation = pair.second.generation - pair.first.generation;
@@ -55,38 +146,220 @@ pub fn find_change(pair: &ReadingPair) -> DiurnalChange {
     let exports = exports / pair.days_spanned();
     let imports = imports / pair.days_spanned();
 
-    DiurnalChange {
+    Some(DiurnalChange {
         generation,
         exports,
         imports, 
+    })
+}
+
+/// The average daily change between two readings
+/// along with the dates it covers.
+pub struct DatedChange {
+    /// The date of the earlier reading.
+    pub start: NaiveDate,
+    /// The date of the later reading.
+    pub end: NaiveDate,
+    /// The average daily change over the period.
+    pub change: DiurnalChange,
+    /// True if either reading is estimated or interpolated.
+    pub estimated: bool,
+}
+
+impl DatedChange {
+    pub fn days_spanned(&self) -> i64 {
+        self.end.signed_duration_since(self.start).num_days()
     }
 }
 
+/// Given readings sorted by date, find the change between
+/// each reading and the next. Readings sharing a date are skipped.
+pub fn find_changes(readings: &[Reading]) -> Vec<DatedChange> {
+    readings.windows(2)
+        .filter_map(|pair| {
+            let pair = ReadingPair {
+                first: pair[0].clone(),
+                second: pair[1].clone(),
+            };
+            find_change(&pair).map(|change| DatedChange {
+                start: pair.first.date,
+                end: pair.second.date,
+                change,
+                estimated: pair.depends_on_estimates(),
+            })
+        })
+        .collect()
+}
+
+/// How many days from either end of a period the readings
+/// used for it may be.
+pub const MAX_DAYS_FROM_PERIOD: i64 = 14;
+
+// The reading whose date is closest to the given date, if
+// there is one close enough.
+fn nearest_reading(readings: &[Reading], date: NaiveDate) -> Option<&Reading> {
+    let distance = |reading: &Reading| reading.date.signed_duration_since(date).num_days().abs();
+    readings.iter()
+        .min_by_key(|reading| distance(reading))
+        .filter(|reading| distance(reading) <= MAX_DAYS_FROM_PERIOD)
+}
+
+/// Choose the readings nearest to the start and end of a period,
+/// giving None if there aren't two distinct readings to use within
+/// `MAX_DAYS_FROM_PERIOD` days of its ends.
+pub fn pair_for_period(readings: &[Reading], start: NaiveDate, end: NaiveDate)
+    -> Option<ReadingPair> {
+    let first = nearest_reading(readings, start)?;
+    let second = nearest_reading(readings, end)?;
+
+    if first.date >= second.date {
+        return None;
+    }
+
+    Some(ReadingPair {
+        first: first.clone(),
+        second: second.clone(),
+    })
+}
+
+/// Check a new reading makes sense, given the reading before it if
+/// there is one. The meter registers only ever count up.
+pub fn validate_reading(reading: &Reading, previous: Option<&Reading>) -> Result<(), String> {
+    let registers = [("generation", reading.generation),
+                     ("exports", reading.exports),
+                     ("imports", reading.imports)];
+
+    for (name, value) in registers.iter() {
+        if value.is_negative() {
+            return Err(format!("{} of {} is not a meter reading", name, value));
+        }
+    }
+
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+
+    if reading.date <= previous.date {
+        return Err(format!("date {} is not after the previous reading on {}",
+                           reading.date, previous.date));
+    }
+
+    let earlier = [previous.generation, previous.exports, previous.imports];
+    for ((name, value), before) in registers.iter().zip(earlier.iter()) {
+        if value < before {
+            return Err(format!("{} went backwards from {:.1} to {:.1}", name, before, value));
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_find_change() {
-        let first = Reading {
-            date: NaiveDate::from_ymd(2001, 1, 1),
-            generation: 10.0,
-            exports: 7.0,
-            imports: 2.0,
+    fn validate_reading_checks() {
+        let reading = |day, generation: i64, imports: i64| {
+            Reading::new(NaiveDate::from_ymd(2001, 1, day), generation.into(), 1.into(), imports.into())
         };
 
-        let second = Reading {
-            date: NaiveDate::from_ymd(2001, 1, 5),
-            generation: 30.0,
-            exports: 19.0,
-            imports: 6.0, 
-        };
+        assert!(validate_reading(&reading(1, 10, 5), None).is_ok());
+        assert!(validate_reading(&reading(2, 11, 5), Some(&reading(1, 10, 5))).is_ok());
+        assert!(validate_reading(&reading(1, 11, 5), Some(&reading(1, 10, 5))).is_err());
+        assert!(validate_reading(&reading(2, 9, 5), Some(&reading(1, 10, 5))).is_err());
+        assert!(validate_reading(&reading(2, 11, 4), Some(&reading(1, 10, 5))).is_err());
+        assert!(validate_reading(&reading(2, -1, 5), None).is_err());
+    }
+
+    #[test]
+    fn test_find_change() {
+        let first = Reading::new(NaiveDate::from_ymd(2001, 1, 1), KilowattHours::from(10),
+                                 KilowattHours::from(7), KilowattHours::from(2));
+
+        let second = Reading::new(NaiveDate::from_ymd(2001, 1, 5), KilowattHours::from(30),
+                                  KilowattHours::from(19), KilowattHours::from(6));
 
         let pair = ReadingPair { first, second };
 
-        let change = find_change(&pair);
-        assert_eq!(change.generation, 5.0);
-        assert_eq!(change.exports, 3.0);
-        assert_eq!(change.imports, 1.0);
+        let change = find_change(&pair).unwrap();
+        assert_eq!(change.generation, KilowattHours::from(5));
+        assert_eq!(change.exports, KilowattHours::from(3));
+        assert_eq!(change.imports, KilowattHours::from(1));
+
+        // No change without any days between the readings.
+        let same_day = ReadingPair { first: pair.first.clone(), second: pair.first.clone() };
+        assert!(find_change(&same_day).is_none());
+        let backwards = ReadingPair { first: pair.second.clone(), second: pair.first };
+        assert!(find_change(&backwards).is_none());
+    }
+
+    #[test]
+    fn find_changes_skips_same_date() {
+        let reading = |day, generation: i64| Reading::new(NaiveDate::from_ymd(2001, 1, day),
+                                                          generation.into(),
+                                                          KilowattHours::ZERO, KilowattHours::ZERO);
+        let readings = vec![reading(1, 10), reading(3, 20),
+                            reading(3, 20), reading(4, 25)];
+
+        let changes = find_changes(&readings);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].change.generation, KilowattHours::from(5));
+        assert_eq!(changes[1].start, NaiveDate::from_ymd(2001, 1, 3));
+        assert_eq!(changes[1].change.generation, KilowattHours::from(5));
+        assert!(!changes[1].estimated);
+    }
+
+    #[test]
+    fn sources() {
+        for source in [Source::Actual, Source::Estimated, Source::Interpolated,
+                       Source::Imported("nem12".to_string())].iter() {
+            assert_eq!(source.to_string().parse(), Ok(source.clone()));
+        }
+        assert_eq!(Source::Imported("nem12".to_string()).to_string(), "imported:nem12");
+        assert!("imported:".parse::<Source>().is_err());
+        assert!("imported:two words".parse::<Source>().is_err());
+
+        let mut pair = ReadingPair {
+            first: Reading::new(NaiveDate::from_ymd(2001, 1, 1), KilowattHours::ZERO,
+                                KilowattHours::ZERO, KilowattHours::ZERO),
+            second: Reading::new(NaiveDate::from_ymd(2001, 1, 2), KilowattHours::ZERO,
+                                 KilowattHours::ZERO, KilowattHours::ZERO),
+        };
+        pair.first.source = Source::Imported("nem12".to_string());
+        assert!(!pair.depends_on_estimates());
+        pair.second.source = Source::Interpolated;
+        assert!(pair.depends_on_estimates());
+    }
+
+    #[test]
+    fn pair_for_period_nearest() {
+        let reading = |month, day| Reading::new(NaiveDate::from_ymd(2001, month, day),
+                                                KilowattHours::ZERO,
+                                                KilowattHours::ZERO, KilowattHours::ZERO);
+        let readings = vec![reading(2, 20), reading(3, 2), 
+                            reading(3, 16), reading(4, 3)];
+
+        let pair = pair_for_period(&readings,
+                                   NaiveDate::from_ymd(2001, 3, 1),
+                                   NaiveDate::from_ymd(2001, 3, 31)).unwrap();
+        assert_eq!(pair.first.date, NaiveDate::from_ymd(2001, 3, 2));
+        assert_eq!(pair.second.date, NaiveDate::from_ymd(2001, 4, 3));
+
+        let none = pair_for_period(&readings,
+                                   NaiveDate::from_ymd(2002, 3, 1),
+                                   NaiveDate::from_ymd(2002, 3, 31));
+        assert!(none.is_none());
+
+        // The readings either side of May are too far from it.
+        let too_far = pair_for_period(&readings,
+                                      NaiveDate::from_ymd(2001, 5, 1),
+                                      NaiveDate::from_ymd(2001, 5, 31));
+        assert!(too_far.is_none());
+        let near_enough = pair_for_period(&readings,
+                                          NaiveDate::from_ymd(2001, 2, 10),
+                                          NaiveDate::from_ymd(2001, 4, 17)).unwrap();
+        assert_eq!(near_enough.first.date, NaiveDate::from_ymd(2001, 2, 20));
     }
 }
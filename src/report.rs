@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use chrono::{ Datelike, Duration, NaiveDate };
+
+use crate::calc::{ calculate, Tariffs };
+use crate::charts::{ escape, svg_bar_chart, svg_line_chart, usage_series, PALETTE };
+use crate::readings::{ find_change, find_changes, DatedChange, Reading, ReadingPair };
+use crate::units::{ Dollars, KilowattHours };
+
+const MONTH_NAMES: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                                 "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 760px; color: #222; }
+.cards { display: flex; flex-wrap: wrap; gap: 1em; margin: 1em 0; }
+.card { border: 1px solid #ddd; border-radius: 6px; padding: 0.8em 1em; min-width: 150px; }
+.card .value { font-size: 1.6em; font-weight: bold; }
+.card .name { color: #666; }
+table { border-collapse: collapse; width: 100%; margin: 1em 0; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.3em 0.5em; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+svg { display: block; margin: 1em 0; }
+";
+
+/// Energy and savings for the days of a month covered by readings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonthlySummary {
+    pub year: i32,
+    pub month: u32,
+    /// How many days of the month the readings cover.
+    pub days: u32,
+    pub generation: KilowattHours,
+    pub imports: KilowattHours,
+    pub exports: KilowattHours,
+    pub self_consumption: KilowattHours,
+    /// Savings in dollars.
+    pub savings: Dollars,
+    /// True if any of the days come from estimated readings.
+    pub estimated: bool,
+}
+
+impl MonthlySummary {
+    /// A short name such as "Mar 2020".
+    pub fn name(&self) -> String {
+        format!("{} {}", MONTH_NAMES[self.month as usize - 1], self.year)
+    }
+}
+
+/// Total up each period's daily averages into the months their days
+/// fall in. As in the seasonal split, a period covers the days after
+/// its first reading up to and including the day of its second.
+pub fn monthly_summaries(changes: &[DatedChange], tariffs: &Tariffs) -> Vec<MonthlySummary> {
+    let mut months: BTreeMap<(i32, u32), MonthlySummary> = BTreeMap::new();
+
+    for change in changes.iter() {
+        let calculation = calculate(change.change.clone(), tariffs.clone());
+        let mut day = change.start + Duration::days(1);
+        while day <= change.end {
+            let summary = months.entry((day.year(), day.month()))
+                .or_insert_with(|| MonthlySummary {
+                    year: day.year(),
+                    month: day.month(),
+                    days: 0,
+                    generation: KilowattHours::ZERO,
+                    imports: KilowattHours::ZERO,
+                    exports: KilowattHours::ZERO,
+                    self_consumption: KilowattHours::ZERO,
+                    savings: Dollars::ZERO,
+                    estimated: false,
+                });
+            summary.days += 1;
+            summary.generation += calculation.generation_kwh;
+            summary.imports += calculation.grid_import_kwh;
+            summary.exports += calculation.grid_export_kwh;
+            summary.self_consumption += calculation.self_consumption.kwh;
+            summary.savings += calculation.savings.total;
+            summary.estimated |= change.estimated;
+            day += Duration::days(1);
+        }
+    }
+
+    months.into_values().collect()
+}
+
+// A summary card with a big value and its name.
+fn card(value: String, name: &str) -> String {
+    format!("<div class=\"card\"><div class=\"value\">{}</div><div class=\"name\">{}</div></div>\n",
+            value, name)
+}
+
+/// A self contained HTML page summarising the readings, sorted by date,
+/// with charts drawn inline so it works offline. None if there
+/// aren't two readings to compare.
+pub fn html_report(readings: &[Reading], tariffs: &Tariffs, generated: NaiveDate) -> Option<String> {
+    let pair = ReadingPair {
+        first: readings.first()?.clone(),
+        second: readings.last()?.clone(),
+    };
+    let overall = calculate(find_change(&pair)?, tariffs.clone());
+    let changes = find_changes(readings);
+    let months = monthly_summaries(&changes, tariffs);
+    let total_savings: Dollars = months.iter().map(|month| month.savings).sum();
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Energy report</title>\n");
+    html.push_str(&format!("<style>{}</style>\n</head>\n<body>\n", STYLE));
+    html.push_str("<h1>Energy report</h1>\n");
+    html.push_str(&format!("<p>{} to {}, {} readings. Generated {}.</p>\n",
+                           pair.first.date.format("%d/%m/%Y"), pair.second.date.format("%d/%m/%Y"),
+                           readings.len(), generated.format("%d/%m/%Y")));
+
+    html.push_str("<div class=\"cards\">\n");
+    html.push_str(&card(format!("{:.1} kWh", overall.generation_kwh), "Generated a day"));
+    html.push_str(&card(format!("{:.1} kWh", overall.total_consumption_kwh), "Used a day"));
+    html.push_str(&card(format!("{:.0}%", overall.self_consumption.fraction_of_generation.percent()),
+                        "Of generation used at home"));
+    html.push_str(&card(format!("${:.2}", overall.savings.total), "Saved a day"));
+    html.push_str(&card(format!("${:.2}", total_savings), "Saved in total"));
+    html.push_str("</div>\n");
+
+    html.push_str("<h2>History</h2>\n");
+    html.push_str(&svg_line_chart("Daily average kWh", &usage_series(&changes, tariffs),
+                                  760.0, 260.0));
+    let savings: Vec<(String, f32)> = months.iter()
+        .map(|month| (month.name(), month.savings.to_f32()))
+        .collect();
+    html.push_str(&svg_bar_chart("Savings by month ($)", &savings, PALETTE[2], 760.0, 220.0));
+
+    html.push_str("<h2>Months</h2>\n<table>\n");
+    html.push_str("<tr><th>Month</th><th>Days</th><th>Generated</th><th>Imported</th>\
+                   <th>Exported</th><th>Self consumed</th><th>Saved</th></tr>\n");
+    for month in months.iter() {
+        let marker = if month.estimated { "*" } else { "" };
+        html.push_str(&format!("<tr><td>{}{}</td><td>{}</td><td>{:.1}</td><td>{:.1}</td>\
+                                <td>{:.1}</td><td>{:.1}</td><td>${:.2}</td></tr>\n",
+                               escape(&month.name()), marker, month.days, month.generation,
+                               month.imports, month.exports, month.self_consumption, month.savings));
+    }
+    html.push_str("</table>\n<p>Energy is in kWh.");
+    if months.iter().any(|month| month.estimated) {
+        html.push_str(" * Worked out from estimated readings.");
+    }
+    html.push_str("</p>\n</body>\n</html>\n");
+
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::readings::{ steady_readings, Source };
+
+    // Periods either side of the end of January.
+    fn readings() -> Vec<Reading> {
+        steady_readings(&[NaiveDate::from_ymd(2020, 1, 25), NaiveDate::from_ymd(2020, 2, 4),
+                          NaiveDate::from_ymd(2020, 2, 10)])
+    }
+
+    #[test]
+    fn monthly_summaries_split_periods() {
+        let months = monthly_summaries(&find_changes(&readings()), &Tariffs::flat(2.into(), 1.into()));
+
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[0].name(), "Jan 2020");
+        assert_eq!(months[0].days, 6);
+        assert_eq!(months[0].generation, KilowattHours::from(60));
+        assert_eq!(months[1].days, 10);
+        assert_eq!(months[1].savings, Dollars::from(150));
+        assert!(!months[0].estimated);
+    }
+
+    #[test]
+    fn html_report_is_self_contained() {
+        let html = html_report(&readings(), &Tariffs::flat(2.into(), 1.into()),
+                               NaiveDate::from_ymd(2020, 3, 1)).unwrap();
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<div class=\"value\">$240.00</div>"));
+        assert!(html.contains("<td>Feb 2020</td><td>10</td><td>100.0</td>"));
+        assert_eq!(html.matches("<svg ").count(), 2);
+        assert!(!html.contains("src="));
+        assert!(!html.contains("href="));
+        assert!(!html.contains("estimated"));
+
+        let mut estimated = readings();
+        estimated[2].source = Source::Estimated;
+        let html = html_report(&estimated, &Tariffs::flat(2.into(), 1.into()),
+                               NaiveDate::from_ymd(2020, 3, 1)).unwrap();
+        assert!(html.contains("<td>Jan 2020</td>"));
+        assert!(html.contains("<td>Feb 2020*</td>"));
+        assert!(html.contains("* Worked out from estimated readings."));
+
+        assert_eq!(html_report(&readings()[..1], &Tariffs::flat(2.into(), 1.into()),
+                               NaiveDate::from_ymd(2020, 3, 1)), None);
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use chrono::{ Datelike, Duration, NaiveDate };
+
+use crate::calc::{ calculate, Calculation, Tariffs };
+use crate::config::{ parse_sections, ConfigError, Section };
+use crate::plans::{ required, section_tariffs };
+use crate::readings::{ find_change, ReadingPair };
+use crate::units::{ Dollars, KilowattHours };
+
+/// A part of the year with its own tariffs, recurring every year.
+#[derive(Debug, PartialEq)]
+pub struct Season {
+    pub name: String,
+    /// The first (month, day) of the season.
+    pub start: (u32, u32),
+    /// The last (month, day) of the season. May be earlier in the
+    /// calendar than the start for seasons spanning new year.
+    pub end: (u32, u32),
+    pub tariffs: Tariffs,
+}
+
+impl Season {
+    /// True if the date falls within the season in any year.
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        let day = (date.month(), date.day());
+        if self.start <= self.end {
+            self.start <= day && day <= self.end
+        } else {
+            day >= self.start || day <= self.end
+        }
+    }
+}
+
+/// Seasons and the tariffs used for days outside all of them.
+pub struct SeasonalTariffs {
+    pub seasons: Vec<Season>,
+    pub otherwise: Tariffs,
+}
+
+/// The name given to days outside every season.
+pub const REST_OF_YEAR: &str = "Rest of year";
+
+impl SeasonalTariffs {
+    // The name and tariffs in effect on a date.
+    fn for_date(&self, date: NaiveDate) -> (&str, &Tariffs) {
+        match self.seasons.iter().find(|season| season.contains(date)) {
+            Some(season) => (&season.name, &season.tariffs),
+            None => (REST_OF_YEAR, &self.otherwise),
+        }
+    }
+}
+
+/// The part of a period falling within one season.
+pub struct SeasonSegment {
+    pub season: String,
+    /// The first day of the segment.
+    pub start: NaiveDate,
+    /// The last day of the segment.
+    pub end: NaiveDate,
+    pub days: i64,
+    /// Daily averages using the season's tariffs.
+    pub calculation: Calculation,
+    /// Dollars paid for imports over the segment.
+    pub cost: Dollars,
+    /// Dollars saved over the segment.
+    pub savings: Dollars,
+}
+
+/// A period split up by season.
+pub struct SeasonalCalculation {
+    pub segments: Vec<SeasonSegment>,
+}
+
+impl SeasonalCalculation {
+    /// Dollars paid for imports over the whole period.
+    pub fn cost(&self) -> Dollars {
+        self.segments.iter().map(|segment| segment.cost).sum()
+    }
+
+    /// Dollars saved over the whole period.
+    pub fn savings(&self) -> Dollars {
+        self.segments.iter().map(|segment| segment.savings).sum()
+    }
+
+    /// The days, cost and savings for each season, in the
+    /// order the seasons first appear.
+    pub fn by_season(&self) -> Vec<(String, i64, Dollars, Dollars)> {
+        let mut totals: Vec<(String, i64, Dollars, Dollars)> = Vec::new();
+        for segment in self.segments.iter() {
+            match totals.iter_mut().find(|total| total.0 == segment.season) {
+                Some(total) => {
+                    total.1 += segment.days;
+                    total.2 += segment.cost;
+                    total.3 += segment.savings;
+                },
+                None => totals.push((segment.season.clone(), segment.days,
+                                     segment.cost, segment.savings)),
+            }
+        }
+        totals
+    }
+}
+
+/// Split the days covered by a pair of readings at season boundaries
+/// and work out each part with that season's tariffs. The energy is
+/// assumed to be spread evenly over the days between the readings.
+/// None unless the second reading is after the first.
+pub fn calculate_seasonal(pair: &ReadingPair,
+                          tariffs: &SeasonalTariffs) -> Option<SeasonalCalculation> {
+    let change = find_change(pair)?;
+    let mut segments: Vec<SeasonSegment> = Vec::new();
+
+    // A reading covers the day it was taken, not the day before.
+    let mut date = pair.first.date + Duration::days(1);
+    while date <= pair.second.date {
+        let (name, season_tariffs) = tariffs.for_date(date);
+
+        let mut end = date;
+        while end < pair.second.date && tariffs.for_date(end + Duration::days(1)).0 == name {
+            end += Duration::days(1);
+        }
+
+        let days = end.signed_duration_since(date).num_days() + 1;
+        let calculation = calculate(change.clone(), season_tariffs.clone());
+        segments.push(SeasonSegment {
+            season: name.to_string(),
+            start: date,
+            end,
+            days,
+            cost: season_tariffs.import_cost(KilowattHours::ZERO, change.imports) * days,
+            savings: calculation.savings.total * days,
+            calculation,
+        });
+
+        date = end + Duration::days(1);
+    }
+
+    Some(SeasonalCalculation { segments })
+}
+
+// Read a DD-MM day of the year.
+fn day_of_year(section: &Section, key: &str) -> Result<(u32, u32), ConfigError> {
+    let error = || ConfigError {
+        line: section.line,
+        message: format!("season {} needs {} as DD-MM", section.name, key),
+    };
+    let value = section.get(key).ok_or_else(error)?;
+    // Any leap year will do to check the day exists.
+    let date = NaiveDate::parse_from_str(&format!("{}-2000", value), "%d-%m-%Y")
+        .map_err(|_e| error())?;
+    Ok((date.month(), date.day()))
+}
+
+/// Read seasons from a file where each season is a section.
+/// The rates are given the same way as for plans.
+///
+/// ```text
+/// [Summer]
+/// from = 01-12     # DD-MM
+/// to = 28-02
+/// import = 0.31
+/// feed_in = 0.07
+/// ```
+pub fn parse_seasons(text: &str) -> Result<Vec<Season>, ConfigError> {
+    parse_sections(text)?.iter()
+        .map(|section| Ok(Season {
+            name: section.name.clone(),
+            start: day_of_year(section, "from")?,
+            end: day_of_year(section, "to")?,
+            tariffs: section_tariffs(section, required(section, "import")?.into())?,
+        }))
+        .collect()
+}
+
+/// Allow a SeasonalCalculation object to be passed to println!() etc.
+impl fmt::Display for SeasonalCalculation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in self.segments.iter() {
+            writeln!(f, "{} to {}: {}",
+                     segment.start.format("%d/%m/%Y"),
+                     segment.end.format("%d/%m/%Y"),
+                     segment.season)?;
+        }
+        writeln!(f)?;
+
+        let cost = self.cost();
+        let savings = self.savings();
+        writeln!(f, "{:<20} {:>6} {:>10} {:>6} {:>10} {:>6}",
+                 "Season", "Days", "Cost", "%", "Savings", "%")?;
+        for (season, days, season_cost, season_savings) in self.by_season() {
+            writeln!(f, "{:<20} {:>6} {:>10.2} {:>5.1}% {:>10.2} {:>5.1}%",
+                     season, days,
+                     season_cost, (season_cost / cost).percent(),
+                     season_savings, (season_savings / savings).percent())?;
+        }
+        writeln!(f, "{:<20} {:>6} {:>10.2} {:>6} {:>10.2}",
+                 "Total", "", cost, "", savings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::{ dollars, kwh, per_kwh };
+    use crate::readings::Reading;
+
+    fn summer() -> Season {
+        Season {
+            name: "Summer".to_string(),
+            start: (12, 1),
+            end: (2, 28),
+            tariffs: Tariffs::flat(per_kwh(0.5), per_kwh(0.1)),
+        }
+    }
+
+    #[test]
+    fn season_contains() {
+        let season = summer();
+        assert!(season.contains(NaiveDate::from_ymd(2019, 12, 1)));
+        assert!(season.contains(NaiveDate::from_ymd(2020, 1, 15)));
+        assert!(season.contains(NaiveDate::from_ymd(2020, 2, 28)));
+        assert!(!season.contains(NaiveDate::from_ymd(2020, 2, 29)));
+        assert!(!season.contains(NaiveDate::from_ymd(2019, 11, 30)));
+    }
+
+    #[test]
+    fn split_at_season_boundary() {
+        let tariffs = SeasonalTariffs {
+            seasons: vec![summer()],
+            otherwise: Tariffs::flat(per_kwh(0.25), per_kwh(0.1)),
+        };
+        let reading = |year, month, day, total| Reading::new(NaiveDate::from_ymd(year, month, day),
+                                                             kwh(total),
+                                                             KilowattHours::ZERO, kwh(total));
+        // 10 days, 1 kWh imported and self consumed each day.
+        let pair = ReadingPair {
+            first: reading(2019, 11, 25, 0.0),
+            second: reading(2019, 12, 5, 10.0),
+        };
+
+        let seasonal = calculate_seasonal(&pair, &tariffs).unwrap();
+        assert_eq!(seasonal.segments.len(), 2);
+        assert_eq!(seasonal.segments[0].season, REST_OF_YEAR);
+        assert_eq!(seasonal.segments[0].days, 5);
+        assert_eq!(seasonal.segments[1].season, "Summer");
+        assert_eq!(seasonal.segments[1].start, NaiveDate::from_ymd(2019, 12, 1));
+        assert_eq!(seasonal.segments[1].days, 5);
+
+        assert_eq!(seasonal.segments[0].cost, dollars(1.25));
+        assert_eq!(seasonal.segments[1].cost, dollars(2.5));
+        assert_eq!(seasonal.savings(), dollars(3.75));
+    }
+
+    #[test]
+    fn parse_seasons_ok() {
+        let text = "[Summer]\nfrom = 01-12\nto = 28-02\nimport = 0.5\nfeed_in = 0.1\n";
+        assert_eq!(parse_seasons(text).unwrap(), vec![summer()]);
+        assert!(parse_seasons("[Bad]\nfrom = 31-02\nto = 01-03\nimport = 0.5").is_err());
+    }
+}
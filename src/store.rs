@@ -0,0 +1,396 @@
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use chrono::NaiveDate;
+use chrono::naive::{ MAX_DATE, MIN_DATE };
+
+use crate::readings::{ validate_reading, Reading, Source };
+
+// The first line of a readings file. Files from before readings
+// had a source and note leave them out.
+const CSV_HEADER: &str = "date,generation,exports,imports,source,note";
+const OLD_CSV_HEADER: &str = "date,generation,exports,imports";
+
+/// Somewhere readings are kept, one for each date.
+///
+/// Stores only need to add, update, delete and look up readings;
+/// the rest is worked out from those, though a store can do it
+/// more quickly if it knows how.
+pub trait ReadingStore {
+    /// Add a new reading, or give an error if there is already
+    /// one for its date.
+    fn add_reading(&mut self, reading: &Reading) -> Result<(), String>;
+
+    /// Replace the values of the stored reading with the same date.
+    fn update_reading(&mut self, reading: &Reading);
+
+    /// Remove the reading for a date, giving false if there wasn't one.
+    fn delete_reading(&mut self, date: NaiveDate) -> bool;
+
+    /// Get the reading for a given date or none if it doesn't exist.
+    fn get_reading_for_date(&self, date: NaiveDate) -> Option<Reading>;
+
+    /// The readings from `start` up to and including `end`, earliest first.
+    fn readings_between(&self, start: NaiveDate, end: NaiveDate) -> Vec<Reading>;
+
+    /// Get every reading, earliest first.
+    fn all_readings(&self) -> Vec<Reading> {
+        self.readings_between(MIN_DATE, MAX_DATE)
+    }
+
+    fn most_recent_reading(&self) -> Option<Reading> {
+        self.all_readings().pop()
+    }
+
+    /// The latest reading made before the given date.
+    fn reading_before(&self, date: NaiveDate) -> Option<Reading> {
+        date.pred_opt().and_then(|day| self.readings_between(MIN_DATE, day).pop())
+    }
+
+    fn number_of_readings(&self) -> i64 {
+        self.all_readings().len() as i64
+    }
+
+    /// Add a reading, or replace the one already stored for its date,
+    /// as long as it follows on from the reading before it.
+    fn save_reading(&mut self, reading: &Reading) -> Result<(), String> {
+        validate_reading(reading, self.reading_before(reading.date).as_ref())?;
+
+        if self.get_reading_for_date(reading.date).is_some() {
+            self.update_reading(reading);
+        } else {
+            self.add_reading(reading)?;
+        }
+        Ok(())
+    }
+
+    /// Save several readings, earliest first, keeping none of them
+    /// if any don't follow on from the reading before.
+    fn save_readings(&mut self, readings: &[Reading]) -> Result<(), String> {
+        let mut readings = readings.to_vec();
+        readings.sort_by_key(|reading| reading.date);
+
+        // Try them on a copy first so a bad one leaves the store alone.
+        let mut copy = MemoryStore { readings: self.all_readings() };
+        for reading in readings.iter() {
+            copy.save_reading(reading)
+                .map_err(|e| format!("{}: {}", reading.date.format("%d/%m/%Y"), e))?;
+        }
+        for reading in readings.iter() {
+            self.save_reading(reading)?;
+        }
+        Ok(())
+    }
+}
+
+/// The error given when adding a reading for a date that already has one.
+pub fn already_stored(date: NaiveDate) -> String {
+    format!("there is already a reading for {}", date.format("%d/%m/%Y"))
+}
+
+/// Readings kept in memory, for tests and short lived use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryStore {
+    // Sorted by date, with no two on the same day.
+    readings: Vec<Reading>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    // Where the reading for a date is, or would go.
+    fn position(&self, date: NaiveDate) -> Result<usize, usize> {
+        self.readings.binary_search_by_key(&date, |reading| reading.date)
+    }
+}
+
+impl ReadingStore for MemoryStore {
+    fn add_reading(&mut self, reading: &Reading) -> Result<(), String> {
+        match self.position(reading.date) {
+            Ok(_) => Err(already_stored(reading.date)),
+            Err(index) => {
+                self.readings.insert(index, reading.clone());
+                Ok(())
+            },
+        }
+    }
+
+    fn update_reading(&mut self, reading: &Reading) {
+        if let Ok(index) = self.position(reading.date) {
+            self.readings[index] = reading.clone();
+        }
+    }
+
+    fn delete_reading(&mut self, date: NaiveDate) -> bool {
+        match self.position(date) {
+            Ok(index) => {
+                self.readings.remove(index);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn get_reading_for_date(&self, date: NaiveDate) -> Option<Reading> {
+        self.position(date).ok().map(|index| self.readings[index].clone())
+    }
+
+    fn readings_between(&self, start: NaiveDate, end: NaiveDate) -> Vec<Reading> {
+        self.readings.iter()
+            .filter(|reading| reading.date >= start && reading.date <= end)
+            .cloned()
+            .collect()
+    }
+
+    fn all_readings(&self) -> Vec<Reading> {
+        self.readings.clone()
+    }
+
+    fn number_of_readings(&self) -> i64 {
+        self.readings.len() as i64
+    }
+}
+
+/// A problem found in a readings file.
+#[derive(Debug, PartialEq)]
+pub struct ReadingsFileError {
+    /// The line the problem was found on, starting from one.
+    pub line: usize,
+    pub message: String,
+}
+
+/// Allow a ReadingsFileError object to be passed to println!() etc.
+impl fmt::Display for ReadingsFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Read readings written by `readings_csv`, sorting them by date.
+pub fn parse_readings_csv(text: &str) -> Result<Vec<Reading>, ReadingsFileError> {
+    let mut store = MemoryStore::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && (line == CSV_HEADER || line == OLD_CSV_HEADER)) {
+            continue;
+        }
+        let error = |message: String| ReadingsFileError { line: index + 1, message };
+
+        // The note is last and may have commas of its own.
+        let fields: Vec<&str> = line.splitn(6, ',').map(|field| field.trim()).collect();
+        if fields.len() != 4 && fields.len() != 6 {
+            return Err(error(format!("expected 6 fields but found {}", fields.len())));
+        }
+        let date = NaiveDate::parse_from_str(fields[0], "%Y-%m-%d")
+            .map_err(|_| error(format!("invalid date {}", fields[0])))?;
+        let mut values = Vec::new();
+        for field in fields[1..4].iter() {
+            values.push(field.parse().map_err(|_| error(format!("invalid number {}", field)))?);
+        }
+        let source = match fields.get(4) {
+            Some(source) => source.parse().map_err(error)?,
+            None => Source::Actual,
+        };
+        let note = fields.get(5).map_or(String::new(), |note| note.to_string());
+        store.add_reading(&Reading { date, generation: values[0], exports: values[1], imports: values[2],
+                                     source, note })
+            .map_err(|_e| error(format!("a second reading for {}", date)))?;
+    }
+    Ok(store.readings)
+}
+
+/// Write readings as CSV with a line for each, in the order given.
+/// Notes are kept to one line.
+pub fn readings_csv(readings: &[Reading]) -> String {
+    let mut text = format!("{}\n", CSV_HEADER);
+    for reading in readings.iter() {
+        let note: Vec<&str> = reading.note.lines().map(|line| line.trim()).collect();
+        text.push_str(&format!("{},{},{},{},{},{}\n", reading.date.format("%Y-%m-%d"),
+                               reading.generation, reading.exports, reading.imports,
+                               reading.source, note.join(" ")));
+    }
+    text
+}
+
+/// Readings kept in a CSV file, one line for each date, so they
+/// can be read, edited and kept in version control. The whole
+/// file is written again after each change.
+pub struct FileStore {
+    path: String,
+    readings: MemoryStore,
+}
+
+impl FileStore {
+    /// Open the readings in a file, which is created on the
+    /// first change if it doesn't exist.
+    pub fn open(path: &str) -> Result<FileStore, String> {
+        let readings = match fs::read_to_string(path) {
+            Ok(text) => parse_readings_csv(&text).map_err(|e| format!("{}: {}", path, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Could not read {}: {}", path, e)),
+        };
+        Ok(FileStore { path: path.to_string(), readings: MemoryStore { readings } })
+    }
+
+    // Write every reading back to the file.
+    fn write(&self) {
+        fs::write(&self.path, readings_csv(&self.readings.readings))
+            .expect("Failed to write readings file.");
+    }
+}
+
+impl ReadingStore for FileStore {
+    fn add_reading(&mut self, reading: &Reading) -> Result<(), String> {
+        self.readings.add_reading(reading)?;
+        self.write();
+        Ok(())
+    }
+
+    fn update_reading(&mut self, reading: &Reading) {
+        self.readings.update_reading(reading);
+        self.write();
+    }
+
+    fn delete_reading(&mut self, date: NaiveDate) -> bool {
+        let deleted = self.readings.delete_reading(date);
+        if deleted {
+            self.write();
+        }
+        deleted
+    }
+
+    fn get_reading_for_date(&self, date: NaiveDate) -> Option<Reading> {
+        self.readings.get_reading_for_date(date)
+    }
+
+    fn readings_between(&self, start: NaiveDate, end: NaiveDate) -> Vec<Reading> {
+        self.readings.readings_between(start, end)
+    }
+
+    fn all_readings(&self) -> Vec<Reading> {
+        self.readings.all_readings()
+    }
+
+    fn number_of_readings(&self) -> i64 {
+        self.readings.number_of_readings()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    #[cfg(feature = "sqlite")]
+    use crate::database::Database;
+    use crate::units::{ kwh, KilowattHours };
+
+    fn reading(day: u32, generation: f64) -> Reading {
+        Reading::new(NaiveDate::from_ymd(2020, 1, day), kwh(generation),
+                     kwh(generation / 2.0), KilowattHours::from(i64::from(day)))
+    }
+
+    // What every store must do, whatever it keeps readings in.
+    fn conforms(store: &mut dyn ReadingStore) {
+        assert_eq!(store.number_of_readings(), 0);
+        assert_eq!(store.most_recent_reading(), None);
+
+        // Added out of order, given back in order.
+        store.add_reading(&reading(10, 20.5)).unwrap();
+        store.add_reading(&reading(1, 10.0)).unwrap();
+        store.add_reading(&reading(20, 31.25)).unwrap();
+        assert_eq!(store.number_of_readings(), 3);
+        assert_eq!(store.all_readings(), vec![reading(1, 10.0), reading(10, 20.5), reading(20, 31.25)]);
+        assert_eq!(store.most_recent_reading(), Some(reading(20, 31.25)));
+
+        // A second reading for a date is turned away and the first kept.
+        let date = reading(10, 0.0).date;
+        assert_eq!(store.add_reading(&reading(10, 99.0)), Err(already_stored(date)));
+        assert_eq!(store.get_reading_for_date(date), Some(reading(10, 20.5)));
+        assert_eq!(store.number_of_readings(), 3);
+
+        assert_eq!(store.get_reading_for_date(reading(10, 0.0).date), Some(reading(10, 20.5)));
+        assert_eq!(store.get_reading_for_date(reading(11, 0.0).date), None);
+
+        let (start, end) = (reading(1, 0.0).date, reading(10, 0.0).date);
+        assert_eq!(store.readings_between(start, end), vec![reading(1, 10.0), reading(10, 20.5)]);
+        assert_eq!(store.readings_between(end, start), vec![]);
+        assert_eq!(store.reading_before(end), Some(reading(1, 10.0)));
+        assert_eq!(store.reading_before(start), None);
+
+        store.update_reading(&reading(10, 21.0));
+        assert_eq!(store.get_reading_for_date(end), Some(reading(10, 21.0)));
+        assert_eq!(store.number_of_readings(), 3);
+
+        assert!(store.delete_reading(end));
+        assert!(!store.delete_reading(end));
+        assert_eq!(store.all_readings(), vec![reading(1, 10.0), reading(20, 31.25)]);
+
+        // Saving checks against the reading before.
+        assert!(store.save_reading(&reading(15, 9.0)).is_err());
+        assert_eq!(store.save_reading(&reading(15, 15.0)), Ok(()));
+        assert_eq!(store.save_reading(&reading(15, 16.0)), Ok(()));
+        assert_eq!(store.get_reading_for_date(reading(15, 0.0).date), Some(reading(15, 16.0)));
+        assert_eq!(store.number_of_readings(), 3);
+
+        // Saving several keeps none of them if any are wrong.
+        assert!(store.save_readings(&[reading(15, 17.0), reading(20, 12.0)]).is_err());
+        assert_eq!(store.get_reading_for_date(reading(15, 0.0).date), Some(reading(15, 16.0)));
+        assert_eq!(store.save_readings(&[reading(20, 32.0), reading(15, 17.0)]), Ok(()));
+        assert_eq!(store.all_readings(), vec![reading(1, 10.0), reading(15, 17.0), reading(20, 32.0)]);
+    }
+
+    // A file name no other test will use.
+    fn temporary_file(name: &str) -> String {
+        env::temp_dir().join(format!("nrgaccounts-{}-{}.csv", process::id(), name))
+            .to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn memory_store_conforms() {
+        conforms(&mut MemoryStore::new());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn database_conforms() {
+        conforms(&mut Database::open(":memory:"));
+    }
+
+    #[test]
+    fn file_store_conforms() {
+        let path = temporary_file("conforms");
+        conforms(&mut FileStore::open(&path).unwrap());
+
+        // What was written is read back the next time.
+        let reopened = FileStore::open(&path).unwrap();
+        assert_eq!(reopened.all_readings(), vec![reading(1, 10.0), reading(15, 17.0), reading(20, 32.0)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn readings_file() {
+        let readings = vec![reading(1, 10.0), reading(2, 12.125)];
+        let text = readings_csv(&readings);
+        assert_eq!(text, "date,generation,exports,imports,source,note\n\
+                          2020-01-01,10,5,1,actual,\n\
+                          2020-01-02,12.125,6.0625,2,actual,\n");
+        assert_eq!(parse_readings_csv(&text), Ok(readings));
+
+        let estimate = parse_readings_csv("2020-01-03,1,1,1,estimated,from the bill, roughly\n").unwrap();
+        assert_eq!(estimate[0].source, Source::Estimated);
+        assert_eq!(estimate[0].note, "from the bill, roughly");
+        assert_eq!(parse_readings_csv("2020-01-03,1,1,1,guessed,\n").unwrap_err().line, 1);
+
+        assert_eq!(parse_readings_csv("2020-01-02,1,1,1\n2020-01-01,1,1,1\n").unwrap()[0].date,
+                   NaiveDate::from_ymd(2020, 1, 1));
+        assert_eq!(parse_readings_csv("2020-01-01,1,1\n").unwrap_err().line, 1);
+        assert_eq!(parse_readings_csv("date,generation,exports,imports\n2020-01-01,1,x,1\n"),
+                   Err(ReadingsFileError { line: 2, message: "invalid number x".to_string() }));
+        assert_eq!(parse_readings_csv("2020-01-01,1,1,1\n2020-01-01,2,2,2\n").unwrap_err().message,
+                   "a second reading for 2020-01-01");
+    }
+}
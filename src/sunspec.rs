@@ -0,0 +1,332 @@
+use std::fmt;
+use chrono::NaiveDate;
+
+use crate::decimal::Decimal;
+use crate::modbus::{ ModbusClient, ModbusError, MAX_REGISTERS };
+use crate::readings::{ Reading, Source };
+use crate::units::KilowattHours;
+
+/// Where devices commonly put the start of their SunSpec map.
+pub const BASE_ADDRESSES: [u16; 3] = [40000, 0, 50000];
+
+/// The Modbus port used when none is given.
+pub const DEFAULT_PORT: u16 = 502;
+
+// "SunS" marks the start of the map.
+const MARKER: [u16; 2] = [0x5375, 0x6e53];
+const END_OF_MAP: u16 = 0xffff;
+
+const COMMON_MODEL: u16 = 1;
+const INVERTER_MODELS: [u16; 3] = [101, 102, 103];
+const METER_MODELS: [u16; 4] = [201, 202, 203, 204];
+
+// Offsets from the first register after a model's id and length.
+const COMMON_MANUFACTURER: usize = 0;
+const COMMON_MODEL_NAME: usize = 16;
+const COMMON_SERIAL: usize = 48;
+const COMMON_LENGTH: usize = 64;
+const INVERTER_WH: usize = 22;
+const INVERTER_WH_SF: usize = 24;
+const METER_TOT_WH_EXP: usize = 36;
+const METER_TOT_WH_IMP: usize = 44;
+const METER_TOT_WH_SF: usize = 52;
+
+/// Something went wrong reading a SunSpec device.
+#[derive(Debug)]
+pub enum SunSpecError {
+    Modbus(ModbusError),
+    /// No SunSpec map was found on the device.
+    NotSunSpec,
+    /// The device doesn't have a model we need.
+    MissingModel(&'static str),
+    /// A register we need isn't implemented.
+    NotImplemented(&'static str),
+    /// No unit ids were given to read.
+    NoUnits,
+}
+
+impl fmt::Display for SunSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SunSpecError::Modbus(e) => write!(f, "{}", e),
+            SunSpecError::NotSunSpec => write!(f, "no SunSpec map found"),
+            SunSpecError::MissingModel(model) => write!(f, "no {} model found", model),
+            SunSpecError::NotImplemented(point) => write!(f, "{} is not implemented", point),
+            SunSpecError::NoUnits => write!(f, "no unit ids to read"),
+        }
+    }
+}
+
+impl From<ModbusError> for SunSpecError {
+    fn from(e: ModbusError) -> SunSpecError {
+        SunSpecError::Modbus(e)
+    }
+}
+
+/// Where a model sits in the register map.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelHeader {
+    /// The SunSpec model id, such as 103 for a three phase inverter.
+    pub id: u16,
+    /// The first register after the id and length.
+    pub address: u16,
+    /// The number of registers in the model.
+    pub length: u16,
+}
+
+/// What was read from the inverter and meter in one poll.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SunSpecDevice {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// The inverter's lifetime generation in kilowatt / hours.
+    pub generation: Option<KilowattHours>,
+    /// The meter's lifetime exports in kilowatt / hours.
+    pub exports: Option<KilowattHours>,
+    /// The meter's lifetime imports in kilowatt / hours.
+    pub imports: Option<KilowattHours>,
+}
+
+impl SunSpecDevice {
+    /// Turn the lifetime totals into a reading for the given date.
+    pub fn reading(&self, date: NaiveDate) -> Result<Reading, SunSpecError> {
+        Ok(Reading {
+            date,
+            generation: self.generation.ok_or(SunSpecError::MissingModel("inverter"))?,
+            exports: self.exports.ok_or(SunSpecError::MissingModel("meter"))?,
+            imports: self.imports.ok_or(SunSpecError::MissingModel("meter"))?,
+            source: Source::Actual,
+            note: String::new(),
+        })
+    }
+}
+
+// Read any number of registers, a block at a time.
+fn read_registers(client: &mut ModbusClient, address: u16, count: usize)
+    -> Result<Vec<u16>, SunSpecError> {
+    let mut registers = Vec::with_capacity(count);
+    while registers.len() < count {
+        let block = (count - registers.len()).min(MAX_REGISTERS as usize) as u16;
+        let start = address + registers.len() as u16;
+        registers.extend(client.read_holding_registers(start, block)?);
+    }
+    Ok(registers)
+}
+
+// Registers holding a string, padded with nulls or spaces.
+fn registers_to_string(registers: &[u16]) -> String {
+    let bytes: Vec<u8> = registers.iter()
+        .flat_map(|register| register.to_be_bytes().to_vec())
+        .collect();
+    String::from_utf8_lossy(&bytes)
+        .trim_end_matches(['\0', ' '])
+        .to_string()
+}
+
+// An accumulator scaled to kilowatt / hours. Zero means not implemented,
+// and so does a scale factor too big to make sense of.
+fn scaled_kwh(registers: &[u16], offset: usize, scale_factor: u16,
+              point: &'static str) -> Result<KilowattHours, SunSpecError> {
+    let value = (registers[offset] as u32) << 16 | registers[offset + 1] as u32;
+    if value == 0 || scale_factor == 0x8000 {
+        return Err(SunSpecError::NotImplemented(point));
+    }
+    Decimal::from_scaled(value as i64, scale_factor as i16 as i32 - 3)
+        .map(KilowattHours::from)
+        .ok_or(SunSpecError::NotImplemented(point))
+}
+
+/// Find the SunSpec map and list the models in it.
+pub fn find_models(client: &mut ModbusClient) -> Result<Vec<ModelHeader>, SunSpecError> {
+    let mut base = None;
+    for address in BASE_ADDRESSES.iter() {
+        match client.read_holding_registers(*address, 2) {
+            Ok(registers) if registers == MARKER => {
+                base = Some(*address);
+                break;
+            },
+            Ok(_) | Err(ModbusError::Exception(_)) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut address = base.ok_or(SunSpecError::NotSunSpec)? + 2;
+
+    let mut models = Vec::new();
+    loop {
+        let header = client.read_holding_registers(address, 2)?;
+        if header[0] == END_OF_MAP {
+            return Ok(models);
+        }
+        models.push(ModelHeader {
+            id: header[0],
+            address: address + 2,
+            length: header[1],
+        });
+        address = address.checked_add(2 + header[1])
+            .ok_or(ModbusError::BadResponse("model runs past the last register"))?;
+    }
+}
+
+/// Read the common, inverter and meter models from the device,
+/// filling in whatever it has.
+pub fn read_device(client: &mut ModbusClient, device: &mut SunSpecDevice)
+    -> Result<(), SunSpecError> {
+    for model in find_models(client)?.iter() {
+        let length = model.length as usize;
+
+        if model.id == COMMON_MODEL && length >= COMMON_LENGTH && device.manufacturer.is_none() {
+            let registers = read_registers(client, model.address, COMMON_LENGTH)?;
+            let text = |offset: usize, count: usize| {
+                registers_to_string(&registers[offset..offset + count])
+            };
+            device.manufacturer = Some(text(COMMON_MANUFACTURER, 16));
+            device.model = Some(text(COMMON_MODEL_NAME, 16));
+            device.serial = Some(text(COMMON_SERIAL, 16));
+        } else if INVERTER_MODELS.contains(&model.id) && length > INVERTER_WH_SF
+            && device.generation.is_none() {
+            let registers = read_registers(client, model.address, INVERTER_WH_SF + 1)?;
+            device.generation = Some(scaled_kwh(&registers, INVERTER_WH,
+                                                registers[INVERTER_WH_SF], "inverter WH")?);
+        } else if METER_MODELS.contains(&model.id) && length > METER_TOT_WH_SF
+            && device.imports.is_none() {
+            // Only the first meter is used, which should be the one at the grid.
+            let registers = read_registers(client, model.address, METER_TOT_WH_SF + 1)?;
+            let scale_factor = registers[METER_TOT_WH_SF];
+            device.exports = Some(scaled_kwh(&registers, METER_TOT_WH_EXP,
+                                             scale_factor, "meter TotWhExp")?);
+            device.imports = Some(scaled_kwh(&registers, METER_TOT_WH_IMP,
+                                             scale_factor, "meter TotWhImp")?);
+        }
+    }
+    Ok(())
+}
+
+/// Poll the devices with the given unit ids at a "host" or "host:port"
+/// address. An inverter and its meter often have different unit ids.
+pub fn poll(address: &str, units: &[u8]) -> Result<SunSpecDevice, SunSpecError> {
+    let address = if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", address, DEFAULT_PORT)
+    };
+
+    let first = *units.first().ok_or(SunSpecError::NoUnits)?;
+    let mut client = ModbusClient::connect(address.as_str(), first)?;
+    let mut device = SunSpecDevice::default();
+    for unit in units.iter() {
+        client.set_unit_id(*unit);
+        read_device(&mut client, &mut device)?;
+    }
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::decimal::Decimal;
+    use crate::modbus::simulator;
+
+    // Lay out a SunSpec map at the base address: common,
+    // inverter 103 and meter 203 models.
+    fn sunspec_registers(base: u16) -> HashMap<u16, u16> {
+        let mut map = MARKER.to_vec();
+
+        let mut common = vec![0u16; 66];
+        for (i, pair) in b"Fronius".chunks(2).enumerate() {
+            common[i] = u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]);
+        }
+        for (i, pair) in b"SN12".chunks(2).enumerate() {
+            common[COMMON_SERIAL + i] = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        map.extend_from_slice(&[1, 66]);
+        map.extend(common);
+
+        // 12,345,678 Wh with a scale factor of zero.
+        let mut inverter = vec![0u16; 50];
+        inverter[INVERTER_WH] = 0x00bc;
+        inverter[INVERTER_WH + 1] = 0x614e;
+        map.extend_from_slice(&[103, 50]);
+        map.extend(inverter);
+
+        // 4,567 and 8,910 in units of 10 Wh.
+        let mut meter = vec![0u16; 105];
+        meter[METER_TOT_WH_EXP + 1] = 4567;
+        meter[METER_TOT_WH_IMP + 1] = 8910;
+        meter[METER_TOT_WH_SF] = 1;
+        map.extend_from_slice(&[203, 105]);
+        map.extend(meter);
+
+        map.extend_from_slice(&[END_OF_MAP, 0]);
+
+        map.into_iter().enumerate()
+            .map(|(i, value)| (base + i as u16, value))
+            .collect()
+    }
+
+    #[test]
+    fn find_models_ok() {
+        let address = simulator::serve(sunspec_registers(40000));
+        let mut client = ModbusClient::connect(address.as_str(), 1).unwrap();
+
+        let models = find_models(&mut client).unwrap();
+        let ids: Vec<u16> = models.iter().map(|model| model.id).collect();
+        assert_eq!(ids, vec![1, 103, 203]);
+        assert_eq!(models[1].address, 40000 + 2 + 2 + 66 + 2);
+    }
+
+    #[test]
+    fn poll_reads_totals() {
+        let address = simulator::serve(sunspec_registers(0));
+
+        let device = poll(&address, &[1]).unwrap();
+        assert_eq!(device.manufacturer, Some("Fronius".to_string()));
+        assert_eq!(device.serial, Some("SN12".to_string()));
+        assert_eq!(device.generation, Some(Decimal::new(12345678, 3).into()));
+        assert_eq!(device.exports, Some(Decimal::new(4567, 2).into()));
+        assert_eq!(device.imports, Some(Decimal::new(891, 1).into()));
+
+        let date = NaiveDate::from_ymd(2020, 1, 1);
+        let reading = device.reading(date).unwrap();
+        assert_eq!(reading.date, date);
+        assert_eq!(reading.imports, device.imports.unwrap());
+    }
+
+    #[test]
+    fn poll_without_meter() {
+        let mut registers = sunspec_registers(40000);
+        // End the map after the inverter.
+        registers.insert(40000 + 2 + 2 + 66 + 2 + 50, END_OF_MAP);
+
+        let device = poll(&simulator::serve(registers), &[1]).unwrap();
+        assert!(device.generation.is_some());
+        match device.reading(NaiveDate::from_ymd(2020, 1, 1)) {
+            Err(SunSpecError::MissingModel("meter")) => (),
+            other => panic!("Expected a missing meter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn poll_not_sunspec() {
+        let registers: HashMap<u16, u16> = (0..10).map(|a| (a, 0)).collect();
+        match poll(&simulator::serve(registers), &[1]) {
+            Err(SunSpecError::NotSunSpec) => (),
+            other => panic!("Expected no SunSpec map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn poll_without_units() {
+        match poll("127.0.0.1:1", &[]) {
+            Err(SunSpecError::NoUnits) => (),
+            other => panic!("Expected no units, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn registers_to_string_trims() {
+        assert_eq!(registers_to_string(&[0x4142, 0x4300, 0]), "ABC");
+        assert_eq!(registers_to_string(&[0x4142, 0x2020]), "AB");
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{ Add, AddAssign, Div, Mul, Neg, Sub, SubAssign };
+use std::str::FromStr;
+use chrono::Duration;
+
+use crate::decimal::{ Decimal, ParseDecimalError };
+
+const SECONDS_PER_HOUR: i64 = 3600;
+
+// What every amount of a unit can do: be added to and taken
+// from amounts of the same unit, scaled by plain numbers and
+// compared with another amount as a fraction.
+macro_rules! quantity {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(Decimal);
+
+        impl $name {
+            pub const ZERO: $name = $name(Decimal::ZERO);
+
+            pub const fn new(value: Decimal) -> $name {
+                $name(value)
+            }
+
+            /// The number without its unit.
+            pub fn value(self) -> Decimal {
+                self.0
+            }
+
+            /// The number as a float, for charts.
+            pub fn to_f32(self) -> f32 {
+                self.0.to_f32()
+            }
+
+            pub fn abs(self) -> $name {
+                $name(self.0.abs())
+            }
+
+            pub fn is_negative(self) -> bool {
+                self.0.is_negative()
+            }
+        }
+
+        impl From<Decimal> for $name {
+            fn from(value: Decimal) -> $name {
+                $name(value)
+            }
+        }
+
+        impl From<i64> for $name {
+            fn from(value: i64) -> $name {
+                $name(Decimal::from(value))
+            }
+        }
+
+        impl From<$name> for Decimal {
+            fn from(value: $name) -> Decimal {
+                value.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseDecimalError;
+
+            fn from_str(text: &str) -> Result<$name, ParseDecimalError> {
+                text.parse().map($name)
+            }
+        }
+
+        /// Allow the amount to be passed to println!() etc. The unit
+        /// is left for the caller to write.
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl Add for $name {
+            type Output = $name;
+
+            fn add(self, other: $name) -> $name {
+                $name(self.0 + other.0)
+            }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, other: $name) {
+                self.0 += other.0;
+            }
+        }
+
+        impl Sub for $name {
+            type Output = $name;
+
+            fn sub(self, other: $name) -> $name {
+                $name(self.0 - other.0)
+            }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, other: $name) {
+                self.0 -= other.0;
+            }
+        }
+
+        impl Neg for $name {
+            type Output = $name;
+
+            fn neg(self) -> $name {
+                $name(-self.0)
+            }
+        }
+
+        /// Such as a daily amount over a number of days.
+        impl Mul<i64> for $name {
+            type Output = $name;
+
+            fn mul(self, other: i64) -> $name {
+                $name(self.0 * other)
+            }
+        }
+
+        impl Mul<Decimal> for $name {
+            type Output = $name;
+
+            fn mul(self, other: Decimal) -> $name {
+                $name(self.0 * other)
+            }
+        }
+
+        /// Such as the daily average over a number of days.
+        impl Div<i64> for $name {
+            type Output = $name;
+
+            fn div(self, other: i64) -> $name {
+                $name(self.0 / other)
+            }
+        }
+
+        impl Div<Decimal> for $name {
+            type Output = $name;
+
+            fn div(self, other: Decimal) -> $name {
+                $name(self.0 / other)
+            }
+        }
+
+        impl Div for $name {
+            type Output = Fraction;
+
+            fn div(self, other: $name) -> Fraction {
+                Fraction(self.0.ratio(other.0))
+            }
+        }
+
+        impl Sum for $name {
+            fn sum<I: Iterator<Item = $name>>(iter: I) -> $name {
+                $name(iter.map(|value| value.0).sum())
+            }
+        }
+
+        impl<'a> Sum<&'a $name> for $name {
+            fn sum<I: Iterator<Item = &'a $name>>(iter: I) -> $name {
+                $name(iter.map(|value| value.0).sum())
+            }
+        }
+    };
+}
+
+quantity! {
+    /// An amount of energy in kilowatt / hours.
+    KilowattHours
+}
+
+quantity! {
+    /// An amount of money in dollars.
+    Dollars
+}
+
+quantity! {
+    /// A price for energy in dollars per kilowatt / hour.
+    DollarsPerKwh
+}
+
+quantity! {
+    /// Power in kilowatts.
+    Kilowatts
+}
+
+/// One amount as a share of another of the same unit, where 1
+/// is the whole. NaN or infinite when the whole is zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Fraction(pub f32);
+
+impl Fraction {
+    /// The fraction as a percentage.
+    pub fn percent(self) -> f32 {
+        self.0 * 100.0
+    }
+}
+
+/// Shorthand for tests, as `dec` is for plain decimals.
+#[cfg(test)]
+pub(crate) fn kwh(value: f64) -> KilowattHours {
+    KilowattHours(crate::decimal::dec(value))
+}
+
+#[cfg(test)]
+pub(crate) fn dollars(value: f64) -> Dollars {
+    Dollars(crate::decimal::dec(value))
+}
+
+#[cfg(test)]
+pub(crate) fn per_kwh(value: f64) -> DollarsPerKwh {
+    DollarsPerKwh(crate::decimal::dec(value))
+}
+
+impl Mul<DollarsPerKwh> for KilowattHours {
+    type Output = Dollars;
+
+    fn mul(self, rate: DollarsPerKwh) -> Dollars {
+        Dollars(self.0 * rate.0)
+    }
+}
+
+impl Mul<KilowattHours> for DollarsPerKwh {
+    type Output = Dollars;
+
+    fn mul(self, kwh: KilowattHours) -> Dollars {
+        kwh * self
+    }
+}
+
+impl Div<KilowattHours> for Dollars {
+    type Output = DollarsPerKwh;
+
+    fn div(self, kwh: KilowattHours) -> DollarsPerKwh {
+        DollarsPerKwh(self.0 / kwh.0)
+    }
+}
+
+/// The average power over a time. Panics if the time is zero.
+impl Div<Duration> for KilowattHours {
+    type Output = Kilowatts;
+
+    fn div(self, time: Duration) -> Kilowatts {
+        Kilowatts(self.0 * SECONDS_PER_HOUR / time.num_seconds())
+    }
+}
+
+impl Mul<Duration> for Kilowatts {
+    type Output = KilowattHours;
+
+    fn mul(self, time: Duration) -> KilowattHours {
+        KilowattHours(self.0 * time.num_seconds() / SECONDS_PER_HOUR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn energy_at_a_price() {
+        let kwh = KilowattHours::from(Decimal::new(125, 1));
+        let rate = DollarsPerKwh::from(Decimal::new(2, 1));
+        assert_eq!(kwh * rate, Dollars::from(Decimal::new(25, 1)));
+        assert_eq!(rate * kwh, kwh * rate);
+        assert_eq!(Dollars::from(5) / KilowattHours::from(20), DollarsPerKwh::from(Decimal::new(25, 2)));
+    }
+
+    #[test]
+    fn same_units_add_up() {
+        let daily = KilowattHours::from(3);
+        assert_eq!(daily * 7 - KilowattHours::from(1), KilowattHours::from(20));
+        assert_eq!((daily * 7) / 7, daily);
+        assert_eq!(-daily, KilowattHours::from(-3));
+        let total: Dollars = [Dollars::from(1), Dollars::from(2)].iter().sum();
+        assert_eq!(total, Dollars::from(3));
+        assert_eq!(format!("{:.2}", Dollars::from(Decimal::new(1005, 3))), "1.01");
+        assert_eq!("1.5".parse(), Ok(KilowattHours::from(Decimal::new(15, 1))));
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(KilowattHours::from(1) / KilowattHours::from(4), Fraction(0.25));
+        assert_eq!((Dollars::from(3) / Dollars::from(4)).percent(), 75.0);
+        assert!((KilowattHours::ZERO / KilowattHours::ZERO).0.is_nan());
+    }
+
+    #[test]
+    fn energy_over_time() {
+        let kw = KilowattHours::from(Decimal::new(15, 1)) / Duration::minutes(30);
+        assert_eq!(kw, Kilowatts::from(3));
+        assert_eq!(kw * Duration::hours(2), KilowattHours::from(6));
+    }
+}
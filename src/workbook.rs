@@ -0,0 +1,464 @@
+use chrono::NaiveDate;
+
+use crate::calc::{ Block, BlockPeriod, Tariffs };
+use crate::charts::escape;
+use crate::decimal::Decimal;
+use crate::readings::{ find_changes, DiurnalChange, Reading };
+use crate::report::{ monthly_summaries, MonthlySummary };
+use crate::units::{ DollarsPerKwh, KilowattHours };
+
+// Spreadsheets count days from here, allowing for 1900 wrongly being a leap year.
+const DAY_ZERO: (i32, u32, u32) = (1899, 12, 30);
+
+// Width of every column, in characters.
+const COLUMN_WIDTH: u32 = 16;
+
+// The date and time stored with each file, 1 Jan 1980 in DOS format.
+const ZIP_TIME: u16 = 0;
+const ZIP_DATE: u16 = 0x21;
+
+const MAIN_NAMESPACE: &str = "http://schemas.openxmlformats.org/spreadsheetml/2006/main";
+const RELATIONSHIPS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+/// How a cell's value is shown. Each is a style in the workbook.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    General,
+    /// A day such as 25/01/2020.
+    Date,
+    /// A month such as Jan 2020.
+    Month,
+    /// Kilowatt / hours to three places.
+    Energy,
+    /// Dollars and cents.
+    Dollars,
+    /// Dollars per kilowatt / hour.
+    Rate,
+    /// Bold text for column headings and totals.
+    Heading,
+}
+
+/// A cell in a sheet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Empty,
+    Text(String, Format),
+    Number(Decimal, Format),
+    Date(NaiveDate, Format),
+    /// A formula, without the leading =, and its value so the
+    /// sheet reads correctly before it is recalculated.
+    Formula(String, Decimal, Format),
+}
+
+/// A named sheet of rows of cells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sheet {
+    pub name: String,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+// A row of column headings.
+fn headings(names: &[&str]) -> Vec<Cell> {
+    names.iter().map(|name| Cell::Text(name.to_string(), Format::Heading)).collect()
+}
+
+/// The letters naming a column, counting from zero.
+pub fn column_name(mut column: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.insert(0, b'A' + (column % 26) as u8);
+        if column < 26 {
+            break;
+        }
+        column = column / 26 - 1;
+    }
+    String::from_utf8(name).unwrap()
+}
+
+// The range of a column from `first` to `last`, counting rows from one.
+fn range(column: usize, first: usize, last: usize) -> String {
+    let name = column_name(column);
+    format!("{}{}:{}{}", name, first, name, last)
+}
+
+/// The number a spreadsheet stores for a date.
+pub fn date_serial(date: NaiveDate) -> i64 {
+    let (year, month, day) = DAY_ZERO;
+    date.signed_duration_since(NaiveDate::from_ymd(year, month, day)).num_days()
+}
+
+// Rows for each block of a tariff.
+fn block_rows(direction: &str, blocks: &[Block], rate: DollarsPerKwh) -> Vec<Vec<Cell>> {
+    let mut rows: Vec<Vec<Cell>> = blocks.iter()
+        .enumerate()
+        .map(|(index, block)| vec![
+            Cell::Text(format!("{} block {}", direction, index + 1), Format::General),
+            Cell::Number(block.kwh.into(), Format::Energy),
+            Cell::Number(block.rate.into(), Format::Rate),
+        ])
+        .collect();
+    let name = if blocks.is_empty() { direction.to_string() } else { format!("{} after blocks", direction) };
+    rows.push(vec![Cell::Text(name, Format::General), Cell::Empty, Cell::Number(rate.into(), Format::Rate)]);
+    rows
+}
+
+/// Sheets of the readings, the changes between them, monthly
+/// summaries and the tariffs used. Readings must be sorted by date.
+pub fn energy_workbook(readings: &[Reading], tariffs: &Tariffs) -> Vec<Sheet> {
+    let mut sheets = Vec::new();
+
+    let mut rows = vec![headings(&["Date", "Generation (kWh)", "Exports (kWh)", "Imports (kWh)",
+                                   "Source", "Note"])];
+    for reading in readings.iter() {
+        rows.push(vec![
+            Cell::Date(reading.date, Format::Date),
+            Cell::Number(reading.generation.into(), Format::Energy),
+            Cell::Number(reading.exports.into(), Format::Energy),
+            Cell::Number(reading.imports.into(), Format::Energy),
+            Cell::Text(reading.source.to_string(), Format::General),
+            Cell::Text(reading.note.clone(), Format::General),
+        ]);
+    }
+    sheets.push(Sheet { name: "Readings".to_string(), rows });
+
+    // Days are worked out from the dates, and the totals weight
+    // each period's daily averages by its days.
+    let changes = find_changes(readings);
+    let mut rows = vec![headings(&["Start", "End", "Days", "Generation a day (kWh)",
+                                   "Exports a day (kWh)", "Imports a day (kWh)"])];
+    for (index, change) in changes.iter().enumerate() {
+        let row = index + 2;
+        rows.push(vec![
+            Cell::Date(change.start, Format::Date),
+            Cell::Date(change.end, Format::Date),
+            Cell::Formula(format!("B{}-A{}", row, row), change.days_spanned().into(), Format::General),
+            Cell::Number(change.change.generation.into(), Format::Energy),
+            Cell::Number(change.change.exports.into(), Format::Energy),
+            Cell::Number(change.change.imports.into(), Format::Energy),
+        ]);
+    }
+    if !changes.is_empty() {
+        let last = changes.len() + 1;
+        let days: i64 = changes.iter().map(|change| change.days_spanned()).sum();
+        let average = |value: fn(&DiurnalChange) -> KilowattHours| {
+            let total: KilowattHours = changes.iter()
+                .map(|change| value(&change.change) * change.days_spanned())
+                .sum();
+            (total / days).into()
+        };
+        let weighted = |column: usize| {
+            format!("SUMPRODUCT({},{})/C{}", range(2, 2, last), range(column, 2, last), last + 1)
+        };
+        rows.push(vec![
+            Cell::Text("Total".to_string(), Format::Heading),
+            Cell::Empty,
+            Cell::Formula(format!("SUM({})", range(2, 2, last)), days.into(), Format::General),
+            Cell::Formula(weighted(3), average(|change| change.generation), Format::Energy),
+            Cell::Formula(weighted(4), average(|change| change.exports), Format::Energy),
+            Cell::Formula(weighted(5), average(|change| change.imports), Format::Energy),
+        ]);
+    }
+    sheets.push(Sheet { name: "Changes".to_string(), rows });
+
+    let months = monthly_summaries(&changes, tariffs);
+    let mut rows = vec![headings(&["Month", "Days", "Generated (kWh)", "Imported (kWh)",
+                                   "Exported (kWh)", "Self consumed (kWh)", "Saved"])];
+    for month in months.iter() {
+        rows.push(vec![
+            Cell::Date(NaiveDate::from_ymd(month.year, month.month, 1), Format::Month),
+            Cell::Number(i64::from(month.days).into(), Format::General),
+            Cell::Number(month.generation.into(), Format::Energy),
+            Cell::Number(month.imports.into(), Format::Energy),
+            Cell::Number(month.exports.into(), Format::Energy),
+            Cell::Number(month.self_consumption.into(), Format::Energy),
+            Cell::Number(month.savings.into(), Format::Dollars),
+        ]);
+    }
+    if !months.is_empty() {
+        let last = months.len() + 1;
+        let sum = |value: fn(&MonthlySummary) -> Decimal| months.iter().map(value).sum();
+        let totals = [
+            (sum(|month| i64::from(month.days).into()), Format::General),
+            (sum(|month| month.generation.into()), Format::Energy),
+            (sum(|month| month.imports.into()), Format::Energy),
+            (sum(|month| month.exports.into()), Format::Energy),
+            (sum(|month| month.self_consumption.into()), Format::Energy),
+            (sum(|month| month.savings.into()), Format::Dollars),
+        ];
+        let mut total = vec![Cell::Text("Total".to_string(), Format::Heading)];
+        for (index, (value, format)) in totals.iter().enumerate() {
+            total.push(Cell::Formula(format!("SUM({})", range(index + 1, 2, last)), *value, *format));
+        }
+        rows.push(total);
+    }
+    sheets.push(Sheet { name: "Months".to_string(), rows });
+
+    let mut rows = vec![headings(&["Tariff", "Block (kWh)", "Rate ($/kWh)"])];
+    rows.extend(block_rows("Import", &tariffs.import_blocks, tariffs.import));
+    rows.extend(block_rows("Export", &tariffs.export_blocks, tariffs.export));
+    if let BlockPeriod::Billing(days) = tariffs.block_period {
+        rows.push(vec![Cell::Text("Blocks per billing period (days)".to_string(), Format::General),
+                       Cell::Number(days, Format::General)]);
+    }
+    sheets.push(Sheet { name: "Tariffs".to_string(), rows });
+
+    sheets
+}
+
+// A worksheet part.
+fn sheet_xml(sheet: &Sheet) -> String {
+    let columns = sheet.rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+                           <worksheet xmlns=\"{}\">", MAIN_NAMESPACE);
+    if columns > 0 {
+        xml.push_str(&format!("<cols><col min=\"1\" max=\"{}\" width=\"{}\" customWidth=\"1\"/></cols>",
+                              columns, COLUMN_WIDTH));
+    }
+    xml.push_str("<sheetData>");
+    for (index, row) in sheet.rows.iter().enumerate() {
+        xml.push_str(&format!("<row r=\"{}\">", index + 1));
+        for (column, cell) in row.iter().enumerate() {
+            let reference = format!("{}{}", column_name(column), index + 1);
+            match cell {
+                Cell::Empty => {},
+                Cell::Text(text, format) => xml.push_str(&format!(
+                    "<c r=\"{}\" s=\"{}\" t=\"inlineStr\"><is><t>{}</t></is></c>",
+                    reference, *format as usize, escape(text))),
+                Cell::Number(value, format) => xml.push_str(&format!(
+                    "<c r=\"{}\" s=\"{}\"><v>{}</v></c>", reference, *format as usize, value)),
+                Cell::Date(date, format) => xml.push_str(&format!(
+                    "<c r=\"{}\" s=\"{}\"><v>{}</v></c>", reference, *format as usize, date_serial(*date))),
+                Cell::Formula(formula, value, format) => xml.push_str(&format!(
+                    "<c r=\"{}\" s=\"{}\"><f>{}</f><v>{}</v></c>",
+                    reference, *format as usize, escape(formula), value)),
+            }
+        }
+        xml.push_str("</row>");
+    }
+    xml.push_str("</sheetData></worksheet>\n");
+    xml
+}
+
+// The styles, in the same order as Format.
+const STYLES: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>
+<styleSheet xmlns=\"http://schemas.openxmlformats.org/spreadsheetml/2006/main\">\
+<numFmts count=\"5\">\
+<numFmt numFmtId=\"164\" formatCode=\"dd/mm/yyyy\"/>\
+<numFmt numFmtId=\"165\" formatCode=\"mmm yyyy\"/>\
+<numFmt numFmtId=\"166\" formatCode=\"#,##0.000\"/>\
+<numFmt numFmtId=\"167\" formatCode=\"&quot;$&quot;#,##0.00\"/>\
+<numFmt numFmtId=\"168\" formatCode=\"&quot;$&quot;0.00000\"/>\
+</numFmts>\
+<fonts count=\"2\">\
+<font><sz val=\"11\"/><name val=\"Calibri\"/></font>\
+<font><b/><sz val=\"11\"/><name val=\"Calibri\"/></font>\
+</fonts>\
+<fills count=\"2\"><fill><patternFill patternType=\"none\"/></fill><fill><patternFill patternType=\"gray125\"/></fill></fills>\
+<borders count=\"1\"><border><left/><right/><top/><bottom/><diagonal/></border></borders>\
+<cellStyleXfs count=\"1\"><xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\"/></cellStyleXfs>\
+<cellXfs count=\"7\">\
+<xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\"/>\
+<xf numFmtId=\"164\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyNumberFormat=\"1\"/>\
+<xf numFmtId=\"165\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyNumberFormat=\"1\"/>\
+<xf numFmtId=\"166\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyNumberFormat=\"1\"/>\
+<xf numFmtId=\"167\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyNumberFormat=\"1\"/>\
+<xf numFmtId=\"168\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyNumberFormat=\"1\"/>\
+<xf numFmtId=\"0\" fontId=\"1\" fillId=\"0\" borderId=\"0\" xfId=\"0\" applyFont=\"1\"/>\
+</cellXfs>\
+<cellStyles count=\"1\"><cellStyle name=\"Normal\" xfId=\"0\" builtinId=\"0\"/></cellStyles>\
+</styleSheet>
+";
+
+/// An Office Open XML (.xlsx) workbook of the sheets. Formulas
+/// are recalculated when it is opened.
+pub fn write_xlsx(sheets: &[Sheet]) -> Vec<u8> {
+    let header = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+    let mut files = Vec::new();
+
+    let mut types = format!("{}<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">\
+                             <Default Extension=\"rels\" ContentType=\"application/vnd.openxmlformats-package.relationships+xml\"/>\
+                             <Default Extension=\"xml\" ContentType=\"application/xml\"/>\
+                             <Override PartName=\"/xl/workbook.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml\"/>\
+                             <Override PartName=\"/xl/styles.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.styles+xml\"/>",
+                            header);
+    for number in 1..=sheets.len() {
+        types.push_str(&format!("<Override PartName=\"/xl/worksheets/sheet{}.xml\" \
+                                 ContentType=\"application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml\"/>",
+                                number));
+    }
+    types.push_str("</Types>\n");
+    files.push(("[Content_Types].xml".to_string(), types));
+
+    files.push(("_rels/.rels".to_string(),
+                format!("{}<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">\
+                         <Relationship Id=\"rId1\" Type=\"{}/officeDocument\" Target=\"xl/workbook.xml\"/>\
+                         </Relationships>\n", header, RELATIONSHIPS)));
+
+    let mut workbook = format!("{}<workbook xmlns=\"{}\" xmlns:r=\"{}\"><sheets>",
+                               header, MAIN_NAMESPACE, RELATIONSHIPS);
+    let mut relationships = format!("{}<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">",
+                                    header);
+    for (index, sheet) in sheets.iter().enumerate() {
+        let number = index + 1;
+        workbook.push_str(&format!("<sheet name=\"{}\" sheetId=\"{}\" r:id=\"rId{}\"/>",
+                                   escape(&sheet.name), number, number));
+        relationships.push_str(&format!("<Relationship Id=\"rId{}\" Type=\"{}/worksheet\" \
+                                         Target=\"worksheets/sheet{}.xml\"/>",
+                                        number, RELATIONSHIPS, number));
+    }
+    workbook.push_str("</sheets><calcPr fullCalcOnLoad=\"1\"/></workbook>\n");
+    relationships.push_str(&format!("<Relationship Id=\"rId{}\" Type=\"{}/styles\" Target=\"styles.xml\"/>\
+                                     </Relationships>\n", sheets.len() + 1, RELATIONSHIPS));
+    files.push(("xl/workbook.xml".to_string(), workbook));
+    files.push(("xl/_rels/workbook.xml.rels".to_string(), relationships));
+    files.push(("xl/styles.xml".to_string(), STYLES.to_string()));
+
+    for (index, sheet) in sheets.iter().enumerate() {
+        files.push((format!("xl/worksheets/sheet{}.xml", index + 1), sheet_xml(sheet)));
+    }
+
+    zip(&files)
+}
+
+/// The CRC-32 checksum used by zip files.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// A zip archive of the files, stored without compression.
+fn zip(files: &[(String, String)]) -> Vec<u8> {
+    fn u16_le(output: &mut Vec<u8>, value: u16) {
+        output.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u32_le(output: &mut Vec<u8>, value: u32) {
+        output.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let mut output = Vec::new();
+    let mut directory = Vec::new();
+
+    for (name, contents) in files.iter() {
+        let offset = output.len() as u32;
+        let crc = crc32(contents.as_bytes());
+        let size = contents.len() as u32;
+
+        // The fields common to the local header and the directory entry.
+        let mut common = Vec::new();
+        u16_le(&mut common, 20);
+        u16_le(&mut common, 0);
+        u16_le(&mut common, 0);
+        u16_le(&mut common, ZIP_TIME);
+        u16_le(&mut common, ZIP_DATE);
+        u32_le(&mut common, crc);
+        u32_le(&mut common, size);
+        u32_le(&mut common, size);
+        u16_le(&mut common, name.len() as u16);
+        u16_le(&mut common, 0);
+
+        u32_le(&mut output, 0x0403_4b50);
+        output.extend_from_slice(&common);
+        output.extend_from_slice(name.as_bytes());
+        output.extend_from_slice(contents.as_bytes());
+
+        u32_le(&mut directory, 0x0201_4b50);
+        u16_le(&mut directory, 20);
+        directory.extend_from_slice(&common);
+        u16_le(&mut directory, 0);
+        u16_le(&mut directory, 0);
+        u16_le(&mut directory, 0);
+        u32_le(&mut directory, 0);
+        u32_le(&mut directory, offset);
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = output.len() as u32;
+    output.extend_from_slice(&directory);
+    u32_le(&mut output, 0x0605_4b50);
+    u16_le(&mut output, 0);
+    u16_le(&mut output, 0);
+    u16_le(&mut output, files.len() as u16);
+    u16_le(&mut output, files.len() as u16);
+    u32_le(&mut output, directory.len() as u32);
+    u32_le(&mut output, directory_offset);
+    u16_le(&mut output, 0);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::readings::steady_readings;
+
+    fn readings() -> Vec<Reading> {
+        steady_readings(&[NaiveDate::from_ymd(2020, 1, 25), NaiveDate::from_ymd(2020, 2, 4),
+                          NaiveDate::from_ymd(2020, 2, 10)])
+    }
+
+    #[test]
+    fn column_names() {
+        assert_eq!(column_name(0), "A");
+        assert_eq!(column_name(25), "Z");
+        assert_eq!(column_name(26), "AA");
+        assert_eq!(column_name(27 * 26), "AAA");
+    }
+
+    #[test]
+    fn date_serials() {
+        assert_eq!(date_serial(NaiveDate::from_ymd(1900, 3, 1)), 61);
+        assert_eq!(date_serial(NaiveDate::from_ymd(2020, 1, 1)), 43831);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn energy_workbook_sheets() {
+        let sheets = energy_workbook(&readings(), &Tariffs::flat(2.into(), 1.into()));
+        let names: Vec<&str> = sheets.iter().map(|sheet| sheet.name.as_str()).collect();
+        assert_eq!(names, ["Readings", "Changes", "Months", "Tariffs"]);
+
+        assert_eq!(sheets[0].rows.len(), 4);
+        assert_eq!(sheets[0].rows[1][0], Cell::Date(NaiveDate::from_ymd(2020, 1, 25), Format::Date));
+        assert_eq!(sheets[0].rows[1][4], Cell::Text("actual".to_string(), Format::General));
+
+        let changes = &sheets[1].rows;
+        assert_eq!(changes[1][2], Cell::Formula("B2-A2".to_string(), 10.into(), Format::General));
+        assert_eq!(changes[3][2], Cell::Formula("SUM(C2:C3)".to_string(), 16.into(), Format::General));
+        assert_eq!(changes[3][3], Cell::Formula("SUMPRODUCT(C2:C3,D2:D3)/C4".to_string(),
+                                                10.into(), Format::Energy));
+
+        let months = &sheets[2].rows;
+        assert_eq!(months.len(), 4);
+        assert_eq!(months[2][0], Cell::Date(NaiveDate::from_ymd(2020, 2, 1), Format::Month));
+        assert_eq!(months[3][6], Cell::Formula("SUM(G2:G3)".to_string(), 240.into(), Format::Dollars));
+
+        assert_eq!(sheets[3].rows[1][2], Cell::Number(2.into(), Format::Rate));
+    }
+
+    #[test]
+    fn write_xlsx_zip() {
+        let xlsx = write_xlsx(&energy_workbook(&readings(), &Tariffs::flat(2.into(), 1.into())));
+
+        assert!(xlsx.starts_with(b"PK\x03\x04"));
+        let end = &xlsx[xlsx.len() - 22..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        // Content types, two relationships, the workbook, styles and four sheets.
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 9);
+
+        let text = String::from_utf8_lossy(&xlsx);
+        assert!(text.contains("xl/worksheets/sheet4.xml"));
+        assert!(text.contains("<sheet name=\"Months\" sheetId=\"3\" r:id=\"rId3\"/>"));
+        assert!(text.contains("<c r=\"A2\" s=\"1\"><v>43855</v></c>"));
+        assert!(text.contains("<c r=\"G4\" s=\"4\"><f>SUM(G2:G3)</f><v>240</v></c>"));
+    }
+}